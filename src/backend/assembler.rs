@@ -6,6 +6,7 @@ pub struct ForwardJump {
 }
 use super::constants_x64::Register;
 use super::dseg::DSeg;
+use crate::trap::{TrapCode, TrapSite};
 use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 pub type Label = usize;
 
@@ -42,6 +43,10 @@ pub struct Assembler {
     pub dseg: DSeg,
     pub jumps: Vec<ForwardJump>,
     pub labels: Vec<Option<usize>>,
+    /// Instructions that may trap.
+    pub traps: Vec<TrapSite>,
+    /// Stubs raising a trap, emitted by `emit_trap_stubs`.
+    trap_stubs: Vec<(TrapCode, Label)>,
 }
 
 impl Assembler {
@@ -56,6 +61,8 @@ impl Assembler {
             dseg: DSeg::new(),
             jumps: Vec::new(),
             labels: Vec::new(),
+            traps: Vec::new(),
+            trap_stubs: Vec::new(),
         }
     }
 
@@ -110,6 +117,37 @@ impl Assembler {
         }
     }
 
+    /// Record that the instruction emitted next may trap with `code`.
+    pub fn trap_site(&mut self, code: TrapCode, divisor: Option<Register>) {
+        self.traps.push(TrapSite {
+            offset: self.pos(),
+            code,
+            divisor,
+        });
+    }
+
+    /// Label of the stub raising `code`, shared by all checks of the
+    /// function.
+    pub fn trap_label(&mut self, code: TrapCode) -> Label {
+        if let Some((_, label)) = self.trap_stubs.iter().find(|(c, _)| *c == code) {
+            return *label;
+        }
+        let label = self.create_label();
+        self.trap_stubs.push((code, label));
+        label
+    }
+
+    /// Emit the stubs of all labels handed out by `trap_label`.
+    pub fn emit_trap_stubs(&mut self) {
+        for (code, label) in std::mem::take(&mut self.trap_stubs) {
+            self.bind_label(label);
+            self.trap_site(code, None);
+            // ud2
+            self.emit(0x0f);
+            self.emit(0x0b);
+        }
+    }
+
     pub fn pos(&self) -> usize {
         self.data.len()
     }
@@ -2,6 +2,7 @@ use super::assembler::*;
 use super::assemblerx64 as buf;
 use super::constants_x64::*;
 use super::*;
+use crate::trap::TrapCode;
 
 pub fn fits_i32(n: i64) -> bool {
     n == (n as i32) as i64
@@ -21,21 +22,26 @@ impl Assembler {
     }
 
     pub fn load_float_const(&mut self, mode: MachineMode, dest: XMMRegister, imm: f64) {
-        let pos = self.pos() as i32;
-
-        match mode {
+        let disp = match mode {
             MachineMode::Float32 => {
-                let off = self.dseg.add_float(imm as f32);
-                buf::movss_load(self, dest, Mem::Base(RIP, -(off + pos + 8)));
+                let disp = self.dseg.add_float(imm as f32);
+                buf::movss_load(self, dest, Mem::Base(RIP, 0));
+                disp
             }
 
             MachineMode::Float64 => {
-                let off = self.dseg.add_double(imm);
-                buf::movsd_load(self, dest, Mem::Base(RIP, -(off + pos + 8)));
+                let disp = self.dseg.add_double(imm);
+                buf::movsd_load(self, dest, Mem::Base(RIP, 0));
+                disp
             }
 
             _ => unreachable!(),
-        }
+        };
+
+        // the length of the load depends on the REX prefix of `dest`
+        let after = self.pos() as i32;
+        let offset = -(disp + after);
+        self.emit_u32_at(after - 4, offset as u32);
     }
 
     pub fn load_true(&mut self, dest: Register) {
@@ -225,8 +231,7 @@ impl Assembler {
 
             Mem::Index(base, index, scale, disp) => match mode {
                 MachineMode::Int8 => {
-                    assert!(scale == 1);
-                    buf::emit_movzx_memindex_byte_reg(self, 0, base, index, disp, dest.reg())
+                    buf::emit_movzx_memindex_byte_reg(self, 0, base, index, scale, disp, dest.reg())
                 }
 
                 MachineMode::Int32 | MachineMode::Int64 | MachineMode::Ptr => {
@@ -237,7 +242,82 @@ impl Assembler {
                 MachineMode::Float64 => buf::movsd_load(self, dest.freg(), mem),
             },
 
-            Mem::Offset(_, _, _) => unimplemented!(),
+            Mem::Offset(_, _, _) => match mode {
+                MachineMode::Int8 => buf::movzxb_mem_reg(self, 0, dest.reg(), mem),
+                MachineMode::Int32 | MachineMode::Int64 | MachineMode::Ptr => {
+                    buf::mov_mem_reg(self, mode, dest.reg(), mem)
+                }
+                MachineMode::Float32 => buf::movss_load(self, dest.freg(), mem),
+                MachineMode::Float64 => buf::movsd_load(self, dest.freg(), mem),
+            },
+        }
+    }
+
+    /// Clear the sign bit of `src`, which is clobbered.
+    pub fn float_abs(&mut self, mode: MachineMode, dest: XMMRegister, src: XMMRegister) {
+        let (fst, snd) = if mode == MachineMode::Float32 {
+            (i32::MAX, 0)
+        } else {
+            (-1, i32::MAX)
+        };
+
+        // align MMX data to 16 bytes
+        self.dseg.align(16);
+        self.dseg.add_int(0);
+        self.dseg.add_int(0);
+        self.dseg.add_int(snd);
+        let disp = self.dseg.add_int(fst);
+
+        match mode {
+            MachineMode::Float32 => buf::andps(self, src, Mem::Base(RIP, 0)),
+            MachineMode::Float64 => buf::andpd(self, src, Mem::Base(RIP, 0)),
+            _ => unimplemented!(),
+        }
+
+        let after = self.pos() as i32;
+        let offset = -(disp + after);
+        self.emit_u32_at(after - 4, offset as u32);
+
+        if dest != src {
+            self.copy_freg(mode, dest, src);
+        }
+    }
+
+    /// Minimum of `lhs` and `rhs`, `rhs` if either is NaN or both are zero.
+    pub fn float_min(
+        &mut self,
+        mode: MachineMode,
+        dest: XMMRegister,
+        lhs: XMMRegister,
+        rhs: XMMRegister,
+    ) {
+        match mode {
+            MachineMode::Float32 => buf::minss(self, lhs, rhs),
+            MachineMode::Float64 => buf::minsd(self, lhs, rhs),
+            _ => unimplemented!(),
+        }
+
+        if dest != lhs {
+            self.copy_freg(mode, dest, lhs);
+        }
+    }
+
+    /// Maximum of `lhs` and `rhs`, `rhs` if either is NaN or both are zero.
+    pub fn float_max(
+        &mut self,
+        mode: MachineMode,
+        dest: XMMRegister,
+        lhs: XMMRegister,
+        rhs: XMMRegister,
+    ) {
+        match mode {
+            MachineMode::Float32 => buf::maxss(self, lhs, rhs),
+            MachineMode::Float64 => buf::maxsd(self, lhs, rhs),
+            _ => unimplemented!(),
+        }
+
+        if dest != lhs {
+            self.copy_freg(mode, dest, lhs);
         }
     }
 
@@ -312,7 +392,13 @@ impl Assembler {
                 MachineMode::Float64 => buf::movsd_store(self, mem, src.freg()),
             },
 
-            Mem::Offset(_, _, _) => unimplemented!(),
+            Mem::Offset(_, _, _) => match mode {
+                MachineMode::Int8 | MachineMode::Int32 | MachineMode::Int64 | MachineMode::Ptr => {
+                    buf::mov_reg_mem(self, mode, mem, src.reg())
+                }
+                MachineMode::Float32 => buf::movss_store(self, mem, src.freg()),
+                MachineMode::Float64 => buf::movsd_store(self, mem, src.freg()),
+            },
         }
     }
 
@@ -352,28 +438,18 @@ impl Assembler {
     }
 
     pub fn cmp_mem(&mut self, mode: MachineMode, mem: Mem, rhs: Register) {
-        match mem {
-            Mem::Local(offset) => buf::emit_cmp_mem_reg(self, mode, RBP, offset, rhs),
-            Mem::Base(base, disp) => buf::emit_cmp_mem_reg(self, mode, base, disp, rhs),
-            Mem::Index(base, index, scale, disp) => {
-                buf::emit_cmp_memindex_reg(self, mode, base, index, scale, disp, rhs)
-            }
-            Mem::Offset(_, _, _) => unimplemented!(),
-        }
+        buf::cmp_mem_reg(self, mode, mem, rhs);
     }
 
     pub fn cmp_mem_imm(&mut self, mode: MachineMode, mem: Mem, imm: i32) {
-        match mem {
-            Mem::Local(_) => unimplemented!(),
-            Mem::Base(base, disp) => buf::emit_cmp_mem_imm(self, mode, base, disp, imm),
-            Mem::Index(_, _, _, _) => unimplemented!(),
-            Mem::Offset(_, _, _) => unimplemented!(),
-        }
+        buf::cmp_mem_imm(self, mode, mem, imm);
     }
 
     pub fn cmp_reg(&mut self, mode: MachineMode, lhs: Register, rhs: Register) {
         let x64 = match mode {
-            MachineMode::Int8 | MachineMode::Int32 => 0,
+            // only the low byte of a byte register is defined
+            MachineMode::Int8 => return buf::emit_cmpb_reg_reg(self, rhs, lhs),
+            MachineMode::Int32 => 0,
             MachineMode::Int64 | MachineMode::Ptr => 1,
             MachineMode::Float32 | MachineMode::Float64 => unreachable!(),
         };
@@ -490,39 +566,92 @@ impl Assembler {
     }
 
     pub fn int_div(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
-        self.div_common(mode, dest, lhs, rhs, RAX);
+        self.div_dividend(mode, lhs, rhs);
+        self.int_div_rem(mode, dest, rhs, true, false, false);
     }
 
     pub fn int_mod(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
-        self.div_common(mode, dest, lhs, rhs, RDX);
+        self.div_dividend(mode, lhs, rhs);
+        self.int_div_rem(mode, dest, rhs, true, true, false);
+    }
+
+    fn div_dividend(&mut self, mode: MachineMode, lhs: Register, rhs: Register) {
+        if lhs != RAX {
+            assert!(rhs != RAX);
+            self.copy_reg(mode, RAX, lhs);
+        }
     }
 
-    fn div_common(
+    /// Quotient, or remainder if `rem` is set, of RAX and `rhs`. Uses RAX
+    /// and RDX, `rhs` must be neither.
+    ///
+    /// The signed remainder of a division by -1 is zero with either policy,
+    /// `idiv` would fault for the smallest dividend. With `checked` a zero
+    /// divisor and the overflowing signed division jump to trap stubs,
+    /// otherwise the division faults and is recorded as trap site.
+    pub fn int_div_rem(
         &mut self,
         mode: MachineMode,
         dest: Register,
-        lhs: Register,
         rhs: Register,
-        result: Register,
+        signed: bool,
+        rem: bool,
+        checked: bool,
     ) {
         let x64 = match mode {
             MachineMode::Int32 => 0,
             MachineMode::Int64 => 1,
             _ => unimplemented!(),
         };
+        let result = if rem { RDX } else { RAX };
+
+        let done = self.create_label();
+        if checked {
+            let zero = self.trap_label(TrapCode::IntegerDivisionByZero);
+            if x64 != 0 {
+                buf::emit_testq_reg_reg(self, rhs, rhs);
+            } else {
+                buf::emit_testl_reg_reg(self, rhs, rhs);
+            }
+            self.jump_if(CondCode::Zero, zero);
+        }
 
-        if lhs != RAX {
-            assert!(rhs != RAX);
-            buf::emit_mov_reg_reg(self, x64, lhs, RAX);
+        if signed && (checked || rem) {
+            let divide = self.create_label();
+            buf::emit_aluq_imm_reg(self, x64, -1, rhs, 0x3d, 0b111);
+            self.jump_if(CondCode::NotEqual, divide);
+            if rem {
+                buf::emit_xor_reg_reg(self, 0, RDX, RDX);
+                self.jump(done);
+            } else {
+                let overflow = self.trap_label(TrapCode::IntegerOverflow);
+                let min = if x64 != 0 { i64::MIN } else { i32::MIN as i64 };
+                self.load_int_const(mode, RDX, min);
+                buf::emit_cmp_reg_reg(self, x64, RDX, RAX);
+                self.jump_if(CondCode::Equal, overflow);
+            }
+            self.bind_label(divide);
         }
 
-        if x64 != 0 {
-            buf::emit_cqo(self);
+        if signed {
+            if x64 != 0 {
+                buf::emit_cqo(self);
+            } else {
+                buf::emit_cdq(self);
+            }
         } else {
-            buf::emit_cdq(self);
+            buf::emit_xor_reg_reg(self, 0, RDX, RDX);
         }
 
-        buf::emit_idiv_reg_reg(self, x64, rhs);
+        if !checked {
+            self.trap_site(TrapCode::IntegerDivisionByZero, Some(rhs));
+        }
+        if signed {
+            buf::emit_idiv_reg_reg(self, x64, rhs);
+        } else {
+            buf::emit_div_reg_reg(self, x64, rhs);
+        }
+        self.bind_label(done);
 
         if dest != result {
             buf::emit_mov_reg_reg(self, x64, result, dest);
@@ -651,7 +780,7 @@ impl Assembler {
 
     pub fn int_or(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
         let x64 = match mode {
-            MachineMode::Int32 => 0,
+            MachineMode::Int8 | MachineMode::Int32 => 0,
             MachineMode::Int64 => 1,
             _ => unimplemented!(),
         };
@@ -665,7 +794,7 @@ impl Assembler {
 
     pub fn int_and(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
         let x64 = match mode {
-            MachineMode::Int32 => 0,
+            MachineMode::Int8 | MachineMode::Int32 => 0,
             MachineMode::Int64 => 1,
             _ => unimplemented!(),
         };
@@ -679,7 +808,7 @@ impl Assembler {
 
     pub fn int_xor(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
         let x64 = match mode {
-            MachineMode::Int32 => 0,
+            MachineMode::Int8 | MachineMode::Int32 => 0,
             MachineMode::Int64 => 1,
             _ => unimplemented!(),
         };
@@ -691,6 +820,157 @@ impl Assembler {
         }
     }
 
+    pub fn int_rol(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
+        let x64 = match mode {
+            MachineMode::Int32 => 0,
+            MachineMode::Int64 => 1,
+            _ => unimplemented!(),
+        };
+
+        if rhs != RCX {
+            assert!(lhs != RCX);
+            buf::emit_mov_reg_reg(self, x64, rhs, RCX);
+        }
+
+        buf::emit_rol_reg_cl(self, x64, lhs);
+
+        if dest != lhs {
+            buf::emit_mov_reg_reg(self, x64, lhs, dest);
+        }
+    }
+
+    pub fn int_ror(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
+        let x64 = match mode {
+            MachineMode::Int32 => 0,
+            MachineMode::Int64 => 1,
+            _ => unimplemented!(),
+        };
+
+        if rhs != RCX {
+            assert!(lhs != RCX);
+            buf::emit_mov_reg_reg(self, x64, rhs, RCX);
+        }
+
+        buf::emit_ror_reg_cl(self, x64, lhs);
+
+        if dest != lhs {
+            buf::emit_mov_reg_reg(self, x64, lhs, dest);
+        }
+    }
+
+    /// Apply `emit` with the immediate `imm` to `lhs` and move the result to
+    /// `dest`.
+    fn int_op_imm(
+        &mut self,
+        mode: MachineMode,
+        dest: Register,
+        lhs: Register,
+        imm: i32,
+        emit: fn(&mut Assembler, u8, Register, i32),
+    ) {
+        let x64 = match mode {
+            MachineMode::Int8 | MachineMode::Int32 => 0,
+            MachineMode::Int64 | MachineMode::Ptr => 1,
+            _ => unimplemented!(),
+        };
+
+        emit(self, x64, lhs, imm);
+
+        if dest != lhs {
+            buf::emit_mov_reg_reg(self, x64, lhs, dest);
+        }
+    }
+
+    pub fn int_and_imm(&mut self, mode: MachineMode, dest: Register, lhs: Register, imm: i32) {
+        self.int_op_imm(mode, dest, lhs, imm, |buf, x64, reg, imm| {
+            buf::emit_and_imm_reg(buf, x64, imm, reg)
+        });
+    }
+
+    pub fn int_or_imm(&mut self, mode: MachineMode, dest: Register, lhs: Register, imm: i32) {
+        self.int_op_imm(mode, dest, lhs, imm, |buf, x64, reg, imm| {
+            buf::emit_or_imm_reg(buf, x64, imm, reg)
+        });
+    }
+
+    pub fn int_xor_imm(&mut self, mode: MachineMode, dest: Register, lhs: Register, imm: i32) {
+        self.int_op_imm(mode, dest, lhs, imm, |buf, x64, reg, imm| {
+            buf::emit_xor_imm_reg(buf, x64, imm, reg)
+        });
+    }
+
+    pub fn int_shl_imm(&mut self, mode: MachineMode, dest: Register, lhs: Register, imm: u8) {
+        self.int_op_imm(mode, dest, lhs, imm as i32, |buf, x64, reg, imm| {
+            buf::emit_shl_reg_imm(buf, x64, reg, imm as u8)
+        });
+    }
+
+    pub fn int_shr_imm(&mut self, mode: MachineMode, dest: Register, lhs: Register, imm: u8) {
+        self.int_op_imm(mode, dest, lhs, imm as i32, |buf, x64, reg, imm| {
+            buf::emit_shr_reg_imm(buf, x64, reg, imm as u8)
+        });
+    }
+
+    pub fn int_sar_imm(&mut self, mode: MachineMode, dest: Register, lhs: Register, imm: u8) {
+        self.int_op_imm(mode, dest, lhs, imm as i32, |buf, x64, reg, imm| {
+            buf::emit_sar_reg_imm(buf, x64, reg, imm as u8)
+        });
+    }
+
+    pub fn int_rol_imm(&mut self, mode: MachineMode, dest: Register, lhs: Register, imm: u8) {
+        self.int_op_imm(mode, dest, lhs, imm as i32, |buf, x64, reg, imm| {
+            buf::emit_rol_reg_imm(buf, x64, reg, imm as u8)
+        });
+    }
+
+    pub fn int_ror_imm(&mut self, mode: MachineMode, dest: Register, lhs: Register, imm: u8) {
+        self.int_op_imm(mode, dest, lhs, imm as i32, |buf, x64, reg, imm| {
+            buf::emit_ror_reg_imm(buf, x64, reg, imm as u8)
+        });
+    }
+
+    /// Count the set bits of `src`. Needs a CPU with `popcnt`.
+    pub fn int_popcnt(&mut self, mode: MachineMode, dest: Register, src: Register) {
+        let x64 = match mode {
+            MachineMode::Int32 => 0,
+            MachineMode::Int64 => 1,
+            _ => unimplemented!(),
+        };
+
+        buf::emit_popcnt(self, x64, src, dest);
+    }
+
+    /// Count the leading zero bits of `src`. R11 is clobbered.
+    pub fn int_clz(&mut self, mode: MachineMode, dest: Register, src: Register) {
+        let (x64, bits) = match mode {
+            MachineMode::Int32 => (0, 32),
+            MachineMode::Int64 => (1, 64),
+            _ => unimplemented!(),
+        };
+
+        // bsr leaves the destination undefined for zero, pretend the highest
+        // set bit is at -1 then
+        self.load_int_const(MachineMode::Int64, R11, -1);
+        buf::emit_bsr(self, x64, src, dest);
+        buf::cmov(self, x64, dest, R11, CondCode::Zero);
+        buf::emit_neg_reg(self, x64, dest);
+        buf::emit_aluq_imm_reg(self, x64, bits - 1, dest, 0x05, 0);
+    }
+
+    /// Count the trailing zero bits of `src`. R11 is clobbered.
+    pub fn int_ctz(&mut self, mode: MachineMode, dest: Register, src: Register) {
+        let (x64, bits) = match mode {
+            MachineMode::Int32 => (0, 32),
+            MachineMode::Int64 => (1, 64),
+            _ => unimplemented!(),
+        };
+
+        // bsf leaves the destination undefined for zero
+        self.load_int_const(MachineMode::Int32, R11, bits);
+        buf::emit_bsf(self, x64, src, dest);
+        buf::cmov(self, x64, dest, R11, CondCode::Zero);
+    }
+
     pub fn int_to_float(
         &mut self,
         dest_mode: MachineMode,
@@ -733,6 +1013,126 @@ impl Assembler {
         }
     }
 
+    /// Convert the unsigned integer in `src` to a float. `src` is left
+    /// unchanged, RAX and R11 are clobbered.
+    pub fn uint_to_float(
+        &mut self,
+        dest_mode: MachineMode,
+        dest: XMMRegister,
+        src_mode: MachineMode,
+        src: Register,
+    ) {
+        if src_mode != MachineMode::Int64 {
+            // zero extended it fits into a signed 64 bit integer
+            buf::emit_mov_reg_reg(self, 0, src, RAX);
+            self.int_to_float(dest_mode, dest, MachineMode::Int64, RAX);
+            return;
+        }
+
+        let big = self.create_label();
+        let done = self.create_label();
+        buf::emit_testq_reg_reg(self, src, src);
+        self.jump_if(CondCode::Less, big);
+        self.int_to_float(dest_mode, dest, MachineMode::Int64, src);
+        self.jump(done);
+
+        // halve the value keeping the lowest bit for rounding, then double it
+        self.bind_label(big);
+        buf::emit_mov_reg_reg(self, 1, src, RAX);
+        buf::emit_shr_reg_imm(self, 1, RAX, 1);
+        self.load_int_const(MachineMode::Int64, R11, 1);
+        buf::emit_and_reg_reg(self, 1, src, R11);
+        buf::emit_or_reg_reg(self, 1, R11, RAX);
+        self.int_to_float(dest_mode, dest, MachineMode::Int64, RAX);
+        self.float_add(dest_mode, dest, dest, dest);
+        self.bind_label(done);
+    }
+
+    /// Truncate the double in `src` to an integer of `bits` bits. Out of
+    /// range values and NaN jump to trap stubs, or saturate when `saturate`
+    /// is set. `src` and XMM1 are clobbered.
+    pub fn float_to_int_checked(
+        &mut self,
+        dest: Register,
+        src: XMMRegister,
+        bits: u32,
+        signed: bool,
+        saturate: bool,
+    ) {
+        let two = |exp: u32| (2.0f64).powi(exp as i32);
+        // values have to be above or at `lo` and below `hi`
+        let (lo, lo_inclusive, hi) = match (signed, bits) {
+            (true, 64) => (-two(63), true, two(63)),
+            (true, _) => (-two(bits - 1) - 1.0, false, two(bits - 1)),
+            (false, _) => (-1.0, false, two(bits)),
+        };
+        let (min, max): (i64, i64) = match (signed, bits) {
+            (true, 64) => (i64::MIN, i64::MAX),
+            (true, _) => (-(1 << (bits - 1)), (1 << (bits - 1)) - 1),
+            (false, 64) => (0, -1),
+            (false, _) => (0, (1 << bits) - 1),
+        };
+        let x64 = if bits == 64 || (bits == 32 && !signed) {
+            1
+        } else {
+            0
+        };
+
+        let (nan, below, above) = if saturate {
+            (
+                self.create_label(),
+                self.create_label(),
+                self.create_label(),
+            )
+        } else {
+            (
+                self.trap_label(TrapCode::BadConversionToInteger),
+                self.trap_label(TrapCode::IntegerOverflow),
+                self.trap_label(TrapCode::IntegerOverflow),
+            )
+        };
+        let done = self.create_label();
+
+        buf::ucomisd(self, src, src);
+        buf::emit_jp(self, nan);
+        self.load_float_const(MachineMode::Float64, XMM1, lo);
+        buf::ucomisd(self, src, XMM1);
+        let cond = if lo_inclusive {
+            CondCode::UnsignedLess
+        } else {
+            CondCode::UnsignedLessEq
+        };
+        self.jump_if(cond, below);
+        self.load_float_const(MachineMode::Float64, XMM1, hi);
+        buf::ucomisd(self, src, XMM1);
+        self.jump_if(CondCode::UnsignedGreaterEq, above);
+
+        if !signed && bits == 64 {
+            // values from 2^63 on are converted with the top bit cleared
+            let small = self.create_label();
+            self.load_float_const(MachineMode::Float64, XMM1, two(63));
+            buf::ucomisd(self, src, XMM1);
+            self.jump_if(CondCode::UnsignedLess, small);
+            buf::subsd(self, src, XMM1);
+            buf::cvttsd2si(self, 1, dest, src);
+            self.load_int_const(MachineMode::Int64, R11, i64::MIN);
+            buf::emit_xor_reg_reg(self, 1, R11, dest);
+            self.jump(done);
+            self.bind_label(small);
+        }
+        buf::cvttsd2si(self, x64, dest, src);
+        self.jump(done);
+
+        if saturate {
+            for (label, value) in [(nan, 0), (below, min), (above, max)].iter() {
+                self.bind_label(*label);
+                self.load_int_const(MachineMode::Int64, dest, *value);
+                self.jump(done);
+            }
+        }
+        self.bind_label(done);
+    }
+
     pub fn float_to_double(&mut self, dest: XMMRegister, src: XMMRegister) {
         buf::cvtss2sd(self, dest, src);
     }
@@ -847,7 +1247,7 @@ pub fn emit_movb_reg_memq(buf: &mut Assembler, src: Register, dest: Register, di
     let dest_msb = if dest == RIP { 0 } else { dest.msb() };
 
     if dest_msb != 0 || src.msb() != 0 || (src != RAX && src != RBX && src != RCX && src != RDX) {
-        emit_rex(buf, 0, src.msb(), 0, dest.msb());
+        emit_rex(buf, 0, src.msb(), 0, dest_msb);
     }
 
     emit_op(buf, 0x88);
@@ -1019,6 +1419,18 @@ pub fn emit_andq_imm_reg(buf: &mut Assembler, imm: i32, reg: Register) {
     emit_aluq_imm_reg(buf, 1, imm, reg, 0x25, 4);
 }
 
+pub fn emit_and_imm_reg(buf: &mut Assembler, x64: u8, imm: i32, reg: Register) {
+    emit_aluq_imm_reg(buf, x64, imm, reg, 0x25, 0b100);
+}
+
+pub fn emit_or_imm_reg(buf: &mut Assembler, x64: u8, imm: i32, reg: Register) {
+    emit_aluq_imm_reg(buf, x64, imm, reg, 0x0d, 0b001);
+}
+
+pub fn emit_xor_imm_reg(buf: &mut Assembler, x64: u8, imm: i32, reg: Register) {
+    emit_aluq_imm_reg(buf, x64, imm, reg, 0x35, 0b110);
+}
+
 fn emit_aluq_imm_reg(
     buf: &mut Assembler,
     x64: u8,
@@ -1220,6 +1632,24 @@ pub fn emit_movsx(buf: &mut Assembler, src: Register, dest: Register) {
     emit_modrm(buf, 0b11, dest.and7(), src.and7());
 }
 
+/// Sign extend the low byte of `src`.
+pub fn emit_movsx_byte(buf: &mut Assembler, x64: u8, src: Register, dest: Register) {
+    if src.msb() != 0 || dest.msb() != 0 || x64 != 0 || !src.is_basic_reg() {
+        emit_rex(buf, x64, dest.msb(), 0, src.msb());
+    }
+
+    emit_op(buf, 0x0f);
+    emit_op(buf, 0xbe);
+    emit_modrm(buf, 0b11, dest.and7(), src.and7());
+}
+
+/// Jump if the last float comparison was unordered.
+pub fn emit_jp(buf: &mut Assembler, lbl: Label) {
+    emit_op(buf, 0x0f);
+    emit_op(buf, 0x8a);
+    buf.emit_label(lbl);
+}
+
 pub fn emit_jmp(buf: &mut Assembler, lbl: Label) {
     emit_op(buf, 0xe9);
     buf.emit_label(lbl);
@@ -1254,7 +1684,107 @@ pub fn lea(buf: &mut Assembler, dest: Register, src: Mem) {
     emit_mem(buf, dest, &src);
 }
 
+/// `movzx dest, byte [src]`, zero extending to 32 or 64 bits.
+pub fn movzxb_mem_reg(buf: &mut Assembler, x64: u8, dest: Register, src: Mem) {
+    emit_rex_mem(buf, x64, dest, &src);
+    emit_op(buf, 0x0f);
+    emit_op(buf, 0xb6);
+    emit_mem(buf, dest, &src);
+}
+
+pub fn mov_mem_reg(buf: &mut Assembler, mode: MachineMode, dest: Register, src: Mem) {
+    match mode {
+        MachineMode::Int8 => {
+            emit_rex_mem_byte(buf, dest, &src);
+            emit_op(buf, 0x8a);
+        }
+        MachineMode::Int32 => {
+            emit_rex_mem(buf, 0, dest, &src);
+            emit_op(buf, 0x8b);
+        }
+        MachineMode::Int64 | MachineMode::Ptr => {
+            emit_rex_mem(buf, 1, dest, &src);
+            emit_op(buf, 0x8b);
+        }
+        MachineMode::Float32 | MachineMode::Float64 => unreachable!(),
+    }
+    emit_mem(buf, dest, &src);
+}
+
+pub fn mov_reg_mem(buf: &mut Assembler, mode: MachineMode, dest: Mem, src: Register) {
+    match mode {
+        MachineMode::Int8 => {
+            emit_rex_mem_byte(buf, src, &dest);
+            emit_op(buf, 0x88);
+        }
+        MachineMode::Int32 => {
+            emit_rex_mem(buf, 0, src, &dest);
+            emit_op(buf, 0x89);
+        }
+        MachineMode::Int64 | MachineMode::Ptr => {
+            emit_rex_mem(buf, 1, src, &dest);
+            emit_op(buf, 0x89);
+        }
+        MachineMode::Float32 | MachineMode::Float64 => unreachable!(),
+    }
+    emit_mem(buf, src, &dest);
+}
+
+/// `cmp [lhs], rhs`
+pub fn cmp_mem_reg(buf: &mut Assembler, mode: MachineMode, lhs: Mem, rhs: Register) {
+    match mode {
+        MachineMode::Int8 => {
+            emit_rex_mem_byte(buf, rhs, &lhs);
+            emit_op(buf, 0x38);
+        }
+        MachineMode::Int32 => {
+            emit_rex_mem(buf, 0, rhs, &lhs);
+            emit_op(buf, 0x39);
+        }
+        MachineMode::Int64 | MachineMode::Ptr => {
+            emit_rex_mem(buf, 1, rhs, &lhs);
+            emit_op(buf, 0x39);
+        }
+        MachineMode::Float32 | MachineMode::Float64 => unreachable!(),
+    }
+    emit_mem(buf, rhs, &lhs);
+}
+
+/// `cmp [lhs], imm`, the immediate is sign extended to the size of `mode`.
+pub fn cmp_mem_imm(buf: &mut Assembler, mode: MachineMode, lhs: Mem, imm: i32) {
+    let (x64, opcode) = match mode {
+        MachineMode::Int8 => {
+            assert!(fits_i8(imm), "Int8 does not support 32 bit values");
+            (0, 0x80)
+        }
+        MachineMode::Int32 => (0, if fits_i8(imm) { 0x83 } else { 0x81 }),
+        MachineMode::Int64 | MachineMode::Ptr => (1, if fits_i8(imm) { 0x83 } else { 0x81 }),
+        MachineMode::Float32 | MachineMode::Float64 => unreachable!(),
+    };
+
+    // the register field holds the opcode extension, 7 for cmp
+    emit_rex_mem(buf, x64, RAX, &lhs);
+    emit_op(buf, opcode);
+    emit_mem(buf, RDI, &lhs);
+
+    if fits_i8(imm) {
+        emit(buf, imm as u8);
+    } else {
+        emit32(buf, imm as u32);
+    }
+}
+
 pub fn emit_rex_mem(buf: &mut Assembler, x64: u8, dest: Register, src: &Mem) {
+    emit_rex_mem_with(buf, x64, dest, src, false);
+}
+
+/// REX prefix for instructions using the low byte of `dest`, SPL, BPL, SIL
+/// and DIL are only accessible with one.
+fn emit_rex_mem_byte(buf: &mut Assembler, dest: Register, src: &Mem) {
+    emit_rex_mem_with(buf, 0, dest, src, !dest.is_basic_reg());
+}
+
+fn emit_rex_mem_with(buf: &mut Assembler, x64: u8, dest: Register, src: &Mem, force: bool) {
     assert!(x64 == 0 || x64 == 1);
 
     let (base_msb, index_msb) = match src {
@@ -1269,7 +1799,7 @@ pub fn emit_rex_mem(buf: &mut Assembler, x64: u8, dest: Register, src: &Mem) {
         &Mem::Offset(index, _, _) => (0, index.msb()),
     };
 
-    if dest.msb() != 0 || index_msb != 0 || base_msb != 0 || x64 != 0 {
+    if dest.msb() != 0 || index_msb != 0 || base_msb != 0 || x64 != 0 || force {
         emit_rex(buf, x64, dest.msb(), index_msb, base_msb);
     }
 }
@@ -1329,6 +1859,15 @@ pub fn emit_imul_reg_reg(buf: &mut Assembler, x64: u8, src: Register, dest: Regi
     emit_modrm(buf, 0b11, dest.and7(), src.and7());
 }
 
+pub fn emit_div_reg_reg(buf: &mut Assembler, x64: u8, reg: Register) {
+    if reg.msb() != 0 || x64 != 0 {
+        emit_rex(buf, x64, 0, 0, reg.msb());
+    }
+
+    emit_op(buf, 0xf7);
+    emit_modrm(buf, 0b11, 0b110, reg.and7());
+}
+
 pub fn emit_idiv_reg_reg(buf: &mut Assembler, x64: u8, reg: Register) {
     if reg.msb() != 0 || x64 != 0 {
         emit_rex(buf, x64, 0, 0, reg.msb());
@@ -1377,7 +1916,6 @@ pub fn emit_mov_memindex_reg(
     dest: Register,
 ) {
     assert!(scale == 8 || scale == 4 || scale == 2 || scale == 1);
-    assert!(mode.size() as i32 == scale);
 
     let (x64, opcode) = match mode {
         MachineMode::Int8 => (0, 0x8a),
@@ -1399,6 +1937,7 @@ pub fn emit_movzx_memindex_byte_reg(
     x64: u8,
     base: Register,
     index: Register,
+    scale: i32,
     disp: i32,
     dest: Register,
 ) {
@@ -1408,7 +1947,7 @@ pub fn emit_movzx_memindex_byte_reg(
 
     emit_op(buf, 0x0f);
     emit_op(buf, 0xb6);
-    emit_membase_with_index_and_scale(buf, base, index, 1, disp, dest);
+    emit_membase_with_index_and_scale(buf, base, index, scale, disp, dest);
 }
 
 pub fn emit_mov_reg_memindex(
@@ -1429,7 +1968,9 @@ pub fn emit_mov_reg_memindex(
         MachineMode::Float32 | MachineMode::Float64 => unreachable!(),
     };
 
-    if x64 != 0 || src.msb() != 0 || index.msb() != 0 || base.msb() != 0 {
+    // without REX the byte registers after BL are AH, CH, DH and BH
+    let byte_rex = mode == MachineMode::Int8 && !src.is_basic_reg();
+    if x64 != 0 || src.msb() != 0 || index.msb() != 0 || base.msb() != 0 || byte_rex {
         emit_rex(buf, x64, src.msb(), index.msb(), base.msb());
     }
 
@@ -1509,6 +2050,8 @@ fn emit_membase_without_base(
     dest: Register,
 ) {
     assert!(scale == 8 || scale == 4 || scale == 2 || scale == 1);
+    // an index of RSP in the SIB byte means no index
+    assert!(index != RSP);
 
     let scale = match scale {
         8 => 3,
@@ -1517,6 +2060,7 @@ fn emit_membase_without_base(
         _ => 0,
     };
 
+    // mod 00 with base RBP is the form without base and a 32 bit displacement
     emit_modrm(buf, 0, dest.and7(), 4);
     emit_sib(buf, scale, index.and7(), 5);
     emit32(buf, disp as u32);
@@ -1531,6 +2075,7 @@ fn emit_membase_with_index_and_scale(
     dest: Register,
 ) {
     assert!(scale == 8 || scale == 4 || scale == 2 || scale == 1);
+    assert!(index != RSP);
 
     let scale = match scale {
         8 => 3,
@@ -1539,7 +2084,8 @@ fn emit_membase_with_index_and_scale(
         _ => 0,
     };
 
-    if disp == 0 {
+    // mod 00 with RBP or R13 as base means no base at all
+    if disp == 0 && base.and7() != RBP.and7() {
         emit_modrm(buf, 0, dest.and7(), 4);
         emit_sib(buf, scale, index.and7(), base.and7());
     } else if fits_i8(disp) {
@@ -1617,6 +2163,15 @@ pub fn emit_movzbl_reg_reg(buf: &mut Assembler, src: Register, dest: Register) {
     emit_modrm(buf, 0b11, dest.and7(), src.and7());
 }
 
+pub fn emit_cmpb_reg_reg(buf: &mut Assembler, src: Register, dest: Register) {
+    if src.msb() != 0 || dest.msb() != 0 || !src.is_basic_reg() || !dest.is_basic_reg() {
+        emit_rex(buf, 0, src.msb(), 0, dest.msb());
+    }
+
+    emit_op(buf, 0x38);
+    emit_modrm(buf, 0b11, src.and7(), dest.and7());
+}
+
 pub fn emit_cmpb_imm_reg(buf: &mut Assembler, imm: u8, dest: Register) {
     if dest == RAX {
         emit_op(buf, 0x3c);
@@ -1665,6 +2220,12 @@ pub fn emit_callq_reg(buf: &mut Assembler, dest: Register) {
     emit_modrm(buf, 0b11, 0b10, dest.and7());
 }
 
+/// `call rel32`, the displacement is relative to the end of the instruction.
+pub fn emit_callq_rel32(buf: &mut Assembler, disp: i32) {
+    emit_op(buf, 0xe8);
+    emit32(buf, disp as u32);
+}
+
 pub fn emit_shlq_reg(buf: &mut Assembler, imm: u8, dest: Register) {
     emit_rex(buf, 1, 0, 0, dest.msb());
     emit_op(buf, 0xC1);
@@ -1701,18 +2262,81 @@ pub fn emit_shr_reg_cl(buf: &mut Assembler, x64: u8, dest: Register) {
 }
 
 pub fn emit_shr_reg_imm(buf: &mut Assembler, x64: u8, dest: Register, imm: u8) {
+    emit_shift_reg_imm(buf, x64, 0b101, dest, imm);
+}
+
+pub fn emit_shl_reg_imm(buf: &mut Assembler, x64: u8, dest: Register, imm: u8) {
+    emit_shift_reg_imm(buf, x64, 0b100, dest, imm);
+}
+
+pub fn emit_sar_reg_imm(buf: &mut Assembler, x64: u8, dest: Register, imm: u8) {
+    emit_shift_reg_imm(buf, x64, 0b111, dest, imm);
+}
+
+pub fn emit_rol_reg_imm(buf: &mut Assembler, x64: u8, dest: Register, imm: u8) {
+    emit_shift_reg_imm(buf, x64, 0b000, dest, imm);
+}
+
+pub fn emit_ror_reg_imm(buf: &mut Assembler, x64: u8, dest: Register, imm: u8) {
+    emit_shift_reg_imm(buf, x64, 0b001, dest, imm);
+}
+
+/// Shift or rotate `dest` by `imm`, using the shorter encoding without
+/// immediate for a single bit.
+fn emit_shift_reg_imm(buf: &mut Assembler, x64: u8, modrm_reg: u8, dest: Register, imm: u8) {
     if dest.msb() != 0 || x64 != 0 {
         emit_rex(buf, x64, 0, 0, dest.msb());
     }
 
     emit_op(buf, if imm == 1 { 0xD1 } else { 0xC1 });
-    emit_modrm(buf, 0b11, 0b101, dest.and7());
+    emit_modrm(buf, 0b11, modrm_reg, dest.and7());
 
     if imm != 1 {
         emit(buf, imm);
     }
 }
 
+pub fn emit_rol_reg_cl(buf: &mut Assembler, x64: u8, dest: Register) {
+    if dest.msb() != 0 || x64 != 0 {
+        emit_rex(buf, x64, 0, 0, dest.msb());
+    }
+
+    emit_op(buf, 0xD3);
+    emit_modrm(buf, 0b11, 0b000, dest.and7());
+}
+
+pub fn emit_ror_reg_cl(buf: &mut Assembler, x64: u8, dest: Register) {
+    if dest.msb() != 0 || x64 != 0 {
+        emit_rex(buf, x64, 0, 0, dest.msb());
+    }
+
+    emit_op(buf, 0xD3);
+    emit_modrm(buf, 0b11, 0b001, dest.and7());
+}
+
+pub fn emit_popcnt(buf: &mut Assembler, x64: u8, src: Register, dest: Register) {
+    emit_op(buf, 0xf3);
+    emit_bit_scan(buf, 0xb8, x64, src, dest);
+}
+
+pub fn emit_bsr(buf: &mut Assembler, x64: u8, src: Register, dest: Register) {
+    emit_bit_scan(buf, 0xbd, x64, src, dest);
+}
+
+pub fn emit_bsf(buf: &mut Assembler, x64: u8, src: Register, dest: Register) {
+    emit_bit_scan(buf, 0xbc, x64, src, dest);
+}
+
+fn emit_bit_scan(buf: &mut Assembler, opcode: u8, x64: u8, src: Register, dest: Register) {
+    if x64 != 0 || src.msb() != 0 || dest.msb() != 0 {
+        emit_rex(buf, x64, dest.msb(), 0, src.msb());
+    }
+
+    emit_op(buf, 0x0f);
+    emit_op(buf, opcode);
+    emit_modrm(buf, 0b11, dest.and7(), src.and7());
+}
+
 pub fn emit_sar_reg_cl(buf: &mut Assembler, x64: u8, dest: Register) {
     if dest.msb() != 0 || x64 != 0 {
         emit_rex(buf, x64, 0, 0, dest.msb());
@@ -1772,6 +2396,22 @@ pub fn sqrtsd(buf: &mut Assembler, dest: XMMRegister, src: XMMRegister) {
     sse_float_freg_freg(buf, true, 0x51, dest, src);
 }
 
+pub fn minss(buf: &mut Assembler, dest: XMMRegister, src: XMMRegister) {
+    sse_float_freg_freg(buf, false, 0x5d, dest, src);
+}
+
+pub fn minsd(buf: &mut Assembler, dest: XMMRegister, src: XMMRegister) {
+    sse_float_freg_freg(buf, true, 0x5d, dest, src);
+}
+
+pub fn maxss(buf: &mut Assembler, dest: XMMRegister, src: XMMRegister) {
+    sse_float_freg_freg(buf, false, 0x5f, dest, src);
+}
+
+pub fn maxsd(buf: &mut Assembler, dest: XMMRegister, src: XMMRegister) {
+    sse_float_freg_freg(buf, true, 0x5f, dest, src);
+}
+
 pub fn movaps(buf: &mut Assembler, dest: XMMRegister, src: XMMRegister) {
     sse_packed_freg_freg(buf, 0x28, dest, src);
 }
@@ -1882,13 +2522,14 @@ pub fn movq_freg_reg(buf: &mut Assembler, dest: XMMRegister, src: Register) {
 
 fn sse_reg_freg(buf: &mut Assembler, op: u8, x64: u8, dest: Register, src: XMMRegister) {
     emit_op(buf, op);
+    // the XMM register goes into the reg field of ModRM
     if x64 != 0 || dest.msb() != 0 || src.msb() != 0 {
-        emit_rex(buf, x64, dest.msb(), 0, src.msb());
+        emit_rex(buf, x64, src.msb(), 0, dest.msb());
     }
 
     emit_op(buf, 0x0f);
     emit_op(buf, 0x7e);
-    emit_modrm(buf, 0b11, dest.and7(), src.and7());
+    emit_modrm(buf, 0b11, src.and7(), dest.and7());
 }
 
 fn sse_freg_reg(buf: &mut Assembler, op: u8, x64: u8, dest: XMMRegister, src: Register) {
@@ -1914,6 +2555,14 @@ pub fn xorpd(buf: &mut Assembler, dest: XMMRegister, src: Mem) {
     sse_float_freg_mem_66(buf, true, 0x57, dest, src);
 }
 
+pub fn andps(buf: &mut Assembler, dest: XMMRegister, src: Mem) {
+    sse_float_freg_mem_66(buf, false, 0x54, dest, src);
+}
+
+pub fn andpd(buf: &mut Assembler, dest: XMMRegister, src: Mem) {
+    sse_float_freg_mem_66(buf, true, 0x54, dest, src);
+}
+
 pub fn sse_packed_freg_freg(buf: &mut Assembler, op: u8, dest: XMMRegister, src: XMMRegister) {
     if dest.msb() != 0 || src.msb() != 0 {
         emit_rex(buf, 0, dest.msb(), 0, src.msb());
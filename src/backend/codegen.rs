@@ -0,0 +1,1080 @@
+//! Lowering of `ir::Body` to x86-64 machine code.
+
+use super::assembler::*;
+use super::assemblerx64::*;
+use super::constants_x64::*;
+use super::regalloc::*;
+use super::*;
+use crate::function::{Reloc, RelocKind};
+use crate::ir::*;
+use crate::trap::TrapPolicy;
+use crate::types::*;
+use std::collections::HashMap;
+
+const SYSV_ARG_GPR: [Register; 6] = [RDI, RSI, RDX, RCX, R8, R9];
+const SYSV_ARG_FPR: [XMMRegister; 8] = [XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6, XMM7];
+const WIN_ARG_GPR: [Register; 4] = [RCX, RDX, R8, R9];
+const WIN_ARG_FPR: [XMMRegister; 4] = [XMM0, XMM1, XMM2, XMM3];
+
+/// Whether `reg` has to be preserved across calls under `call_conv`.
+pub fn is_callee_saved(reg: Reg, call_conv: CallConv) -> bool {
+    match (call_conv, reg) {
+        (CallConv::SystemV, Reg::Gpr(reg)) => {
+            matches!(reg, RBX | RBP | R12 | R13 | R14 | R15)
+        }
+        (CallConv::SystemV, Reg::Float(_)) => false,
+        (CallConv::WindowsFastcall, Reg::Gpr(reg)) => {
+            matches!(reg, RBX | RBP | RDI | RSI | R12 | R13 | R14 | R15)
+        }
+        (CallConv::WindowsFastcall, Reg::Float(reg)) => reg as i32 >= XMM6 as i32,
+    }
+}
+
+/// Argument registers for `params` under `call_conv`. `None` marks arguments
+/// passed on the stack.
+pub fn arg_registers(params: &[Type], call_conv: CallConv) -> Vec<Option<Reg>> {
+    let (mut gpr, mut fpr) = (0, 0);
+
+    params
+        .iter()
+        .enumerate()
+        .map(|(idx, ty)| match call_conv {
+            CallConv::WindowsFastcall => {
+                if idx >= WIN_ARG_GPR.len() {
+                    None
+                } else if ty.is_float() {
+                    Some(Reg::Float(WIN_ARG_FPR[idx]))
+                } else {
+                    Some(Reg::Gpr(WIN_ARG_GPR[idx]))
+                }
+            }
+            CallConv::SystemV => {
+                if ty.is_float() {
+                    fpr += 1;
+                    SYSV_ARG_FPR.get(fpr - 1).map(|reg| Reg::Float(*reg))
+                } else {
+                    gpr += 1;
+                    SYSV_ARG_GPR.get(gpr - 1).map(|reg| Reg::Gpr(*reg))
+                }
+            }
+        })
+        .collect()
+}
+
+pub struct Codegen<'a> {
+    body: &'a Body,
+    signature: &'a Signature,
+    alloc: &'a Allocation,
+    points: &'a ProgramPoints,
+    /// Needed to fix up split values on control flow edges, `None` when the
+    /// allocation never splits values.
+    liveness: Option<&'a Liveness>,
+    asm: &'a mut Assembler,
+    relocs: &'a mut Vec<Reloc>,
+    trap_policy: TrapPolicy,
+    indirect_calls: bool,
+    /// Data segment entries holding the slot address of a symbol.
+    slot_entries: HashMap<String, i32>,
+    block_labels: HashMap<Block, Label>,
+    epilog: Label,
+    /// Position of the frame size immediate in the prolog.
+    frame_size_at: usize,
+    /// Lowest RBP offset used by the frame.
+    stack_offset: i32,
+    /// Callee-saved registers clobbered by the function and the RBP offset
+    /// they are saved at.
+    saved_regs: Vec<(Reg, i32)>,
+    /// Slots caller-saved registers are preserved in around calls.
+    call_save_slots: HashMap<Reg, i32>,
+    /// Size of the area at the bottom of the frame holding outgoing stack
+    /// arguments.
+    outgoing_size: i32,
+}
+
+impl<'a> Codegen<'a> {
+    pub(crate) fn new(
+        body: &'a Body,
+        signature: &'a Signature,
+        alloc: &'a Allocation,
+        points: &'a ProgramPoints,
+        liveness: Option<&'a Liveness>,
+        asm: &'a mut Assembler,
+        relocs: &'a mut Vec<Reloc>,
+    ) -> Codegen<'a> {
+        let mut block_labels = HashMap::new();
+        for block in body.layout.iter() {
+            block_labels.insert(*block, asm.create_label());
+        }
+        let epilog = asm.create_label();
+
+        Codegen {
+            body,
+            signature,
+            alloc,
+            points,
+            liveness,
+            asm,
+            relocs,
+            trap_policy: TrapPolicy::Hardware,
+            indirect_calls: false,
+            slot_entries: HashMap::new(),
+            block_labels,
+            epilog,
+            frame_size_at: 0,
+            stack_offset: alloc.stack_offset,
+            saved_regs: vec![],
+            call_save_slots: HashMap::new(),
+            outgoing_size: 0,
+        }
+    }
+
+    pub fn set_trap_policy(&mut self, trap_policy: TrapPolicy) {
+        self.trap_policy = trap_policy;
+    }
+
+    pub fn set_indirect_calls(&mut self, indirect_calls: bool) {
+        self.indirect_calls = indirect_calls;
+    }
+
+    /// Labels the blocks were bound to.
+    pub fn block_labels(&self) -> &HashMap<Block, Label> {
+        &self.block_labels
+    }
+
+    pub fn generate(&mut self) {
+        self.prolog();
+
+        let layout = &self.body.layout;
+        for (idx, block) in layout.iter().enumerate() {
+            let next = layout.get(idx + 1).cloned();
+            self.asm.bind_label(self.block_labels[block]);
+
+            if idx == 0 {
+                self.entry_params(*block);
+            }
+            self.spill_params(*block);
+
+            let insts = &self.body.block(*block).insts;
+            for (inst_idx, inst) in insts.iter().enumerate() {
+                self.emit_inst(*inst, next, inst_idx + 1 == insts.len());
+            }
+        }
+
+        self.epilog();
+        self.asm.emit_trap_stubs();
+        self.patch_frame_size();
+    }
+
+    fn prolog(&mut self) {
+        emit_pushq_reg(self.asm, RBP);
+        emit_mov_reg_reg(self.asm, 1, RSP, RBP);
+        // the frame size is only known after code generation, i32::MAX is a
+        // placeholder forcing a full imm32 that patch_frame_size overwrites
+        emit_subq_imm_reg(self.asm, i32::MAX, RSP);
+        self.frame_size_at = self.asm.pos() - 4;
+
+        let mut used: Vec<Reg> = self
+            .alloc
+            .used
+            .iter()
+            .cloned()
+            .filter(|reg| is_callee_saved(*reg, self.signature.call_conv))
+            .collect();
+        used.sort();
+
+        for reg in used {
+            // XMM registers are preserved as a whole
+            let size = match reg {
+                Reg::Gpr(_) => 8,
+                Reg::Float(_) => 16,
+            };
+            self.stack_offset = align(self.stack_offset + size, size);
+            let off = -self.stack_offset;
+            match reg {
+                Reg::Gpr(reg) => emit_movq_reg_memq(self.asm, reg, RBP, off),
+                Reg::Float(reg) => movups_store(self.asm, Mem::Local(off), reg),
+            }
+            self.saved_regs.push((reg, off));
+        }
+    }
+
+    fn epilog(&mut self) {
+        self.asm.bind_label(self.epilog);
+        for (reg, off) in self.saved_regs.clone() {
+            match reg {
+                Reg::Gpr(reg) => emit_movq_memq_reg(self.asm, RBP, off, reg),
+                Reg::Float(reg) => movups_load(self.asm, reg, Mem::Local(off)),
+            }
+        }
+        emit_mov_reg_reg(self.asm, 1, RBP, RSP);
+        emit_popq_reg(self.asm, RBP);
+        emit_retq(self.asm);
+    }
+
+    /// Total size of the frame below the saved RBP. RSP is 16 byte aligned
+    /// after pushing RBP, keep it that way for calls.
+    pub fn frame_size(&self) -> i32 {
+        align(self.stack_offset + self.outgoing_size, 16)
+    }
+
+    fn patch_frame_size(&mut self) {
+        let size = self.frame_size();
+        self.asm.emit_u32_at(self.frame_size_at as i32, size as u32);
+    }
+
+    /// Move incoming arguments from their ABI locations to the locations of
+    /// the entry block parameters.
+    fn entry_params(&mut self, entry: Block) {
+        let start = self.points.block_start(entry);
+        let regs = arg_registers(&self.signature.params, self.signature.call_conv);
+        // return address and saved RBP
+        let mut stack_off = 16;
+        if self.signature.call_conv == CallConv::WindowsFastcall {
+            // shadow space reserved by the caller
+            stack_off += 32;
+        }
+
+        let mut moves = vec![];
+        for (param, reg) in self.body.block(entry).params.iter().zip(regs.iter()) {
+            let from = match reg {
+                Some(Reg::Gpr(reg)) => Location::Gpr(*reg),
+                Some(Reg::Float(reg)) => Location::Fpr(*reg),
+                // stack arguments are read from the caller's frame
+                None => {
+                    let loc = Location::Stack(stack_off);
+                    stack_off += 8;
+                    loc
+                }
+            };
+            moves.push((
+                from,
+                self.alloc.location(*param, start),
+                self.body.value_type(*param),
+            ));
+        }
+
+        self.parallel_move(moves);
+    }
+
+    /// Store split block parameters to their spill slot.
+    fn spill_params(&mut self, block: Block) {
+        let start = self.points.block_start(block);
+
+        for param in self.body.block(block).params.iter() {
+            let loc = self.alloc.location(*param, start);
+            if let Some(slot) = self.alloc.spill_slot(*param) {
+                if !loc.is_stack() {
+                    self.emit_move(loc, Location::Stack(slot), self.body.value_type(*param));
+                }
+            }
+        }
+    }
+
+    fn location(&self, value: Value, pos: usize) -> Location {
+        self.alloc.location(value, pos)
+    }
+
+    /// Register holding `value` at `pos`, loading it into `scratch` if it
+    /// lives on the stack.
+    fn use_gpr(&mut self, value: Value, pos: usize, scratch: Register) -> Register {
+        let loc = self.location(value, pos);
+        if loc.is_gpr() {
+            loc.gpr()
+        } else {
+            let ty = self.body.value_type(value);
+            self.emit_move(loc, Location::Gpr(scratch), ty);
+            scratch
+        }
+    }
+
+    fn use_fpr(&mut self, value: Value, pos: usize, scratch: XMMRegister) -> XMMRegister {
+        let loc = self.location(value, pos);
+        if loc.is_fpr() {
+            loc.fpr()
+        } else {
+            let ty = self.body.value_type(value);
+            self.emit_move(loc, Location::Fpr(scratch), ty);
+            scratch
+        }
+    }
+
+    /// Store `value`, an operand of the instruction at `pos`, to `mem`. The
+    /// address must not use RDX or XMM0.
+    fn store(&mut self, value: Value, pos: usize, mem: Mem) {
+        let ty = self.body.value_type(value);
+        let src = if ty.is_float() {
+            Reg::Float(self.use_fpr(value, pos, XMM0))
+        } else {
+            Reg::Gpr(self.use_gpr(value, pos, RDX))
+        };
+        self.asm.store_mem(ty.to_machine(), mem, src);
+    }
+
+    /// Register the result of the instruction at `pos` should be computed in.
+    fn def_gpr(&self, value: Value, pos: usize, scratch: Register) -> Register {
+        match self.location(value, pos + 1) {
+            Location::Gpr(reg) => reg,
+            _ => scratch,
+        }
+    }
+
+    fn def_fpr(&self, value: Value, pos: usize, scratch: XMMRegister) -> XMMRegister {
+        match self.location(value, pos + 1) {
+            Location::Fpr(reg) => reg,
+            _ => scratch,
+        }
+    }
+
+    /// Move the result of the instruction at `pos` from `src` to its location.
+    fn finish_def(&mut self, value: Value, pos: usize, src: Location) {
+        let ty = self.body.value_type(value);
+        let loc = self.location(value, pos + 1);
+        self.emit_move(src, loc, ty);
+
+        if let Some(slot) = self.alloc.spill_slot(value) {
+            if !loc.is_stack() {
+                self.emit_move(loc, Location::Stack(slot), ty);
+            }
+        }
+    }
+
+    pub fn emit_move(&mut self, from: Location, to: Location, ty: Type) {
+        if from == to {
+            return;
+        }
+        let mode = ty.to_machine();
+
+        match (from, to) {
+            (Location::Gpr(src), Location::Gpr(dst)) => {
+                emit_mov_reg_reg(self.asm, ty.x64(), src, dst)
+            }
+            (Location::Fpr(src), Location::Fpr(dst)) => self.asm.copy_freg(mode, dst, src),
+            (Location::Gpr(src), Location::Stack(off)) => {
+                self.asm.store_mem(mode, Mem::Local(off), Reg::Gpr(src))
+            }
+            (Location::Fpr(src), Location::Stack(off)) => {
+                self.asm.store_mem(mode, Mem::Local(off), Reg::Float(src))
+            }
+            (Location::Stack(off), Location::Gpr(dst)) => {
+                self.asm.load_mem(mode, Reg::Gpr(dst), Mem::Local(off))
+            }
+            (Location::Stack(off), Location::Fpr(dst)) => {
+                self.asm.load_mem(mode, Reg::Float(dst), Mem::Local(off))
+            }
+            (Location::Stack(src), Location::Stack(dst)) => {
+                if ty.is_float() {
+                    self.asm.load_mem(mode, Reg::Float(XMM0), Mem::Local(src));
+                    self.asm.store_mem(mode, Mem::Local(dst), Reg::Float(XMM0));
+                } else {
+                    self.asm.load_mem(mode, Reg::Gpr(RAX), Mem::Local(src));
+                    self.asm.store_mem(mode, Mem::Local(dst), Reg::Gpr(RAX));
+                }
+            }
+            _ => unreachable!("move between register classes"),
+        }
+    }
+
+    /// Perform all `moves` as if they happened at the same time.
+    fn parallel_move(&mut self, moves: Vec<(Location, Location, Type)>) {
+        let mut pending: Vec<(Location, Location, Type)> = moves
+            .into_iter()
+            .filter(|(from, to, _)| from != to)
+            .collect();
+
+        while !pending.is_empty() {
+            let ready = pending
+                .iter()
+                .position(|(_, to, _)| !pending.iter().any(|(from, _, _)| from == to));
+
+            match ready {
+                Some(idx) => {
+                    let (from, to, ty) = pending.remove(idx);
+                    self.emit_move(from, to, ty);
+                }
+                None => {
+                    // every destination is still read by another move, break the
+                    // cycle by saving one source in a scratch register
+                    let (from, _, ty) = pending[0];
+                    let tmp = if ty.is_float() {
+                        Location::Fpr(XMM1)
+                    } else {
+                        Location::Gpr(R11)
+                    };
+                    self.emit_move(from, tmp, ty);
+                    for pending_move in pending.iter_mut() {
+                        if pending_move.0 == from {
+                            pending_move.0 = tmp;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Moves needed on the edge from the instruction at `pos` to `succ`.
+    fn edge_moves(
+        &self,
+        pos: usize,
+        succ: Block,
+        args: &[Value],
+    ) -> Vec<(Location, Location, Type)> {
+        let start = self.points.block_start(succ);
+        let params = &self.body.block(succ).params;
+
+        let mut moves: Vec<(Location, Location, Type)> = args
+            .iter()
+            .zip(params.iter())
+            .map(|(arg, param)| {
+                (
+                    self.location(*arg, pos),
+                    self.location(*param, start),
+                    self.body.value_type(*param),
+                )
+            })
+            .collect();
+
+        if let Some(liveness) = self.liveness {
+            // values split inside a loop have to be reloaded on the back edge,
+            // the stack slot is always valid so stores are never needed
+            for value in liveness.live_in(succ).iter() {
+                let from = self.location(*value, pos);
+                let to = self.location(*value, start);
+                if from != to && !to.is_stack() {
+                    moves.push((from, to, self.body.value_type(*value)));
+                }
+            }
+        }
+
+        moves
+    }
+
+    /// Emit `inst`. `next` is the block placed right after it and `last` is
+    /// set when nothing else follows it in its block.
+    fn emit_inst(&mut self, inst: Inst, next: Option<Block>, last: bool) {
+        let pos = self.points.inst(inst);
+        // code only falls through from the last instruction of a block
+        let next = if last { next } else { None };
+        let result = self.body.inst_result(inst);
+
+        match self.body.inst(inst).clone() {
+            InstData::IConst { ty, imm } => {
+                let value = result.unwrap();
+                let dst = self.def_gpr(value, pos, RAX);
+                if imm == 0 {
+                    emit_xor_reg_reg(self.asm, 1, dst, dst);
+                } else {
+                    self.asm.load_int_const(ty.to_machine(), dst, imm);
+                }
+                self.finish_def(value, pos, Location::Gpr(dst));
+            }
+
+            InstData::FConst { ty, imm } => {
+                let value = result.unwrap();
+                let dst = self.def_fpr(value, pos, XMM0);
+                if imm.to_bits() == 0 {
+                    pxor(self.asm, dst, dst);
+                } else {
+                    self.asm.load_float_const(ty.to_machine(), dst, imm);
+                }
+                self.finish_def(value, pos, Location::Fpr(dst));
+            }
+
+            InstData::Unary { op, x } if op.is_float() => {
+                let value = result.unwrap();
+                let mode = self.body.value_type(x).to_machine();
+                // the operations work in place
+                let dst = self.def_fpr(value, pos, XMM0);
+                let src = self.location(x, pos);
+                self.emit_move(src, Location::Fpr(dst), self.body.value_type(x));
+                match op {
+                    UnaryOp::FNeg => self.asm.float_neg(mode, dst, dst),
+                    UnaryOp::FAbs => self.asm.float_abs(mode, dst, dst),
+                    UnaryOp::FSqrt => self.asm.float_sqrt(mode, dst, dst),
+                    _ => unreachable!(),
+                }
+                self.finish_def(value, pos, Location::Fpr(dst));
+            }
+
+            InstData::Unary { op, x } => {
+                let value = result.unwrap();
+                let mode = match self.body.value_type(x) {
+                    Type::Pointer => MachineMode::Int64,
+                    ty => ty.to_machine(),
+                };
+                match op {
+                    UnaryOp::BNot | UnaryOp::INeg => {
+                        let dst = self.def_gpr(value, pos, RAX);
+                        let src = self.location(x, pos);
+                        self.emit_move(src, Location::Gpr(dst), self.body.value_type(x));
+                        if op == UnaryOp::BNot {
+                            self.asm.int_not(mode, dst, dst);
+                        } else {
+                            self.asm.int_neg(mode, dst, dst);
+                        }
+                        self.finish_def(value, pos, Location::Gpr(dst));
+                    }
+                    _ => {
+                        let src = self.use_gpr(x, pos, RCX);
+                        let dst = self.def_gpr(value, pos, RAX);
+                        match op {
+                            UnaryOp::Popcnt => self.asm.int_popcnt(mode, dst, src),
+                            UnaryOp::Clz => self.asm.int_clz(mode, dst, src),
+                            UnaryOp::Ctz => self.asm.int_ctz(mode, dst, src),
+                            _ => unreachable!(),
+                        }
+                        self.finish_def(value, pos, Location::Gpr(dst));
+                    }
+                }
+            }
+
+            InstData::Binary { op, x, y } if op.is_float() => {
+                let value = result.unwrap();
+                let ty = self.body.value_type(x);
+                let f: &dyn Fn(&mut Assembler, MachineMode, XMMRegister, XMMRegister, XMMRegister) =
+                    match op {
+                        BinaryOp::FAdd => &Assembler::float_add,
+                        BinaryOp::FSub => &Assembler::float_sub,
+                        BinaryOp::FMul => &Assembler::float_mul,
+                        BinaryOp::FDiv => &Assembler::float_div,
+                        BinaryOp::FMin => &Assembler::float_min,
+                        BinaryOp::FMax => &Assembler::float_max,
+                        _ => unreachable!(),
+                    };
+
+                let rhs = self.use_fpr(y, pos, XMM1);
+                let dst = match self.location(value, pos + 1) {
+                    Location::Fpr(reg) if reg != rhs => reg,
+                    _ => XMM0,
+                };
+                let lhs = self.location(x, pos);
+                self.emit_move(lhs, Location::Fpr(dst), ty);
+                f(self.asm, ty.to_machine(), dst, dst, rhs);
+                self.finish_def(value, pos, Location::Fpr(dst));
+            }
+
+            InstData::Binary { op, x, y } if op.is_division() => {
+                let value = result.unwrap();
+                let ty = self.body.value_type(x);
+                let mode = match ty {
+                    Type::Pointer => MachineMode::Int64,
+                    _ => ty.to_machine(),
+                };
+                let signed = op == BinaryOp::IDiv || op == BinaryOp::IMod;
+                let rem = op == BinaryOp::IMod || op == BinaryOp::URem;
+                let checked = self.trap_policy == TrapPolicy::Checked;
+
+                // division works on RAX:RDX
+                let rhs = self.use_gpr(y, pos, RCX);
+                let lhs = self.location(x, pos);
+                self.emit_move(lhs, Location::Gpr(RAX), ty);
+                self.asm.int_div_rem(mode, RAX, rhs, signed, rem, checked);
+                self.finish_def(value, pos, Location::Gpr(RAX));
+            }
+
+            InstData::Binary { op, x, y } => {
+                let value = result.unwrap();
+                let ty = self.body.value_type(x);
+                // pointer arithmetic is plain 64 bit arithmetic
+                let mode = match ty {
+                    Type::Pointer => MachineMode::Int64,
+                    _ => ty.to_machine(),
+                };
+                let f: &dyn Fn(&mut Assembler, MachineMode, Register, Register, Register) = match op
+                {
+                    BinaryOp::IAdd => &Assembler::int_add,
+                    BinaryOp::ISub => &Assembler::int_sub,
+                    BinaryOp::IMul => &Assembler::int_mul,
+                    BinaryOp::BAnd => &Assembler::int_and,
+                    BinaryOp::BOr => &Assembler::int_or,
+                    BinaryOp::BXor => &Assembler::int_xor,
+                    BinaryOp::IShl => &Assembler::int_shl,
+                    BinaryOp::UShr => &Assembler::int_shr,
+                    BinaryOp::SShr => &Assembler::int_sar,
+                    BinaryOp::Rotl => &Assembler::int_rol,
+                    BinaryOp::Rotr => &Assembler::int_ror,
+                    _ => unreachable!(),
+                };
+
+                // variable shifts take the amount in CL, which is where a
+                // spilled `y` ends up
+                let rhs = self.use_gpr(y, pos, RCX);
+                let dst = match self.location(value, pos + 1) {
+                    Location::Gpr(reg) if reg != rhs => reg,
+                    _ => RAX,
+                };
+                let lhs = self.location(x, pos);
+                self.emit_move(lhs, Location::Gpr(dst), ty);
+                f(self.asm, mode, dst, dst, rhs);
+                self.finish_def(value, pos, Location::Gpr(dst));
+            }
+
+            InstData::BinaryImm { op, x, imm } => {
+                let value = result.unwrap();
+                let ty = self.body.value_type(x);
+                let mode = match ty {
+                    Type::Pointer => MachineMode::Int64,
+                    _ => ty.to_machine(),
+                };
+                let dst = match self.location(value, pos + 1) {
+                    Location::Gpr(reg) => reg,
+                    _ => RAX,
+                };
+                let lhs = self.location(x, pos);
+                self.emit_move(lhs, Location::Gpr(dst), ty);
+                // the builder keeps shift amounts below the width and other
+                // immediates in 32 bits
+                match op {
+                    BinaryOp::BAnd => self.asm.int_and_imm(mode, dst, dst, imm as i32),
+                    BinaryOp::BOr => self.asm.int_or_imm(mode, dst, dst, imm as i32),
+                    BinaryOp::BXor => self.asm.int_xor_imm(mode, dst, dst, imm as i32),
+                    BinaryOp::IShl => self.asm.int_shl_imm(mode, dst, dst, imm as u8),
+                    BinaryOp::UShr => self.asm.int_shr_imm(mode, dst, dst, imm as u8),
+                    BinaryOp::SShr => self.asm.int_sar_imm(mode, dst, dst, imm as u8),
+                    BinaryOp::Rotl => self.asm.int_rol_imm(mode, dst, dst, imm as u8),
+                    BinaryOp::Rotr => self.asm.int_ror_imm(mode, dst, dst, imm as u8),
+                    _ => unreachable!(),
+                }
+                self.finish_def(value, pos, Location::Gpr(dst));
+            }
+
+            InstData::Convert { op, ty, x } => {
+                let value = result.unwrap();
+                let from = self.body.value_type(x);
+                match op {
+                    ConvertOp::SExtend | ConvertOp::UExtend | ConvertOp::IReduce => {
+                        let src = self.use_gpr(x, pos, RAX);
+                        let dst = self.def_gpr(value, pos, RAX);
+                        match (op, from) {
+                            (ConvertOp::SExtend, Type::I8) => {
+                                let x64 = if ty == Type::I64 { 1 } else { 0 };
+                                emit_movsx_byte(self.asm, x64, src, dst);
+                            }
+                            (ConvertOp::SExtend, _) => self.asm.extend_int_long(dst, src),
+                            (ConvertOp::UExtend, Type::I8) => {
+                                emit_movzbl_reg_reg(self.asm, src, dst)
+                            }
+                            // 32 bit moves clear the upper half
+                            _ => emit_mov_reg_reg(self.asm, 0, src, dst),
+                        }
+                        self.finish_def(value, pos, Location::Gpr(dst));
+                    }
+
+                    ConvertOp::FcvtFromSInt | ConvertOp::FcvtFromUInt => {
+                        let signed = op == ConvertOp::FcvtFromSInt;
+                        // the unsigned conversion uses RAX and R11 itself
+                        let mut src = self.use_gpr(x, pos, RCX);
+                        let mut src_mode = from.to_machine();
+                        if from == Type::I8 {
+                            if signed {
+                                emit_movsx_byte(self.asm, 0, src, RCX);
+                            } else {
+                                emit_movzbl_reg_reg(self.asm, src, RCX);
+                            }
+                            src = RCX;
+                            src_mode = MachineMode::Int32;
+                        }
+                        let dst = self.def_fpr(value, pos, XMM0);
+                        if signed {
+                            self.asm.int_to_float(ty.to_machine(), dst, src_mode, src);
+                        } else {
+                            self.asm.uint_to_float(ty.to_machine(), dst, src_mode, src);
+                        }
+                        self.finish_def(value, pos, Location::Fpr(dst));
+                    }
+
+                    ConvertOp::FcvtToSInt
+                    | ConvertOp::FcvtToUInt
+                    | ConvertOp::FcvtToSIntSat
+                    | ConvertOp::FcvtToUIntSat => {
+                        let signed = op == ConvertOp::FcvtToSInt || op == ConvertOp::FcvtToSIntSat;
+                        let saturate =
+                            op == ConvertOp::FcvtToSIntSat || op == ConvertOp::FcvtToUIntSat;
+                        // the checks work on a double in XMM0
+                        let src = self.use_fpr(x, pos, XMM0);
+                        if from == Type::F32 {
+                            self.asm.float_to_double(XMM0, src);
+                        } else if src != XMM0 {
+                            self.asm.copy_freg(MachineMode::Float64, XMM0, src);
+                        }
+                        let dst = self.def_gpr(value, pos, RAX);
+                        self.asm
+                            .float_to_int_checked(dst, XMM0, ty.bits(), signed, saturate);
+                        self.finish_def(value, pos, Location::Gpr(dst));
+                    }
+
+                    ConvertOp::FPromote | ConvertOp::FDemote => {
+                        let src = self.use_fpr(x, pos, XMM0);
+                        let dst = self.def_fpr(value, pos, XMM0);
+                        if op == ConvertOp::FPromote {
+                            self.asm.float_to_double(dst, src);
+                        } else {
+                            self.asm.double_to_float(dst, src);
+                        }
+                        self.finish_def(value, pos, Location::Fpr(dst));
+                    }
+
+                    ConvertOp::Bitcast if from.is_float() => {
+                        let src = self.use_fpr(x, pos, XMM0);
+                        let dst = self.def_gpr(value, pos, RAX);
+                        match from {
+                            Type::F32 => movd_reg_freg(self.asm, dst, src),
+                            _ => movq_reg_freg(self.asm, dst, src),
+                        }
+                        self.finish_def(value, pos, Location::Gpr(dst));
+                    }
+
+                    ConvertOp::Bitcast => {
+                        let src = self.use_gpr(x, pos, RAX);
+                        let dst = self.def_fpr(value, pos, XMM0);
+                        match from {
+                            Type::I32 => movd_freg_reg(self.asm, dst, src),
+                            _ => movq_freg_reg(self.asm, dst, src),
+                        }
+                        self.finish_def(value, pos, Location::Fpr(dst));
+                    }
+                }
+            }
+
+            InstData::IntCmp { cc, x, y } => {
+                let value = result.unwrap();
+                let mode = self.body.value_type(x).to_machine();
+                let lhs = self.use_gpr(x, pos, RAX);
+                let rhs = self.use_gpr(y, pos, RCX);
+                self.asm.cmp_reg(mode, lhs, rhs);
+                self.asm.set(RAX, cc);
+                self.finish_def(value, pos, Location::Gpr(RAX));
+            }
+
+            InstData::FloatCmp { cc, x, y } => {
+                let value = result.unwrap();
+                let mode = self.body.value_type(x).to_machine();
+                let lhs = self.use_fpr(x, pos, XMM0);
+                let rhs = self.use_fpr(y, pos, XMM1);
+                self.asm.float_cmp(mode, RAX, lhs, rhs, cc);
+                self.finish_def(value, pos, Location::Gpr(RAX));
+            }
+
+            InstData::Load { ty, base, offset } => {
+                let value = result.unwrap();
+                let base = self.use_gpr(base, pos, RAX);
+                if ty.is_float() {
+                    let dst = self.def_fpr(value, pos, XMM0);
+                    self.asm
+                        .load_mem(ty.to_machine(), Reg::Float(dst), Mem::Base(base, offset));
+                    self.finish_def(value, pos, Location::Fpr(dst));
+                } else {
+                    let dst = self.def_gpr(value, pos, RAX);
+                    self.asm
+                        .load_mem(ty.to_machine(), Reg::Gpr(dst), Mem::Base(base, offset));
+                    self.finish_def(value, pos, Location::Gpr(dst));
+                }
+            }
+
+            InstData::Store {
+                value,
+                base,
+                offset,
+            } => {
+                let base = self.use_gpr(base, pos, RAX);
+                self.store(value, pos, Mem::Base(base, offset));
+            }
+
+            InstData::LoadIndexed {
+                ty,
+                base,
+                index,
+                scale,
+                offset,
+            } => {
+                let value = result.unwrap();
+                let base = self.use_gpr(base, pos, RAX);
+                let index = self.use_gpr(index, pos, RCX);
+                let mem = Mem::Index(base, index, scale as i32, offset);
+                if ty.is_float() {
+                    let dst = self.def_fpr(value, pos, XMM0);
+                    self.asm.load_mem(ty.to_machine(), Reg::Float(dst), mem);
+                    self.finish_def(value, pos, Location::Fpr(dst));
+                } else {
+                    let dst = self.def_gpr(value, pos, RAX);
+                    self.asm.load_mem(ty.to_machine(), Reg::Gpr(dst), mem);
+                    self.finish_def(value, pos, Location::Gpr(dst));
+                }
+            }
+
+            InstData::StoreIndexed {
+                value,
+                base,
+                index,
+                scale,
+                offset,
+            } => {
+                let base = self.use_gpr(base, pos, RAX);
+                let index = self.use_gpr(index, pos, RCX);
+                self.store(value, pos, Mem::Index(base, index, scale as i32, offset));
+            }
+
+            InstData::StackAddr { slot } => {
+                let value = result.unwrap();
+                let dst = self.def_gpr(value, pos, RAX);
+                lea(
+                    self.asm,
+                    dst,
+                    Mem::Local(self.alloc.stack_slots[slot.0 as usize]),
+                );
+                self.finish_def(value, pos, Location::Gpr(dst));
+            }
+
+            InstData::SymbolAddr { name } => {
+                let value = result.unwrap();
+                let dst = self.def_gpr(value, pos, RAX);
+                if self.indirect_calls {
+                    self.load_slot_addr(name, dst);
+                    emit_movq_memq_reg(self.asm, dst, 0, dst);
+                } else {
+                    emit_movq_memq_reg(self.asm, RIP, 0, dst);
+                    self.relocs.push(Reloc {
+                        kind: RelocKind::GotRel32,
+                        global_name: name,
+                        offset: self.asm.pos() - 4,
+                        addend: -4,
+                    });
+                }
+                self.finish_def(value, pos, Location::Gpr(dst));
+            }
+
+            InstData::Call {
+                name,
+                args,
+                signature,
+            } => self.emit_call(pos, name, &args, &signature, result),
+
+            InstData::Jump { block, args } => {
+                let moves = self.edge_moves(pos, block, &args);
+                self.parallel_move(moves);
+                if next != Some(block) {
+                    emit_jmp(self.asm, self.block_labels[&block]);
+                }
+            }
+
+            InstData::Brif {
+                cond,
+                then_block,
+                then_args,
+                else_block,
+                else_args,
+            } => {
+                let ty = self.body.value_type(cond);
+                match self.location(cond, pos) {
+                    // only the low byte of a boolean register is defined
+                    Location::Gpr(reg) if ty == Type::I8 => {
+                        emit_movzbl_reg_reg(self.asm, reg, RAX);
+                        emit_testl_reg_reg(self.asm, RAX, RAX);
+                    }
+                    Location::Gpr(reg) if ty.x64() != 0 => emit_testq_reg_reg(self.asm, reg, reg),
+                    Location::Gpr(reg) => emit_testl_reg_reg(self.asm, reg, reg),
+                    loc => {
+                        self.emit_move(loc, Location::Gpr(RAX), ty);
+                        if ty.x64() != 0 {
+                            emit_testq_reg_reg(self.asm, RAX, RAX);
+                        } else {
+                            emit_testl_reg_reg(self.asm, RAX, RAX);
+                        }
+                    }
+                }
+
+                let then_moves = self.edge_moves(pos, then_block, &then_args);
+                let else_moves = self.edge_moves(pos, else_block, &else_args);
+                let then_label = self.block_labels[&then_block];
+                let else_label = self.block_labels[&else_block];
+
+                if else_moves.is_empty() {
+                    self.asm.jump_if(CondCode::Zero, else_label);
+                    self.parallel_move(then_moves);
+                    if next != Some(then_block) {
+                        emit_jmp(self.asm, then_label);
+                    }
+                } else {
+                    let else_args_label = self.asm.create_label();
+                    self.asm.jump_if(CondCode::Zero, else_args_label);
+                    self.parallel_move(then_moves);
+                    emit_jmp(self.asm, then_label);
+                    self.asm.bind_label(else_args_label);
+                    self.parallel_move(else_moves);
+                    if next != Some(else_block) {
+                        emit_jmp(self.asm, else_label);
+                    }
+                }
+            }
+
+            InstData::Return { value } => {
+                if let Some(value) = value {
+                    let ty = self.body.value_type(value);
+                    let to = if ty.is_float() {
+                        Location::Fpr(XMM0)
+                    } else {
+                        Location::Gpr(RAX)
+                    };
+                    let from = self.location(value, pos);
+                    self.emit_move(from, to, ty);
+                }
+                if !last || next.is_some() {
+                    emit_jmp(self.asm, self.epilog);
+                }
+            }
+        }
+    }
+
+    /// Lower a call following the convention of `signature`. Arguments that
+    /// do not fit into registers are stored to the outgoing area at the
+    /// bottom of the frame, caller-saved registers holding live values are
+    /// preserved in the frame around the call.
+    fn emit_call(
+        &mut self,
+        pos: usize,
+        name: String,
+        args: &[Value],
+        signature: &Signature,
+        result: Option<Value>,
+    ) {
+        let call_conv = signature.call_conv;
+        let types: Vec<Type> = args.iter().map(|arg| self.body.value_type(*arg)).collect();
+        let regs = arg_registers(&types, call_conv);
+
+        let saved: Vec<(Value, Reg)> = self
+            .alloc
+            .live_across(pos)
+            .into_iter()
+            .filter(|(_, reg)| !is_callee_saved(*reg, call_conv))
+            .collect();
+        for (value, reg) in saved.iter() {
+            let slot = self.call_save_slot(*reg);
+            let ty = self.body.value_type(*value);
+            self.emit_move(Location::from_reg(*reg), Location::Stack(slot), ty);
+        }
+
+        // stack arguments go first, they are moved through scratch registers
+        // that may be argument registers as well
+        let mut stack_off = match call_conv {
+            // shadow space for the four register arguments
+            CallConv::WindowsFastcall => 32,
+            CallConv::SystemV => 0,
+        };
+        for ((arg, reg), ty) in args.iter().zip(regs.iter()).zip(types.iter()) {
+            if reg.is_some() {
+                continue;
+            }
+            let mode = ty.to_machine();
+            let dst = Mem::Base(RSP, stack_off);
+            match self.location(*arg, pos) {
+                Location::Gpr(src) => self.asm.store_mem(mode, dst, Reg::Gpr(src)),
+                Location::Fpr(src) => self.asm.store_mem(mode, dst, Reg::Float(src)),
+                loc if ty.is_float() => {
+                    self.emit_move(loc, Location::Fpr(XMM0), *ty);
+                    self.asm.store_mem(mode, dst, Reg::Float(XMM0));
+                }
+                loc => {
+                    self.emit_move(loc, Location::Gpr(RAX), *ty);
+                    self.asm.store_mem(mode, dst, Reg::Gpr(RAX));
+                }
+            }
+            stack_off += 8;
+        }
+        self.outgoing_size = self.outgoing_size.max(stack_off);
+
+        let mut moves = vec![];
+        for ((arg, reg), ty) in args.iter().zip(regs.iter()).zip(types.iter()) {
+            if let Some(reg) = reg {
+                moves.push((self.location(*arg, pos), Location::from_reg(*reg), *ty));
+            }
+        }
+        self.parallel_move(moves);
+
+        if signature.varargs {
+            match call_conv {
+                // AL holds an upper bound on the number of vector registers used
+                CallConv::SystemV => {
+                    let used = regs
+                        .iter()
+                        .filter(|reg| matches!(reg, Some(Reg::Float(_))))
+                        .count();
+                    self.asm
+                        .load_int_const(MachineMode::Int32, RAX, used as i64);
+                }
+                // variadic floats are passed in the integer register as well
+                CallConv::WindowsFastcall => {
+                    for (idx, reg) in regs.iter().enumerate() {
+                        if let Some(Reg::Float(reg)) = reg {
+                            movq_reg_freg(self.asm, WIN_ARG_GPR[idx], *reg);
+                        }
+                    }
+                }
+            }
+        }
+
+        if self.indirect_calls {
+            // R11 is neither an argument register nor callee-saved
+            self.load_slot_addr(name, R11);
+            emit_movq_memq_reg(self.asm, R11, 0, R11);
+            emit_callq_reg(self.asm, R11);
+        } else {
+            // the module places its functions close to each other and routes
+            // far targets through a stub, so a rel32 call always reaches
+            emit_callq_rel32(self.asm, 0);
+            self.relocs.push(Reloc {
+                kind: RelocKind::Rel32,
+                global_name: name,
+                offset: self.asm.pos() - 4,
+                // the displacement is relative to the end of the instruction
+                addend: -4,
+            });
+        }
+
+        for (value, reg) in saved.iter() {
+            let slot = self.call_save_slots[reg];
+            let ty = self.body.value_type(*value);
+            self.emit_move(Location::Stack(slot), Location::from_reg(*reg), ty);
+        }
+
+        if signature.ret != Type::Void {
+            let src = if signature.ret.is_float() {
+                Location::Fpr(XMM0)
+            } else {
+                Location::Gpr(RAX)
+            };
+            self.finish_def(result.unwrap(), pos, src);
+        }
+    }
+
+    /// Load the address of the slot of symbol `name` into `dst`. The module
+    /// fills in the data segment entry holding it when placing the code.
+    fn load_slot_addr(&mut self, name: String, dst: Register) {
+        let disp = match self.slot_entries.get(&name) {
+            Some(disp) => *disp,
+            None => {
+                let disp = self.asm.dseg.add_addr(std::ptr::null());
+                self.slot_entries.insert(name.clone(), disp);
+                disp
+            }
+        };
+        emit_movq_memq_reg(self.asm, RIP, 0, dst);
+        let after = self.asm.pos() as i32;
+        self.asm.emit_u32_at(after - 4, -(disp + after) as u32);
+        self.relocs.push(Reloc {
+            kind: RelocKind::Slot,
+            global_name: name,
+            offset: after as usize - 4,
+            addend: 0,
+        });
+    }
+
+    /// Frame slot `reg` is preserved in across calls.
+    fn call_save_slot(&mut self, reg: Reg) -> i32 {
+        if let Some(slot) = self.call_save_slots.get(&reg) {
+            return *slot;
+        }
+        self.stack_offset = align(self.stack_offset + 8, 8);
+        let slot = -self.stack_offset;
+        self.call_save_slots.insert(reg, slot);
+        slot
+    }
+}
@@ -41,6 +41,10 @@ impl Value {
     }
 }
 
+// The addresses are only copied into the code, never dereferenced, so a
+// function can be built on another thread.
+unsafe impl Send for DSeg {}
+
 impl DSeg {
     pub fn new() -> DSeg {
         DSeg {
@@ -65,6 +69,15 @@ impl DSeg {
         self.size
     }
 
+    /// Entry placed `disp` bytes before the end of the segment, which is the
+    /// start of the code.
+    pub fn entry(&self, disp: i32) -> Option<&Value> {
+        self.entries
+            .iter()
+            .find(|entry| entry.disp == disp)
+            .map(|entry| &entry.value)
+    }
+
     pub fn finish(&self, ptr: *const u8) {
         for entry in &self.entries {
             let offset = self.size - entry.disp;
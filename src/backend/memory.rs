@@ -0,0 +1,313 @@
+//! Pages holding generated code.
+//!
+//! Code is written while its pages are readable and writable and only made
+//! executable afterwards, no page is ever writable and executable at the
+//! same time.
+
+use crate::error::{PeaceError, Result};
+use std::mem;
+use std::ptr;
+
+fn round_up_to_page_size(size: usize, page_size: usize) -> usize {
+    (size + (page_size - 1)) & !(page_size - 1)
+}
+
+/// A simple struct consisting of a pointer and length.
+struct PtrLen {
+    ptr: *mut u8,
+    len: usize,
+    /// Bytes handed out by `Memory::allocate` and not freed yet.
+    live: usize,
+}
+
+impl PtrLen {
+    /// Create a new empty `PtrLen`.
+    fn new() -> Self {
+        Self {
+            ptr: ptr::null_mut(),
+            len: 0,
+            live: 0,
+        }
+    }
+
+    /// Create a new `PtrLen` pointing to at least `size` bytes of memory,
+    /// suitably sized and aligned for memory protection.
+    #[cfg(not(target_os = "windows"))]
+    fn with_size(size: usize) -> Result<Self> {
+        let page_size = region::page::size();
+        let alloc_size = round_up_to_page_size(size, page_size);
+        unsafe {
+            let mut ptr: *mut libc::c_void = ptr::null_mut();
+            let err = libc::posix_memalign(&mut ptr, page_size, alloc_size);
+            if err == 0 {
+                Ok(Self {
+                    ptr: ptr as *mut u8,
+                    len: alloc_size,
+                    live: 0,
+                })
+            } else {
+                Err(PeaceError::Memory(errno::Errno(err).to_string()))
+            }
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    fn with_size(size: usize) -> Result<Self> {
+        use winapi::um::memoryapi::VirtualAlloc;
+        use winapi::um::winnt::{MEM_COMMIT, MEM_RESERVE, PAGE_READWRITE};
+
+        let page_size = region::page::size();
+
+        // VirtualAlloc always rounds up to the next multiple of the page size
+        let ptr = unsafe {
+            VirtualAlloc(
+                ptr::null_mut(),
+                size,
+                MEM_COMMIT | MEM_RESERVE,
+                PAGE_READWRITE,
+            )
+        };
+        if !ptr.is_null() {
+            Ok(Self {
+                ptr: ptr as *mut u8,
+                len: round_up_to_page_size(size, page_size),
+                live: 0,
+            })
+        } else {
+            Err(PeaceError::Memory(errno::errno().to_string()))
+        }
+    }
+
+    fn contains(&self, ptr: *const u8) -> bool {
+        let start = self.ptr as usize;
+        self.len != 0 && start <= ptr as usize && (ptr as usize) < start + self.len
+    }
+
+    fn make_writable(&self) -> Result<()> {
+        unsafe {
+            region::protect(self.ptr, self.len, region::Protection::READ_WRITE).map_err(|err| {
+                PeaceError::Memory(format!("unable to make memory writable: {}", err))
+            })
+        }
+    }
+
+    /// Give the pages back to the system.
+    #[cfg(not(target_os = "windows"))]
+    fn release(self) {
+        // memory that stays executable must not go back to malloc, rather
+        // leak it
+        if self.len != 0 && self.make_writable().is_ok() {
+            unsafe { libc::free(self.ptr as *mut libc::c_void) };
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    fn release(self) {
+        use winapi::um::memoryapi::VirtualFree;
+        use winapi::um::winnt::MEM_RELEASE;
+
+        if self.len != 0 {
+            unsafe { VirtualFree(self.ptr as *mut _, 0, MEM_RELEASE) };
+        }
+    }
+}
+
+/// Bytes of code memory held by a `Memory`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Pages taken from the system, including those kept for reuse.
+    pub reserved: usize,
+    /// Bytes handed out by `allocate` and not freed.
+    pub used: usize,
+    /// Bytes passed to `free` so far.
+    pub freed: usize,
+}
+
+/// JIT memory manager. This manages pages of suitably aligned and
+/// accessible memory.
+///
+/// Memory handed out by `allocate` is readable and writable until
+/// `set_readable_and_executable` or `set_readonly` is called, later
+/// allocations start on fresh pages. Pages whose allocations were all freed
+/// are made writable again and reused, all pages are released on drop.
+pub struct Memory {
+    /// Blocks of pages in the order they were filled, released blocks are
+    /// left empty so the indices stay valid.
+    allocations: Vec<PtrLen>,
+    /// Number of `allocations` that are no longer writable.
+    executable: usize,
+    current: PtrLen,
+    position: usize,
+    /// Released blocks, writable and ready for reuse.
+    free: Vec<PtrLen>,
+    freed: usize,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Self {
+            allocations: Vec::new(),
+            executable: 0,
+            current: PtrLen::new(),
+            position: 0,
+            free: Vec::new(),
+            freed: 0,
+        }
+    }
+
+    fn finish_current(&mut self) {
+        self.allocations
+            .push(mem::replace(&mut self.current, PtrLen::new()));
+        self.position = 0;
+    }
+
+    /// The smallest released block holding `size` bytes, or a new one.
+    fn new_block(&mut self, size: usize) -> Result<PtrLen> {
+        let reuse = self
+            .free
+            .iter()
+            .enumerate()
+            .filter(|(_, block)| block.len >= size)
+            .min_by_key(|(_, block)| block.len)
+            .map(|(idx, _)| idx);
+        match reuse {
+            Some(idx) => Ok(self.free.swap_remove(idx)),
+            None => PtrLen::with_size(size),
+        }
+    }
+
+    /// Allocate `size` writable bytes aligned to `align`, a power of two no
+    /// larger than a page.
+    pub fn allocate(&mut self, size: usize, align: usize) -> Result<*mut u8> {
+        if !align.is_power_of_two() || align > region::page::size() {
+            return Err(PeaceError::Memory(format!(
+                "unsupported alignment {}",
+                align
+            )));
+        }
+
+        let position = (self.position + align - 1) & !(align - 1);
+        if position <= self.current.len && size <= self.current.len - position {
+            let ptr = unsafe { self.current.ptr.add(position) };
+            self.position = position + size;
+            self.current.live += size;
+            return Ok(ptr);
+        }
+
+        self.finish_current();
+
+        // TODO: Allocate more at a time.
+        self.current = self.new_block(size)?;
+        self.current.live = size;
+        self.position = size;
+        Ok(self.current.ptr)
+    }
+
+    /// Give back `size` bytes at `ptr`, allocated by `allocate`. The pages
+    /// are reused once everything allocated on them is freed.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may execute or access the freed bytes anymore.
+    pub unsafe fn free(&mut self, ptr: *const u8, size: usize) -> Result<()> {
+        if size == 0 {
+            return Ok(());
+        }
+        let not_allocated = || {
+            PeaceError::Memory(format!(
+                "{} bytes at {:p} were not allocated by this Memory",
+                size, ptr
+            ))
+        };
+
+        if self.current.contains(ptr) {
+            if size > self.current.live {
+                return Err(not_allocated());
+            }
+            self.freed += size;
+            self.current.live -= size;
+            if self.current.live == 0 {
+                self.position = 0;
+            }
+            return Ok(());
+        }
+
+        let idx = self
+            .allocations
+            .iter()
+            .position(|block| block.contains(ptr) && block.live >= size)
+            .ok_or_else(not_allocated)?;
+        self.freed += size;
+        self.allocations[idx].live -= size;
+        if self.allocations[idx].live == 0 {
+            let block = mem::replace(&mut self.allocations[idx], PtrLen::new());
+            block.make_writable()?;
+            self.free.push(block);
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        let blocks = || {
+            self.allocations
+                .iter()
+                .chain(Some(&self.current))
+                .chain(self.free.iter())
+        };
+        MemoryStats {
+            reserved: blocks().map(|block| block.len).sum(),
+            used: blocks().map(|block| block.live).sum(),
+            freed: self.freed,
+        }
+    }
+
+    /// Set all memory allocated in this `Memory` up to now as readable and executable.
+    pub fn set_readable_and_executable(&mut self) -> Result<()> {
+        self.protect(region::Protection::READ_EXECUTE, "readable+executable")
+    }
+
+    /// Set all memory allocated in this `Memory` up to now as readonly.
+    pub fn set_readonly(&mut self) -> Result<()> {
+        self.protect(region::Protection::READ, "readonly")
+    }
+
+    fn protect(&mut self, protection: region::Protection, what: &str) -> Result<()> {
+        self.finish_current();
+
+        for &PtrLen { ptr, len, .. } in &self.allocations[self.executable..] {
+            if len != 0 {
+                unsafe {
+                    region::protect(ptr, len, protection).map_err(|err| {
+                        PeaceError::Memory(format!("unable to make memory {}: {}", what, err))
+                    })?;
+                }
+            }
+        }
+        self.executable = self.allocations.len();
+        Ok(())
+    }
+}
+
+// The pages are owned by the `Memory` and only changed through `&mut self`.
+unsafe impl Send for Memory {}
+unsafe impl Sync for Memory {}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::new()
+    }
+}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        let current = mem::replace(&mut self.current, PtrLen::new());
+        for block in self
+            .allocations
+            .drain(..)
+            .chain(Some(current))
+            .chain(self.free.drain(..))
+        {
+            block.release();
+        }
+    }
+}
@@ -0,0 +1,459 @@
+//! Register allocation for `ir::Body`.
+//!
+//! Program points are numbered linearly following the block layout: a block
+//! starts at an even position where its parameters are defined, every
+//! instruction reads its operands at its own (even) position and defines its
+//! result at the following odd position. Live intervals are contiguous ranges
+//! of those positions.
+
+use super::align;
+use super::constants_x64::*;
+use crate::ir::*;
+use crate::types::*;
+use std::collections::{HashMap, HashSet};
+
+#[cfg(windows)]
+pub const AVAIL_GPR: [Register; 7] = [RBX, RSI, RDI, R12, R13, R14, R15];
+#[cfg(not(windows))]
+pub const AVAIL_GPR: [Register; 5] = [RBX, R12, R13, R14, R15];
+
+#[cfg(windows)]
+pub const AVAIL_FPR: [XMMRegister; 8] = [XMM8, XMM9, XMM10, XMM11, XMM12, XMM13, XMM14, XMM15];
+#[cfg(not(windows))]
+pub const AVAIL_FPR: [XMMRegister; 6] = [XMM10, XMM11, XMM12, XMM13, XMM14, XMM15];
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Hash)]
+pub enum Location {
+    Gpr(Register),
+    Fpr(XMMRegister),
+    /// Offset relative to RBP
+    Stack(i32),
+}
+
+impl Location {
+    pub fn gpr(&self) -> Register {
+        match self {
+            Location::Gpr(reg) => *reg,
+            _ => panic!("location is not a general purpose register"),
+        }
+    }
+
+    pub fn fpr(&self) -> XMMRegister {
+        match self {
+            Location::Fpr(reg) => *reg,
+            _ => panic!("location is not a floating point register"),
+        }
+    }
+
+    pub fn off(&self) -> i32 {
+        match self {
+            Location::Stack(off) => *off,
+            _ => panic!("location is not a stack slot"),
+        }
+    }
+
+    pub fn is_gpr(&self) -> bool {
+        matches!(self, Location::Gpr(_))
+    }
+
+    pub fn is_fpr(&self) -> bool {
+        matches!(self, Location::Fpr(_))
+    }
+
+    pub fn is_stack(&self) -> bool {
+        matches!(self, Location::Stack(_))
+    }
+
+    pub fn from_reg(reg: Reg) -> Location {
+        match reg {
+            Reg::Gpr(reg) => Location::Gpr(reg),
+            Reg::Float(reg) => Location::Fpr(reg),
+        }
+    }
+}
+
+/// Linear positions of blocks and instructions.
+#[derive(Clone, Debug, Default)]
+pub struct ProgramPoints {
+    block_start: HashMap<Block, usize>,
+    block_end: HashMap<Block, usize>,
+    inst_pos: HashMap<Inst, usize>,
+}
+
+impl ProgramPoints {
+    pub fn new(body: &Body) -> ProgramPoints {
+        let mut points = ProgramPoints::default();
+        let mut pos = 0;
+
+        for block in body.layout.iter() {
+            points.block_start.insert(*block, pos);
+            for inst in body.block(*block).insts.iter() {
+                pos += 2;
+                points.inst_pos.insert(*inst, pos);
+            }
+            points.block_end.insert(*block, pos);
+            pos += 2;
+        }
+
+        points
+    }
+
+    /// Position where the parameters of `block` are defined.
+    pub fn block_start(&self, block: Block) -> usize {
+        self.block_start[&block]
+    }
+
+    /// Position of the last instruction in `block`.
+    pub fn block_end(&self, block: Block) -> usize {
+        self.block_end[&block]
+    }
+
+    pub fn inst(&self, inst: Inst) -> usize {
+        self.inst_pos[&inst]
+    }
+}
+
+/// Values live on entry to and on exit from each block.
+#[derive(Clone, Debug, Default)]
+pub struct Liveness {
+    live_in: HashMap<Block, HashSet<Value>>,
+    live_out: HashMap<Block, HashSet<Value>>,
+}
+
+impl Liveness {
+    pub fn new(body: &Body) -> Liveness {
+        let mut uses: HashMap<Block, HashSet<Value>> = HashMap::new();
+        let mut defs: HashMap<Block, HashSet<Value>> = HashMap::new();
+
+        for block in body.layout.iter() {
+            let mut block_defs: HashSet<Value> =
+                body.block(*block).params.iter().cloned().collect();
+            let mut block_uses = HashSet::new();
+
+            for inst in body.block(*block).insts.iter() {
+                for arg in body.inst(*inst).args() {
+                    if !block_defs.contains(&arg) {
+                        block_uses.insert(arg);
+                    }
+                }
+                if let Some(result) = body.inst_result(*inst) {
+                    block_defs.insert(result);
+                }
+            }
+
+            uses.insert(*block, block_uses);
+            defs.insert(*block, block_defs);
+        }
+
+        let mut liveness = Liveness::default();
+        for block in body.layout.iter() {
+            liveness.live_in.insert(*block, HashSet::new());
+            liveness.live_out.insert(*block, HashSet::new());
+        }
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+
+            for block in body.layout.iter().rev() {
+                let mut live_out = HashSet::new();
+                for succ in body.successors(*block) {
+                    if let Some(live) = liveness.live_in.get(&succ) {
+                        live_out.extend(live.iter().cloned());
+                    }
+                }
+
+                let mut live_in: HashSet<Value> =
+                    live_out.difference(&defs[block]).cloned().collect();
+                live_in.extend(uses[block].iter().cloned());
+
+                if live_in != liveness.live_in[block] || live_out != liveness.live_out[block] {
+                    changed = true;
+                    liveness.live_in.insert(*block, live_in);
+                    liveness.live_out.insert(*block, live_out);
+                }
+            }
+        }
+
+        liveness
+    }
+
+    pub fn live_in(&self, block: Block) -> &HashSet<Value> {
+        &self.live_in[&block]
+    }
+
+    pub fn live_out(&self, block: Block) -> &HashSet<Value> {
+        &self.live_out[&block]
+    }
+}
+
+#[derive(Clone, Debug, Copy, PartialEq)]
+struct Assignment {
+    reg: Option<Reg>,
+    /// First position from which the value lives in `slot` only.
+    split_at: Option<usize>,
+    slot: Option<i32>,
+    /// Live interval of the value.
+    start: usize,
+    end: usize,
+}
+
+/// Result of register allocation.
+///
+/// A value is either kept in a register for its whole lifetime, kept in a
+/// stack slot for its whole lifetime, or split: it lives in a register up to
+/// `split_at` and in its stack slot afterwards. Split values are stored to
+/// their slot right after their definition, so the slot is valid on every
+/// path and only the way back into the register needs fix up moves.
+#[derive(Clone, Debug, Default)]
+pub struct Allocation {
+    assignments: HashMap<Value, Assignment>,
+    /// Registers handed out to values.
+    pub used: HashSet<Reg>,
+    /// Stack offset of the last allocated spill slot.
+    pub stack_offset: i32,
+    /// Offsets from RBP of the stack slots of the body.
+    pub stack_slots: Vec<i32>,
+}
+
+impl Allocation {
+    /// Location of `value` at position `pos`.
+    pub fn location(&self, value: Value, pos: usize) -> Location {
+        let assignment = &self.assignments[&value];
+
+        match (assignment.reg, assignment.split_at) {
+            (Some(reg), Some(split_at)) if pos < split_at => Location::from_reg(reg),
+            (Some(reg), None) => Location::from_reg(reg),
+            _ => Location::Stack(-assignment.slot.unwrap()),
+        }
+    }
+
+    /// Stack slot a value that starts out in a register has to be stored to
+    /// right after its definition.
+    pub fn spill_slot(&self, value: Value) -> Option<i32> {
+        let assignment = &self.assignments[&value];
+
+        match (assignment.reg, assignment.slot) {
+            (Some(_), Some(slot)) => Some(-slot),
+            _ => None,
+        }
+    }
+
+    /// Values that are in a register both before and after the instruction at
+    /// `pos`, sorted by value. A call at `pos` must not clobber them.
+    pub fn live_across(&self, pos: usize) -> Vec<(Value, Reg)> {
+        let mut live: Vec<(Value, Reg)> = self
+            .assignments
+            .iter()
+            .filter(|(value, assignment)| {
+                assignment.start <= pos
+                    && assignment.end > pos
+                    && !self.location(**value, pos + 1).is_stack()
+            })
+            .map(|(value, assignment)| (*value, assignment.reg.unwrap()))
+            .collect();
+        live.sort();
+        live
+    }
+
+    /// Place the stack slots of `body` below the frame used so far.
+    fn allocate_stack_slots(&mut self, body: &Body) {
+        for slot in body.stack_slots.iter() {
+            self.stack_offset = align(self.stack_offset + slot.size as i32, slot.align as i32);
+            self.stack_slots.push(-self.stack_offset);
+        }
+    }
+
+    fn allocate_slot(&mut self, ty: Type) -> i32 {
+        let size = ty.to_machine().size() as i32;
+        self.stack_offset = align(self.stack_offset + size, size);
+        self.stack_offset
+    }
+}
+
+fn values(body: &Body) -> Vec<Value> {
+    let mut values = vec![];
+    for block in body.layout.iter() {
+        values.extend(body.block(*block).params.iter().cloned());
+        for inst in body.block(*block).insts.iter() {
+            if let Some(result) = body.inst_result(*inst) {
+                if body.value_type(result) != Type::Void {
+                    values.push(result);
+                }
+            }
+        }
+    }
+    values
+}
+
+/// Keep every value in its own stack slot. This needs no analysis at all and
+/// is used when optimizations are disabled.
+pub fn allocate_stack(body: &Body, stack_offset: i32) -> Allocation {
+    let mut alloc = Allocation {
+        stack_offset,
+        ..Allocation::default()
+    };
+    alloc.allocate_stack_slots(body);
+
+    for value in values(body) {
+        let slot = alloc.allocate_slot(body.value_type(value));
+        alloc.assignments.insert(
+            value,
+            Assignment {
+                reg: None,
+                split_at: None,
+                slot: Some(slot),
+                start: 0,
+                end: 0,
+            },
+        );
+    }
+
+    alloc
+}
+
+#[derive(Clone, Debug)]
+struct Interval {
+    value: Value,
+    start: usize,
+    end: usize,
+}
+
+fn build_intervals(body: &Body, points: &ProgramPoints, liveness: &Liveness) -> Vec<Interval> {
+    let mut ranges: HashMap<Value, (usize, usize)> = HashMap::new();
+    let mut extend = |value: Value, pos: usize| {
+        let range = ranges.entry(value).or_insert((pos, pos));
+        range.0 = range.0.min(pos);
+        range.1 = range.1.max(pos);
+    };
+
+    for block in body.layout.iter() {
+        let start = points.block_start(*block);
+        let end = points.block_end(*block);
+
+        for param in body.block(*block).params.iter() {
+            extend(*param, start);
+        }
+        for value in liveness.live_in(*block).iter() {
+            extend(*value, start);
+        }
+        for value in liveness.live_out(*block).iter() {
+            extend(*value, end);
+        }
+
+        for inst in body.block(*block).insts.iter() {
+            let pos = points.inst(*inst);
+            for arg in body.inst(*inst).args() {
+                extend(arg, pos);
+            }
+            if let Some(result) = body.inst_result(*inst) {
+                extend(result, pos + 1);
+            }
+        }
+    }
+
+    let mut intervals: Vec<Interval> = values(body)
+        .into_iter()
+        .map(|value| {
+            let (start, end) = ranges[&value];
+            Interval { value, start, end }
+        })
+        .collect();
+    intervals.sort_by_key(|interval| (interval.start, interval.value));
+    intervals
+}
+
+/// Linear scan register allocation. When no register is free the interval
+/// ending last is split at the current position and continues in a stack slot.
+pub fn linear_scan(
+    body: &Body,
+    points: &ProgramPoints,
+    liveness: &Liveness,
+    stack_offset: i32,
+) -> Allocation {
+    let mut alloc = Allocation {
+        stack_offset,
+        ..Allocation::default()
+    };
+    alloc.allocate_stack_slots(body);
+    let intervals = build_intervals(body, points, liveness);
+    // intervals currently holding a register, as (end, value, register)
+    let mut active: Vec<(usize, Value, Reg)> = vec![];
+
+    for current in intervals.iter() {
+        active.retain(|(end, _, _)| *end >= current.start);
+
+        let ty = body.value_type(current.value);
+        let candidates: Vec<Reg> = if ty.is_float() {
+            AVAIL_FPR.iter().map(|reg| Reg::Float(*reg)).collect()
+        } else {
+            AVAIL_GPR.iter().map(|reg| Reg::Gpr(*reg)).collect()
+        };
+
+        let free = candidates
+            .iter()
+            .find(|reg| !active.iter().any(|(_, _, used)| used == *reg))
+            .cloned();
+
+        if let Some(reg) = free {
+            alloc.used.insert(reg);
+            alloc.assignments.insert(
+                current.value,
+                Assignment {
+                    reg: Some(reg),
+                    split_at: None,
+                    slot: None,
+                    start: current.start,
+                    end: current.end,
+                },
+            );
+            active.push((current.end, current.value, reg));
+            continue;
+        }
+
+        // no register left: split whichever interval of the same class ends last
+        let victim = active
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, _, reg))| candidates.contains(reg))
+            .max_by_key(|(_, (end, _, _))| *end)
+            .map(|(idx, entry)| (idx, *entry));
+
+        match victim {
+            Some((idx, (end, value, reg))) if end > current.end => {
+                let slot = alloc.allocate_slot(body.value_type(value));
+                let assignment = alloc.assignments.get_mut(&value).unwrap();
+                assignment.split_at = Some(current.start);
+                assignment.slot = Some(slot);
+
+                active.remove(idx);
+                alloc.assignments.insert(
+                    current.value,
+                    Assignment {
+                        reg: Some(reg),
+                        split_at: None,
+                        slot: None,
+                        start: current.start,
+                        end: current.end,
+                    },
+                );
+                active.push((current.end, current.value, reg));
+            }
+            _ => {
+                let slot = alloc.allocate_slot(ty);
+                alloc.assignments.insert(
+                    current.value,
+                    Assignment {
+                        reg: None,
+                        split_at: None,
+                        slot: Some(slot),
+                        start: current.start,
+                        end: current.end,
+                    },
+                );
+            }
+        }
+    }
+
+    alloc
+}
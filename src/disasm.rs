@@ -0,0 +1,98 @@
+//! Disassembly of generated code, annotated with block and label names,
+//! relocation targets and the constants loaded from the data segment.
+
+use crate::backend::dseg;
+use crate::function::Function;
+use capstone::arch::x86::X86OperandType;
+use capstone::arch::ArchOperand;
+use capstone::prelude::*;
+use std::fmt::Write;
+
+fn constant(value: &dseg::Value) -> String {
+    match value {
+        dseg::Value::Ptr(ptr) => format!("{:p}", ptr),
+        dseg::Value::Float(v) => format!("{:?}f32", v),
+        dseg::Value::Double(v) => format!("{:?}f64", v),
+        dseg::Value::Int(v) => format!("{}i32", v),
+        dseg::Value::F4(v) => format!("{:?}", v),
+    }
+}
+
+/// Disassemble `code`, the code of `func` placed at `address`.
+pub(crate) fn disassemble(func: &Function, code: &[u8], address: u64) -> String {
+    let cs = Capstone::new()
+        .x86()
+        .mode(arch::x86::ArchMode::Mode64)
+        .syntax(arch::x86::ArchSyntax::Intel)
+        .detail(true)
+        .build()
+        .expect("failed to create disassembler");
+    let insns = match cs.disasm_all(code, address) {
+        Ok(insns) => insns,
+        Err(err) => return format!("; disassembly failed: {}\n", err),
+    };
+
+    let names = func.block_names();
+    let mut out = String::new();
+    writeln!(out, "{}:", func.name).unwrap();
+
+    for insn in insns.iter() {
+        let offset = (insn.address() - address) as usize;
+        let len = insn.bytes().len();
+        for (_, name) in names.iter().filter(|(at, _)| *at == offset) {
+            writeln!(out, "{}:", name).unwrap();
+        }
+
+        write!(
+            out,
+            "  {:#x}: {} {}",
+            insn.address(),
+            insn.mnemonic().unwrap_or(""),
+            insn.op_str().unwrap_or("")
+        )
+        .unwrap();
+
+        let mut notes = vec![];
+        for site in func.asm.traps.iter().filter(|site| site.offset == offset) {
+            notes.push(format!("trap {}", site.code));
+        }
+        for reloc in func.relocs.iter() {
+            if reloc.offset >= offset && reloc.offset < offset + len {
+                notes.push(format!("{:?} {}", reloc.kind, reloc.global_name));
+            }
+        }
+
+        // RIP-relative loads before the start of the code read the data segment
+        if notes.is_empty() {
+            let operands = match cs.insn_detail(&insn) {
+                Ok(detail) => detail.arch_detail().operands(),
+                Err(_) => vec![],
+            };
+            for operand in operands {
+                let mem = match operand {
+                    ArchOperand::X86Operand(op) => match op.op_type {
+                        X86OperandType::Mem(mem) => mem,
+                        _ => continue,
+                    },
+                    _ => continue,
+                };
+                if cs.reg_name(mem.base()).as_deref() != Some("rip") {
+                    continue;
+                }
+                let target = (offset + len) as i64 + mem.disp();
+                if target < 0 {
+                    match func.asm.dseg.entry(-target as i32) {
+                        Some(value) => notes.push(constant(value)),
+                        None => notes.push(format!("data segment - {:#x}", -target)),
+                    }
+                }
+            }
+        }
+
+        if !notes.is_empty() {
+            write!(out, "    ; {}", notes.join(", ")).unwrap();
+        }
+        writeln!(out).unwrap();
+    }
+    out
+}
@@ -0,0 +1,59 @@
+use crate::trap::TrapCode;
+use crate::verifier::VerifierError;
+use std::fmt;
+
+/// Errors reported while building, compiling or linking a `Module`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeaceError {
+    /// A symbol is neither defined in the module nor exported by the
+    /// process or another module.
+    UnresolvedSymbol(String),
+    /// A label was used without being created by `new_label`.
+    UndefinedLabel(String),
+    /// An operand, argument or return value has the wrong type.
+    TypeMismatch(String),
+    /// The operation is valid IR, but not supported by the backend.
+    Unsupported(String),
+    /// Allocating or protecting memory failed.
+    Memory(String),
+    /// Reading or writing a file failed.
+    Io(String),
+    /// The textual IR given to `parser::parse_module` is malformed.
+    Parse(String),
+    /// The function body is inconsistent, see `Function::verify`.
+    Verifier(Vec<VerifierError>),
+    /// Generated code called through `trap::catch_traps` trapped.
+    Trap(TrapCode),
+}
+
+pub type Result<T> = std::result::Result<T, PeaceError>;
+
+impl fmt::Display for PeaceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeaceError::UnresolvedSymbol(name) => write!(f, "unresolved symbol {}", name),
+            PeaceError::UndefinedLabel(name) => write!(f, "undefined label {}", name),
+            PeaceError::TypeMismatch(msg) => write!(f, "type mismatch: {}", msg),
+            PeaceError::Unsupported(msg) => write!(f, "unsupported: {}", msg),
+            PeaceError::Memory(msg) => write!(f, "memory error: {}", msg),
+            PeaceError::Io(msg) => write!(f, "I/O error: {}", msg),
+            PeaceError::Parse(msg) => write!(f, "parse error: {}", msg),
+            PeaceError::Trap(code) => write!(f, "trap: {}", code),
+            PeaceError::Verifier(errors) => {
+                write!(f, "verifier failed:")?;
+                for error in errors.iter() {
+                    write!(f, "\n  {}", error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for PeaceError {}
+
+impl From<std::io::Error> for PeaceError {
+    fn from(err: std::io::Error) -> PeaceError {
+        PeaceError::Io(err.to_string())
+    }
+}
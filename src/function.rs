@@ -1,175 +1,209 @@
-use crate::backend::align;
 use crate::backend::assembler::*;
-use crate::backend::assemblerx64::*;
+use crate::backend::codegen::Codegen;
 use crate::backend::constants_x64::*;
+use crate::backend::regalloc::*;
 use crate::backend::*;
+use crate::error::{PeaceError, Result};
+use crate::ir::*;
 use crate::module::*;
+use crate::trap::TrapPolicy;
 use crate::types::*;
+use crate::verifier::{verify, VerifierError};
 use std::collections::{HashMap, HashSet};
 
-#[derive(Clone, Debug, Copy, PartialEq)]
-enum ValueData {
-    Gpr(Register),
-    Fpr(XMMRegister),
-    Stack(i32),
+/// How the address of a symbol is patched into the code. `S` is the address
+/// of the symbol, `A` the addend, `P` the address of the patched field, `G`
+/// the address of the symbol's GOT slot and `L` the address of the slot
+/// `Module::redefine_function` swaps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RelocKind {
+    /// 8-byte absolute address, `S + A`.
+    Abs64,
+    /// 4-byte displacement of a call or jump, `S + A - P`. Targets out of
+    /// range are reached through a stub.
+    Rel32,
+    /// 4-byte RIP-relative displacement of a data access, `S + A - P`.
+    RipRel32,
+    /// 4-byte RIP-relative displacement of a slot holding the address of the
+    /// symbol, `G + A - P`.
+    GotRel32,
+    /// 4-byte RIP-relative displacement of a data segment entry, the entry is
+    /// set to `L`. Only supported for code placed by `Module::finish`.
+    Slot,
 }
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub(crate) struct Reloc {
+    pub kind: RelocKind,
     pub global_name: String,
-    pub at: usize,
-    pub to: usize,
+    /// Position of the patched field in the code.
+    pub offset: usize,
+    pub addend: i64,
 }
 
-impl ValueData {
-    pub fn gpr(&self) -> Register {
-        match self {
-            ValueData::Gpr(reg) => *reg,
-            _ => panic!(""),
-        }
-    }
-    pub fn fpr(&self) -> XMMRegister {
-        match self {
-            ValueData::Fpr(reg) => *reg,
-            _ => panic!(""),
-        }
-    }
-    pub fn off(&self) -> i32 {
-        match self {
-            ValueData::Stack(off) => *off,
-            _ => panic!(""),
-        }
-    }
-
-    pub fn is_gpr(&self) -> bool {
-        match self {
-            ValueData::Gpr(_) => true,
-            _ => false,
-        }
-    }
-    pub fn is_off(&self) -> bool {
-        match self {
-            ValueData::Stack(_) => true,
-            _ => false,
-        }
-    }
-
-    pub fn is_fpr(&self) -> bool {
-        !self.is_gpr() && !self.is_off()
-    }
+/// How much work `finalize` spends on the generated code.
+#[derive(Clone, Debug, PartialEq, Eq, Copy)]
+pub enum OptLevel {
+    /// Keep every value in its own stack slot, like the old single pass code
+    /// generator did. Fastest to compile.
+    None,
+    /// Linear scan register allocation.
+    Speed,
 }
 
-#[cfg(target_family = "windows")]
-const ARG_GPR: [Register; 4] = [RCX, RDX, R8, R9];
-#[cfg(target_family = "windows")]
-const ARG_FPR: [Register; 4] = [XMM0, XMM1, XMM2, XMM3];
-
-#[cfg(target_family = "unix")]
-const ARG_GPR: [Register; 6] = [RDI, RSI, RDX, RCX, R8, R9];
-#[cfg(target_family = "unix")]
-const ARG_FPR: [XMMRegister; 8] = [XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6, XMM7];
 #[derive(Clone)]
 pub struct Function {
     pub name: String,
+    pub signature: Signature,
     pub asm: Assembler,
-    pub free: HashSet<Reg>,
     pub stack_offset: i32,
+    /// Registers used by the generated code, filled in by `finalize`.
     pub used: HashSet<Reg>,
     pub(crate) relocs: Vec<Reloc>,
-    variables: HashMap<u32, (Type, i32)>,
-    values: HashMap<Value, (ValueData, Type)>,
-    value_id: u32,
-    labels: HashMap<String, usize>,
+    pub opt_level: OptLevel,
+    pub trap_policy: TrapPolicy,
+    /// Reach other functions and symbols through their slot, see
+    /// `set_indirect_calls`.
+    pub indirect_calls: bool,
+    body: Body,
+    current_block: Block,
+    labels: HashMap<String, Block>,
+    /// Code offset of every placed block, filled in by `finalize`.
+    block_offsets: HashMap<Block, usize>,
+    finalized: bool,
     pub linkage: crate::module::Linkage,
 }
 
 impl Function {
-    pub fn new(name: &str, linkage: Linkage) -> Function {
-        let mut f = Function {
-            values: HashMap::new(),
+    pub fn new(name: &str, linkage: Linkage, signature: Signature) -> Function {
+        let mut body = Body::new();
+        let entry = body.make_block();
+        body.layout.push(entry);
+        // function parameters are the parameters of the entry block
+        for ty in signature.params.iter() {
+            let param = body.make_value(*ty);
+            body.blocks[entry.0 as usize].params.push(param);
+        }
+
+        Function {
             name: name.to_owned(),
-            linkage: linkage,
+            signature,
+            linkage,
             asm: Assembler::new(),
-            free: HashSet::new(),
             stack_offset: 0,
             used: HashSet::new(),
             relocs: vec![],
-            variables: HashMap::new(),
-            value_id: 0,
+            opt_level: OptLevel::Speed,
+            trap_policy: TrapPolicy::Hardware,
+            indirect_calls: false,
+            body,
+            current_block: entry,
             labels: HashMap::new(),
-        };
-        f.new_label("<__epilog__>");
-        f.prolog();
-        f
+            block_offsets: HashMap::new(),
+            finalized: false,
+        }
     }
-    pub fn new_label(&mut self, name: &str) {
-        let label = self.asm.create_label();
-        self.labels.insert(name.to_owned(), label);
+
+    /// Create a function from an already built `body`. Its first block in
+    /// the layout is the entry block.
+    pub(crate) fn from_body(
+        name: &str,
+        linkage: Linkage,
+        signature: Signature,
+        body: Body,
+    ) -> Result<Function> {
+        let mut func = Function::new(name, linkage, signature);
+        func.current_block = *body
+            .layout
+            .last()
+            .ok_or_else(|| PeaceError::Unsupported(format!("function {} has no blocks", name)))?;
+        func.body = body;
+        Ok(func)
     }
 
-    pub fn bind_label(&mut self, name: &str) {
-        let label = self.labels.get(name).expect("Label not found");
+    pub fn set_opt_level(&mut self, opt_level: OptLevel) {
+        self.opt_level = opt_level;
+    }
 
-        self.asm.bind_label(*label);
+    /// How divisions guard against a zero divisor and overflow.
+    pub fn set_trap_policy(&mut self, trap_policy: TrapPolicy) {
+        self.trap_policy = trap_policy;
     }
 
-    pub fn asm_mut<'a>(&'a mut self) -> &'a mut Assembler {
-        &mut self.asm
+    /// Call functions and take symbol addresses through a slot owned by the
+    /// module instead of the address fixed by `finish`, such calls pick up
+    /// definitions replaced by `Module::redefine_function`.
+    pub fn set_indirect_calls(&mut self, indirect_calls: bool) {
+        self.indirect_calls = indirect_calls;
     }
 
-    pub fn get_value_type(&self, value: Value) -> Type {
-        self.values.get(&value).expect("Value not found").1
+    /// Instructions built so far.
+    pub fn body(&self) -> &Body {
+        &self.body
     }
 
-    fn get_value_loc(&self, value: Value) -> ValueData {
-        self.values.get(&value).expect("Value not found").0
+    /// Get the value of the `idx`th function parameter.
+    pub fn param(&self, idx: usize) -> Result<Value> {
+        let params = &self.body.block(Block::new(0)).params;
+        params.get(idx).cloned().ok_or_else(|| {
+            PeaceError::TypeMismatch(format!(
+                "function {} has only {} parameters",
+                self.name,
+                params.len()
+            ))
+        })
     }
 
-    fn free(&mut self, v: Value) {
-        let loc = self.get_value_loc(v);
-        match loc {
-            ValueData::Gpr(reg) => {
-                self.used.remove(&Reg::Gpr(reg));
-            }
-            ValueData::Fpr(reg) => {
-                self.used.remove(&Reg::Float(reg));
-            }
-            _ => {}
-        };
-        self.values.remove(&v);
+    fn label(&self, name: &str) -> Result<Block> {
+        self.labels
+            .get(name)
+            .cloned()
+            .ok_or_else(|| PeaceError::UndefinedLabel(name.to_owned()))
     }
 
-    fn allocate_reg(&mut self, ty: Type) -> ValueData {
-        #[cfg(windows)]
-        const AVAIL_GPR: [Register; 7] = [RBX, RSI, RDI, R12, R13, R14, R15];
-        #[cfg(not(windows))]
-        const AVAIL_GPR: [Register; 5] = [RBX, R12, R13, R14, R15];
+    pub fn new_label(&mut self, name: &str) -> Result<()> {
+        if self.labels.contains_key(name) {
+            return Err(PeaceError::Unsupported(format!(
+                "label {} is created twice",
+                name
+            )));
+        }
+        let block = self.create_block();
+        self.labels.insert(name.to_owned(), block);
+        Ok(())
+    }
 
-        #[cfg(windows)]
-        const AVAIL_FPR: [XMMRegister; 10] =
-            [XMM8, XMM9, XMM10, XMM11, XMM12, XMM13, XMM14, XMM15, XMM16];
-        #[cfg(not(windows))]
-        const AVAIL_FPR: [XMMRegister; 6] = [XMM10, XMM11, XMM12, XMM13, XMM14, XMM15];
+    /// Bind `name` to the current position. Code before the label falls
+    /// through into it.
+    pub fn bind_label(&mut self, name: &str) -> Result<()> {
+        let block = self.label(name)?;
 
-        if !ty.is_float() {
-            for reg in AVAIL_GPR.iter() {
-                if !self.used.contains(&Reg::Gpr(*reg)) {
-                    self.used.insert(Reg::Gpr(*reg));
+        if self.body.terminator(self.current_block).is_none() {
+            self.br(block, &[])?;
+        }
+        self.switch_to_block(block)
+    }
 
-                    return ValueData::Gpr(*reg);
-                }
-            }
-            let off = self.allocate_in_stack(ty);
-            ValueData::Stack(-off)
-        } else {
-            for reg in AVAIL_FPR.iter() {
-                if !self.used.contains(&Reg::Float(*reg)) {
-                    self.used.insert(Reg::Float(*reg));
-                    return ValueData::Fpr(*reg);
-                }
-            }
-            let off = self.allocate_in_stack(ty);
-            ValueData::Stack(-off)
+    pub fn asm_mut<'a>(&'a mut self) -> &'a mut Assembler {
+        &mut self.asm
+    }
+
+    pub fn get_value_type(&self, value: Value) -> Result<Type> {
+        self.body
+            .value_types
+            .get(value.0 as usize)
+            .cloned()
+            .ok_or_else(|| PeaceError::TypeMismatch(format!("value v{} does not exist", value.0)))
+    }
+
+    fn check_block(&self, block: Block) -> Result<()> {
+        if block.0 as usize >= self.body.blocks.len() {
+            return Err(PeaceError::TypeMismatch(format!(
+                "block{} does not exist",
+                block.0
+            )));
         }
+        Ok(())
     }
 
     pub fn allocate_in_stack(&mut self, ty: Type) -> i32 {
@@ -179,402 +213,766 @@ impl Function {
         offset
     }
 
-    pub fn iconst(&mut self, ty: Type, imm: impl Into<i64>) -> Value {
-        assert!(!ty.is_float());
-        let value = Value::new(self.value_id);
-        self.value_id += 1;
-        let loc = self.allocate_reg(ty);
+    /// Append an instruction producing a value of type `ty` to the current block.
+    fn push_value(&mut self, data: InstData, ty: Type) -> Value {
+        let value = self.body.make_value(ty);
+        self.body.push_inst(self.current_block, data, Some(value));
+        value
+    }
 
-        if loc.is_gpr() {
-            let imm = imm.into();
-            if imm == 0 {
-                emit_xor_reg_reg(&mut self.asm, 1, loc.gpr(), loc.gpr());
-            } else {
-                self.asm.load_int_const(ty.to_machine(), loc.gpr(), imm);
-            }
-        } else {
-            self.asm.load_int_const(ty.to_machine(), RAX, imm.into());
-            self.asm
-                .store_mem(ty.to_machine(), Mem::Local(loc.off()), Reg::Gpr(RAX));
+    fn push_inst(&mut self, data: InstData) {
+        self.body.push_inst(self.current_block, data, None);
+    }
+
+    pub fn iconst(&mut self, ty: Type, imm: impl Into<i64>) -> Result<Value> {
+        if ty.is_float() || ty == Type::Void {
+            return Err(PeaceError::TypeMismatch(format!("iconst of type {:?}", ty)));
         }
-        self.values.insert(value, (loc, ty));
-        value
+        Ok(self.push_value(
+            InstData::IConst {
+                ty,
+                imm: imm.into(),
+            },
+            ty,
+        ))
     }
-    fn bin_int(
-        &mut self,
-        x: Value,
-        y: Value,
-        f: &Fn(&mut Assembler, MachineMode, Register, Register, Register),
-    ) -> Value {
-        let value = Value::new(self.value_id);
-        self.value_id += 1;
-        let ty = self.get_value_type(x);
-        let x_loc = self.get_value_loc(x);
-        let y_loc = self.get_value_loc(y);
-        self.free(x);
-        self.free(y);
-        let loc = self.allocate_reg(ty);
-
-        self.values.insert(value, (loc, ty));
-
-        if x_loc.is_gpr() && y_loc.is_gpr() {
-            if loc.is_gpr() {
-                f(
-                    &mut self.asm,
-                    ty.to_machine(),
-                    loc.gpr(),
-                    x_loc.gpr(),
-                    y_loc.gpr(),
-                );
-            } else {
-                f(
-                    &mut self.asm,
-                    ty.to_machine(),
-                    RAX,
-                    x_loc.gpr(),
-                    y_loc.gpr(),
-                );
-                self.asm
-                    .store_mem(ty.to_machine(), Mem::Local(loc.off()), Reg::Gpr(RAX));
-            }
-        } else {
-            if x_loc.is_gpr() {
-                self.asm
-                    .load_mem(ty.to_machine(), Reg::Gpr(RAX), Mem::Local(y_loc.off()));
-                f(&mut self.asm, ty.to_machine(), RAX, x_loc.gpr(), RAX);
-                if loc.is_gpr() {
-                    emit_mov_reg_reg(&mut self.asm, ty.x64(), RAX, loc.gpr());
-                } else {
-                    self.asm
-                        .store_mem(ty.to_machine(), Mem::Local(loc.off()), Reg::Gpr(RAX));
-                }
-            } else {
-                self.asm
-                    .load_mem(ty.to_machine(), Reg::Gpr(RAX), Mem::Local(x_loc.off()));
-                self.asm
-                    .load_mem(ty.to_machine(), Reg::Gpr(RCX), Mem::Local(y_loc.off()));
-                if loc.is_gpr() {
-                    f(&mut self.asm, ty.to_machine(), loc.gpr(), RAX, RCX);
-                } else {
-                    f(&mut self.asm, ty.to_machine(), RAX, RAX, RCX);
-                    self.asm
-                        .store_mem(ty.to_machine(), Mem::Local(loc.off()), Reg::Gpr(RAX));
-                }
-            }
+
+    pub fn fconst(&mut self, ty: Type, imm: impl Into<f64>) -> Result<Value> {
+        if !ty.is_float() {
+            return Err(PeaceError::TypeMismatch(format!("fconst of type {:?}", ty)));
         }
-        value
+        Ok(self.push_value(
+            InstData::FConst {
+                ty,
+                imm: imm.into(),
+            },
+            ty,
+        ))
     }
-    pub fn prolog(&mut self) {
-        emit_pushq_reg(&mut self.asm, RBP);
-        emit_mov_reg_reg(&mut self.asm, 1, RSP, RBP);
+
+    /// Check that `x` and `y` are integers of the same type and return it.
+    fn int_operands(&self, x: Value, y: Value, what: &str) -> Result<Type> {
+        let (x_ty, y_ty) = (self.get_value_type(x)?, self.get_value_type(y)?);
+        if x_ty != y_ty || x_ty.is_float() || x_ty == Type::Void {
+            return Err(PeaceError::TypeMismatch(format!(
+                "{} of {:?} and {:?}",
+                what, x_ty, y_ty
+            )));
+        }
+        Ok(x_ty)
     }
+
+    fn bin_int(&mut self, x: Value, y: Value, op: BinaryOp) -> Result<Value> {
+        let ty = self.int_operands(x, y, &format!("{:?}", op))?;
+        // only the low byte of I8 values is defined, which just bitwise
+        // operations leave alone
+        if ty == Type::I8 && !op.is_bitwise() {
+            return Err(PeaceError::Unsupported(format!("{:?} on I8", op)));
+        }
+        Ok(self.push_value(InstData::Binary { op, x, y }, ty))
+    }
+
     /// Integer addition
-    pub fn iadd(&mut self, x: Value, y: Value) -> Value {
-        self.bin_int(x, y, &Assembler::int_add)
+    pub fn iadd(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_int(x, y, BinaryOp::IAdd)
     }
     /// Integer multiplication
-    pub fn imul(&mut self, x: Value, y: Value) -> Value {
-        self.bin_int(x, y, &Assembler::int_mul)
+    pub fn imul(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_int(x, y, BinaryOp::IMul)
     }
     /// Integer substraction
-    pub fn isub(&mut self, x: Value, y: Value) -> Value {
-        self.bin_int(x, y, &Assembler::int_sub)
+    pub fn isub(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_int(x, y, BinaryOp::ISub)
     }
     /// Integer division
-    pub fn idiv(&mut self, x: Value, y: Value) -> Value {
-        self.bin_int(x, y, &Assembler::int_div)
+    pub fn idiv(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_int(x, y, BinaryOp::IDiv)
     }
 
-    pub fn imod(&mut self, x: Value, y: Value) -> Value {
-        self.bin_int(x, y, &Assembler::int_mod)
+    pub fn imod(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_int(x, y, BinaryOp::IMod)
+    }
+    /// Unsigned integer division
+    pub fn udiv(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_int(x, y, BinaryOp::UDiv)
+    }
+    /// Unsigned integer remainder
+    pub fn urem(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_int(x, y, BinaryOp::URem)
     }
 
+    /// Bitwise and, also of I8 values such as comparison results
+    pub fn band(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_int(x, y, BinaryOp::BAnd)
+    }
+    /// Bitwise or, also of I8 values such as comparison results
+    pub fn bor(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_int(x, y, BinaryOp::BOr)
+    }
+    /// Bitwise exclusive or, also of I8 values such as comparison results
+    pub fn bxor(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_int(x, y, BinaryOp::BXor)
+    }
+    /// Shift left by `y` modulo the width of the type
+    pub fn ishl(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_int(x, y, BinaryOp::IShl)
+    }
+    /// Logical shift right by `y` modulo the width of the type
+    pub fn ushr(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_int(x, y, BinaryOp::UShr)
+    }
+    /// Arithmetic shift right by `y` modulo the width of the type
+    pub fn sshr(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_int(x, y, BinaryOp::SShr)
+    }
+    /// Rotate left by `y` modulo the width of the type
+    pub fn rotl(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_int(x, y, BinaryOp::Rotl)
+    }
+    /// Rotate right by `y` modulo the width of the type
+    pub fn rotr(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_int(x, y, BinaryOp::Rotr)
+    }
 
-    pub fn jump(&mut self, label: &str) {
-        let l = self.labels.get(label).expect("Label not found");
-        emit_jmp(&mut self.asm, *l);
+    fn bin_int_imm(&mut self, x: Value, imm: i64, op: BinaryOp) -> Result<Value> {
+        let ty = self.int_operands(x, x, &format!("{:?}", op))?;
+        if ty == Type::I8 && !op.is_bitwise() {
+            return Err(PeaceError::Unsupported(format!("{:?} on I8", op)));
+        }
+        let imm = if op.is_shift() {
+            imm & (ty.bits() as i64 - 1)
+        } else if ty == Type::I8 {
+            imm as i8 as i64
+        } else if ty == Type::I32 {
+            imm as i32 as i64
+        } else if imm < i32::MIN as i64 || imm > i32::MAX as i64 {
+            // x86 only has sign extended 32 bit immediates
+            let y = self.iconst(ty, imm)?;
+            return self.bin_int(x, y, op);
+        } else {
+            imm
+        };
+        Ok(self.push_value(InstData::BinaryImm { op, x, imm }, ty))
     }
 
-    pub fn int_cmp(&mut self, x: Value, y: Value, cc: CondCode) -> Value {
-        let value = Value::new(self.value_id);
-        self.value_id += 1;
-        let (x_loc, x_ty) = (self.get_value_loc(x), self.get_value_type(x));
-        let (y_loc, y_ty) = (self.get_value_loc(y), self.get_value_type(y));
+    /// Bitwise and with a constant
+    pub fn band_imm(&mut self, x: Value, imm: i64) -> Result<Value> {
+        self.bin_int_imm(x, imm, BinaryOp::BAnd)
+    }
+    /// Bitwise or with a constant
+    pub fn bor_imm(&mut self, x: Value, imm: i64) -> Result<Value> {
+        self.bin_int_imm(x, imm, BinaryOp::BOr)
+    }
+    /// Bitwise exclusive or with a constant
+    pub fn bxor_imm(&mut self, x: Value, imm: i64) -> Result<Value> {
+        self.bin_int_imm(x, imm, BinaryOp::BXor)
+    }
+    /// Shift left by a constant
+    pub fn ishl_imm(&mut self, x: Value, imm: i64) -> Result<Value> {
+        self.bin_int_imm(x, imm, BinaryOp::IShl)
+    }
+    /// Logical shift right by a constant
+    pub fn ushr_imm(&mut self, x: Value, imm: i64) -> Result<Value> {
+        self.bin_int_imm(x, imm, BinaryOp::UShr)
+    }
+    /// Arithmetic shift right by a constant
+    pub fn sshr_imm(&mut self, x: Value, imm: i64) -> Result<Value> {
+        self.bin_int_imm(x, imm, BinaryOp::SShr)
+    }
+    /// Rotate left by a constant
+    pub fn rotl_imm(&mut self, x: Value, imm: i64) -> Result<Value> {
+        self.bin_int_imm(x, imm, BinaryOp::Rotl)
+    }
+    /// Rotate right by a constant
+    pub fn rotr_imm(&mut self, x: Value, imm: i64) -> Result<Value> {
+        self.bin_int_imm(x, imm, BinaryOp::Rotr)
+    }
 
-        self.free(x);
-        self.free(y);
+    fn unary_int(&mut self, x: Value, op: UnaryOp) -> Result<Value> {
+        let ty = self.int_operands(x, x, &format!("{:?}", op))?;
+        if ty == Type::I8 && op != UnaryOp::BNot {
+            return Err(PeaceError::Unsupported(format!("{:?} on I8", op)));
+        }
+        Ok(self.push_value(InstData::Unary { op, x }, ty))
+    }
 
-        let loc = self.allocate_reg(Type::I8);
+    /// Bitwise not, also of I8 values
+    pub fn bnot(&mut self, x: Value) -> Result<Value> {
+        self.unary_int(x, UnaryOp::BNot)
+    }
+    /// Integer negation
+    pub fn ineg(&mut self, x: Value) -> Result<Value> {
+        self.unary_int(x, UnaryOp::INeg)
+    }
+    /// Number of set bits, the CPU needs to support `popcnt`
+    pub fn popcnt(&mut self, x: Value) -> Result<Value> {
+        self.unary_int(x, UnaryOp::Popcnt)
+    }
+    /// Number of leading zero bits, the width of the type if `x` is zero
+    pub fn clz(&mut self, x: Value) -> Result<Value> {
+        self.unary_int(x, UnaryOp::Clz)
+    }
+    /// Number of trailing zero bits, the width of the type if `x` is zero
+    pub fn ctz(&mut self, x: Value) -> Result<Value> {
+        self.unary_int(x, UnaryOp::Ctz)
+    }
 
-        if x_loc.is_off() && y_loc.is_off() {
-            self.asm
-                .load_mem(x_ty.to_machine(), Reg::Gpr(RAX), Mem::Local(x_loc.off()));
-            self.asm
-                .load_mem(y_ty.to_machine(), Reg::Gpr(RBX), Mem::Local(y_loc.off()));
-            self.asm.cmp_reg(x_ty.to_machine(), RAX, RBX);
-            self.asm.set(RAX, cc);
-        } else if x_loc.is_off() {
-            self.asm
-                .load_mem(x_ty.to_machine(), Reg::Gpr(RAX), Mem::Local(x_loc.off()));
-            self.asm.cmp_reg(x_ty.to_machine(), RAX, y_loc.gpr());
-            self.asm.set(RAX, cc);
-        } else if y_loc.is_off() {
-            self.asm
-                .load_mem(y_ty.to_machine(), Reg::Gpr(RAX), Mem::Local(y_loc.off()));
-            self.asm.cmp_reg(x_ty.to_machine(), x_loc.gpr(), RAX);
-            self.asm.set(RAX, cc);
-        } else {
-            self.asm
-                .cmp_reg(x_ty.to_machine(), x_loc.gpr(), y_loc.gpr());
-            self.asm.set(RAX, cc);
+    /// Check that `x` and `y` are floats of the same type and return it.
+    fn float_operands(&self, x: Value, y: Value, what: &str) -> Result<Type> {
+        let (x_ty, y_ty) = (self.get_value_type(x)?, self.get_value_type(y)?);
+        if x_ty != y_ty || !x_ty.is_float() {
+            return Err(PeaceError::TypeMismatch(format!(
+                "{} of {:?} and {:?}",
+                what, x_ty, y_ty
+            )));
         }
+        Ok(x_ty)
+    }
 
-        if loc.is_off() {
-            self.asm
-                .store_mem(MachineMode::Int8, Mem::Local(loc.off()), Reg::Gpr(RAX));
-        } else {
-            emit_movb_reg_reg(&mut self.asm, RAX, loc.gpr());
-        }
+    fn bin_float(&mut self, x: Value, y: Value, op: BinaryOp) -> Result<Value> {
+        let ty = self.float_operands(x, y, &format!("{:?}", op))?;
+        Ok(self.push_value(InstData::Binary { op, x, y }, ty))
+    }
 
-        self.values.insert(value, (loc, x_ty));
+    fn unary_float(&mut self, x: Value, op: UnaryOp) -> Result<Value> {
+        let ty = self.float_operands(x, x, &format!("{:?}", op))?;
+        Ok(self.push_value(InstData::Unary { op, x }, ty))
+    }
 
-        value
+    /// Float addition
+    pub fn fadd(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_float(x, y, BinaryOp::FAdd)
+    }
+    /// Float substraction
+    pub fn fsub(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_float(x, y, BinaryOp::FSub)
+    }
+    /// Float multiplication
+    pub fn fmul(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_float(x, y, BinaryOp::FMul)
+    }
+    /// Float division
+    pub fn fdiv(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_float(x, y, BinaryOp::FDiv)
+    }
+    /// Smaller of `x` and `y`. Like `minsd` this is `y` if either is NaN or
+    /// both are zero.
+    pub fn fmin(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_float(x, y, BinaryOp::FMin)
+    }
+    /// Larger of `x` and `y`. Like `maxsd` this is `y` if either is NaN or
+    /// both are zero.
+    pub fn fmax(&mut self, x: Value, y: Value) -> Result<Value> {
+        self.bin_float(x, y, BinaryOp::FMax)
     }
 
-    pub fn float_cmp(&mut self, x: Value, y: Value, cc: CondCode) -> Value {
-        let value = Value::new(self.value_id);
-        self.value_id += 1;
-
-        let (x_loc, x_ty) = (self.get_value_loc(x), self.get_value_type(x));
-        let (y_loc, y_ty) = (self.get_value_loc(y), self.get_value_type(y));
-
-        assert!(x_ty.is_float() && y_ty.is_float(), "Float values expected");
-
-        self.free(x);
-        self.free(y);
-        let loc = self.allocate_reg(Type::I8);
-        if x_loc.is_off() && y_loc.is_off() {
-            self.asm
-                .load_mem(x_ty.to_machine(), Reg::Float(XMM0), Mem::Local(x_loc.off()));
-            self.asm
-                .load_mem(y_ty.to_machine(), Reg::Float(XMM1), Mem::Local(y_loc.off()));
-
-            self.asm.float_cmp(x_ty.to_machine(), RAX, XMM0, XMM1, cc);
-
-        } else if x_loc.is_off() {
-            self.asm
-                .load_mem(x_ty.to_machine(), Reg::Float(XMM0), Mem::Local(x_loc.off()));
-            self.asm
-                .float_cmp(x_ty.to_machine(), RAX, XMM0, y_loc.fpr(), cc);
-        } else if y_loc.is_off() {
-            self.asm
-                .load_mem(y_ty.to_machine(), Reg::Float(XMM0), Mem::Local(y_loc.off()));
-            self.asm
-                .float_cmp(x_ty.to_machine(), RAX, x_loc.fpr(), XMM0, cc);
-        } else {
-            self.asm
-                .float_cmp(x_ty.to_machine(), RAX, x_loc.fpr(), y_loc.fpr(), cc);
-        }
-        if loc.is_off() {
-            self.asm
-                .store_mem(MachineMode::Int8, Mem::Local(loc.off()), Reg::Gpr(RAX));
-        } else {
-            emit_movb_reg_reg(&mut self.asm, RAX, loc.gpr());
+    pub fn fneg(&mut self, x: Value) -> Result<Value> {
+        self.unary_float(x, UnaryOp::FNeg)
+    }
+
+    pub fn fabs(&mut self, x: Value) -> Result<Value> {
+        self.unary_float(x, UnaryOp::FAbs)
+    }
+
+    pub fn fsqrt(&mut self, x: Value) -> Result<Value> {
+        self.unary_float(x, UnaryOp::FSqrt)
+    }
+
+    fn convert(&mut self, op: ConvertOp, ty: Type, x: Value) -> Result<Value> {
+        let from = self.get_value_type(x)?;
+        if !op.accepts(from, ty) {
+            return Err(PeaceError::TypeMismatch(format!(
+                "{:?} from {:?} to {:?}",
+                op, from, ty
+            )));
         }
+        Ok(self.push_value(InstData::Convert { op, ty, x }, ty))
+    }
 
-        self.values.insert(value, (loc, x_ty));
-        value
+    /// Sign extend the integer `x` to the wider type `ty`.
+    pub fn sextend(&mut self, ty: Type, x: Value) -> Result<Value> {
+        self.convert(ConvertOp::SExtend, ty, x)
+    }
+    /// Zero extend the integer `x` to the wider type `ty`.
+    pub fn uextend(&mut self, ty: Type, x: Value) -> Result<Value> {
+        self.convert(ConvertOp::UExtend, ty, x)
+    }
+    /// Keep the low bits of `x` as the narrower type `ty`.
+    pub fn ireduce(&mut self, ty: Type, x: Value) -> Result<Value> {
+        self.convert(ConvertOp::IReduce, ty, x)
+    }
+    /// Convert the signed integer `x` to a float.
+    pub fn fcvt_from_sint(&mut self, ty: Type, x: Value) -> Result<Value> {
+        self.convert(ConvertOp::FcvtFromSInt, ty, x)
+    }
+    /// Convert the unsigned integer `x` to a float.
+    pub fn fcvt_from_uint(&mut self, ty: Type, x: Value) -> Result<Value> {
+        self.convert(ConvertOp::FcvtFromUInt, ty, x)
+    }
+    /// Truncate `x` to a signed integer, trapping on NaN and values out of range.
+    pub fn fcvt_to_sint(&mut self, ty: Type, x: Value) -> Result<Value> {
+        self.convert(ConvertOp::FcvtToSInt, ty, x)
+    }
+    /// Truncate `x` to an unsigned integer, trapping on NaN and values out of
+    /// range.
+    pub fn fcvt_to_uint(&mut self, ty: Type, x: Value) -> Result<Value> {
+        self.convert(ConvertOp::FcvtToUInt, ty, x)
+    }
+    /// Truncate `x` to a signed integer, saturating values out of range. NaN
+    /// becomes zero.
+    pub fn fcvt_to_sint_sat(&mut self, ty: Type, x: Value) -> Result<Value> {
+        self.convert(ConvertOp::FcvtToSIntSat, ty, x)
+    }
+    /// Truncate `x` to an unsigned integer, saturating values out of range.
+    /// NaN becomes zero.
+    pub fn fcvt_to_uint_sat(&mut self, ty: Type, x: Value) -> Result<Value> {
+        self.convert(ConvertOp::FcvtToUIntSat, ty, x)
     }
 
-    pub fn load(&mut self, base: Value, offset: i32, ty: Type) -> Value {
-        assert!(!self.get_value_type(base).is_float());
-        let value = Value::new(self.value_id);
-        self.value_id += 1;
-        let loc = self.get_value_loc(base);
-        self.free(base);
-        if loc.is_off() {
-            self.asm
-                .load_mem(ty.to_machine(), Reg::Gpr(RAX), Mem::Local(loc.off()));
-        }
-        let new_loc = self.allocate_reg(ty);
-        if ty.is_float() {
-            self.asm.load_mem(
-                ty.to_machine(),
-                Reg::Float(XMM0),
-                Mem::Base(if loc.is_off() { RAX } else { loc.gpr() }, offset),
-            );
-            if new_loc.is_off() {
-                self.asm
-                    .store_mem(ty.to_machine(), Mem::Local(new_loc.off()), Reg::Float(XMM0));
-            } else {
-                if ty.x64() != 0 {
-                    movsd(&mut self.asm, loc.fpr(), XMM0);
-                } else {
-                    movss(&mut self.asm, loc.fpr(), XMM0);
-                }
-            }
-        } else {
-            self.asm.load_mem(
-                ty.to_machine(),
-                Reg::Gpr(RAX),
-                Mem::Base(if loc.is_off() { RAX } else { loc.gpr() }, offset),
-            );
-
-            if new_loc.is_off() {
-                self.asm
-                    .store_mem(ty.to_machine(), Mem::Local(new_loc.off()), Reg::Gpr(RAX));
-            } else {
-                emit_mov_reg_reg(&mut self.asm, ty.x64(), RAX, loc.gpr());
-            }
+    pub fn fpromote(&mut self, ty: Type, x: Value) -> Result<Value> {
+        self.convert(ConvertOp::FPromote, ty, x)
+    }
+
+    pub fn fdemote(&mut self, ty: Type, x: Value) -> Result<Value> {
+        self.convert(ConvertOp::FDemote, ty, x)
+    }
+
+    /// Reinterpret the bits of `x` as `ty`, an integer and a float of the same
+    /// size.
+    pub fn bitcast(&mut self, ty: Type, x: Value) -> Result<Value> {
+        self.convert(ConvertOp::Bitcast, ty, x)
+    }
+
+    pub fn jump(&mut self, label: &str) -> Result<()> {
+        let block = self.label(label)?;
+        self.br(block, &[])
+    }
+
+    /// Create a new basic block. The block is not placed into the code
+    /// until `switch_to_block` is called.
+    pub fn create_block(&mut self) -> Block {
+        self.body.make_block()
+    }
+
+    /// Append a parameter of type `ty` to `block`. Every branch to `block` has to
+    /// pass a value of this type.
+    pub fn append_block_param(&mut self, block: Block, ty: Type) -> Result<Value> {
+        self.check_block(block)?;
+        let value = self.body.make_value(ty);
+        self.body.blocks[block.0 as usize].params.push(value);
+        Ok(value)
+    }
+
+    /// Start emitting code into `block`. Blocks are placed into the code in
+    /// the order they are first switched to.
+    pub fn switch_to_block(&mut self, block: Block) -> Result<()> {
+        self.check_block(block)?;
+        if !self.body.layout.contains(&block) {
+            self.body.layout.push(block);
         }
+        self.current_block = block;
+        Ok(())
+    }
 
-        self.values.insert(value, (new_loc, ty));
-        value
+    /// Unconditional branch to `block` passing `args` as block parameters.
+    pub fn br(&mut self, block: Block, args: &[Value]) -> Result<()> {
+        self.check_block_args(block, args)?;
+        self.push_inst(InstData::Jump {
+            block,
+            args: args.to_vec(),
+        });
+        Ok(())
+    }
+
+    /// Branch to `then_block` if `cond` is non-zero and to `else_block` otherwise.
+    pub fn brif(
+        &mut self,
+        cond: Value,
+        then_block: Block,
+        then_args: &[Value],
+        else_block: Block,
+        else_args: &[Value],
+    ) -> Result<()> {
+        let ty = self.get_value_type(cond)?;
+        if ty.is_float() || ty == Type::Void {
+            return Err(PeaceError::TypeMismatch(format!(
+                "branch condition of type {:?}",
+                ty
+            )));
+        }
+        self.check_block_args(then_block, then_args)?;
+        self.check_block_args(else_block, else_args)?;
+        self.push_inst(InstData::Brif {
+            cond,
+            then_block,
+            then_args: then_args.to_vec(),
+            else_block,
+            else_args: else_args.to_vec(),
+        });
+        Ok(())
     }
 
-    pub fn ret(&mut self, x: Value) {
-        let loc = self.get_value_loc(x);
+    fn check_block_args(&self, block: Block, args: &[Value]) -> Result<()> {
+        self.check_block(block)?;
+        // the entry block starts by moving the arguments into its parameters,
+        // a branch back to it would do that again
+        if self.body.layout.first() == Some(&block) {
+            return Err(PeaceError::Unsupported(format!(
+                "branch to the entry block block{}",
+                block.0
+            )));
+        }
+        let params = &self.body.block(block).params;
+        let types = |values: &[Value]| -> Result<Vec<Type>> {
+            values.iter().map(|v| self.get_value_type(*v)).collect()
+        };
+        let (param_types, arg_types) = (types(params)?, types(args)?);
 
-        if loc.is_gpr() {
-            emit_mov_reg_reg(&mut self.asm, 1, loc.gpr(), RAX);
-        } else if loc.is_fpr() {
-            movsd(&mut self.asm, XMM0, loc.fpr());
-        } else {
-            let ty = self.get_value_type(x);
-            if ty.is_float() {
-                self.asm
-                    .load_mem(ty.to_machine(), Reg::Float(XMM0), Mem::Local(loc.off()));
-            } else {
-                self.asm
-                    .load_mem(ty.to_machine(), Reg::Gpr(RAX), Mem::Local(loc.off()));
-            }
+        if param_types != arg_types {
+            return Err(PeaceError::TypeMismatch(format!(
+                "block {} takes {:?}, got {:?}",
+                block.0, param_types, arg_types
+            )));
         }
+        Ok(())
+    }
+
+    pub fn int_cmp(&mut self, x: Value, y: Value, cc: CondCode) -> Result<Value> {
+        self.int_operands(x, y, "int_cmp")?;
+        Ok(self.push_value(InstData::IntCmp { cc, x, y }, Type::I8))
+    }
 
-        self.jump("<__epilog__>");
+    pub fn float_cmp(&mut self, x: Value, y: Value, cc: CondCode) -> Result<Value> {
+        let (x_ty, y_ty) = (self.get_value_type(x)?, self.get_value_type(y)?);
+        if x_ty != y_ty || !x_ty.is_float() {
+            return Err(PeaceError::TypeMismatch(format!(
+                "float_cmp of {:?} and {:?}",
+                x_ty, y_ty
+            )));
+        }
+        Ok(self.push_value(InstData::FloatCmp { cc, x, y }, Type::I8))
+    }
 
+    pub fn load(&mut self, base: Value, offset: i32, ty: Type) -> Result<Value> {
+        let base_ty = self.get_value_type(base)?;
+        if base_ty.is_float() || base_ty == Type::Void || ty == Type::Void {
+            return Err(PeaceError::TypeMismatch(format!(
+                "load of {:?} from base of type {:?}",
+                ty, base_ty
+            )));
+        }
+        Ok(self.push_value(InstData::Load { ty, base, offset }, ty))
     }
 
-    pub fn finalize(&mut self) {
-        let l = self.labels.get("<__epilog__>").unwrap();
-        self.asm.bind_label(*l);
-        emit_popq_reg(&mut self.asm, RBP);
-        self.asm.emit(0xc3);
+    /// Store `value` to `base + offset`.
+    pub fn store(&mut self, value: Value, base: Value, offset: i32) -> Result<()> {
+        let (ty, base_ty) = (self.get_value_type(value)?, self.get_value_type(base)?);
+        if base_ty.is_float() || base_ty == Type::Void || ty == Type::Void {
+            return Err(PeaceError::TypeMismatch(format!(
+                "store of {:?} to base of type {:?}",
+                ty, base_ty
+            )));
+        }
+        self.push_inst(InstData::Store {
+            value,
+            base,
+            offset,
+        });
+        Ok(())
     }
 
-    pub fn call(&mut self, fname: &str, args: &[Value], ret: Type) -> Value {
-        let value = Value::new(self.value_id);
+    /// Check the address `base + index * scale` of an indexed load or store.
+    fn check_indexed(&self, what: &str, base: Value, index: Value, scale: u8) -> Result<()> {
+        let (base_ty, index_ty) = (self.get_value_type(base)?, self.get_value_type(index)?);
+        if base_ty.is_float() || base_ty == Type::Void {
+            return Err(PeaceError::TypeMismatch(format!(
+                "{} with base of type {:?}",
+                what, base_ty
+            )));
+        }
+        // the index takes part in 64 bit address arithmetic
+        if index_ty != Type::I64 && index_ty != Type::Pointer {
+            return Err(PeaceError::TypeMismatch(format!(
+                "{} with index of type {:?}",
+                what, index_ty
+            )));
+        }
+        if !matches!(scale, 1 | 2 | 4 | 8) {
+            return Err(PeaceError::Unsupported(format!(
+                "{} with scale {}, only 1, 2, 4 and 8 are encodable",
+                what, scale
+            )));
+        }
+        Ok(())
+    }
 
-        self.value_id += 1;
+    /// Load a value of type `ty` from `base + index * scale + offset`.
+    pub fn load_indexed(
+        &mut self,
+        base: Value,
+        index: Value,
+        scale: u8,
+        offset: i32,
+        ty: Type,
+    ) -> Result<Value> {
+        self.check_indexed("load_indexed", base, index, scale)?;
+        if ty == Type::Void {
+            return Err(PeaceError::TypeMismatch("load_indexed of Void".to_owned()));
+        }
+        Ok(self.push_value(
+            InstData::LoadIndexed {
+                ty,
+                base,
+                index,
+                scale,
+                offset,
+            },
+            ty,
+        ))
+    }
 
-        let mut used = vec![];
+    /// Store `value` to `base + index * scale + offset`.
+    pub fn store_indexed(
+        &mut self,
+        value: Value,
+        base: Value,
+        index: Value,
+        scale: u8,
+        offset: i32,
+    ) -> Result<()> {
+        self.check_indexed("store_indexed", base, index, scale)?;
+        if self.get_value_type(value)? == Type::Void {
+            return Err(PeaceError::TypeMismatch("store_indexed of Void".to_owned()));
+        }
+        self.push_inst(InstData::StoreIndexed {
+            value,
+            base,
+            index,
+            scale,
+            offset,
+        });
+        Ok(())
+    }
 
-        let register_args = {
-            let mut temp: Vec<(ValueData, Reg, Type)> = vec![];
-            let mut pc = 0;
-            let mut fpc = 0;
+    /// Reserve `size` bytes in the stack frame, aligned to `align`. The frame
+    /// is only 16 byte aligned, so `align` has to be a power of two up to 16.
+    pub fn stack_slot(&mut self, size: u32, align: u32) -> Result<StackSlot> {
+        if !align.is_power_of_two() || align > 16 {
+            return Err(PeaceError::Unsupported(format!(
+                "stack slot aligned to {} bytes",
+                align
+            )));
+        }
+        Ok(self.body.make_stack_slot(size, align))
+    }
 
-            for (idx, value) in args.iter().rev().enumerate() {
-                used.push(idx);
+    /// Address of `slot`, valid until the function returns.
+    pub fn stack_addr(&mut self, slot: StackSlot) -> Result<Value> {
+        if slot.0 as usize >= self.body.stack_slots.len() {
+            return Err(PeaceError::TypeMismatch(format!(
+                "stack slot ss{} does not exist",
+                slot.0
+            )));
+        }
+        Ok(self.push_value(InstData::StackAddr { slot }, Type::Pointer))
+    }
 
-                let ty = self.get_value_type(*value);
-                let loc = self.get_value_loc(*value);
-                if !ty.is_float() {
-                    /*if loc.is_gpr() {
-                        emit_mov_reg_reg(&mut self.asm, ty.x64(), loc.gpr(), ARG_GPR[pc]);
-                    } else {
-                        self.asm.store_mem(ty.to_machine(),Mem::Local(loc.off()),Reg::Gpr(ARG_GPR[pc]));
-                    }*/
+    /// Address of the function or data object `name`. It is loaded from a
+    /// slot filled in by the module, so `name` may be anywhere in memory.
+    pub fn symbol_addr(&mut self, name: &str) -> Result<Value> {
+        if name.is_empty() || name.contains('\0') {
+            return Err(PeaceError::UnresolvedSymbol(name.to_owned()));
+        }
+        Ok(self.push_value(
+            InstData::SymbolAddr {
+                name: name.to_owned(),
+            },
+            Type::Pointer,
+        ))
+    }
 
-                    temp.push((loc, Reg::Gpr(ARG_GPR[pc]), ty));
-                    if pc != ARG_GPR.len() {
-                        pc += 1;
-                    }
-                } else {
-                    if fpc != ARG_FPR.len() {
-                        fpc += 1;
-                    }
-                    temp.push((loc, Reg::Float(ARG_FPR[fpc]), ty));
-                }
-                self.free(*value);
-            }
+    pub fn ret(&mut self, x: Value) -> Result<()> {
+        let ty = self.get_value_type(x)?;
+        if ty != self.signature.ret {
+            return Err(PeaceError::TypeMismatch(format!(
+                "function {} returns {:?}, got value of type {:?}",
+                self.name, self.signature.ret, ty
+            )));
+        }
+        self.push_inst(InstData::Return { value: Some(x) });
+        Ok(())
+    }
 
-            temp
-        };
+    /// Return from a function with a `Void` return type.
+    pub fn ret_void(&mut self) -> Result<()> {
+        if self.signature.ret != Type::Void {
+            return Err(PeaceError::TypeMismatch(format!(
+                "function {} returns {:?}",
+                self.name, self.signature.ret
+            )));
+        }
+        self.push_inst(InstData::Return { value: None });
+        Ok(())
+    }
 
-        for (idx, value) in args.iter().rev().enumerate() {
-            if used.contains(&idx) {
+    /// End blocks without a terminator like `finalize` does: they fall through
+    /// to the next block, the last one returns.
+    fn seal_blocks(body: &mut Body) {
+        let layout = body.layout.clone();
+        for (idx, block) in layout.iter().enumerate() {
+            if body.terminator(*block).is_some() {
                 continue;
             }
+            let data = match layout.get(idx + 1) {
+                Some(next) => InstData::Jump {
+                    block: *next,
+                    args: vec![],
+                },
+                None => InstData::Return { value: None },
+            };
+            body.push_inst(*block, data, None);
+        }
+    }
 
-            let loc = self.get_value_loc(*value);
+    /// Check the instructions built so far as `finalize` would see them.
+    pub fn verify(&self) -> Result<()> {
+        let mut body = self.body.clone();
+        Function::seal_blocks(&mut body);
+        self.verify_body(&body)
+    }
 
-            if loc.is_gpr() {
-                emit_pushq_reg(&mut self.asm, loc.gpr());
-            } else {
-                unimplemented!()
+    fn verify_body(&self, body: &Body) -> Result<()> {
+        let mut errors = match verify(body, &self.signature) {
+            Ok(()) => vec![],
+            Err(errors) => errors,
+        };
 
+        let mut labels: Vec<(&String, &Block)> = self.labels.iter().collect();
+        labels.sort();
+        for (name, block) in labels {
+            if !body.layout.contains(block) {
+                errors.push(VerifierError {
+                    block: Some(*block),
+                    inst: None,
+                    message: format!("label {} is never bound", name),
+                });
             }
         }
 
-        for (loc, to, ty) in register_args.iter() {
-            if !ty.is_float() {
-                if loc.is_gpr() {
-                    emit_mov_reg_reg(&mut self.asm, ty.x64(), loc.gpr(), to.reg());
-                } else {
-                    self.asm
-                        .load_mem(ty.to_machine(), *to, Mem::Local(loc.off()));
-                }
-            } else {
-                if loc.is_fpr() {
-                    if ty.x64() == 0 {
-                        movss(&mut self.asm, to.freg(), loc.fpr());
-                    } else {
-                        movsd(&mut self.asm, to.freg(), loc.fpr());
-                    }
-                } else {
-                    self.asm
-                        .load_mem(ty.to_machine(), *to, Mem::Local(loc.off()));
-                }
-            }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(PeaceError::Verifier(errors))
         }
+    }
 
-        self.asm.load_int_const(MachineMode::Ptr, RAX, 0);
-        self.relocs.push(Reloc {
-            global_name: fname.to_owned(),
-            at: self.asm.pos() - 8,
-            to: self.asm.pos(),
-        });
-        emit_callq_reg(&mut self.asm, RAX);
-        if ret != Type::Void {
-            let loc = self.allocate_reg(ret);
-
-            if loc.is_fpr() {
-                if ret.x64() == 0 {
-                    movss(&mut self.asm, loc.fpr(), XMM0);
-                } else {
-                    movsd(&mut self.asm, loc.fpr(), XMM0);
-                }
-            } else if loc.is_gpr() {
-                emit_mov_reg_reg(&mut self.asm, ret.x64(), RAX, loc.gpr());
-            } else {
-                if ret.is_float() {
-                    self.asm.store_mem(
-                        ret.to_machine(),
-                        Mem::Local(loc.off()),
-                        Reg::Float(loc.fpr()),
-                    );
-                } else {
-                    self.asm.store_mem(
-                        ret.to_machine(),
-                        Mem::Local(loc.off()),
-                        Reg::Gpr(loc.gpr()),
-                    );
-                }
+    /// Allocate registers and generate machine code for the function.
+    pub fn finalize(&mut self) -> Result<()> {
+        Function::seal_blocks(&mut self.body);
+        self.verify_body(&self.body)?;
+
+        let points = ProgramPoints::new(&self.body);
+        let (alloc, liveness) = match self.opt_level {
+            OptLevel::None => (allocate_stack(&self.body, self.stack_offset), None),
+            OptLevel::Speed => {
+                let liveness = Liveness::new(&self.body);
+                let alloc = linear_scan(&self.body, &points, &liveness, self.stack_offset);
+                (alloc, Some(liveness))
             }
+        };
 
+        let mut codegen = Codegen::new(
+            &self.body,
+            &self.signature,
+            &alloc,
+            &points,
+            liveness.as_ref(),
+            &mut self.asm,
+            &mut self.relocs,
+        );
+        codegen.set_trap_policy(self.trap_policy);
+        codegen.set_indirect_calls(self.indirect_calls);
+        codegen.generate();
+        let block_labels = codegen.block_labels().clone();
+
+        self.block_offsets = block_labels
+            .iter()
+            .filter_map(|(block, label)| self.asm.labels[*label].map(|offset| (*block, offset)))
+            .collect();
+        self.stack_offset = alloc.stack_offset;
+        self.used = alloc.used;
+        self.finalized = true;
+        Ok(())
+    }
+
+    /// Whether `finalize` generated the code of the function.
+    pub fn is_finalized(&self) -> bool {
+        self.finalized
+    }
 
-            self.values.insert(value, (loc, ret));
+    /// Names of the code offsets the blocks were placed at, `blockN` or the
+    /// name of the label bound to the block.
+    pub(crate) fn block_names(&self) -> Vec<(usize, String)> {
+        let mut names: Vec<(usize, String)> = self
+            .block_offsets
+            .iter()
+            .map(|(block, offset)| {
+                let label = self.labels.iter().find(|(_, b)| *b == block);
+                match label {
+                    Some((name, _)) => (*offset, name.clone()),
+                    None => (*offset, block.to_string()),
+                }
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Disassemble the code generated by `finalize`, before the module is
+    /// finished. Calls and symbol addresses still have the displacements of
+    /// their unresolved relocations.
+    pub fn disassemble_pending(&self) -> String {
+        let mut asm = self.asm.clone();
+        asm.fix_forward_jumps();
+        crate::disasm::disassemble(self, asm.data(), 0)
+    }
+
+    pub fn call(&mut self, fname: &str, args: &[Value], ret: Type) -> Result<Value> {
+        let params = args
+            .iter()
+            .map(|arg| self.get_value_type(*arg))
+            .collect::<Result<_>>()?;
+        self.call_with_signature(fname, &Signature::new(params, ret), args)
+    }
+
+    /// Call `fname` using `signature`. Variadic callees accept more arguments
+    /// than `signature.params`.
+    pub fn call_with_signature(
+        &mut self,
+        fname: &str,
+        signature: &Signature,
+        args: &[Value],
+    ) -> Result<Value> {
+        let fixed = signature.params.len();
+        let types: Vec<Type> = args
+            .iter()
+            .map(|arg| self.get_value_type(*arg))
+            .collect::<Result<_>>()?;
+        let count_ok = types.len() == fixed || (signature.varargs && types.len() > fixed);
+        if !count_ok || types[..fixed.min(types.len())] != signature.params[..] {
+            return Err(PeaceError::TypeMismatch(format!(
+                "{} takes {:?}, got {:?}",
+                fname, signature.params, types
+            )));
         }
-        value
+
+        Ok(self.push_value(
+            InstData::Call {
+                name: fname.to_owned(),
+                args: args.to_vec(),
+                signature: signature.clone(),
+            },
+            signature.ret,
+        ))
     }
 }
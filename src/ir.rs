@@ -0,0 +1,439 @@
+use crate::backend::CondCode;
+use crate::types::*;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash, PartialOrd, Ord)]
+pub struct Inst(pub u32);
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub enum BinaryOp {
+    IAdd,
+    ISub,
+    IMul,
+    IDiv,
+    IMod,
+    UDiv,
+    URem,
+    FAdd,
+    FSub,
+    FMul,
+    FDiv,
+    /// `y` if either operand is NaN or both are zero, like `minsd`.
+    FMin,
+    /// `y` if either operand is NaN or both are zero, like `maxsd`.
+    FMax,
+    BAnd,
+    BOr,
+    BXor,
+    /// Shifts and rotates use the amount modulo the number of bits.
+    IShl,
+    UShr,
+    SShr,
+    Rotl,
+    Rotr,
+}
+
+impl BinaryOp {
+    /// Whether the operands are floats rather than integers.
+    pub fn is_float(self) -> bool {
+        matches!(
+            self,
+            BinaryOp::FAdd
+                | BinaryOp::FSub
+                | BinaryOp::FMul
+                | BinaryOp::FDiv
+                | BinaryOp::FMin
+                | BinaryOp::FMax
+        )
+    }
+
+    /// Whether the operation is an integer division, which may trap.
+    pub fn is_division(self) -> bool {
+        matches!(
+            self,
+            BinaryOp::IDiv | BinaryOp::IMod | BinaryOp::UDiv | BinaryOp::URem
+        )
+    }
+
+    /// Whether the operation also exists with an immediate right operand.
+    pub fn has_imm_form(self) -> bool {
+        self.is_bitwise() || self.is_shift()
+    }
+
+    /// Whether every bit of the result only depends on the same bit of the
+    /// operands.
+    pub fn is_bitwise(self) -> bool {
+        matches!(self, BinaryOp::BAnd | BinaryOp::BOr | BinaryOp::BXor)
+    }
+
+    pub fn is_shift(self) -> bool {
+        matches!(
+            self,
+            BinaryOp::IShl | BinaryOp::UShr | BinaryOp::SShr | BinaryOp::Rotl | BinaryOp::Rotr
+        )
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub enum UnaryOp {
+    FNeg,
+    FAbs,
+    FSqrt,
+    BNot,
+    INeg,
+    /// Number of set bits, needs a CPU with `popcnt`.
+    Popcnt,
+    /// Number of leading zero bits, the width of the type for zero.
+    Clz,
+    /// Number of trailing zero bits, the width of the type for zero.
+    Ctz,
+}
+
+impl UnaryOp {
+    /// Whether the operand is a float rather than an integer.
+    pub fn is_float(self) -> bool {
+        matches!(self, UnaryOp::FNeg | UnaryOp::FAbs | UnaryOp::FSqrt)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub enum ConvertOp {
+    SExtend,
+    UExtend,
+    IReduce,
+    FcvtFromSInt,
+    FcvtFromUInt,
+    /// Traps if the value is NaN or does not fit into the integer type.
+    FcvtToSInt,
+    FcvtToUInt,
+    /// NaN becomes zero, values out of range the smallest or largest integer.
+    FcvtToSIntSat,
+    FcvtToUIntSat,
+    FPromote,
+    FDemote,
+    /// Reinterpret the bits of an integer as float of the same size or the
+    /// other way around.
+    Bitcast,
+}
+
+impl ConvertOp {
+    /// Whether a value of type `from` can be converted to `to`.
+    pub fn accepts(self, from: Type, to: Type) -> bool {
+        use ConvertOp::*;
+        match self {
+            SExtend | UExtend => from.is_int() && to.is_int() && from.bits() < to.bits(),
+            IReduce => from.is_int() && to.is_int() && from.bits() > to.bits(),
+            FcvtFromSInt | FcvtFromUInt => from.is_int() && to.is_float(),
+            FcvtToSInt | FcvtToUInt | FcvtToSIntSat | FcvtToUIntSat => {
+                from.is_float() && to.is_int()
+            }
+            FPromote => from == Type::F32 && to == Type::F64,
+            FDemote => from == Type::F64 && to == Type::F32,
+            Bitcast => {
+                from.bits() == to.bits()
+                    && from != Type::I8
+                    && ((from.is_int() && to.is_float()) || (from.is_float() && to.is_int()))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum InstData {
+    IConst {
+        ty: Type,
+        imm: i64,
+    },
+    FConst {
+        ty: Type,
+        imm: f64,
+    },
+    Unary {
+        op: UnaryOp,
+        x: Value,
+    },
+    Binary {
+        op: BinaryOp,
+        x: Value,
+        y: Value,
+    },
+    /// `op` with the constant `imm` as right operand.
+    BinaryImm {
+        op: BinaryOp,
+        x: Value,
+        imm: i64,
+    },
+    /// Convert `x` to type `ty`.
+    Convert {
+        op: ConvertOp,
+        ty: Type,
+        x: Value,
+    },
+    IntCmp {
+        cc: CondCode,
+        x: Value,
+        y: Value,
+    },
+    FloatCmp {
+        cc: CondCode,
+        x: Value,
+        y: Value,
+    },
+    Load {
+        ty: Type,
+        base: Value,
+        offset: i32,
+    },
+    Store {
+        value: Value,
+        base: Value,
+        offset: i32,
+    },
+    /// Load from `base + index * scale + offset`.
+    LoadIndexed {
+        ty: Type,
+        base: Value,
+        index: Value,
+        scale: u8,
+        offset: i32,
+    },
+    /// Store to `base + index * scale + offset`.
+    StoreIndexed {
+        value: Value,
+        base: Value,
+        index: Value,
+        scale: u8,
+        offset: i32,
+    },
+    /// Address of a stack slot.
+    StackAddr {
+        slot: StackSlot,
+    },
+    /// Address of a function or data object.
+    SymbolAddr {
+        name: String,
+    },
+    Call {
+        name: String,
+        args: Vec<Value>,
+        /// Signature of the callee, the types of `args` for calls that do not
+        /// name one.
+        signature: Signature,
+    },
+    Jump {
+        block: Block,
+        args: Vec<Value>,
+    },
+    Brif {
+        cond: Value,
+        then_block: Block,
+        then_args: Vec<Value>,
+        else_block: Block,
+        else_args: Vec<Value>,
+    },
+    Return {
+        value: Option<Value>,
+    },
+}
+
+impl InstData {
+    /// Values read by this instruction, including block arguments.
+    pub fn args(&self) -> Vec<Value> {
+        match self {
+            InstData::IConst { .. }
+            | InstData::FConst { .. }
+            | InstData::SymbolAddr { .. }
+            | InstData::StackAddr { .. } => vec![],
+            InstData::Unary { x, .. }
+            | InstData::BinaryImm { x, .. }
+            | InstData::Convert { x, .. } => vec![*x],
+            InstData::Binary { x, y, .. }
+            | InstData::IntCmp { x, y, .. }
+            | InstData::FloatCmp { x, y, .. } => vec![*x, *y],
+            InstData::Load { base, .. } => vec![*base],
+            InstData::Store { value, base, .. } => vec![*value, *base],
+            InstData::LoadIndexed { base, index, .. } => vec![*base, *index],
+            InstData::StoreIndexed {
+                value, base, index, ..
+            } => vec![*value, *base, *index],
+            InstData::Call { args, .. } | InstData::Jump { args, .. } => args.clone(),
+            InstData::Brif {
+                cond,
+                then_args,
+                else_args,
+                ..
+            } => {
+                let mut args = vec![*cond];
+                args.extend_from_slice(then_args);
+                args.extend_from_slice(else_args);
+                args
+            }
+            InstData::Return { value } => value.iter().cloned().collect(),
+        }
+    }
+
+    /// Mutable references to the values `args` returns, in the same order.
+    pub fn args_mut(&mut self) -> Vec<&mut Value> {
+        match self {
+            InstData::IConst { .. }
+            | InstData::FConst { .. }
+            | InstData::SymbolAddr { .. }
+            | InstData::StackAddr { .. } => vec![],
+            InstData::Unary { x, .. }
+            | InstData::BinaryImm { x, .. }
+            | InstData::Convert { x, .. } => vec![x],
+            InstData::Binary { x, y, .. }
+            | InstData::IntCmp { x, y, .. }
+            | InstData::FloatCmp { x, y, .. } => vec![x, y],
+            InstData::Load { base, .. } => vec![base],
+            InstData::Store { value, base, .. } => vec![value, base],
+            InstData::LoadIndexed { base, index, .. } => vec![base, index],
+            InstData::StoreIndexed {
+                value, base, index, ..
+            } => vec![value, base, index],
+            InstData::Call { args, .. } | InstData::Jump { args, .. } => args.iter_mut().collect(),
+            InstData::Brif {
+                cond,
+                then_args,
+                else_args,
+                ..
+            } => {
+                let mut args = vec![cond];
+                args.extend(then_args.iter_mut());
+                args.extend(else_args.iter_mut());
+                args
+            }
+            InstData::Return { value } => value.iter_mut().collect(),
+        }
+    }
+
+    pub fn is_terminator(&self) -> bool {
+        matches!(
+            self,
+            InstData::Jump { .. } | InstData::Brif { .. } | InstData::Return { .. }
+        )
+    }
+
+    /// Successor blocks together with the arguments passed to them.
+    pub fn successors(&self) -> Vec<(Block, &[Value])> {
+        match self {
+            InstData::Jump { block, args } => vec![(*block, &args[..])],
+            InstData::Brif {
+                then_block,
+                then_args,
+                else_block,
+                else_args,
+                ..
+            } => vec![(*then_block, &then_args[..]), (*else_block, &else_args[..])],
+            _ => vec![],
+        }
+    }
+
+    /// Mutable references to the successor blocks.
+    pub fn successor_blocks_mut(&mut self) -> Vec<&mut Block> {
+        match self {
+            InstData::Jump { block, .. } => vec![block],
+            InstData::Brif {
+                then_block,
+                else_block,
+                ..
+            } => vec![then_block, else_block],
+            _ => vec![],
+        }
+    }
+}
+
+/// A piece of the stack frame whose address can be taken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StackSlotData {
+    pub size: u32,
+    /// A power of two no larger than 16.
+    pub align: u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BlockData {
+    pub params: Vec<Value>,
+    pub insts: Vec<Inst>,
+}
+
+/// Instructions, values and blocks of a function in the order they were built.
+#[derive(Clone, Debug, Default)]
+pub struct Body {
+    pub insts: Vec<InstData>,
+    pub results: Vec<Option<Value>>,
+    pub value_types: Vec<Type>,
+    pub blocks: Vec<BlockData>,
+    /// Blocks in the order they are placed in the code.
+    pub layout: Vec<Block>,
+    pub stack_slots: Vec<StackSlotData>,
+}
+
+impl Body {
+    pub fn new() -> Body {
+        Body::default()
+    }
+
+    pub fn make_value(&mut self, ty: Type) -> Value {
+        let value = Value::new(self.value_types.len() as u32);
+        self.value_types.push(ty);
+        value
+    }
+
+    pub fn make_block(&mut self) -> Block {
+        let block = Block::new(self.blocks.len() as u32);
+        self.blocks.push(BlockData::default());
+        block
+    }
+
+    pub fn make_stack_slot(&mut self, size: u32, align: u32) -> StackSlot {
+        let slot = StackSlot::new(self.stack_slots.len() as u32);
+        self.stack_slots.push(StackSlotData { size, align });
+        slot
+    }
+
+    pub fn value_type(&self, value: Value) -> Type {
+        self.value_types[value.0 as usize]
+    }
+
+    pub fn inst(&self, inst: Inst) -> &InstData {
+        &self.insts[inst.0 as usize]
+    }
+
+    pub fn inst_result(&self, inst: Inst) -> Option<Value> {
+        self.results[inst.0 as usize]
+    }
+
+    pub fn block(&self, block: Block) -> &BlockData {
+        &self.blocks[block.0 as usize]
+    }
+
+    /// Append `data` to the end of `block`.
+    pub fn push_inst(&mut self, block: Block, data: InstData, result: Option<Value>) -> Inst {
+        let inst = Inst(self.insts.len() as u32);
+        self.insts.push(data);
+        self.results.push(result);
+        self.blocks[block.0 as usize].insts.push(inst);
+        inst
+    }
+
+    /// Last instruction of `block` if it is a terminator.
+    pub fn terminator(&self, block: Block) -> Option<Inst> {
+        self.block(block)
+            .insts
+            .last()
+            .cloned()
+            .filter(|inst| self.inst(*inst).is_terminator())
+    }
+
+    pub fn successors(&self, block: Block) -> Vec<Block> {
+        match self.terminator(block) {
+            Some(inst) => self
+                .inst(inst)
+                .successors()
+                .iter()
+                .map(|(block, _)| *block)
+                .collect(),
+            None => vec![],
+        }
+    }
+}
@@ -6,6 +6,15 @@
 #![allow(unused_macros)]
 
 pub mod backend;
+mod disasm;
+pub mod error;
 pub mod function;
+pub mod ir;
 pub mod module;
+pub mod object;
+pub mod parser;
+pub mod printer;
+pub mod trap;
+pub mod typed;
 pub mod types;
+pub mod verifier;
@@ -1,48 +1,31 @@
 extern crate peace;
 
+use peace::error::PeaceError;
 use peace::module::*;
-use peace::types::Type;
+use peace::types::{Signature, Type};
 
-
-use capstone::prelude::*;
-use std::mem;
-
-fn main() {
+fn main() -> Result<(), PeaceError> {
     let mut module = Module::new();
 
-    module.declare_function("printf", Linkage::Import);
-    module.declare_function("main", Linkage::Local);
-
+    module.declare_function(
+        "printf",
+        Linkage::Import,
+        Signature::new_varargs(vec![Type::Pointer], Type::I32),
+    )?;
+    module.declare_function("main", Linkage::Local, Signature::new(vec![], Type::I32))?;
 
-    let builder = module.get_function("main");
+    let builder = module.get_function("main")?;
     let int = Type::I32;
-    let v0 = builder.iconst(int, 4);
-    let v1 = builder.iconst(int, 5);
-    let v2 = builder.iadd(v0, v1);
-    builder.ret(v2);
-    builder.finalize();
-    module.finish();
-
-    let (data, size) = module.get_finalized_data("main");
-
-    let cs: Capstone = Capstone::new()
-        .x86()
-        .mode(arch::x86::ArchMode::Mode64)
-        .syntax(arch::x86::ArchSyntax::Intel)
-        .detail(true)
-        .build()
-        .unwrap();
-
-    let slice = unsafe { ::std::slice::from_raw_parts(data, size) };
-
-    let ins = cs.disasm_all(slice, 0);
-
-    for i in &ins {
-        println!("{}", i);
-    }
-
-
-    let f: fn() -> i64 = unsafe { mem::transmute(data) };
-    println!("{}", f());
-
+    let v0 = builder.iconst(int, 4)?;
+    let v1 = builder.iconst(int, 5)?;
+    let v2 = builder.iadd(v0, v1)?;
+    builder.ret(v2)?;
+    builder.finalize()?;
+    module.finish()?;
+
+    print!("{}", module.disassemble("main")?);
+
+    let f = module.get_typed::<fn() -> i32>("main")?;
+    println!("{}", f.call(()));
+    Ok(())
 }
@@ -1,18 +1,66 @@
 use libc::{dlsym, RTLD_DEFAULT};
 
 use std::ffi::CString;
+use std::sync::{Mutex, OnceLock};
+
+/// Addresses of symbols exported by JIT compiled modules in this process,
+/// stored as `usize` since raw pointers are not `Send`.
+fn jit_symbols() -> &'static Mutex<HashMap<String, usize>> {
+    static SYMBOLS: OnceLock<Mutex<HashMap<String, usize>>> = OnceLock::new();
+    SYMBOLS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Make `addr` available under `name` to modules importing it. A later
+/// definition of the same name replaces earlier ones.
+pub fn register_symbol(name: &str, addr: *const u8) {
+    jit_symbols()
+        .lock()
+        .unwrap()
+        .insert(name.to_owned(), addr as usize);
+}
+
+pub fn unregister_symbol(name: &str) {
+    jit_symbols().lock().unwrap().remove(name);
+}
+
+/// Unregister `name` if it still refers to `addr`, keeping a later
+/// definition by another module.
+fn unregister_symbol_at(name: &str, addr: *const u8) {
+    let mut symbols = jit_symbols().lock().unwrap();
+    if symbols.get(name) == Some(&(addr as usize)) {
+        symbols.remove(name);
+    }
+}
+
+/// Address of a symbol exported by a JIT compiled module.
+pub fn lookup_symbol(name: &str) -> Option<*const u8> {
+    jit_symbols()
+        .lock()
+        .unwrap()
+        .get(name)
+        .map(|addr| *addr as *const u8)
+}
+
+/// Resolve an imported symbol, preferring symbols defined by JIT compiled
+/// modules over those of the host process.
+fn find_symbol(name: &str) -> Result<*const u8> {
+    match lookup_symbol(name) {
+        Some(addr) => Ok(addr),
+        None => find_host_symbol(name),
+    }
+}
 
 #[cfg(not(windows))]
-fn find_symbol(name: &str) -> *const u8 {
-    let c_str = CString::new(name).unwrap();
+fn find_host_symbol(name: &str) -> Result<*const u8> {
+    let c_str = CString::new(name).map_err(|_| PeaceError::UnresolvedSymbol(name.to_owned()))?;
     let c_str_ptr = c_str.as_ptr();
     let sym = unsafe { dlsym(RTLD_DEFAULT, c_str_ptr) };
 
     if sym.is_null() {
-        panic!("can't resolve symbol {}", name);
+        return Err(PeaceError::UnresolvedSymbol(name.to_owned()));
     }
 
-    sym as *const u8
+    Ok(sym as *const u8)
 }
 
 pub fn flush_icache(_: *const u8, _: usize) {
@@ -23,10 +71,10 @@ pub fn flush_icache(_: *const u8, _: usize) {
 }
 
 #[cfg(windows)]
-fn find_symbol(name: &str) -> *const u8 {
+fn find_host_symbol(name: &str) -> Result<*const u8> {
     const MSVCRT_DLL: &[u8] = b"msvcrt.dll\0";
 
-    let c_str = CString::new(name).unwrap();
+    let c_str = CString::new(name).map_err(|_| PeaceError::UnresolvedSymbol(name.to_owned()))?;
     let c_str_ptr = c_str.as_ptr();
 
     unsafe {
@@ -42,15 +90,15 @@ fn find_symbol(name: &str) -> *const u8 {
             if addr.is_null() {
                 continue;
             }
-            return addr as *const u8;
+            return Ok(addr as *const u8);
         }
 
         let msg = if handles[1].is_null() {
-            "(msvcrt not loaded)"
+            " (msvcrt not loaded)"
         } else {
             ""
         };
-        panic!("cannot resolve address of symbol {} {}", name, msg);
+        Err(PeaceError::UnresolvedSymbol(format!("{}{}", name, msg)))
     }
 }
 
@@ -58,6 +106,24 @@ fn find_symbol(name: &str) -> *const u8 {
 pub enum Linkage {
     Import,
     Local,
+    /// Defined in this module and visible to other modules. References from
+    /// this module always bind to this definition.
+    Export,
+    /// Like `Export`, but another definition of the symbol may take its place
+    /// when an object file is linked.
+    Preemptible,
+}
+
+impl Linkage {
+    /// Whether the symbol is defined in this module.
+    pub fn is_definition(self) -> bool {
+        self != Linkage::Import
+    }
+
+    /// Whether other modules can refer to the symbol.
+    pub fn is_exported(self) -> bool {
+        matches!(self, Linkage::Export | Linkage::Preemptible)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Copy)]
@@ -75,39 +141,299 @@ pub struct DataContext {
     pub linkage: Linkage,
 }
 use crate::function::*;
+use crate::types::Signature;
 
-use crate::backend::get_executable_memory;
-use std::collections::HashMap;
+use crate::backend::memory::{Memory, MemoryStats};
+use crate::backend::{align, copy_code};
+use crate::error::{PeaceError, Result};
+use crate::object::*;
+use crate::trap::{register_traps, unregister_traps};
+use crate::typed::{JitFunction, TypedFunction};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
 use std::mem;
+use std::panic;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Size of a stub jumping to a function outside of a region.
+const STUB_SIZE: usize = 16;
+
+/// Code placed by one call of `Module::finish`: the functions, followed by
+/// the stubs and GOT slots they use.
+struct CodeRegion {
+    start: *mut u8,
+    size: usize,
+    /// Functions placed in the region that were not removed yet.
+    functions: Vec<String>,
+}
+
+impl CodeRegion {
+    fn contains(&self, ptr: *const u8) -> bool {
+        let start = self.start as usize;
+        start <= ptr as usize && (ptr as usize) < start + self.size
+    }
+}
+
+/// Number of live `CodeGuard`s per epoch. Retiring code starts a new epoch,
+/// the code is freed once no guard of that or an earlier epoch is left.
+#[derive(Default)]
+struct Epochs {
+    current: u64,
+    guards: BTreeMap<u64, usize>,
+}
+
+impl Epochs {
+    /// Start a new epoch and return the one code retired now belongs to.
+    fn retire(&mut self) -> u64 {
+        self.current += 1;
+        self.current - 1
+    }
+
+    fn is_quiescent(&self, epoch: u64) -> bool {
+        self.guards.range(..=epoch).next().is_none()
+    }
+}
+
+/// Code of a module and the memory it refers to, moved here when the module
+/// is dropped and freed once the last `CodeGuard` is gone as well.
+#[derive(Default)]
+struct KeptCode {
+    memory: Memory,
+    regions: Vec<CodeRegion>,
+    slots: HashMap<String, Box<AtomicPtr<u8>>>,
+    owned_data: Vec<Box<[u8]>>,
+}
+
+// The regions point into `memory`.
+unsafe impl Send for KeptCode {}
+
+impl Drop for KeptCode {
+    fn drop(&mut self) {
+        for region in self.regions.iter() {
+            unregister_traps(region.start, region.size);
+        }
+    }
+}
 
+/// Held by a thread while it may run code of a module, see `Module::pin`.
+pub struct CodeGuard {
+    epochs: Arc<Mutex<Epochs>>,
+    epoch: u64,
+    _code: Arc<Mutex<KeptCode>>,
+}
+
+impl Drop for CodeGuard {
+    fn drop(&mut self) {
+        let mut epochs = self.epochs.lock().unwrap();
+        let count = epochs.guards.get_mut(&self.epoch).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            epochs.guards.remove(&self.epoch);
+        }
+    }
+}
 
 pub struct Module {
     pub data: HashMap<String, DataContext>,
     pub uncompiled_functions: HashMap<String, Function>,
     pub uncompiled_data: HashMap<String, DataContext>,
+    /// Jump stubs for imported functions, used by calls that cannot reach
+    /// them directly.
+    stubs: HashMap<String, *const u8>,
+    /// Slots holding the address of symbols accessed through `GotRel32`.
+    got: HashMap<String, *const u8>,
+    /// Contents of data objects defined by `define_data_owned`.
+    owned_data: Vec<Box<[u8]>>,
+    /// Pages holding the code placed by `finish`.
+    memory: Memory,
+    regions: Vec<CodeRegion>,
+    /// Current address of the symbols reached through `RelocKind::Slot`,
+    /// boxed so the code can refer to the slots.
+    slots: HashMap<String, Box<AtomicPtr<u8>>>,
+    /// Whether declared functions use indirect calls.
+    indirect_calls: bool,
+    /// Regions without functions and the epoch they were retired in, freed
+    /// once no thread can be running them anymore.
+    retired: Vec<(u64, CodeRegion)>,
+    epochs: Arc<Mutex<Epochs>>,
+    /// Takes over the code on drop while guards are left.
+    kept: Arc<Mutex<KeptCode>>,
 }
 
+// The raw pointers refer to code and data owned by the module or to symbols
+// of the process, they are only changed through `&mut self`.
+unsafe impl Send for Module {}
+unsafe impl Sync for Module {}
+
 impl Module {
     pub fn new() -> Module {
         Module {
             uncompiled_data: HashMap::default(),
             uncompiled_functions: HashMap::default(),
             data: HashMap::default(),
+            stubs: HashMap::default(),
+            got: HashMap::default(),
+            owned_data: vec![],
+            memory: Memory::new(),
+            regions: vec![],
+            slots: HashMap::default(),
+            indirect_calls: false,
+            retired: vec![],
+            epochs: Arc::default(),
+            kept: Arc::default(),
         }
     }
 
-    pub fn get_function<'r>(&'r mut self, fname: &str) -> &'r mut Function {
+    pub fn get_function<'r>(&'r mut self, fname: &str) -> Result<&'r mut Function> {
         self.uncompiled_functions
             .get_mut(fname)
-            .expect("function not found")
+            .ok_or_else(|| PeaceError::UnresolvedSymbol(fname.to_owned()))
+    }
+
+    /// Signature `name` was declared with.
+    pub fn signature(&self, name: &str) -> Option<&Signature> {
+        self.uncompiled_functions
+            .get(name)
+            .map(|func| &func.signature)
     }
 
-    pub fn declare_function(&mut self, name: &str, linkage: Linkage) {
-        let func = Function::new(name, linkage);
+    /// Declare the function `name`. Declaring it again with the same linkage
+    /// and signature keeps the function as it is.
+    pub fn declare_function(
+        &mut self,
+        name: &str,
+        linkage: Linkage,
+        signature: Signature,
+    ) -> Result<()> {
+        if self.is_data(name) {
+            return Err(PeaceError::Unsupported(format!(
+                "{} is declared as data and as function",
+                name
+            )));
+        }
+        if let Some(declared) = self.uncompiled_functions.get(name) {
+            if declared.signature != signature {
+                return Err(PeaceError::TypeMismatch(format!(
+                    "{} is declared as {} and as {}",
+                    name, declared.signature, signature
+                )));
+            }
+            if declared.linkage != linkage {
+                return Err(PeaceError::Unsupported(format!(
+                    "{} is declared as {:?} and as {:?}",
+                    name, declared.linkage, linkage
+                )));
+            }
+            return Ok(());
+        }
+        let mut func = Function::new(name, linkage, signature);
+        func.set_indirect_calls(self.indirect_calls);
         self.uncompiled_functions.insert(name.to_owned(), func);
+        Ok(())
+    }
+
+    /// Add `func`, built and finalized apart from the module, e.g. on another
+    /// thread. It replaces the declaration of the same name, which has to
+    /// have the same signature.
+    pub fn define_function(&mut self, func: Function) -> Result<()> {
+        if self.is_data(&func.name) {
+            return Err(PeaceError::Unsupported(format!(
+                "{} is declared as data and as function",
+                func.name
+            )));
+        }
+        if self.is_placed(&func.name) {
+            return Err(PeaceError::Unsupported(format!(
+                "defining {} again after finish, use redefine_function",
+                func.name
+            )));
+        }
+        if let Some(declared) = self.signature(&func.name) {
+            if *declared != func.signature {
+                return Err(PeaceError::TypeMismatch(format!(
+                    "{} is declared as {}, but defined as {}",
+                    func.name, declared, func.signature
+                )));
+            }
+        }
+        self.uncompiled_functions.insert(func.name.clone(), func);
+        Ok(())
+    }
+
+    /// Finalize the functions defined in the module that are not finalized
+    /// yet, spread over one thread per available core.
+    pub fn finalize_functions(&mut self) -> Result<()> {
+        let mut pending: Vec<&mut Function> = self
+            .uncompiled_functions
+            .values_mut()
+            .filter(|func| func.linkage.is_definition() && !func.is_finalized())
+            .collect();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        // report the same error regardless of the number of threads
+        pending.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+        let chunk_size = pending.len().div_ceil(threads);
+        thread::scope(|scope| {
+            let workers: Vec<_> = pending
+                .chunks_mut(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || chunk.iter_mut().try_for_each(|func| func.finalize()))
+                })
+                .collect();
+            workers.into_iter().try_for_each(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|err| panic::resume_unwind(err))
+            })
+        })
+    }
+
+    /// Make functions declared from now on use indirect calls, so they keep
+    /// calling the current definition of functions redefined later, see
+    /// `Function::set_indirect_calls`.
+    pub fn set_indirect_calls(&mut self, indirect_calls: bool) {
+        self.indirect_calls = indirect_calls;
     }
 
-    pub fn declare_data(&mut self, _name: String, _linkage: Linkage) {
+    /// Whether `name` is declared or defined as data.
+    fn is_data(&self, name: &str) -> bool {
+        self.uncompiled_data.contains_key(name)
+            || self
+                .data
+                .get(name)
+                .is_some_and(|data| data.kind == DataKind::Data)
+    }
+
+    /// Declare the data object `name`. Declaring it again with the same
+    /// linkage keeps its contents.
+    pub fn declare_data(&mut self, _name: String, _linkage: Linkage) -> Result<()> {
+        if self.uncompiled_functions.contains_key(&_name) {
+            return Err(PeaceError::Unsupported(format!(
+                "{} is declared as function and as data",
+                _name
+            )));
+        }
+        let declared = self
+            .uncompiled_data
+            .get(&_name)
+            .or_else(|| self.data.get(&_name));
+        if let Some(declared) = declared {
+            if declared.linkage != _linkage {
+                return Err(PeaceError::Unsupported(format!(
+                    "{} is declared as {:?} and as {:?}",
+                    _name, declared.linkage, _linkage
+                )));
+            }
+            return Ok(());
+        }
         let ctx = DataContext {
             data: 0 as *const u8,
             kind: DataKind::Data,
@@ -117,107 +443,282 @@ impl Module {
         };
         self.data.insert(_name.clone(), ctx.clone());
         self.uncompiled_data.insert(_name, ctx);
+        Ok(())
     }
 
-    pub fn define_data(&mut self, name: String, data: &[u8]) {
+    pub fn define_data(&mut self, name: String, data: &[u8]) -> Result<()> {
+        if self.uncompiled_functions.contains_key(&name) {
+            return Err(PeaceError::Unsupported(format!(
+                "defining function {} as data",
+                name
+            )));
+        }
+        if let Some(defined) = self.data.get(&name) {
+            if defined.linkage == Linkage::Import {
+                return Err(PeaceError::Unsupported(format!(
+                    "defining imported data {}",
+                    name
+                )));
+            }
+            if defined.is_sized {
+                return Err(PeaceError::Unsupported(format!(
+                    "data {} is defined twice",
+                    name
+                )));
+            }
+        }
+        // keep the linkage the data was declared with
+        let linkage = self
+            .uncompiled_data
+            .get(&name)
+            .map_or(Linkage::Local, |data| data.linkage);
         let data = DataContext {
             data: data.as_ptr(),
             kind: DataKind::Data,
             is_sized: true,
             size: data.len(),
-            linkage: Linkage::Local,
+            linkage,
         };
         self.data.insert(name, data);
+        Ok(())
     }
 
-    pub fn reloc_fix(&mut self) {
-        let funcs = self.uncompiled_functions.clone();
-
-        for (_, func) in funcs.iter() {
-            let fct: &Function = func;
-
-            for reloc in fct.relocs.iter() {
-                let reloc: &Reloc = reloc;
+    /// Like `define_data`, but the module keeps `data` alive itself.
+    pub fn define_data_owned(&mut self, name: String, data: Vec<u8>) -> Result<()> {
+        let data = data.into_boxed_slice();
+        self.define_data(name, &data)?;
+        self.owned_data.push(data);
+        Ok(())
+    }
 
-                let name = &fct.name;
+    /// Apply the relocations of the functions `names`.
+    fn reloc_fix(&self, names: &[String]) -> Result<()> {
+        for name in names.iter() {
+            let func = &self.uncompiled_functions[name];
+            let code = self.data[&func.name].data as *mut u8;
 
-                let (data, _) = self.get_finalized_data(&reloc.global_name);
-                let (curr, _) = self.get_finalized_data(&name).clone();
+            for reloc in func.relocs.iter() {
+                unsafe { self.apply_reloc(code, &func.name, reloc)? };
+            }
+        }
+        Ok(())
+    }
 
-                unsafe {
-                    let offset = data.offset(0);
-                    let slice: [u8; 8] = mem::transmute(offset);
+    /// Patch `reloc` into the code of `fname` starting at `code`. All symbols
+    /// have to be resolved and stubs and GOT slots allocated.
+    unsafe fn apply_reloc(&self, code: *mut u8, fname: &str, reloc: &Reloc) -> Result<()> {
+        let name = &reloc.global_name;
+        let symbol = self
+            .data
+            .get(name)
+            .ok_or_else(|| PeaceError::UnresolvedSymbol(name.to_owned()))?
+            .data as isize;
+        let at = code.add(reloc.offset);
+        let pc = at as isize;
 
-                    let mut pc = 0;
-                    for i in reloc.at..reloc.to {
-                        let byte = &mut *(curr.offset(i as isize) as *mut u8);
-                        *byte = slice[pc];
-                        pc += 1;
+        let disp = match reloc.kind {
+            RelocKind::Abs64 => {
+                (at as *mut i64).write_unaligned(symbol as i64 + reloc.addend);
+                return Ok(());
+            }
+            RelocKind::Rel32 => {
+                let disp = symbol + reloc.addend as isize - pc;
+                match self.stubs.get(name) {
+                    Some(stub) if disp != disp as i32 as isize => {
+                        *stub as isize + reloc.addend as isize - pc
                     }
+                    _ => disp,
                 }
             }
+            RelocKind::RipRel32 => symbol + reloc.addend as isize - pc,
+            RelocKind::GotRel32 => self.got[name] as isize + reloc.addend as isize - pc,
+            RelocKind::Slot => {
+                // the displacement set by codegen locates the data segment
+                // entry, relative to the end of the instruction
+                let entry = at.offset(4 + (at as *const i32).read_unaligned() as isize);
+                let slot: *const AtomicPtr<u8> = &*self.slots[name];
+                (entry as *mut *const AtomicPtr<u8>).write_unaligned(slot);
+                return Ok(());
+            }
+        };
+
+        if disp != disp as i32 as isize {
+            return Err(PeaceError::Unsupported(format!(
+                "{} is out of range of {}",
+                name, fname
+            )));
         }
+        (at as *mut i32).write_unaligned(disp as i32);
+        Ok(())
     }
-    pub fn get_finalized_data(&mut self, f: &str) -> (*mut u8, usize) {
-        let data = self.data.get(f).expect("Data not found");
+
+    pub fn get_finalized_data(&self, f: &str) -> Result<(*mut u8, usize)> {
+        let data = self
+            .data
+            .get(f)
+            .ok_or_else(|| PeaceError::UnresolvedSymbol(f.to_owned()))?;
 
         if data.is_sized {
-            return (data.data as *mut _, data.size);
+            Ok((data.data as *mut _, data.size))
         } else {
-            return (data.data as *mut _, 0);
+            Ok((data.data as *mut _, 0))
         }
     }
 
-    pub fn get_finalized_function(&mut self, f: &str) -> *mut u8 {
-        let data: &DataContext = self.data.get(f).expect("Data not found");
+    pub fn get_finalized_function(&self, f: &str) -> Result<*mut u8> {
+        let data: &DataContext = self
+            .data
+            .get(f)
+            .ok_or_else(|| PeaceError::UnresolvedSymbol(f.to_owned()))?;
         if data.kind != DataKind::Function {
-            panic!("Data is not a function");
+            Err(PeaceError::TypeMismatch(format!("{} is not a function", f)))
         } else {
-            return data.data as *mut _;
+            Ok(data.data as *mut _)
+        }
+    }
+
+    /// Fail if a function defined in the module was not finalized, its code
+    /// would be empty.
+    fn check_finalized(&self) -> Result<()> {
+        let mut pending: Vec<&String> = self
+            .uncompiled_functions
+            .values()
+            .filter(|func| func.linkage.is_definition() && !func.is_finalized())
+            .map(|func| &func.name)
+            .collect();
+        pending.sort();
+        match pending.first() {
+            Some(name) => Err(PeaceError::Unsupported(format!(
+                "{} was not finalized",
+                name
+            ))),
+            None => Ok(()),
         }
     }
 
-    pub fn finish(&mut self) {
+    pub fn finish(&mut self) -> Result<()> {
+        self.check_finalized()?;
         for (name, ctx) in self.uncompiled_data.iter_mut() {
             let data: &mut DataContext = ctx;
 
             match &data.linkage {
-                Linkage::Local => continue,
+                Linkage::Local | Linkage::Export | Linkage::Preemptible => continue,
                 Linkage::Import => {
-                    let symbol = find_symbol(name);
+                    let symbol = find_symbol(name)?;
                     data.data = symbol;
+                    self.data.insert(name.to_owned(), data.clone());
                 }
             }
         }
 
-        for (name, func) in self.uncompiled_functions.iter_mut() {
+        // functions placed by an earlier call stay where they are
+        let mut names: Vec<String> = vec![];
+        for (name, func) in self.uncompiled_functions.iter() {
             match &func.linkage {
-                Linkage::Local => (),
+                Linkage::Local | Linkage::Export | Linkage::Preemptible => {
+                    if !self.is_placed(name) {
+                        names.push(name.to_owned())
+                    }
+                }
                 Linkage::Import => {
-                    let func: extern "C" fn() = unsafe { mem::transmute(find_symbol(name)) };
+                    let func = find_symbol(name)?;
 
                     let data = DataContext {
-                        data: func as *const u8,
+                        data: func,
                         size: 0,
                         is_sized: false,
                         linkage: Linkage::Import,
                         kind: DataKind::Function,
                     };
                     self.data.insert(name.to_owned(), data);
-                    continue;
                 }
             }
-            let asm = func.asm_mut();
+        }
+        names.sort();
+        if !names.is_empty() {
+            self.place(&names)?;
+        }
+
+        for (name, data) in self.data.iter() {
+            let placed_now = data.kind == DataKind::Data || names.contains(name);
+            if data.linkage.is_exported() && placed_now {
+                register_symbol(name, data.data);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the code of the function defined as `name` was placed.
+    fn is_placed(&self, name: &str) -> bool {
+        self.data
+            .get(name)
+            .is_some_and(|data| data.kind == DataKind::Function && data.linkage.is_definition())
+    }
+
+    /// Place the code like `finish` and turn the module into a handle that
+    /// can be shared between threads.
+    pub fn compile(mut self) -> Result<CompiledModule> {
+        self.finish()?;
+        Ok(CompiledModule { module: self })
+    }
+
+    /// Symbols the functions `names` refer to with relocations of `kind`.
+    fn reloc_targets(&self, names: &[String], kind: RelocKind) -> Vec<String> {
+        let mut targets: Vec<String> = names
+            .iter()
+            .flat_map(|name| self.uncompiled_functions[name].relocs.iter())
+            .filter(|reloc| reloc.kind == kind)
+            .map(|reloc| reloc.global_name.clone())
+            .collect();
+        targets.sort();
+        targets.dedup();
+        targets
+    }
 
+    /// Place the finalized functions `names` into a new region. Imports have
+    /// to be resolved already.
+    fn place(&mut self, names: &[String]) -> Result<()> {
+        // place the functions into one region so they reach each other with
+        // rel32 calls, every function is preceded by its data segment
+        let mut offsets = vec![];
+        let mut size = 0;
+        for name in names.iter() {
+            let asm = self.uncompiled_functions.get_mut(name).unwrap().asm_mut();
             asm.fix_forward_jumps();
-            let data = asm.data().clone();
-            let dseg = &asm.dseg;
-            let total_size = data.len() + dseg.size() as usize;
-            let ptr = get_executable_memory(asm).ptr();
-            flush_icache(ptr, total_size);
+            let start = align(size + asm.dseg.size(), 16);
+            size = start + asm.data().len() as i32;
+            offsets.push(start);
+        }
+
+        // imported functions may be anywhere in the address space and earlier
+        // regions far away, calls to them go through a `jmp [rip]` stub
+        // followed by the absolute address
+        let mut stub_targets = self.reloc_targets(names, RelocKind::Rel32);
+        stub_targets.retain(|name| !names.contains(name));
+        let stubs_start = align(size, 16);
+        size = stubs_start + STUB_SIZE as i32 * stub_targets.len() as i32;
+
+        let got_symbols = self.reloc_targets(names, RelocKind::GotRel32);
+        let got_start = align(size, 8);
+        size = got_start + 8 * got_symbols.len() as i32;
+
+        // written while writable, made executable once everything is patched
+        let region = self.memory.allocate(size as usize, 16)?;
+        self.regions.push(CodeRegion {
+            start: region,
+            size: size as usize,
+            functions: names.to_vec(),
+        });
+
+        for (name, start) in names.iter().zip(offsets.iter()) {
+            let func = &self.uncompiled_functions[name];
+            let code_size = func.asm.data().len();
+            let ptr = unsafe { region.add(*start as usize) };
+            unsafe { copy_code(&func.asm, ptr) };
+            register_traps(ptr, code_size, &func.asm.traps);
             let data = DataContext {
                 data: ptr,
-                size: total_size,
+                size: code_size,
                 is_sized: true,
                 kind: DataKind::Function,
                 linkage: func.linkage,
@@ -225,143 +726,477 @@ impl Module {
             self.data.insert(name.to_owned(), data);
         }
 
-        self.reloc_fix();
-    }
-}
+        for (idx, name) in stub_targets.iter().enumerate() {
+            let (target, _) = self.get_finalized_data(name)?;
+            unsafe {
+                let stub = region.add(stubs_start as usize + idx * STUB_SIZE);
+                // jmp qword ptr [rip + 0]
+                let jmp: [u8; 6] = [0xff, 0x25, 0, 0, 0, 0];
+                ptr::copy_nonoverlapping(jmp.as_ptr(), stub, jmp.len());
+                (stub.add(jmp.len()) as *mut *const u8).write_unaligned(target);
+                self.stubs.insert(name.to_owned(), stub);
+            }
+        }
 
-fn round_up_to_page_size(size: usize, page_size: usize) -> usize {
-    (size + (page_size - 1)) & !(page_size - 1)
-}
+        for (idx, name) in got_symbols.iter().enumerate() {
+            let target = self
+                .data
+                .get(name)
+                .ok_or_else(|| PeaceError::UnresolvedSymbol(name.to_owned()))?
+                .data;
+            unsafe {
+                let slot = region.add(got_start as usize + idx * 8);
+                (slot as *mut *const u8).write_unaligned(target);
+                self.got.insert(name.to_owned(), slot);
+            }
+        }
 
-/// A simple struct consisting of a pointer and length.
-struct PtrLen {
-    ptr: *mut u8,
-    len: usize,
-}
+        for name in self.reloc_targets(names, RelocKind::Slot) {
+            if !self.slots.contains_key(&name) {
+                let target = self
+                    .data
+                    .get(&name)
+                    .ok_or_else(|| PeaceError::UnresolvedSymbol(name.clone()))?
+                    .data;
+                let slot = Box::new(AtomicPtr::new(target as *mut u8));
+                self.slots.insert(name, slot);
+            }
+        }
 
-impl PtrLen {
-    /// Create a new empty `PtrLen`.
-    fn new() -> Self {
-        Self {
-            ptr: std::ptr::null_mut(),
-            len: 0,
-        }
-    }
-
-    /// Create a new `PtrLen` pointing to at least `size` bytes of memory,
-    /// suitably sized and aligned for memory protection.
-    #[cfg(not(target_os = "windows"))]
-    fn with_size(size: usize) -> Result<Self, String> {
-        let page_size = region::page::size();
-        let alloc_size = round_up_to_page_size(size, page_size);
-        unsafe {
-            let mut ptr: *mut libc::c_void = mem::uninitialized();
-            let err = libc::posix_memalign(&mut ptr, page_size, alloc_size);
-            if err == 0 {
-                Ok(Self {
-                    ptr: ptr as *mut u8,
-                    len: alloc_size,
-                })
-            } else {
-                Err(errno::Errno(err).to_string())
+        self.reloc_fix(names)?;
+        self.memory.set_readable_and_executable()?;
+        flush_icache(region, size as usize);
+
+        // only executable code may be reached through a slot
+        for (name, slot) in self.slots.iter() {
+            if let Some(data) = self.data.get(name) {
+                slot.store(data.data as *mut u8, Ordering::Release);
             }
         }
+        Ok(())
     }
 
-    #[cfg(target_os = "windows")]
-    fn with_size(size: usize) -> Result<Self, String> {
-        use winapi::um::memoryapi::VirtualAlloc;
-        use winapi::um::winnt::{MEM_COMMIT, MEM_RESERVE, PAGE_READWRITE};
+    /// Replace the code of function `name` placed by `finish`. `build` fills
+    /// in a new body with the same signature, which is finalized and placed,
+    /// then the slot of `name` is switched to it at once. Calls already
+    /// running finish in the old code.
+    ///
+    /// Fails unless every other function of the module refers to `name`
+    /// through its slot, see `Function::set_indirect_calls`. Other modules
+    /// find the new definition, but code that already resolved the old one
+    /// and pointers from `get_finalized_function` must not be used once it
+    /// is freed. The old code is retired when no other function placed
+    /// together with it is left, and freed once no `CodeGuard` from before
+    /// is alive. On failure the old definition stays in place.
+    pub fn redefine_function<F>(&mut self, name: &str, build: F) -> Result<()>
+    where
+        F: FnOnce(&mut Function) -> Result<()>,
+    {
+        let old = self
+            .uncompiled_functions
+            .get(name)
+            .filter(|func| func.linkage.is_definition())
+            .ok_or_else(|| PeaceError::UnresolvedSymbol(name.to_owned()))?;
+        let old_data = self
+            .data
+            .get(name)
+            .filter(|data| data.kind == DataKind::Function)
+            .cloned()
+            .ok_or_else(|| PeaceError::Unsupported(format!("redefining {} before finish", name)))?;
+        let mut users: Vec<&String> = self
+            .uncompiled_functions
+            .values()
+            .filter(|func| func.name != name)
+            .filter(|func| {
+                func.relocs
+                    .iter()
+                    .any(|reloc| reloc.global_name == name && reloc.kind != RelocKind::Slot)
+            })
+            .map(|func| &func.name)
+            .collect();
+        users.sort();
+        if let Some(user) = users.first() {
+            return Err(PeaceError::Unsupported(format!(
+                "redefining {}, which {} calls directly",
+                name, user
+            )));
+        }
 
-        let page_size = region::page::size();
+        let mut func = Function::new(name, old.linkage, old.signature.clone());
+        func.set_opt_level(old.opt_level);
+        func.set_trap_policy(old.trap_policy);
+        func.set_indirect_calls(old.indirect_calls);
+        build(&mut func)?;
+        func.finalize()?;
 
-        // VirtualAlloc always rounds up to the next multiple of the page size
-        let ptr = unsafe {
-            VirtualAlloc(
-                ptr::null_mut(),
-                size,
-                MEM_COMMIT | MEM_RESERVE,
-                PAGE_READWRITE,
-            )
-        };
-        if !ptr.is_null() {
-            Ok(Self {
-                ptr: ptr as *mut u8,
-                len: round_up_to_page_size(size, page_size),
-            })
-        } else {
-            Err(errno::errno().to_string())
+        let old = self
+            .uncompiled_functions
+            .insert(name.to_owned(), func)
+            .unwrap();
+        let names = [name.to_owned()];
+        if let Err(err) = self.place(&names) {
+            self.uncompiled_functions.insert(name.to_owned(), old);
+            let new_data = self.data.insert(name.to_owned(), old_data.clone()).unwrap();
+            if new_data.data != old_data.data {
+                for region in self.regions.iter_mut() {
+                    if region.contains(new_data.data) {
+                        region.functions.clear();
+                    }
+                }
+                self.release_regions()?;
+            }
+            return Err(err);
+        }
+
+        let data = &self.data[name];
+        if data.linkage.is_exported() {
+            register_symbol(name, data.data);
+        }
+        let new_code = data.data;
+        for region in self.regions.iter_mut() {
+            if !region.contains(new_code) {
+                region.functions.retain(|function| function != name);
+            }
         }
+        self.release_regions()
     }
-}
 
-/// JIT memory manager. This manages pages of suitably aligned and
-/// accessible memory.
-pub struct Memory {
-    allocations: Vec<PtrLen>,
-    executable: usize,
-    current: PtrLen,
-    position: usize,
-}
+    /// Function `name` placed by `finish` as a handle called with the Rust
+    /// signature `F`, such as `fn(i64, f64) -> i64`. Fails unless `F` matches
+    /// the signature `name` was declared with. The handle holds a guard, see
+    /// `pin`.
+    pub fn get_typed<F: JitFunction>(&self, name: &str) -> Result<TypedFunction<F>> {
+        let code = self.get_finalized_function(name)?;
+        let signature = self
+            .signature(name)
+            .ok_or_else(|| PeaceError::UnresolvedSymbol(name.to_owned()))?;
+        let expected = F::signature();
+        if *signature != expected {
+            return Err(PeaceError::TypeMismatch(format!(
+                "{} is declared as {}, not {}",
+                name, signature, expected
+            )));
+        }
+        Ok(TypedFunction::new(code, self.pin()))
+    }
+
+    /// Mark the current thread as running code of the module until the guard
+    /// is dropped, code retired in the meantime is not freed. Threads calling
+    /// into the module while it redefines or removes functions have to hold
+    /// a guard. Dropping the module unregisters its symbols, but its code is
+    /// only freed with the last guard.
+    pub fn pin(&self) -> CodeGuard {
+        let mut epochs = self.epochs.lock().unwrap();
+        let epoch = epochs.current;
+        *epochs.guards.entry(epoch).or_insert(0) += 1;
+        CodeGuard {
+            epochs: self.epochs.clone(),
+            epoch,
+            _code: self.kept.clone(),
+        }
+    }
+
+    /// Remove function `name` from the module. Its code is retired once no
+    /// other function placed together with it by `finish` is left.
+    ///
+    /// Fails while another function of the module refers to `name`. Other
+    /// modules no longer find the function, but code that already resolved
+    /// it and pointers from `get_finalized_function` must not be used
+    /// afterwards.
+    pub fn remove_function(&mut self, name: &str) -> Result<()> {
+        if !self.uncompiled_functions.contains_key(name) {
+            return Err(PeaceError::UnresolvedSymbol(name.to_owned()));
+        }
+        let mut users: Vec<&String> = self
+            .uncompiled_functions
+            .values()
+            .filter(|func| func.name != name)
+            .filter(|func| func.relocs.iter().any(|reloc| reloc.global_name == name))
+            .map(|func| &func.name)
+            .collect();
+        users.sort();
+        if let Some(user) = users.first() {
+            return Err(PeaceError::Unsupported(format!(
+                "removing {}, which is still used by {}",
+                name, user
+            )));
+        }
 
-impl Memory {
-    pub fn new() -> Self {
-        Self {
-            allocations: Vec::new(),
-            executable: 0,
-            current: PtrLen::new(),
-            position: 0,
+        self.uncompiled_functions.remove(name);
+        if let Some(data) = self.data.remove(name) {
+            if data.linkage.is_exported() {
+                unregister_symbol_at(name, data.data);
+            }
         }
+        for region in self.regions.iter_mut() {
+            region.functions.retain(|function| function != name);
+        }
+        self.release_regions()
     }
 
-    fn finish_current(&mut self) {
-        self.allocations
-            .push(mem::replace(&mut self.current, PtrLen::new()));
-        self.position = 0;
+    /// Retire the regions whose functions were all removed or redefined.
+    fn release_regions(&mut self) -> Result<()> {
+        let (unused, used): (Vec<CodeRegion>, Vec<CodeRegion>) = mem::take(&mut self.regions)
+            .into_iter()
+            .partition(|region| region.functions.is_empty());
+        self.regions = used;
+
+        if !unused.is_empty() {
+            let epoch = self.epochs.lock().unwrap().retire();
+            for region in unused {
+                self.stubs.retain(|_, stub| !region.contains(*stub));
+                self.got.retain(|_, slot| !region.contains(*slot));
+                self.retired.push((epoch, region));
+            }
+        }
+        self.free_retired_code()
     }
 
-    /// TODO: Use a proper error type.
-    pub fn allocate(&mut self, size: usize) -> Result<*mut u8, String> {
-        if size <= self.current.len - self.position {
-            // TODO: Ensure overflow is not possible.
-            let ptr = unsafe { self.current.ptr.add(self.position) };
-            self.position += size;
-            return Ok(ptr);
+    /// Free the retired code no `CodeGuard` protects anymore. Happens on its
+    /// own when functions are redefined or removed.
+    pub fn free_retired_code(&mut self) -> Result<()> {
+        let (free, keep): (Vec<_>, Vec<_>) = {
+            let epochs = self.epochs.lock().unwrap();
+            mem::take(&mut self.retired)
+                .into_iter()
+                .partition(|(epoch, _)| epochs.is_quiescent(*epoch))
+        };
+        self.retired = keep;
+
+        for (_, region) in free {
+            unregister_traps(region.start, region.size);
+            unsafe { self.memory.free(region.start, region.size)? };
         }
+        Ok(())
+    }
+
+    /// Bytes of code memory reserved, in use and freed by this module.
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.memory.stats()
+    }
 
-        self.finish_current();
+    /// Disassemble the code of function `name` placed by `finish`.
+    pub fn disassemble(&self, name: &str) -> Result<String> {
+        let func = self
+            .uncompiled_functions
+            .get(name)
+            .filter(|func| func.linkage.is_definition())
+            .ok_or_else(|| PeaceError::UnresolvedSymbol(name.to_owned()))?;
+        let code = self
+            .data
+            .get(name)
+            .filter(|data| data.kind == DataKind::Function)
+            .ok_or_else(|| {
+                PeaceError::Unsupported(format!("disassembly of {} before finish", name))
+            })?;
 
-        // TODO: Allocate more at a time.
-        self.current = PtrLen::with_size(size)?;
-        self.position = size;
-        Ok(self.current.ptr)
+        let code_slice = unsafe { std::slice::from_raw_parts(code.data, code.size) };
+        Ok(crate::disasm::disassemble(
+            func,
+            code_slice,
+            code.data as u64,
+        ))
     }
 
-    /// Set all memory allocated in this `Memory` up to now as readable and executable.
-    pub fn set_readable_and_executable(&mut self) {
-        self.finish_current();
+    /// Write all finalized functions and defined data into an ELF relocatable
+    /// object instead of placing them into executable memory.
+    pub fn emit_object(&mut self) -> Result<Vec<u8>> {
+        self.check_finalized()?;
+        let mut obj = ElfObject::new();
+        let mut symbols: HashMap<String, usize> = HashMap::new();
+
+        let binding = |linkage: Linkage| match linkage {
+            Linkage::Local => Binding::Local,
+            _ => Binding::Global,
+        };
+        let visibility = |linkage: Linkage| match linkage {
+            Linkage::Export => Visibility::Protected,
+            _ => Visibility::Default,
+        };
+
+        let mut names: Vec<String> = self.uncompiled_functions.keys().cloned().collect();
+        names.sort();
+
+        let mut starts = HashMap::new();
+        for name in names.iter() {
+            let func = self.uncompiled_functions.get_mut(name).unwrap();
+            let (section, offset, size) = if func.linkage == Linkage::Import {
+                (Section::Undefined, 0, 0)
+            } else {
+                func.asm.fix_forward_jumps();
+                // the data segment has to directly precede the code
+                let dseg_size = func.asm.dseg.size() as usize;
+                let start = align((obj.text.len() + dseg_size) as i32, 16) as usize;
+                obj.text.resize(start + func.asm.data().len(), 0);
+                unsafe { copy_code(&func.asm, obj.text.as_mut_ptr().add(start)) };
+                starts.insert(name.clone(), start);
+                (Section::Text, start, func.asm.data().len())
+            };
+
+            let idx = obj.add_symbol(Symbol {
+                name: name.clone(),
+                section,
+                binding: binding(func.linkage),
+                visibility: visibility(func.linkage),
+                is_function: true,
+                offset: offset as u64,
+                size: size as u64,
+            });
+            symbols.insert(name.clone(), idx);
+        }
+
+        let mut data_names: Vec<String> = self
+            .data
+            .iter()
+            .filter(|(_, data)| data.kind == DataKind::Data)
+            .map(|(name, _)| name.clone())
+            .chain(self.uncompiled_data.keys().cloned())
+            .collect();
+        data_names.sort();
+        data_names.dedup();
 
-        for &PtrLen { ptr, len } in &self.allocations[self.executable..] {
-            if len != 0 {
-                unsafe {
-                    region::protect(ptr, len, region::Protection::ReadExecute)
-                        .expect("unable to make memory readable+executable");
+        for name in data_names {
+            let data = self
+                .data
+                .get(&name)
+                .or_else(|| self.uncompiled_data.get(&name))
+                .ok_or_else(|| PeaceError::UnresolvedSymbol(name.clone()))?;
+            let (section, offset, size) = match data.linkage {
+                Linkage::Import => (Section::Undefined, 0, 0),
+                _ if !data.is_sized => continue,
+                _ => {
+                    let start = align(obj.data.len() as i32, 8) as usize;
+                    obj.data.resize(start, 0);
+                    let bytes = unsafe { std::slice::from_raw_parts(data.data, data.size) };
+                    obj.data.extend_from_slice(bytes);
+                    (Section::Data, start, data.size)
                 }
+            };
+
+            let idx = obj.add_symbol(Symbol {
+                name: name.clone(),
+                section,
+                binding: binding(data.linkage),
+                visibility: visibility(data.linkage),
+                is_function: false,
+                offset: offset as u64,
+                size: size as u64,
+            });
+            symbols.insert(name, idx);
+        }
+
+        for name in names.iter() {
+            let start = match starts.get(name) {
+                Some(start) => *start,
+                None => continue,
+            };
+
+            for reloc in self.uncompiled_functions[name].relocs.iter() {
+                let symbol = match symbols.get(&reloc.global_name) {
+                    Some(idx) => *idx,
+                    // not declared in this module, leave it to the linker
+                    None => {
+                        let idx = obj.add_symbol(Symbol {
+                            name: reloc.global_name.clone(),
+                            section: Section::Undefined,
+                            binding: Binding::Global,
+                            visibility: Visibility::Default,
+                            is_function: false,
+                            offset: 0,
+                            size: 0,
+                        });
+                        symbols.insert(reloc.global_name.clone(), idx);
+                        idx
+                    }
+                };
+
+                let kind = match reloc.kind {
+                    RelocKind::Abs64 => R_X86_64_64,
+                    RelocKind::Rel32 => R_X86_64_PLT32,
+                    RelocKind::RipRel32 => R_X86_64_PC32,
+                    RelocKind::GotRel32 => R_X86_64_GOTPCREL,
+                    RelocKind::Slot => {
+                        return Err(PeaceError::Unsupported(format!(
+                            "indirect calls of {} in an object file",
+                            name
+                        )))
+                    }
+                };
+                obj.relocs.push(Relocation {
+                    offset: (start + reloc.offset) as u64,
+                    symbol,
+                    kind,
+                    addend: reloc.addend,
+                });
             }
         }
+
+        Ok(obj.write())
     }
 
-    /// Set all memory allocated in this `Memory` up to now as readonly.
-    pub fn set_readonly(&mut self) {
-        self.finish_current();
+    /// Write an ELF relocatable object to `path`, see `emit_object`.
+    pub fn write_object<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let bytes = self.emit_object()?;
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+}
 
-        for &PtrLen { ptr, len } in &self.allocations[self.executable..] {
-            if len != 0 {
-                unsafe {
-                    region::protect(ptr, len, region::Protection::Read)
-                        .expect("unable to make memory readonly");
-                }
+/// Symbols of the module are unregistered, its code and trap sites are
+/// freed once no `CodeGuard` is left.
+impl Drop for Module {
+    fn drop(&mut self) {
+        for (name, data) in self.data.iter() {
+            if data.linkage.is_exported() {
+                unregister_symbol_at(name, data.data);
             }
         }
+        let mut kept = self.kept.lock().unwrap();
+        kept.memory = mem::take(&mut self.memory);
+        let retired = self.retired.drain(..).map(|(_, region)| region);
+        kept.regions = self.regions.drain(..).chain(retired).collect();
+        kept.slots = mem::take(&mut self.slots);
+        kept.owned_data = mem::take(&mut self.owned_data);
+    }
+}
+
+/// A module whose code was placed by `Module::compile`. It can no longer be
+/// changed, so it can be shared between threads, e.g. behind an `Arc`. The
+/// code is freed when it is dropped.
+pub struct CompiledModule {
+    module: Module,
+}
+
+impl CompiledModule {
+    pub fn get_finalized_function(&self, name: &str) -> Result<*const u8> {
+        self.module
+            .get_finalized_function(name)
+            .map(|code| code as *const u8)
+    }
+
+    pub fn get_finalized_data(&self, name: &str) -> Result<(*const u8, usize)> {
+        self.module
+            .get_finalized_data(name)
+            .map(|(data, size)| (data as *const u8, size))
+    }
+
+    /// Signature `name` was declared with.
+    pub fn signature(&self, name: &str) -> Option<&Signature> {
+        self.module.signature(name)
+    }
+
+    /// Disassemble the code of function `name`.
+    pub fn disassemble(&self, name: &str) -> Result<String> {
+        self.module.disassemble(name)
+    }
+
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.module.memory_stats()
     }
-}
\ No newline at end of file
+
+    /// Function `name` as a handle called with the Rust signature `F`, see
+    /// `Module::get_typed`.
+    pub fn get_typed<F: JitFunction>(&self, name: &str) -> Result<TypedFunction<F>> {
+        self.module.get_typed(name)
+    }
+}
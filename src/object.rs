@@ -0,0 +1,333 @@
+//! Writer for ELF64 relocatable object files.
+//!
+//! Only what `Module` needs is supported: one `.text` and one `.data`
+//! section, a symbol table and the relocations of `.text`.
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::io::Write;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+
+const STV_DEFAULT: u8 = 0;
+const STV_PROTECTED: u8 = 3;
+
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+pub const R_X86_64_64: u32 = 1;
+pub const R_X86_64_PC32: u32 = 2;
+pub const R_X86_64_GOTPCREL: u32 = 9;
+pub const R_X86_64_PLT32: u32 = 4;
+
+const EHDR_SIZE: usize = 64;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+
+// section header indices
+const TEXT: u16 = 1;
+const DATA: u16 = 2;
+const SYMTAB: u32 = 3;
+const STRTAB: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Section {
+    Text,
+    Data,
+    /// The symbol is defined in another object.
+    Undefined,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Binding {
+    Local,
+    Global,
+    /// Global, but may be overridden by a definition in another object.
+    Weak,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Visibility {
+    /// References may be bound to a definition in another module at load time.
+    Default,
+    /// Visible to other modules, but references from this module always bind
+    /// to this definition.
+    Protected,
+}
+
+#[derive(Clone, Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub section: Section,
+    pub binding: Binding,
+    pub visibility: Visibility,
+    pub is_function: bool,
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Relocation {
+    /// Offset of the patched field in `.text`.
+    pub offset: u64,
+    /// Index into `ElfObject::symbols`.
+    pub symbol: usize,
+    /// One of the `R_X86_64_*` constants.
+    pub kind: u32,
+    pub addend: i64,
+}
+
+/// Contents of an object file.
+#[derive(Clone, Debug, Default)]
+pub struct ElfObject {
+    pub text: Vec<u8>,
+    pub data: Vec<u8>,
+    pub symbols: Vec<Symbol>,
+    pub relocs: Vec<Relocation>,
+}
+
+struct SectionHeader<'a> {
+    name: &'static str,
+    ty: u32,
+    flags: u64,
+    contents: &'a [u8],
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+impl<'a> SectionHeader<'a> {
+    fn write(&self, buf: &mut Vec<u8>, name: u32, offset: u64) {
+        buf.write_u32::<LittleEndian>(name).unwrap();
+        buf.write_u32::<LittleEndian>(self.ty).unwrap();
+        buf.write_u64::<LittleEndian>(self.flags).unwrap();
+        // address, objects are not loaded as they are
+        buf.write_u64::<LittleEndian>(0).unwrap();
+        buf.write_u64::<LittleEndian>(offset).unwrap();
+        buf.write_u64::<LittleEndian>(self.contents.len() as u64)
+            .unwrap();
+        buf.write_u32::<LittleEndian>(self.link).unwrap();
+        buf.write_u32::<LittleEndian>(self.info).unwrap();
+        buf.write_u64::<LittleEndian>(self.align).unwrap();
+        buf.write_u64::<LittleEndian>(self.entsize).unwrap();
+    }
+}
+
+fn pad(buf: &mut Vec<u8>, align: usize) {
+    while !buf.len().is_multiple_of(align) {
+        buf.push(0);
+    }
+}
+
+/// Append `name` to a string table and return its offset.
+fn add_string(table: &mut Vec<u8>, name: &str) -> u32 {
+    let offset = table.len() as u32;
+    table.extend_from_slice(name.as_bytes());
+    table.push(0);
+    offset
+}
+
+impl ElfObject {
+    pub fn new() -> ElfObject {
+        ElfObject::default()
+    }
+
+    /// Add `symbol` and return its index for use in relocations.
+    pub fn add_symbol(&mut self, symbol: Symbol) -> usize {
+        self.symbols.push(symbol);
+        self.symbols.len() - 1
+    }
+
+    /// Serialize the object file.
+    pub fn write(&self) -> Vec<u8> {
+        // local symbols have to come first, index 0 is the null symbol
+        let mut order: Vec<usize> = (0..self.symbols.len()).collect();
+        order.sort_by_key(|idx| self.symbols[*idx].binding != Binding::Local);
+        let mut sym_index = vec![0; self.symbols.len()];
+        for (pos, idx) in order.iter().enumerate() {
+            sym_index[*idx] = pos + 1;
+        }
+        let first_global = 1 + order
+            .iter()
+            .filter(|idx| self.symbols[**idx].binding == Binding::Local)
+            .count();
+
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; SYM_SIZE];
+        for idx in order.iter() {
+            let symbol = &self.symbols[*idx];
+            let bind = match symbol.binding {
+                Binding::Local => STB_LOCAL,
+                Binding::Global => STB_GLOBAL,
+                Binding::Weak => STB_WEAK,
+            };
+            let ty = match (symbol.section, symbol.is_function) {
+                (Section::Undefined, _) => STT_NOTYPE,
+                (_, true) => STT_FUNC,
+                (_, false) => STT_OBJECT,
+            };
+            let shndx = match symbol.section {
+                Section::Text => TEXT,
+                Section::Data => DATA,
+                Section::Undefined => 0,
+            };
+
+            let name = add_string(&mut strtab, &symbol.name);
+            symtab.write_u32::<LittleEndian>(name).unwrap();
+            symtab.push(bind << 4 | ty);
+            symtab.push(match symbol.visibility {
+                Visibility::Default => STV_DEFAULT,
+                Visibility::Protected => STV_PROTECTED,
+            });
+            symtab.write_u16::<LittleEndian>(shndx).unwrap();
+            symtab.write_u64::<LittleEndian>(symbol.offset).unwrap();
+            symtab.write_u64::<LittleEndian>(symbol.size).unwrap();
+        }
+
+        let mut rela = vec![];
+        for reloc in self.relocs.iter() {
+            let info = (sym_index[reloc.symbol] as u64) << 32 | reloc.kind as u64;
+            rela.write_u64::<LittleEndian>(reloc.offset).unwrap();
+            rela.write_u64::<LittleEndian>(info).unwrap();
+            rela.write_i64::<LittleEndian>(reloc.addend).unwrap();
+        }
+
+        let empty = vec![];
+        let sections = [
+            SectionHeader {
+                name: ".text",
+                ty: SHT_PROGBITS,
+                flags: SHF_ALLOC | SHF_EXECINSTR,
+                contents: &self.text,
+                link: 0,
+                info: 0,
+                align: 16,
+                entsize: 0,
+            },
+            SectionHeader {
+                name: ".data",
+                ty: SHT_PROGBITS,
+                flags: SHF_ALLOC | SHF_WRITE,
+                contents: &self.data,
+                link: 0,
+                info: 0,
+                align: 8,
+                entsize: 0,
+            },
+            SectionHeader {
+                name: ".symtab",
+                ty: SHT_SYMTAB,
+                flags: 0,
+                contents: &symtab,
+                link: STRTAB,
+                info: first_global as u32,
+                align: 8,
+                entsize: SYM_SIZE as u64,
+            },
+            SectionHeader {
+                name: ".strtab",
+                ty: SHT_STRTAB,
+                flags: 0,
+                contents: &strtab,
+                link: 0,
+                info: 0,
+                align: 1,
+                entsize: 0,
+            },
+            SectionHeader {
+                name: ".rela.text",
+                ty: SHT_RELA,
+                flags: SHF_INFO_LINK,
+                contents: &rela,
+                link: SYMTAB,
+                info: TEXT as u32,
+                align: 8,
+                entsize: RELA_SIZE as u64,
+            },
+            // marks the stack as non executable
+            SectionHeader {
+                name: ".note.GNU-stack",
+                ty: SHT_PROGBITS,
+                flags: 0,
+                contents: &empty,
+                link: 0,
+                info: 0,
+                align: 1,
+                entsize: 0,
+            },
+        ];
+
+        let mut shstrtab = vec![0];
+        let names: Vec<u32> = sections
+            .iter()
+            .map(|section| add_string(&mut shstrtab, section.name))
+            .collect();
+        let shstrtab_name = add_string(&mut shstrtab, ".shstrtab");
+        let shstrtab_header = SectionHeader {
+            name: ".shstrtab",
+            ty: SHT_STRTAB,
+            flags: 0,
+            contents: &shstrtab,
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        };
+
+        let mut out = vec![0; EHDR_SIZE];
+        // section 0 is the null section
+        let mut headers = vec![0; SHDR_SIZE];
+        for (section, name) in sections
+            .iter()
+            .zip(names.iter())
+            .chain(std::iter::once((&shstrtab_header, &shstrtab_name)))
+        {
+            pad(&mut out, section.align as usize);
+            let offset = out.len() as u64;
+            out.extend_from_slice(section.contents);
+            section.write(&mut headers, *name, offset);
+        }
+        let shnum = sections.len() as u16 + 2;
+
+        pad(&mut out, 8);
+        let shoff = out.len() as u64;
+        out.extend_from_slice(&headers);
+
+        let mut header = &mut out[..EHDR_SIZE];
+        // magic, 64 bit, little endian, version 1, System V ABI
+        let mut ident = [0; 16];
+        ident[..7].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1]);
+        header.write_all(&ident).unwrap();
+        // e_type = ET_REL, e_machine = EM_X86_64, e_version
+        header.write_u16::<LittleEndian>(1).unwrap();
+        header.write_u16::<LittleEndian>(62).unwrap();
+        header.write_u32::<LittleEndian>(1).unwrap();
+        // no entry point and no program headers
+        header.write_u64::<LittleEndian>(0).unwrap();
+        header.write_u64::<LittleEndian>(0).unwrap();
+        header.write_u64::<LittleEndian>(shoff).unwrap();
+        header.write_u32::<LittleEndian>(0).unwrap();
+        header.write_u16::<LittleEndian>(EHDR_SIZE as u16).unwrap();
+        header.write_u16::<LittleEndian>(0).unwrap();
+        header.write_u16::<LittleEndian>(0).unwrap();
+        header.write_u16::<LittleEndian>(SHDR_SIZE as u16).unwrap();
+        header.write_u16::<LittleEndian>(shnum).unwrap();
+        header.write_u16::<LittleEndian>(shnum - 1).unwrap();
+
+        out
+    }
+}
@@ -0,0 +1,796 @@
+//! Reader for the textual IR printed by the `Display` impls of `Function` and
+//! `Module`.
+//!
+//! Value and block numbers are renumbered densely keeping their order, so
+//! printing a parsed module gives back the same text unless numbers were
+//! skipped. Comments start with `;` and run to the end of the line.
+
+use crate::error::{PeaceError, Result};
+use crate::function::Function;
+use crate::ir::*;
+use crate::module::*;
+use crate::printer::*;
+use crate::types::*;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::Path;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Int(i64),
+    Float(f64),
+    Str(Vec<u8>),
+    Punct(&'static str),
+}
+
+const PUNCTS: [&str; 11] = ["->", "...", "(", ")", "{", "}", ",", ":", "=", "+", "*"];
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$'
+}
+
+fn error(line: usize, message: String) -> PeaceError {
+    PeaceError::Parse(format!("line {}: {}", line, message))
+}
+
+/// Split `text` into tokens, each with the line it starts on.
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>> {
+    let mut tokens = vec![];
+    for (idx, line) in text.lines().enumerate() {
+        let line_no = idx + 1;
+        let mut rest = line;
+        loop {
+            rest = rest.trim_start();
+            if rest.is_empty() || rest.starts_with(';') {
+                break;
+            }
+
+            if let Some(punct) = PUNCTS.iter().find(|punct| rest.starts_with(**punct)) {
+                tokens.push((Token::Punct(punct), line_no));
+                rest = &rest[punct.len()..];
+                continue;
+            }
+
+            let first = rest.chars().next().unwrap();
+            if first == '"' {
+                let (bytes, len) = unquote(&rest[1..])
+                    .ok_or_else(|| error(line_no, "unterminated or malformed string".to_owned()))?;
+                tokens.push((Token::Str(bytes), line_no));
+                rest = &rest[len + 1..];
+            } else if first.is_ascii_digit() || first == '-' {
+                // integers, floats like `-1.5e-7` and `-inf`
+                let mut len = 1;
+                for (idx, c) in rest.char_indices().skip(1) {
+                    let exponent_sign =
+                        (c == '-' || c == '+') && matches!(rest.as_bytes()[idx - 1], b'e' | b'E');
+                    if !(c.is_ascii_alphanumeric() || c == '.' || exponent_sign) {
+                        break;
+                    }
+                    len = idx + c.len_utf8();
+                }
+                let text = &rest[..len];
+                let token = match (text.parse(), text.parse()) {
+                    (Ok(imm), _) => Token::Int(imm),
+                    (_, Ok(imm)) => Token::Float(imm),
+                    _ => return Err(error(line_no, format!("bad number {}", text))),
+                };
+                tokens.push((token, line_no));
+                rest = &rest[len..];
+            } else if is_word_char(first) {
+                let len = rest.find(|c| !is_word_char(c)).unwrap_or(rest.len());
+                tokens.push((Token::Word(rest[..len].to_owned()), line_no));
+                rest = &rest[len..];
+            } else {
+                return Err(error(line_no, format!("unexpected character {:?}", first)));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Decode a string after its opening quote, returning the bytes and the
+/// length including the closing quote.
+fn unquote(text: &str) -> Option<(Vec<u8>, usize)> {
+    let bytes = text.as_bytes();
+    let mut out = vec![];
+    let mut idx = 0;
+    loop {
+        match bytes.get(idx)? {
+            b'"' => return Some((out, idx + 1)),
+            b'\\' => {
+                let hex = text.get(idx + 1..idx + 3)?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+                idx += 3;
+            }
+            byte => {
+                out.push(*byte);
+                idx += 1;
+            }
+        }
+    }
+}
+
+fn lookup<T: Copy>(table: &[(T, &'static str)], name: &str) -> Option<T> {
+    table.iter().find(|(_, n)| *n == name).map(|(k, _)| *k)
+}
+
+/// Blocks of a function as written, before they are turned into a `Body`.
+struct ParsedBlock {
+    block: Block,
+    params: Vec<(Value, Type)>,
+    insts: Vec<ParsedInst>,
+    line: usize,
+}
+
+struct ParsedInst {
+    data: InstData,
+    result: Option<(Value, Type)>,
+    line: usize,
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn line(&self) -> usize {
+        match self.tokens.get(self.pos) {
+            Some((_, line)) => *line,
+            None => self.tokens.last().map_or(1, |(_, line)| *line),
+        }
+    }
+
+    fn error<T>(&self, message: String) -> Result<T> {
+        Err(error(self.line(), message))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn peek_at(&self, ahead: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + ahead).map(|(token, _)| token)
+    }
+
+    fn is_punct(&self, punct: &str) -> bool {
+        matches!(self.peek(), Some(Token::Punct(p)) if *p == punct)
+    }
+
+    fn eat_punct(&mut self, punct: &str) -> bool {
+        if self.is_punct(punct) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_punct(&mut self, punct: &str) -> Result<()> {
+        if self.eat_punct(punct) {
+            Ok(())
+        } else {
+            self.error(format!("expected `{}`, found {}", punct, self.describe()))
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self.peek() {
+            Some(Token::Word(word)) => format!("`{}`", word),
+            Some(Token::Int(imm)) => format!("`{}`", imm),
+            Some(Token::Float(imm)) => format!("`{:?}`", imm),
+            Some(Token::Str(_)) => "string".to_owned(),
+            Some(Token::Punct(punct)) => format!("`{}`", punct),
+            None => "end of input".to_owned(),
+        }
+    }
+
+    fn word(&mut self) -> Result<String> {
+        match self.peek() {
+            Some(Token::Word(word)) => {
+                let word = word.clone();
+                self.pos += 1;
+                Ok(word)
+            }
+            _ => self.error(format!("expected a name, found {}", self.describe())),
+        }
+    }
+
+    fn int(&mut self) -> Result<i64> {
+        match self.peek() {
+            Some(Token::Int(imm)) => {
+                let imm = *imm;
+                self.pos += 1;
+                Ok(imm)
+            }
+            _ => self.error(format!("expected an integer, found {}", self.describe())),
+        }
+    }
+
+    fn float(&mut self) -> Result<f64> {
+        let imm = match self.peek() {
+            Some(Token::Int(imm)) => *imm as f64,
+            Some(Token::Float(imm)) => *imm,
+            Some(Token::Word(word)) if word == "inf" || word == "NaN" => word.parse().unwrap(),
+            _ => return self.error(format!("expected a float, found {}", self.describe())),
+        };
+        self.pos += 1;
+        Ok(imm)
+    }
+
+    fn numbered(&mut self, prefix: &str) -> Result<u32> {
+        let word = self.word()?;
+        match word.strip_prefix(prefix).map(|n| n.parse()) {
+            Some(Ok(n)) => Ok(n),
+            _ => {
+                self.pos -= 1;
+                self.error(format!("expected a {}, found `{}`", prefix, word))
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        self.numbered("v").map(Value::new)
+    }
+
+    fn block(&mut self) -> Result<Block> {
+        self.numbered("block").map(Block::new)
+    }
+
+    fn stack_slot(&mut self) -> Result<StackSlot> {
+        self.numbered("ss").map(StackSlot::new)
+    }
+
+    /// `+8` or `-8` after the base of an address, zero if there is none.
+    fn displacement(&mut self) -> Result<i32> {
+        let offset = match self.peek() {
+            Some(Token::Int(imm)) if *imm < 0 => self.int()?,
+            Some(Token::Punct("+")) => {
+                self.pos += 1;
+                self.int()?
+            }
+            _ => 0,
+        };
+        if offset < i32::MIN as i64 || offset > i32::MAX as i64 {
+            return self.error(format!("offset {} out of range", offset));
+        }
+        Ok(offset as i32)
+    }
+
+    /// `v1*8`, the index and scale of an indexed address.
+    fn scaled_index(&mut self) -> Result<(Value, u8)> {
+        let index = self.value()?;
+        self.expect_punct("*")?;
+        let scale = self.int()?;
+        if scale < 0 || scale > u8::MAX as i64 {
+            return self.error(format!("scale {} out of range", scale));
+        }
+        Ok((index, scale as u8))
+    }
+
+    /// `ss0 = stack_slot 16, 8` declarations before the first block.
+    fn stack_slots(&mut self) -> Result<Vec<StackSlotData>> {
+        let mut slots = vec![];
+        while matches!(self.peek(), Some(Token::Word(word)) if word.starts_with("ss"))
+            && self.peek_at(1) == Some(&Token::Punct("="))
+        {
+            let slot = self.stack_slot()?;
+            if slot.0 as usize != slots.len() {
+                return self.error(format!("expected ss{}, found {}", slots.len(), slot));
+            }
+            self.expect_punct("=")?;
+            if self.word()? != "stack_slot" {
+                self.pos -= 1;
+                return self.error(format!("expected `stack_slot`, found {}", self.describe()));
+            }
+            let size = self.int()?;
+            self.expect_punct(",")?;
+            let align = self.int()?;
+            if size < 0 || size > u32::MAX as i64 || align < 0 || align > u32::MAX as i64 {
+                return self.error(format!("stack slot of {} bytes aligned to {}", size, align));
+            }
+            slots.push(StackSlotData {
+                size: size as u32,
+                align: align as u32,
+            });
+        }
+        Ok(slots)
+    }
+
+    fn is_value(token: Option<&Token>) -> bool {
+        matches!(token, Some(Token::Word(word))
+            if word.len() > 1 && word.starts_with('v') && word[1..].bytes().all(|b| b.is_ascii_digit()))
+    }
+
+    fn named<T: Copy>(&mut self, table: &[(T, &'static str)], what: &str) -> Result<T> {
+        let word = self.word()?;
+        match lookup(table, &word) {
+            Some(item) => Ok(item),
+            None => {
+                self.pos -= 1;
+                self.error(format!("unknown {} `{}`", what, word))
+            }
+        }
+    }
+
+    fn ty(&mut self) -> Result<Type> {
+        self.named(&TYPE_NAMES, "type")
+    }
+
+    fn linkage(&mut self) -> Result<Linkage> {
+        self.named(&LINKAGE_NAMES, "linkage")
+    }
+
+    fn signature(&mut self) -> Result<Signature> {
+        let mut params = vec![];
+        let mut varargs = false;
+        self.expect_punct("(")?;
+        while !self.eat_punct(")") {
+            if !params.is_empty() || varargs {
+                self.expect_punct(",")?;
+            }
+            if varargs {
+                return self.error("`...` has to be the last parameter".to_owned());
+            }
+            if self.eat_punct("...") {
+                varargs = true;
+            } else {
+                params.push(self.ty()?);
+            }
+        }
+        self.expect_punct("->")?;
+        let ret = self.ty()?;
+
+        let mut signature = Signature::new(params, ret);
+        signature.varargs = varargs;
+        if let Some(Token::Word(word)) = self.peek() {
+            if let Some(call_conv) = lookup(&CALL_CONV_NAMES, word) {
+                signature.call_conv = call_conv;
+                self.pos += 1;
+            }
+        }
+        Ok(signature)
+    }
+
+    /// `(v1, v2)`, or nothing for no values.
+    fn value_list(&mut self) -> Result<Vec<Value>> {
+        let mut values = vec![];
+        if self.eat_punct("(") {
+            while !self.eat_punct(")") {
+                if !values.is_empty() {
+                    self.expect_punct(",")?;
+                }
+                values.push(self.value()?);
+            }
+        }
+        Ok(values)
+    }
+
+    fn block_call(&mut self) -> Result<(Block, Vec<Value>)> {
+        let block = self.block()?;
+        Ok((block, self.value_list()?))
+    }
+
+    /// Parse the operands of `opcode`, returning the instruction and the type
+    /// of its result if it has one.
+    fn inst(&mut self, opcode: &str) -> Result<(InstData, Option<Type>)> {
+        let (name, suffix) = match opcode.find('.') {
+            Some(idx) => (&opcode[..idx], Some(&opcode[idx + 1..])),
+            None => (opcode, None),
+        };
+        let suffix_ty = || suffix.and_then(|suffix| lookup(&TYPE_NAMES, suffix));
+        let suffix_cc = || suffix.and_then(|suffix| lookup(&COND_CODE_NAMES, suffix));
+
+        let inst = match (name, suffix) {
+            ("iconst", Some(_)) if suffix_ty().is_some() => {
+                let ty = suffix_ty().unwrap();
+                (
+                    InstData::IConst {
+                        ty,
+                        imm: self.int()?,
+                    },
+                    Some(ty),
+                )
+            }
+            ("fconst", Some(_)) if suffix_ty().is_some() => {
+                let ty = suffix_ty().unwrap();
+                (
+                    InstData::FConst {
+                        ty,
+                        imm: self.float()?,
+                    },
+                    Some(ty),
+                )
+            }
+            ("icmp", Some(_)) | ("fcmp", Some(_)) if suffix_cc().is_some() => {
+                let cc = suffix_cc().unwrap();
+                let x = self.value()?;
+                self.expect_punct(",")?;
+                let y = self.value()?;
+                let data = if name == "icmp" {
+                    InstData::IntCmp { cc, x, y }
+                } else {
+                    InstData::FloatCmp { cc, x, y }
+                };
+                (data, Some(Type::I8))
+            }
+            ("load", Some(_)) if suffix_ty().is_some() => {
+                let ty = suffix_ty().unwrap();
+                let base = self.value()?;
+                let offset = self.displacement()?;
+                (InstData::Load { ty, base, offset }, Some(ty))
+            }
+            ("store", None) => {
+                let value = self.value()?;
+                self.expect_punct(",")?;
+                let base = self.value()?;
+                let offset = self.displacement()?;
+                (
+                    InstData::Store {
+                        value,
+                        base,
+                        offset,
+                    },
+                    None,
+                )
+            }
+            ("load_indexed", Some(_)) if suffix_ty().is_some() => {
+                let ty = suffix_ty().unwrap();
+                let base = self.value()?;
+                self.expect_punct(",")?;
+                let (index, scale) = self.scaled_index()?;
+                let offset = self.displacement()?;
+                (
+                    InstData::LoadIndexed {
+                        ty,
+                        base,
+                        index,
+                        scale,
+                        offset,
+                    },
+                    Some(ty),
+                )
+            }
+            ("store_indexed", None) => {
+                let value = self.value()?;
+                self.expect_punct(",")?;
+                let base = self.value()?;
+                self.expect_punct(",")?;
+                let (index, scale) = self.scaled_index()?;
+                let offset = self.displacement()?;
+                (
+                    InstData::StoreIndexed {
+                        value,
+                        base,
+                        index,
+                        scale,
+                        offset,
+                    },
+                    None,
+                )
+            }
+            ("stack_addr", None) => {
+                let slot = self.stack_slot()?;
+                (InstData::StackAddr { slot }, Some(Type::Pointer))
+            }
+            ("symbol_addr", None) => {
+                let name = self.word()?;
+                (InstData::SymbolAddr { name }, Some(Type::Pointer))
+            }
+            ("call", None) => {
+                let name = self.word()?;
+                let args = self.value_list()?;
+                self.expect_punct(":")?;
+                let signature = self.signature()?;
+                let ret = signature.ret;
+                (
+                    InstData::Call {
+                        name,
+                        args,
+                        signature,
+                    },
+                    Some(ret),
+                )
+            }
+            ("jump", None) => {
+                let (block, args) = self.block_call()?;
+                (InstData::Jump { block, args }, None)
+            }
+            ("brif", None) => {
+                let cond = self.value()?;
+                self.expect_punct(",")?;
+                let (then_block, then_args) = self.block_call()?;
+                self.expect_punct(",")?;
+                let (else_block, else_args) = self.block_call()?;
+                (
+                    InstData::Brif {
+                        cond,
+                        then_block,
+                        then_args,
+                        else_block,
+                        else_args,
+                    },
+                    None,
+                )
+            }
+            ("return", None) => {
+                // the value has to be on the same line, the next line may
+                // define a value in an unreachable block
+                let line = self.line();
+                let value = if Parser::is_value(self.peek())
+                    && self.tokens[self.pos].1 == line
+                    && self.peek_at(1) != Some(&Token::Punct("="))
+                {
+                    Some(self.value()?)
+                } else {
+                    None
+                };
+                (InstData::Return { value }, None)
+            }
+            _ if name.ends_with("_imm") && suffix_ty().is_some() => {
+                let op = match lookup(&BINARY_OP_NAMES, &name[..name.len() - 4]) {
+                    Some(op) if op.has_imm_form() => op,
+                    _ => return self.error(format!("unknown instruction `{}`", opcode)),
+                };
+                let x = self.value()?;
+                self.expect_punct(",")?;
+                let imm = self.int()?;
+                // the same immediates `Function` creates: shift amounts within
+                // the width of the type, others a sign extended imm32
+                let ty = suffix_ty().unwrap();
+                let in_range = if op.is_shift() {
+                    imm >= 0 && imm < ty.bits() as i64
+                } else {
+                    imm >= i32::MIN as i64 && imm <= i32::MAX as i64
+                };
+                if !in_range {
+                    self.pos -= 1;
+                    return self.error(format!(
+                        "immediate {} is out of range for `{}`",
+                        imm, opcode
+                    ));
+                }
+                (InstData::BinaryImm { op, x, imm }, suffix_ty())
+            }
+            _ => match (
+                lookup(&BINARY_OP_NAMES, name),
+                lookup(&UNARY_OP_NAMES, name),
+                lookup(&CONVERT_OP_NAMES, name),
+            ) {
+                (Some(op), _, _) if suffix_ty().is_some() => {
+                    let x = self.value()?;
+                    self.expect_punct(",")?;
+                    let y = self.value()?;
+                    (InstData::Binary { op, x, y }, suffix_ty())
+                }
+                (_, Some(op), _) if suffix_ty().is_some() => {
+                    let x = self.value()?;
+                    (InstData::Unary { op, x }, suffix_ty())
+                }
+                (_, _, Some(op)) if suffix_ty().is_some() => {
+                    let ty = suffix_ty().unwrap();
+                    let x = self.value()?;
+                    (InstData::Convert { op, ty, x }, Some(ty))
+                }
+                _ => return self.error(format!("unknown instruction `{}`", opcode)),
+            },
+        };
+        Ok(inst)
+    }
+
+    fn blocks(&mut self) -> Result<Vec<ParsedBlock>> {
+        let mut blocks: Vec<ParsedBlock> = vec![];
+        while !self.eat_punct("}") {
+            if self.peek().is_none() {
+                return self.error("expected `}` at the end of the function".to_owned());
+            }
+
+            let result = if Parser::is_value(self.peek()) {
+                let value = self.value()?;
+                self.expect_punct("=")?;
+                Some(value)
+            } else {
+                None
+            };
+
+            let line = self.line();
+            let word = self.word()?;
+            let is_header = word.starts_with("block")
+                && result.is_none()
+                && (self.is_punct(":") || self.is_punct("("));
+            if is_header {
+                self.pos -= 1;
+                let block = self.block()?;
+                let mut params = vec![];
+                if self.eat_punct("(") {
+                    while !self.eat_punct(")") {
+                        if !params.is_empty() {
+                            self.expect_punct(",")?;
+                        }
+                        let value = self.value()?;
+                        self.expect_punct(":")?;
+                        params.push((value, self.ty()?));
+                    }
+                }
+                self.expect_punct(":")?;
+                blocks.push(ParsedBlock {
+                    block,
+                    params,
+                    insts: vec![],
+                    line,
+                });
+                continue;
+            }
+
+            let (data, ty) = self.inst(&word)?;
+            let result = match (result, ty) {
+                (Some(value), Some(ty)) => Some((value, ty)),
+                (None, None) => None,
+                (None, Some(_)) => {
+                    return Err(error(line, format!("`{}` needs a result value", word)))
+                }
+                (Some(_), None) => {
+                    return Err(error(line, format!("`{}` does not produce a value", word)))
+                }
+            };
+            match blocks.last_mut() {
+                Some(block) => block.insts.push(ParsedInst { data, result, line }),
+                None => return Err(error(line, "instruction outside of a block".to_owned())),
+            }
+        }
+        Ok(blocks)
+    }
+
+    fn function(&mut self, module: &mut Module) -> Result<()> {
+        let line = self.line();
+        let linkage = self.linkage()?;
+        let name = self.word()?;
+        let signature = self.signature()?;
+        if module.uncompiled_functions.contains_key(&name) {
+            return Err(error(line, format!("function {} is defined twice", name)));
+        }
+        if linkage == Linkage::Import {
+            module.declare_function(&name, linkage, signature)?;
+            return Ok(());
+        }
+
+        self.expect_punct("{")?;
+        let stack_slots = self.stack_slots()?;
+        let blocks = self.blocks()?;
+        let mut body = build_body(&blocks, &signature, line)?;
+        body.stack_slots = stack_slots;
+        let func = Function::from_body(&name, linkage, signature, body)?;
+        func.verify()?;
+        module.define_function(func)
+    }
+
+    fn data(&mut self, module: &mut Module) -> Result<()> {
+        let linkage = self.linkage()?;
+        let name = self.word()?;
+        module.declare_data(name.clone(), linkage)?;
+        if self.eat_punct("=") {
+            match self.peek() {
+                Some(Token::Str(bytes)) => {
+                    let bytes = bytes.clone();
+                    self.pos += 1;
+                    module.define_data_owned(name, bytes)?;
+                }
+                _ => return self.error(format!("expected a string, found {}", self.describe())),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Lay out `blocks` in the order they were written. Values and blocks are
+/// numbered densely in the order of their numbers in the text, the function
+/// header is on `line`.
+fn build_body(blocks: &[ParsedBlock], signature: &Signature, line: usize) -> Result<Body> {
+    match blocks.first() {
+        Some(entry) if entry.block == Block::new(0) => {
+            let types: Vec<Type> = entry.params.iter().map(|(_, ty)| *ty).collect();
+            if types != signature.params {
+                return Err(error(
+                    entry.line,
+                    format!(
+                        "entry block takes {:?}, but the signature has {:?}",
+                        types, signature.params
+                    ),
+                ));
+            }
+        }
+        _ => return Err(error(line, "the first block has to be block0".to_owned())),
+    }
+
+    // numbers of every value and block mentioned, uses included
+    let mut value_numbers = BTreeSet::new();
+    let mut block_numbers = BTreeSet::new();
+    for parsed in blocks.iter() {
+        block_numbers.insert(parsed.block);
+        for (value, _) in parsed.params.iter() {
+            value_numbers.insert(*value);
+        }
+        for inst in parsed.insts.iter() {
+            value_numbers.extend(inst.data.args());
+            value_numbers.extend(inst.result.iter().map(|(value, _)| *value));
+            block_numbers.extend(inst.data.successors().iter().map(|(block, _)| *block));
+        }
+    }
+
+    let mut body = Body::new();
+    let blocks_map: HashMap<Block, Block> = block_numbers
+        .into_iter()
+        .map(|block| (block, body.make_block()))
+        .collect();
+    // numbers that are not defined stay `Void` and are reported by the verifier
+    let values_map: HashMap<Value, Value> = value_numbers
+        .into_iter()
+        .map(|value| (value, body.make_value(Type::Void)))
+        .collect();
+
+    let mut defined = HashSet::new();
+    let mut define = |body: &mut Body, value: Value, ty: Type, line: usize| {
+        if !defined.insert(value) {
+            return Err(error(line, format!("{} is defined twice", value)));
+        }
+        body.value_types[values_map[&value].0 as usize] = ty;
+        Ok(())
+    };
+
+    for parsed in blocks.iter() {
+        let block = blocks_map[&parsed.block];
+        if body.layout.contains(&block) {
+            return Err(error(
+                parsed.line,
+                format!("{} is defined twice", parsed.block),
+            ));
+        }
+        body.layout.push(block);
+        for (value, ty) in parsed.params.iter() {
+            define(&mut body, *value, *ty, parsed.line)?;
+            body.blocks[block.0 as usize].params.push(values_map[value]);
+        }
+        for inst in parsed.insts.iter() {
+            if let Some((value, ty)) = inst.result {
+                define(&mut body, value, ty, inst.line)?;
+            }
+            let mut data = inst.data.clone();
+            for arg in data.args_mut() {
+                *arg = values_map[arg];
+            }
+            for succ in data.successor_blocks_mut() {
+                *succ = blocks_map[succ];
+            }
+            let result = inst.result.map(|(value, _)| values_map[&value]);
+            body.push_inst(block, data, result);
+        }
+    }
+    Ok(body)
+}
+
+/// Read a module in the format printed by `Module`'s `Display` impl. Every
+/// function is verified.
+pub fn parse_module(text: &str) -> Result<Module> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        pos: 0,
+    };
+    let mut module = Module::new();
+
+    while parser.peek().is_some() {
+        match parser.word()?.as_str() {
+            "function" => parser.function(&mut module)?,
+            "data" => parser.data(&mut module)?,
+            word => {
+                parser.pos -= 1;
+                return parser.error(format!("expected `function` or `data`, found `{}`", word));
+            }
+        }
+    }
+    Ok(module)
+}
+
+/// Read a `.peace` file with `parse_module`.
+pub fn parse_file<P: AsRef<Path>>(path: P) -> Result<Module> {
+    parse_module(&std::fs::read_to_string(path)?)
+}
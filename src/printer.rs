@@ -0,0 +1,386 @@
+//! Textual form of the IR.
+//!
+//! Functions print as one line per block header and instruction, for example
+//!
+//! ```text
+//! function export add(i64, i64) -> i64 {
+//! block0(v0: i64, v1: i64):
+//!     v2 = iadd.i64 v0, v1
+//!     return v2
+//! }
+//! ```
+//!
+//! Stack slots are declared before the first block, `ss0 = stack_slot 16, 8`
+//! reserves 16 bytes aligned to 8.
+//!
+//! Value and block numbers are the ones used by the builder, so the text of a
+//! function can be read back by `parser::parse_module` without changes.
+
+use crate::backend::CondCode;
+use crate::function::Function;
+use crate::ir::*;
+use crate::module::*;
+use crate::types::*;
+use std::fmt;
+
+pub(crate) const TYPE_NAMES: [(Type, &str); 7] = [
+    (Type::I8, "i8"),
+    (Type::I32, "i32"),
+    (Type::I64, "i64"),
+    (Type::F32, "f32"),
+    (Type::F64, "f64"),
+    (Type::Pointer, "ptr"),
+    (Type::Void, "void"),
+];
+
+pub(crate) const COND_CODE_NAMES: [(CondCode, &str); 12] = [
+    (CondCode::Zero, "z"),
+    (CondCode::NonZero, "nz"),
+    (CondCode::Equal, "eq"),
+    (CondCode::NotEqual, "ne"),
+    (CondCode::Greater, "gt"),
+    (CondCode::GreaterEq, "ge"),
+    (CondCode::Less, "lt"),
+    (CondCode::LessEq, "le"),
+    (CondCode::UnsignedGreater, "ugt"),
+    (CondCode::UnsignedGreaterEq, "uge"),
+    (CondCode::UnsignedLess, "ult"),
+    (CondCode::UnsignedLessEq, "ule"),
+];
+
+pub(crate) const LINKAGE_NAMES: [(Linkage, &str); 4] = [
+    (Linkage::Import, "import"),
+    (Linkage::Local, "local"),
+    (Linkage::Export, "export"),
+    (Linkage::Preemptible, "preemptible"),
+];
+
+pub(crate) const CALL_CONV_NAMES: [(CallConv, &str); 2] = [
+    (CallConv::SystemV, "system_v"),
+    (CallConv::WindowsFastcall, "windows_fastcall"),
+];
+
+pub(crate) const BINARY_OP_NAMES: [(BinaryOp, &str); 21] = [
+    (BinaryOp::IAdd, "iadd"),
+    (BinaryOp::ISub, "isub"),
+    (BinaryOp::IMul, "imul"),
+    (BinaryOp::IDiv, "idiv"),
+    (BinaryOp::IMod, "imod"),
+    (BinaryOp::UDiv, "udiv"),
+    (BinaryOp::URem, "urem"),
+    (BinaryOp::FAdd, "fadd"),
+    (BinaryOp::FSub, "fsub"),
+    (BinaryOp::FMul, "fmul"),
+    (BinaryOp::FDiv, "fdiv"),
+    (BinaryOp::FMin, "fmin"),
+    (BinaryOp::FMax, "fmax"),
+    (BinaryOp::BAnd, "band"),
+    (BinaryOp::BOr, "bor"),
+    (BinaryOp::BXor, "bxor"),
+    (BinaryOp::IShl, "ishl"),
+    (BinaryOp::UShr, "ushr"),
+    (BinaryOp::SShr, "sshr"),
+    (BinaryOp::Rotl, "rotl"),
+    (BinaryOp::Rotr, "rotr"),
+];
+
+pub(crate) const UNARY_OP_NAMES: [(UnaryOp, &str); 8] = [
+    (UnaryOp::FNeg, "fneg"),
+    (UnaryOp::FAbs, "fabs"),
+    (UnaryOp::FSqrt, "fsqrt"),
+    (UnaryOp::BNot, "bnot"),
+    (UnaryOp::INeg, "ineg"),
+    (UnaryOp::Popcnt, "popcnt"),
+    (UnaryOp::Clz, "clz"),
+    (UnaryOp::Ctz, "ctz"),
+];
+
+pub(crate) const CONVERT_OP_NAMES: [(ConvertOp, &str); 12] = [
+    (ConvertOp::SExtend, "sextend"),
+    (ConvertOp::UExtend, "uextend"),
+    (ConvertOp::IReduce, "ireduce"),
+    (ConvertOp::FcvtFromSInt, "fcvt_from_sint"),
+    (ConvertOp::FcvtFromUInt, "fcvt_from_uint"),
+    (ConvertOp::FcvtToSInt, "fcvt_to_sint"),
+    (ConvertOp::FcvtToUInt, "fcvt_to_uint"),
+    (ConvertOp::FcvtToSIntSat, "fcvt_to_sint_sat"),
+    (ConvertOp::FcvtToUIntSat, "fcvt_to_uint_sat"),
+    (ConvertOp::FPromote, "fpromote"),
+    (ConvertOp::FDemote, "fdemote"),
+    (ConvertOp::Bitcast, "bitcast"),
+];
+
+fn name_of<T: PartialEq>(table: &[(T, &'static str)], key: T) -> &'static str {
+    table.iter().find(|(k, _)| *k == key).unwrap().1
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", name_of(&TYPE_NAMES, *self))
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "block{}", self.0)
+    }
+}
+
+impl fmt::Display for StackSlot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ss{}", self.0)
+    }
+}
+
+impl fmt::Display for Linkage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", name_of(&LINKAGE_NAMES, *self))
+    }
+}
+
+/// `(i64, ptr, ...) -> i32`, followed by the calling convention if it is not
+/// the one of the host.
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut params: Vec<String> = self.params.iter().map(|ty| ty.to_string()).collect();
+        if self.varargs {
+            params.push("...".to_owned());
+        }
+        write!(f, "({}) -> {}", params.join(", "), self.ret)?;
+        if self.call_conv != CallConv::host() {
+            write!(f, " {}", name_of(&CALL_CONV_NAMES, self.call_conv))?;
+        }
+        Ok(())
+    }
+}
+
+fn values(values: &[Value]) -> String {
+    let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+    values.join(", ")
+}
+
+/// `block3` or `block3(v1, v2)`.
+fn block_call(block: Block, args: &[Value]) -> String {
+    if args.is_empty() {
+        block.to_string()
+    } else {
+        format!("{}({})", block, values(args))
+    }
+}
+
+/// `+8` or `-8` after the base of an address, nothing for zero.
+fn displacement(offset: i32) -> String {
+    match offset {
+        0 => String::new(),
+        _ if offset < 0 => offset.to_string(),
+        _ => format!("+{}", offset),
+    }
+}
+
+fn write_inst(f: &mut fmt::Formatter, body: &Body, inst: Inst) -> fmt::Result {
+    write!(f, "    ")?;
+    if let Some(result) = body.inst_result(inst) {
+        write!(f, "{} = ", result)?;
+    }
+
+    match body.inst(inst) {
+        InstData::IConst { ty, imm } => write!(f, "iconst.{} {}", ty, imm),
+        // `{:?}` prints enough digits to read back the same value
+        InstData::FConst { ty, imm } => write!(f, "fconst.{} {:?}", ty, imm),
+        InstData::Unary { op, x } => write!(
+            f,
+            "{}.{} {}",
+            name_of(&UNARY_OP_NAMES, *op),
+            body.value_type(*x),
+            x
+        ),
+        InstData::Binary { op, x, y } => write!(
+            f,
+            "{}.{} {}, {}",
+            name_of(&BINARY_OP_NAMES, *op),
+            body.value_type(*x),
+            x,
+            y
+        ),
+        // the immediate forms are named after the operation, `band_imm`
+        InstData::BinaryImm { op, x, imm } => write!(
+            f,
+            "{}_imm.{} {}, {}",
+            name_of(&BINARY_OP_NAMES, *op),
+            body.value_type(*x),
+            x,
+            imm
+        ),
+        InstData::Convert { op, ty, x } => {
+            write!(f, "{}.{} {}", name_of(&CONVERT_OP_NAMES, *op), ty, x)
+        }
+        InstData::IntCmp { cc, x, y } => {
+            write!(f, "icmp.{} {}, {}", name_of(&COND_CODE_NAMES, *cc), x, y)
+        }
+        InstData::FloatCmp { cc, x, y } => {
+            write!(f, "fcmp.{} {}, {}", name_of(&COND_CODE_NAMES, *cc), x, y)
+        }
+        InstData::Load { ty, base, offset } => {
+            write!(f, "load.{} {}{}", ty, base, displacement(*offset))
+        }
+        InstData::Store {
+            value,
+            base,
+            offset,
+        } => write!(f, "store {}, {}{}", value, base, displacement(*offset)),
+        InstData::LoadIndexed {
+            ty,
+            base,
+            index,
+            scale,
+            offset,
+        } => write!(
+            f,
+            "load_indexed.{} {}, {}*{}{}",
+            ty,
+            base,
+            index,
+            scale,
+            displacement(*offset)
+        ),
+        InstData::StoreIndexed {
+            value,
+            base,
+            index,
+            scale,
+            offset,
+        } => write!(
+            f,
+            "store_indexed {}, {}, {}*{}{}",
+            value,
+            base,
+            index,
+            scale,
+            displacement(*offset)
+        ),
+        InstData::StackAddr { slot } => write!(f, "stack_addr {}", slot),
+        InstData::SymbolAddr { name } => write!(f, "symbol_addr {}", name),
+        InstData::Call {
+            name,
+            args,
+            signature,
+        } => write!(f, "call {}({}) : {}", name, values(args), signature),
+        InstData::Jump { block, args } => write!(f, "jump {}", block_call(*block, args)),
+        InstData::Brif {
+            cond,
+            then_block,
+            then_args,
+            else_block,
+            else_args,
+        } => write!(
+            f,
+            "brif {}, {}, {}",
+            cond,
+            block_call(*then_block, then_args),
+            block_call(*else_block, else_args)
+        ),
+        InstData::Return { value: Some(value) } => write!(f, "return {}", value),
+        InstData::Return { value: None } => write!(f, "return"),
+    }
+}
+
+/// Print `body` as its stack slots followed by the blocks of a function, in
+/// layout order.
+pub fn write_body(f: &mut fmt::Formatter, body: &Body) -> fmt::Result {
+    for (idx, slot) in body.stack_slots.iter().enumerate() {
+        writeln!(
+            f,
+            "    {} = stack_slot {}, {}",
+            StackSlot::new(idx as u32),
+            slot.size,
+            slot.align
+        )?;
+    }
+    for block in body.layout.iter() {
+        let data = body.block(*block);
+        write!(f, "{}", block)?;
+        if !data.params.is_empty() {
+            let params: Vec<String> = data
+                .params
+                .iter()
+                .map(|param| format!("{}: {}", param, body.value_type(*param)))
+                .collect();
+            write!(f, "({})", params.join(", "))?;
+        }
+        writeln!(f, ":")?;
+
+        for inst in data.insts.iter() {
+            write_inst(f, body, *inst)?;
+            writeln!(f)?;
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "function {} {}{}",
+            self.linkage, self.name, self.signature
+        )?;
+        if self.linkage == Linkage::Import {
+            return writeln!(f);
+        }
+
+        writeln!(f, " {{")?;
+        write_body(f, self.body())?;
+        writeln!(f, "}}")
+    }
+}
+
+/// Quote `bytes` as a string, escaping everything but printable ASCII as
+/// `\xx` in hex.
+fn quote(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for byte in bytes {
+        match byte {
+            b'"' | b'\\' => out.push_str(&format!("\\{:02x}", byte)),
+            0x20..=0x7e => out.push(*byte as char),
+            _ => out.push_str(&format!("\\{:02x}", byte)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Data objects followed by functions, both sorted by name.
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut data: Vec<(&String, &DataContext)> = self
+            .data
+            .iter()
+            .filter(|(_, ctx)| ctx.kind == DataKind::Data)
+            .collect();
+        data.sort_by_key(|(name, _)| *name);
+        for (name, ctx) in data.iter() {
+            write!(f, "data {} {}", ctx.linkage, name)?;
+            if ctx.is_sized && ctx.linkage != Linkage::Import {
+                let bytes = unsafe { std::slice::from_raw_parts(ctx.data, ctx.size) };
+                write!(f, " = {}", quote(bytes))?;
+            }
+            writeln!(f)?;
+        }
+
+        let mut names: Vec<&String> = self.uncompiled_functions.keys().collect();
+        names.sort();
+        for (idx, name) in names.iter().enumerate() {
+            if idx > 0 || !data.is_empty() {
+                writeln!(f)?;
+            }
+            write!(f, "{}", self.uncompiled_functions[*name])?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,355 @@
+//! Traps raised by generated code.
+//!
+//! Division by zero, overflowing division and checked float to integer
+//! conversions stop execution of the generated code. Either the hardware
+//! faults on the instruction itself (`TrapPolicy::Hardware`), or the code
+//! checks the operands and jumps to a stub executing `ud2`
+//! (`TrapPolicy::Checked`). Every such position is recorded as a `TrapSite`
+//! and registered by `Module::finish`.
+//!
+//! Calls made through `catch_traps` turn the resulting SIGFPE or SIGILL into
+//! a `PeaceError::Trap`. Outside of `catch_traps`, and for faults in code
+//! that is not generated by us, the signals keep their previous handling and
+//! usually kill the process.
+
+use crate::backend::constants_x64::Register;
+use crate::error::{PeaceError, Result};
+use std::fmt;
+use std::hint;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Why generated code trapped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TrapCode {
+    IntegerDivisionByZero,
+    /// Signed division of the smallest integer by -1, or a float converted
+    /// to an integer that cannot hold it.
+    IntegerOverflow,
+    /// NaN converted to an integer.
+    BadConversionToInteger,
+}
+
+const TRAP_CODES: [TrapCode; 3] = [
+    TrapCode::IntegerDivisionByZero,
+    TrapCode::IntegerOverflow,
+    TrapCode::BadConversionToInteger,
+];
+
+impl fmt::Display for TrapCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrapCode::IntegerDivisionByZero => write!(f, "integer division by zero"),
+            TrapCode::IntegerOverflow => write!(f, "integer overflow"),
+            TrapCode::BadConversionToInteger => write!(f, "NaN converted to integer"),
+        }
+    }
+}
+
+/// How a function guards operations that can trap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrapPolicy {
+    /// Let the instruction fault. Shortest code, the only way for a host
+    /// without `catch_traps` to notice is SIGFPE. The signed remainder of a
+    /// division by -1 is still zero as with `Checked`.
+    Hardware,
+    /// Check the operands first and jump to a stub raising the trap.
+    Checked,
+}
+
+/// Position of an instruction that may trap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TrapSite {
+    /// Offset of the instruction in the code.
+    pub offset: usize,
+    pub code: TrapCode,
+    /// Divisor of a division faulting in hardware. The fault does not tell
+    /// division by zero apart from overflow, its value does.
+    pub divisor: Option<Register>,
+}
+
+/// Trap sites of code placed in memory.
+#[derive(Clone)]
+struct CodeTraps {
+    start: usize,
+    end: usize,
+    sites: Arc<[TrapSite]>,
+}
+
+/// Trap sites of all placed code sorted by address. The signal handler
+/// reads them without taking a lock: it announces itself in `READERS` before
+/// loading the list, and writers only free a list they replaced once no
+/// reader is left.
+static TRAPS: AtomicPtr<Vec<CodeTraps>> = AtomicPtr::new(ptr::null_mut());
+static READERS: AtomicUsize = AtomicUsize::new(0);
+/// Serializes the writers of `TRAPS`.
+static WRITER: Mutex<()> = Mutex::new(());
+
+/// Publish a copy of the registered trap sites changed by `f`.
+fn update_traps<F: FnOnce(&mut Vec<CodeTraps>)>(f: F) {
+    let _writer = WRITER.lock().unwrap_or_else(|err| err.into_inner());
+    let old = TRAPS.load(Ordering::SeqCst);
+    let mut traps = unsafe { old.as_ref() }.cloned().unwrap_or_default();
+    f(&mut traps);
+    traps.sort_by_key(|code| code.start);
+    TRAPS.store(Box::into_raw(Box::new(traps)), Ordering::SeqCst);
+
+    // a handler may still be looking at the old list, those that start from
+    // now on see the new one
+    while READERS.load(Ordering::SeqCst) != 0 {
+        hint::spin_loop();
+    }
+    if !old.is_null() {
+        drop(unsafe { Box::from_raw(old) });
+    }
+}
+
+/// Record the trap sites of `size` bytes of code placed at `start`.
+pub(crate) fn register_traps(start: *const u8, size: usize, sites: &[TrapSite]) {
+    if sites.is_empty() {
+        return;
+    }
+    let start = start as usize;
+    update_traps(|traps| {
+        traps.retain(|code| code.end <= start || code.start >= start + size);
+        traps.push(CodeTraps {
+            start,
+            end: start + size,
+            sites: sites.into(),
+        });
+    });
+}
+
+/// Forget the trap sites of code placed in the `size` bytes at `start`.
+pub(crate) fn unregister_traps(start: *const u8, size: usize) {
+    let start = start as usize;
+    update_traps(|traps| traps.retain(|code| code.end <= start || code.start >= start + size));
+}
+
+/// The trap site at address `pc`, if any. Safe to call from the signal
+/// handler, it neither locks nor allocates.
+fn find_trap(pc: usize) -> Option<TrapSite> {
+    READERS.fetch_add(1, Ordering::SeqCst);
+    let traps = unsafe { TRAPS.load(Ordering::SeqCst).as_ref() };
+    let site = traps.and_then(|traps| {
+        let idx = traps.partition_point(|code| code.start <= pc);
+        let code = traps[..idx].last().filter(|code| pc < code.end)?;
+        code.sites
+            .iter()
+            .find(|site| code.start + site.offset == pc)
+            .copied()
+    });
+    READERS.fetch_sub(1, Ordering::SeqCst);
+    site
+}
+
+/// Run `f`, which calls generated code, and report a trap in that code as
+/// `PeaceError::Trap`.
+///
+/// Execution does not return into `f` after a trap, so like with `longjmp`
+/// the destructors of values owned by `f` and the code it called are not
+/// run. A panic in `f` cannot unwind through the call into the generated
+/// trampoline and aborts the process. Only supported on Linux, elsewhere `f`
+/// simply runs and traps stay fatal.
+pub fn catch_traps<R, F: FnOnce() -> R>(f: F) -> Result<R> {
+    imp::catch_traps(f)
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod imp {
+    use super::*;
+    use crate::backend::assembler::Assembler;
+    use crate::backend::assemblerx64::*;
+    use crate::backend::constants_x64::*;
+    use crate::backend::copy_code;
+    use crate::backend::memory::Memory;
+    use std::cell::Cell;
+    use std::mem;
+
+    thread_local! {
+        /// Stack pointer of the innermost `catch_traps` of the thread, zero
+        /// outside of one.
+        static CATCH_SP: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// `trampoline(call, data, sp_slot)` saves the callee-saved registers,
+    /// stores its stack pointer to `sp_slot` and returns what `call(data)`
+    /// returns, zero. On a trap the signal handler resumes at `landing` with
+    /// the saved stack pointer and the trap code plus one in RAX.
+    struct Trampoline {
+        code: usize,
+        landing: usize,
+    }
+
+    type TrampolineFn = extern "C" fn(extern "C" fn(*mut u8), *mut u8, *mut usize) -> usize;
+
+    const SAVED: [Register; 6] = [RBP, RBX, R12, R13, R14, R15];
+
+    fn trampoline() -> Result<&'static Trampoline> {
+        static TRAMPOLINE: OnceLock<Result<Trampoline>> = OnceLock::new();
+        let trampoline = TRAMPOLINE.get_or_init(|| {
+            let mut asm = Assembler::new();
+            for reg in SAVED.iter() {
+                emit_pushq_reg(&mut asm, *reg);
+            }
+            // keep the stack 16 byte aligned for the call
+            emit_subq_imm_reg(&mut asm, 8, RSP);
+            emit_movq_reg_memq(&mut asm, RSP, RDX, 0);
+            emit_mov_reg_reg(&mut asm, 1, RDI, RAX);
+            emit_mov_reg_reg(&mut asm, 1, RSI, RDI);
+            emit_callq_reg(&mut asm, RAX);
+            emit_xor_reg_reg(&mut asm, 0, RAX, RAX);
+            let landing = asm.pos();
+            emit_addq_imm_reg(&mut asm, 8, RSP);
+            for reg in SAVED.iter().rev() {
+                emit_popq_reg(&mut asm, *reg);
+            }
+            emit_retq(&mut asm);
+
+            let mut memory = Memory::new();
+            let code = memory.allocate(asm.data().len(), 16)?;
+            unsafe { copy_code(&asm, code) };
+            memory.set_readable_and_executable()?;
+            // used until the process exits
+            mem::forget(memory);
+            Ok(Trampoline {
+                code: code as usize,
+                landing: code as usize + landing,
+            })
+        });
+        trampoline.as_ref().map_err(|err| err.clone())
+    }
+
+    extern "C" fn call_closure<F: FnMut()>(data: *mut u8) {
+        unsafe { (*(data as *mut F))() }
+    }
+
+    pub(super) fn catch_traps<R, F: FnOnce() -> R>(f: F) -> Result<R> {
+        let trampoline = trampoline()?;
+        install_handlers()?;
+
+        let mut f = Some(f);
+        let mut result = None;
+        let mut closure = || result = Some((f.take().unwrap())());
+
+        let call: TrampolineFn = unsafe { mem::transmute(trampoline.code) };
+        let slot = CATCH_SP.with(|sp| sp.as_ptr());
+        let outer = unsafe { *slot };
+        let code = call(
+            call_closure::<&mut dyn FnMut()>,
+            &mut (&mut closure as &mut dyn FnMut()) as *mut _ as *mut u8,
+            slot,
+        );
+        unsafe { *slot = outer };
+
+        match code {
+            0 => Ok(result.unwrap()),
+            code => Err(PeaceError::Trap(TRAP_CODES[code - 1])),
+        }
+    }
+
+    /// Handlers that were installed before ours, for SIGFPE and SIGILL.
+    static PREVIOUS: OnceLock<Result<[libc::sigaction; 2]>> = OnceLock::new();
+
+    fn install_handlers() -> Result<()> {
+        let previous = PREVIOUS.get_or_init(|| unsafe {
+            let mut previous: [libc::sigaction; 2] = mem::zeroed();
+            for (idx, signal) in [libc::SIGFPE, libc::SIGILL].iter().enumerate() {
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction = handler as Handler as usize;
+                action.sa_flags = libc::SA_SIGINFO;
+                libc::sigemptyset(&mut action.sa_mask);
+                if libc::sigaction(*signal, &action, &mut previous[idx]) != 0 {
+                    return Err(PeaceError::Unsupported(format!(
+                        "installing the trap handler failed: {}",
+                        errno::errno()
+                    )));
+                }
+            }
+            Ok(previous)
+        });
+        previous.as_ref().map(|_| ()).map_err(|err| err.clone())
+    }
+
+    fn greg(reg: Register) -> usize {
+        let idx = match reg {
+            RAX => libc::REG_RAX,
+            RCX => libc::REG_RCX,
+            RDX => libc::REG_RDX,
+            RBX => libc::REG_RBX,
+            RSP => libc::REG_RSP,
+            RBP => libc::REG_RBP,
+            RSI => libc::REG_RSI,
+            RDI => libc::REG_RDI,
+            R8 => libc::REG_R8,
+            R9 => libc::REG_R9,
+            R10 => libc::REG_R10,
+            R11 => libc::REG_R11,
+            R12 => libc::REG_R12,
+            R13 => libc::REG_R13,
+            R14 => libc::REG_R14,
+            R15 => libc::REG_R15,
+            _ => unreachable!(),
+        };
+        idx as usize
+    }
+
+    type Handler = extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void);
+
+    extern "C" fn handler(signal: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+        unsafe {
+            let gregs = &mut (*(ctx as *mut libc::ucontext_t)).uc_mcontext.gregs;
+            let pc = gregs[libc::REG_RIP as usize] as usize;
+            let sp = CATCH_SP.try_with(|sp| sp.get()).unwrap_or(0);
+
+            if let (true, Some(site)) = (sp != 0, find_trap(pc)) {
+                let code = match site.divisor {
+                    // a faulting division by anything but zero overflowed,
+                    // the low half of the divisor is enough to tell
+                    Some(reg) if gregs[greg(reg)] as u32 != 0 => TrapCode::IntegerOverflow,
+                    _ => site.code,
+                };
+                let idx = TRAP_CODES.iter().position(|c| *c == code).unwrap();
+                gregs[libc::REG_RSP as usize] = sp as i64;
+                gregs[libc::REG_RIP as usize] = trampoline().unwrap().landing as i64;
+                gregs[libc::REG_RAX as usize] = idx as i64 + 1;
+                return;
+            }
+
+            // not ours, handle the signal like before
+            let previous = match PREVIOUS.get() {
+                Some(Ok(previous)) => previous,
+                _ => return,
+            };
+            let previous = &previous[if signal == libc::SIGFPE { 0 } else { 1 }];
+            match previous.sa_sigaction {
+                libc::SIG_DFL | libc::SIG_IGN => {
+                    // the instruction faults again when the handler returns
+                    libc::sigaction(signal, previous, ptr::null_mut());
+                }
+                action if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+                    let action: extern "C" fn(
+                        libc::c_int,
+                        *mut libc::siginfo_t,
+                        *mut libc::c_void,
+                    ) = mem::transmute(action);
+                    action(signal, info, ctx);
+                }
+                action => {
+                    let action: extern "C" fn(libc::c_int) = mem::transmute(action);
+                    action(signal);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+mod imp {
+    use super::*;
+
+    pub(super) fn catch_traps<R, F: FnOnce() -> R>(f: F) -> Result<R> {
+        Ok(f())
+    }
+}
@@ -0,0 +1,148 @@
+//! Calling generated code with a Rust signature checked against the one the
+//! function was declared with.
+
+use crate::module::CodeGuard;
+use crate::types::{Signature, Type};
+use std::marker::PhantomData;
+use std::mem;
+
+/// A Rust type passed to and returned from generated code as `TYPE`.
+///
+/// # Safety
+///
+/// Values of the type have to be passed like values of `TYPE` by the host
+/// calling convention.
+pub unsafe trait JitType {
+    const TYPE: Type;
+}
+
+/// A Rust type returned from generated code, `()` for `Type::Void`.
+///
+/// # Safety
+///
+/// See `JitType`.
+pub unsafe trait JitReturn {
+    const TYPE: Type;
+}
+
+macro_rules! jit_type {
+    ($ty:ty, $jit:ident) => {
+        unsafe impl JitType for $ty {
+            const TYPE: Type = Type::$jit;
+        }
+    };
+}
+
+jit_type!(i8, I8);
+jit_type!(u8, I8);
+jit_type!(i32, I32);
+jit_type!(u32, I32);
+jit_type!(i64, I64);
+jit_type!(u64, I64);
+jit_type!(isize, I64);
+jit_type!(usize, I64);
+jit_type!(f32, F32);
+jit_type!(f64, F64);
+
+unsafe impl<T> JitType for *const T {
+    const TYPE: Type = Type::Pointer;
+}
+
+unsafe impl<T> JitType for *mut T {
+    const TYPE: Type = Type::Pointer;
+}
+
+unsafe impl<T: JitType> JitReturn for T {
+    const TYPE: Type = T::TYPE;
+}
+
+unsafe impl JitReturn for () {
+    const TYPE: Type = Type::Void;
+}
+
+/// A Rust function type describing the signature of generated code, such as
+/// `fn(i64, f64) -> i64`. Calls always use the host calling convention, so
+/// `fn` and `extern "C" fn` types describe the same code.
+///
+/// # Safety
+///
+/// `call` has to pass `Args` and return `Ret` as described by `signature`.
+pub unsafe trait JitFunction {
+    /// The parameter types as a tuple.
+    type Args;
+    type Ret;
+
+    fn signature() -> Signature;
+
+    /// Call the code at `code` with `args`.
+    ///
+    /// # Safety
+    ///
+    /// The code has to be a function with the signature `signature()`.
+    unsafe fn call(code: *const u8, args: Self::Args) -> Self::Ret;
+}
+
+macro_rules! jit_function {
+    (@impl $fn:ty; $($param:ident $arg:ident),*) => {
+        unsafe impl<R: JitReturn, $($param: JitType),*> JitFunction for $fn {
+            type Args = ($($param,)*);
+            type Ret = R;
+
+            fn signature() -> Signature {
+                Signature::new(vec![$($param::TYPE),*], R::TYPE)
+            }
+
+            unsafe fn call(code: *const u8, ($($arg,)*): Self::Args) -> R {
+                let func: extern "C" fn($($param),*) -> R = mem::transmute(code);
+                func($($arg),*)
+            }
+        }
+    };
+    ($($param:ident $arg:ident),*) => {
+        jit_function!(@impl fn($($param),*) -> R; $($param $arg),*);
+        jit_function!(@impl extern "C" fn($($param),*) -> R; $($param $arg),*);
+    };
+}
+
+jit_function!();
+jit_function!(A a);
+jit_function!(A a, B b);
+jit_function!(A a, B b, C c);
+jit_function!(A a, B b, C c, D d);
+jit_function!(A a, B b, C c, D d, E e);
+jit_function!(A a, B b, C c, D d, E e, F f);
+jit_function!(A a, B b, C c, D d, E e, F f, G g);
+jit_function!(A a, B b, C c, D d, E e, F f, G g, H h);
+
+/// A function of a module whose signature was checked against `F`, created
+/// by `Module::get_typed`. The code stays alive as long as the handle, even
+/// when the function is removed or redefined or the module is dropped.
+pub struct TypedFunction<F> {
+    code: *const u8,
+    _guard: CodeGuard,
+    marker: PhantomData<F>,
+}
+
+// The code is never changed once it is placed.
+unsafe impl<F> Send for TypedFunction<F> {}
+unsafe impl<F> Sync for TypedFunction<F> {}
+
+impl<F: JitFunction> TypedFunction<F> {
+    pub(crate) fn new(code: *const u8, guard: CodeGuard) -> TypedFunction<F> {
+        TypedFunction {
+            code,
+            _guard: guard,
+            marker: PhantomData,
+        }
+    }
+
+    /// Call the function with the tuple of arguments `args`.
+    pub fn call(&self, args: F::Args) -> F::Ret {
+        unsafe { F::call(self.code, args) }
+    }
+
+    /// Address of the code.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.code
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum Type {
     I8,
     I32,
@@ -29,10 +29,32 @@ impl Type {
     }
 
     pub fn x64(&self) -> u8 {
-        if *self == Type::I64 || *self == Type::F64 {1} else {0}
+        if *self == Type::I64 || *self == Type::F64 || *self == Type::Pointer {
+            1
+        } else {
+            0
+        }
+    }
+    pub fn is_int(&self) -> bool {
+        matches!(self, Type::I8 | Type::I32 | Type::I64)
+    }
+
+    /// Size in bits of integer and float types.
+    pub fn bits(&self) -> u32 {
+        match self {
+            Type::I8 => 8,
+            Type::I32 | Type::F32 => 32,
+            Type::I64 | Type::F64 | Type::Pointer => 64,
+            Type::Void => 0,
+        }
     }
+
     pub fn is_float(&self) -> bool {
-        if *self == Type::F32 || *self == Type::F64 {true} else {false}
+        if *self == Type::F32 || *self == Type::F64 {
+            true
+        } else {
+            false
+        }
     }
 }
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash, PartialOrd, Ord)]
@@ -42,4 +64,68 @@ impl Value {
     pub fn new(v: u32) -> Value {
         Value(v)
     }
-}
\ No newline at end of file
+}
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash, PartialOrd, Ord)]
+pub struct Block(pub u32);
+
+impl Block {
+    pub fn new(b: u32) -> Block {
+        Block(b)
+    }
+}
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash, PartialOrd, Ord)]
+pub struct StackSlot(pub u32);
+
+impl StackSlot {
+    pub fn new(s: u32) -> StackSlot {
+        StackSlot(s)
+    }
+}
+
+/// Calling convention of a function.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CallConv {
+    SystemV,
+    WindowsFastcall,
+}
+
+impl CallConv {
+    /// Calling convention used by the host platform.
+    pub fn host() -> CallConv {
+        if cfg!(windows) {
+            CallConv::WindowsFastcall
+        } else {
+            CallConv::SystemV
+        }
+    }
+}
+
+/// Parameter and return types of a function.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Signature {
+    pub params: Vec<Type>,
+    pub ret: Type,
+    pub call_conv: CallConv,
+    /// Further arguments of any type may follow `params`.
+    pub varargs: bool,
+}
+
+impl Signature {
+    /// Create a signature using the host calling convention.
+    pub fn new(params: Vec<Type>, ret: Type) -> Signature {
+        Signature {
+            params,
+            ret,
+            call_conv: CallConv::host(),
+            varargs: false,
+        }
+    }
+
+    /// Create a signature for a variadic function such as `printf`.
+    pub fn new_varargs(params: Vec<Type>, ret: Type) -> Signature {
+        Signature {
+            varargs: true,
+            ..Signature::new(params, ret)
+        }
+    }
+}
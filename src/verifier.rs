@@ -0,0 +1,484 @@
+//! Consistency checks for `ir::Body`.
+//!
+//! The builder methods of `Function` already reject most ill-typed
+//! instructions, the verifier checks the properties that only hold for the
+//! function as a whole: values are defined once and before their uses, every
+//! block ends in a terminator and branches only go to placed blocks other
+//! than the entry block.
+
+use crate::ir::*;
+use crate::types::*;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// A single problem found by the verifier.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerifierError {
+    pub block: Option<Block>,
+    pub inst: Option<Inst>,
+    pub message: String,
+}
+
+impl fmt::Display for VerifierError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.inst, self.block) {
+            (Some(inst), Some(block)) => write!(f, "inst{} in block{}: ", inst.0, block.0)?,
+            (None, Some(block)) => write!(f, "block{}: ", block.0)?,
+            _ => (),
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+struct Verifier<'a> {
+    body: &'a Body,
+    signature: &'a Signature,
+    errors: Vec<VerifierError>,
+    /// Block and index in the block of the instruction defining each value,
+    /// `None` for block parameters.
+    defs: HashMap<Value, (Block, Option<usize>)>,
+    dominators: HashMap<Block, HashSet<Block>>,
+    /// Values an error was reported for, instructions using them are not
+    /// checked any further.
+    poisoned: HashSet<Value>,
+}
+
+/// `ty` for messages, `<none>` for values without a type.
+fn show(ty: Option<Type>) -> String {
+    ty.map_or("<none>".to_owned(), |ty| format!("{:?}", ty))
+}
+
+fn show_all(types: &[Option<Type>]) -> String {
+    let types: Vec<String> = types.iter().map(|ty| show(*ty)).collect();
+    format!("[{}]", types.join(", "))
+}
+
+/// Check `body` of a function with `signature`, returning every error found.
+pub fn verify(body: &Body, signature: &Signature) -> Result<(), Vec<VerifierError>> {
+    let mut verifier = Verifier {
+        body,
+        signature,
+        errors: vec![],
+        defs: HashMap::new(),
+        dominators: dominators(body),
+        poisoned: HashSet::new(),
+    };
+    verifier.run();
+
+    if verifier.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(verifier.errors)
+    }
+}
+
+/// Dominator sets of all blocks reachable from the entry block, the first
+/// block in the layout.
+pub fn dominators(body: &Body) -> HashMap<Block, HashSet<Block>> {
+    let mut doms: HashMap<Block, HashSet<Block>> = HashMap::new();
+    let entry = match body.layout.first() {
+        Some(entry) => *entry,
+        None => return doms,
+    };
+
+    // reachable blocks in depth first order
+    let mut reachable = vec![];
+    let mut visited = HashSet::new();
+    let mut stack = vec![entry];
+    while let Some(block) = stack.pop() {
+        if !visited.insert(block) {
+            continue;
+        }
+        reachable.push(block);
+        for succ in body.successors(block) {
+            if body.layout.contains(&succ) {
+                stack.push(succ);
+            }
+        }
+    }
+
+    let mut preds: HashMap<Block, Vec<Block>> = HashMap::new();
+    for block in reachable.iter() {
+        for succ in body.successors(*block) {
+            preds.entry(succ).or_default().push(*block);
+        }
+    }
+
+    let all: HashSet<Block> = reachable.iter().cloned().collect();
+    for block in reachable.iter() {
+        doms.insert(*block, all.clone());
+    }
+    doms.insert(entry, [entry].iter().cloned().collect());
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in reachable.iter().skip(1) {
+            let mut new = all.clone();
+            for pred in preds.get(block).map_or(&[][..], |preds| &preds[..]) {
+                new = new.intersection(&doms[pred]).cloned().collect();
+            }
+            new.insert(*block);
+            if new != doms[block] {
+                doms.insert(*block, new);
+                changed = true;
+            }
+        }
+    }
+
+    doms
+}
+
+impl<'a> Verifier<'a> {
+    fn error(&mut self, block: Option<Block>, inst: Option<Inst>, message: String) {
+        self.errors.push(VerifierError {
+            block,
+            inst,
+            message,
+        });
+    }
+
+    fn ty(&self, value: Value) -> Option<Type> {
+        self.body.value_types.get(value.0 as usize).cloned()
+    }
+
+    fn run(&mut self) {
+        self.collect_defs();
+
+        for (idx, slot) in self.body.stack_slots.iter().enumerate() {
+            if !slot.align.is_power_of_two() || slot.align > 16 {
+                self.error(
+                    None,
+                    None,
+                    format!("ss{} is aligned to {} bytes", idx, slot.align),
+                );
+            }
+        }
+
+        for block in self.body.layout.iter() {
+            let insts = &self.body.block(*block).insts;
+            match insts.last() {
+                Some(inst) if self.body.inst(*inst).is_terminator() => (),
+                _ => self.error(
+                    Some(*block),
+                    None,
+                    "block does not end in a terminator".to_owned(),
+                ),
+            }
+
+            for (idx, inst) in insts.iter().enumerate() {
+                let data = self.body.inst(*inst);
+                if data.is_terminator() && idx + 1 != insts.len() {
+                    self.error(
+                        Some(*block),
+                        Some(*inst),
+                        "terminator in the middle of the block".to_owned(),
+                    );
+                }
+                self.check_uses(*block, idx, *inst);
+                self.check_types(*block, *inst);
+            }
+        }
+    }
+
+    fn collect_defs(&mut self) {
+        let body = self.body;
+        let define = |verifier: &mut Verifier, value: Value, def: (Block, Option<usize>)| {
+            if verifier.defs.insert(value, def).is_some() {
+                verifier.error(
+                    Some(def.0),
+                    def.1.map(|idx| body.block(def.0).insts[idx]),
+                    format!("v{} is defined more than once", value.0),
+                );
+            }
+        };
+
+        for block in body.layout.iter() {
+            for param in body.block(*block).params.iter() {
+                define(self, *param, (*block, None));
+            }
+            for (idx, inst) in body.block(*block).insts.iter().enumerate() {
+                if let Some(result) = body.inst_result(*inst) {
+                    define(self, result, (*block, Some(idx)));
+                }
+            }
+        }
+    }
+
+    /// Check that every operand of `inst`, the `idx`th instruction of `block`,
+    /// is defined before it on every path.
+    fn check_uses(&mut self, block: Block, idx: usize, inst: Inst) {
+        let doms = match self.dominators.get(&block) {
+            Some(doms) => doms.clone(),
+            // uses in unreachable code never execute
+            None => return,
+        };
+
+        for arg in self.body.inst(inst).args() {
+            if self.poisoned.contains(&arg) {
+                continue;
+            }
+            let dominated = match self.defs.get(&arg) {
+                None => {
+                    self.error(
+                        Some(block),
+                        Some(inst),
+                        format!("v{} is used but never defined", arg.0),
+                    );
+                    self.poisoned.insert(arg);
+                    continue;
+                }
+                Some((def_block, _)) if *def_block != block => doms.contains(def_block),
+                Some((_, None)) => true,
+                Some((_, Some(def_idx))) => *def_idx < idx,
+            };
+
+            if !dominated {
+                self.error(
+                    Some(block),
+                    Some(inst),
+                    format!("v{} is used before its definition", arg.0),
+                );
+            }
+        }
+    }
+
+    fn check_types(&mut self, block: Block, inst: Inst) {
+        let body = self.body;
+
+        // an operand without a usable type is reported once, the
+        // instruction and the uses of its result are not checked further
+        let mut poisoned = false;
+        for arg in body.inst(inst).args() {
+            if self.poisoned.contains(&arg) {
+                poisoned = true;
+                continue;
+            }
+            let message = match self.ty(arg) {
+                None => format!("v{} has no type", arg.0),
+                Some(Type::Void) => format!("v{} of type Void used as operand", arg.0),
+                Some(_) => continue,
+            };
+            self.error(Some(block), Some(inst), message);
+            self.poisoned.insert(arg);
+            poisoned = true;
+        }
+        if poisoned {
+            self.poisoned.extend(body.inst_result(inst));
+            return;
+        }
+
+        let result = body.inst_result(inst).and_then(|value| self.ty(value));
+        let mut errors = vec![];
+        let mut expect = |what: &str, ok: bool| {
+            if !ok {
+                errors.push(what.to_owned());
+            }
+        };
+
+        match body.inst(inst) {
+            InstData::IConst { ty, .. } => {
+                expect("iconst of a float type", !ty.is_float());
+                expect("iconst result type differs", result == Some(*ty));
+            }
+            InstData::FConst { ty, .. } => {
+                expect("fconst of an integer type", ty.is_float());
+                expect("fconst result type differs", result == Some(*ty));
+            }
+            InstData::Unary { op, x } => {
+                let x = self.ty(*x);
+                expect(
+                    &format!("{:?} of {}", op, show(x)),
+                    x.is_some_and(|ty| ty.is_float() == op.is_float()),
+                );
+                expect("result type differs from operand type", result == x);
+            }
+            InstData::Binary { op, x, y } => {
+                let (x, y) = (self.ty(*x), self.ty(*y));
+                expect(
+                    &format!("{:?} of {} and {}", op, show(x), show(y)),
+                    x == y && x.is_some_and(|ty| ty.is_float() == op.is_float()),
+                );
+                expect("result type differs from operand type", result == x);
+            }
+            InstData::BinaryImm { op, x, imm } => {
+                let x = self.ty(*x);
+                expect(
+                    &format!("{:?} with immediate of {}", op, show(x)),
+                    op.has_imm_form() && x.is_some_and(|ty| !ty.is_float()),
+                );
+                // shift amounts are masked by the builder, other immediates
+                // are encoded as sign extended imm32
+                let in_range = match x {
+                    Some(ty) if op.is_shift() => *imm >= 0 && *imm < ty.bits() as i64,
+                    _ => *imm >= i32::MIN as i64 && *imm <= i32::MAX as i64,
+                };
+                expect(
+                    &format!("immediate {} of {:?} is out of range", imm, op),
+                    in_range,
+                );
+                expect("result type differs from operand type", result == x);
+            }
+            InstData::Convert { op, ty, x } => {
+                let x = self.ty(*x);
+                expect(
+                    &format!("{:?} from {} to {:?}", op, show(x), ty),
+                    x.is_some_and(|x| op.accepts(x, *ty)),
+                );
+                expect("conversion result type differs", result == Some(*ty));
+            }
+            InstData::IntCmp { x, y, .. } => {
+                let (x, y) = (self.ty(*x), self.ty(*y));
+                expect(
+                    &format!("int_cmp of {} and {}", show(x), show(y)),
+                    x == y && !x.is_none_or(|ty| ty.is_float()),
+                );
+                expect("comparison result is not I8", result == Some(Type::I8));
+            }
+            InstData::FloatCmp { x, y, .. } => {
+                let (x, y) = (self.ty(*x), self.ty(*y));
+                expect(
+                    &format!("float_cmp of {} and {}", show(x), show(y)),
+                    x == y && x.is_some_and(|ty| ty.is_float()),
+                );
+                expect("comparison result is not I8", result == Some(Type::I8));
+            }
+            InstData::Load { ty, base, .. } => {
+                let base = self.ty(*base);
+                expect(
+                    &format!("load from base of type {}", show(base)),
+                    !base.is_none_or(|ty| ty.is_float()),
+                );
+                expect("load result type differs", result == Some(*ty));
+            }
+            InstData::Store { base, .. } => {
+                let base = self.ty(*base);
+                expect(
+                    &format!("store to base of type {}", show(base)),
+                    !base.is_none_or(|ty| ty.is_float()),
+                );
+            }
+            InstData::LoadIndexed {
+                base, index, scale, ..
+            }
+            | InstData::StoreIndexed {
+                base, index, scale, ..
+            } => {
+                let (base, index) = (self.ty(*base), self.ty(*index));
+                expect(
+                    &format!("indexed access with base of type {}", show(base)),
+                    !base.is_none_or(|ty| ty.is_float()),
+                );
+                expect(
+                    &format!("indexed access with index of type {}", show(index)),
+                    index == Some(Type::I64) || index == Some(Type::Pointer),
+                );
+                expect(
+                    &format!("indexed access with scale {}", scale),
+                    matches!(scale, 1 | 2 | 4 | 8),
+                );
+                if let InstData::LoadIndexed { ty, .. } = body.inst(inst) {
+                    expect("load result type differs", result == Some(*ty));
+                }
+            }
+            InstData::StackAddr { slot } => {
+                expect(
+                    &format!("stack slot ss{} does not exist", slot.0),
+                    (slot.0 as usize) < body.stack_slots.len(),
+                );
+                expect(
+                    "stack address is not a Pointer",
+                    result == Some(Type::Pointer),
+                );
+            }
+            InstData::SymbolAddr { .. } => {
+                expect(
+                    "symbol address is not a Pointer",
+                    result == Some(Type::Pointer),
+                );
+            }
+            InstData::Call {
+                name,
+                args,
+                signature,
+            } => {
+                let types: Vec<Option<Type>> = args.iter().map(|arg| self.ty(*arg)).collect();
+                let fixed: Vec<Option<Type>> =
+                    signature.params.iter().map(|ty| Some(*ty)).collect();
+                let count_ok =
+                    types.len() == fixed.len() || (signature.varargs && types.len() > fixed.len());
+                expect(
+                    &format!(
+                        "{} takes {:?}, got {}",
+                        name,
+                        signature.params,
+                        show_all(&types)
+                    ),
+                    count_ok && types[..fixed.len().min(types.len())] == fixed[..],
+                );
+                expect("call result type differs", result == Some(signature.ret));
+            }
+            InstData::Jump { .. } | InstData::Brif { .. } => {
+                if let InstData::Brif { cond, .. } = body.inst(inst) {
+                    let cond = self.ty(*cond);
+                    expect(
+                        &format!("branch condition of type {}", show(cond)),
+                        !cond.is_none_or(|ty| ty.is_float()),
+                    );
+                }
+                for (succ, args) in body.inst(inst).successors() {
+                    if !body.layout.contains(&succ) {
+                        expect(
+                            &format!("branch to block{} which is never placed", succ.0),
+                            false,
+                        );
+                        continue;
+                    }
+                    // the entry block starts by moving the arguments into
+                    // its parameters
+                    if body.layout.first() == Some(&succ) {
+                        expect(&format!("branch to the entry block block{}", succ.0), false);
+                        continue;
+                    }
+                    let params: Vec<Option<Type>> = body
+                        .block(succ)
+                        .params
+                        .iter()
+                        .map(|p| self.ty(*p))
+                        .collect();
+                    let types: Vec<Option<Type>> = args.iter().map(|arg| self.ty(*arg)).collect();
+                    expect(
+                        &format!(
+                            "block{} takes {}, got {}",
+                            succ.0,
+                            show_all(&params),
+                            show_all(&types)
+                        ),
+                        params == types,
+                    );
+                }
+            }
+            InstData::Return { value } => {
+                let ret = self.signature.ret;
+                match value.and_then(|value| self.ty(value)) {
+                    // also produced for a last block without `ret`
+                    None => expect(
+                        &format!("missing return value, function returns {:?}", ret),
+                        ret == Type::Void,
+                    ),
+                    Some(ty) => expect(
+                        &format!("return of {:?} in function returning {:?}", ty, ret),
+                        ty == ret,
+                    ),
+                }
+            }
+        }
+
+        if !errors.is_empty() {
+            self.poisoned.extend(body.inst_result(inst));
+        }
+        for message in errors {
+            self.error(Some(block), Some(inst), message);
+        }
+    }
+}
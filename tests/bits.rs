@@ -0,0 +1,265 @@
+use peace::backend::CondCode;
+use peace::error::Result;
+use peace::function::Function;
+use peace::module::{Linkage, Module};
+use peace::types::{Signature, Type, Value};
+
+/// A module with `f(params) -> ret` computed by `build` from the parameters.
+fn compile<F>(params: &[Type], ret: Type, build: F) -> Module
+where
+    F: FnOnce(&mut Function, &[Value]) -> Result<Value>,
+{
+    let mut module = Module::new();
+    module
+        .declare_function("f", Linkage::Local, Signature::new(params.to_vec(), ret))
+        .unwrap();
+    let b = module.get_function("f").unwrap();
+    let params: Vec<Value> = (0..params.len()).map(|idx| b.param(idx).unwrap()).collect();
+    let r = build(b, &params).unwrap();
+    b.ret(r).unwrap();
+    b.finalize().unwrap();
+    module.finish().unwrap();
+    module
+}
+
+#[test]
+fn comparison_results_are_combined() {
+    // 0 <= x < 10 && x != 5 || x == 100
+    let module = compile(&[Type::I64], Type::I8, |b, params| {
+        let x = params[0];
+        let zero = b.iconst(Type::I64, 0)?;
+        let ten = b.iconst(Type::I64, 10)?;
+        let five = b.iconst(Type::I64, 5)?;
+        let hundred = b.iconst(Type::I64, 100)?;
+        let lo = b.int_cmp(x, zero, CondCode::GreaterEq)?;
+        let hi = b.int_cmp(x, ten, CondCode::Less)?;
+        let in_range = b.band(lo, hi)?;
+        let is_five = b.int_cmp(x, five, CondCode::Equal)?;
+        let not_five = b.bnot(is_five)?;
+        let not_five = b.band_imm(not_five, 1)?;
+        let r = b.band(in_range, not_five)?;
+        let is_hundred = b.int_cmp(x, hundred, CondCode::Equal)?;
+        b.bor(r, is_hundred)
+    });
+    let f = module.get_typed::<fn(i64) -> u8>("f").unwrap();
+    for x in -3..120 {
+        let expected = ((0..10).contains(&x) && x != 5) || x == 100;
+        assert_eq!(f.call((x,)), expected as u8, "{}", x);
+    }
+
+    let module = compile(&[Type::I64, Type::I64], Type::I8, |b, params| {
+        let x = b.ireduce(Type::I8, params[0])?;
+        let y = b.ireduce(Type::I8, params[1])?;
+        let r = b.bxor(x, y)?;
+        let r = b.bxor_imm(r, -1)?;
+        let zero = b.iconst(Type::I8, 0)?;
+        b.int_cmp(r, zero, CondCode::Equal)
+    });
+    let f = module.get_typed::<fn(i64, i64) -> u8>("f").unwrap();
+    assert_eq!(f.call((0x1f0, 0x20f)), 1);
+    assert_eq!(f.call((0x1f0, 0x10e)), 0);
+}
+
+type Op = fn(&mut Function, Value, Value) -> Result<Value>;
+
+const SHIFTS: [(&str, Op); 5] = [
+    ("ishl", Function::ishl),
+    ("ushr", Function::ushr),
+    ("sshr", Function::sshr),
+    ("rotl", Function::rotl),
+    ("rotr", Function::rotr),
+];
+
+/// `op` of Rust on 64 bit integers, the amount taken modulo 64.
+fn expected64(name: &str, x: i64, amount: i64) -> i64 {
+    let amount = amount as u32;
+    match name {
+        "ishl" => x.wrapping_shl(amount),
+        "ushr" => (x as u64).wrapping_shr(amount) as i64,
+        "sshr" => x.wrapping_shr(amount),
+        "rotl" => x.rotate_left(amount % 64),
+        _ => x.rotate_right(amount % 64),
+    }
+}
+
+fn expected32(name: &str, x: i32, amount: i32) -> i32 {
+    let amount = amount as u32;
+    match name {
+        "ishl" => x.wrapping_shl(amount),
+        "ushr" => (x as u32).wrapping_shr(amount) as i32,
+        "sshr" => x.wrapping_shr(amount),
+        "rotl" => x.rotate_left(amount % 32),
+        _ => x.rotate_right(amount % 32),
+    }
+}
+
+const X64: i64 = -0x0123_4567_89ab_cdef;
+const X32: i32 = -0x0123_4567;
+const AMOUNTS: [i64; 8] = [0, 1, 7, 31, 32, 33, 63, 64 + 5];
+
+#[test]
+fn variable_shifts() {
+    for (name, op) in SHIFTS.iter() {
+        let module = compile(&[Type::I64, Type::I64], Type::I64, |b, params| {
+            op(b, params[0], params[1])
+        });
+        let f = module.get_typed::<fn(i64, i64) -> i64>("f").unwrap();
+        for amount in AMOUNTS.iter() {
+            assert_eq!(
+                f.call((X64, *amount)),
+                expected64(name, X64, *amount),
+                "{} {}",
+                name,
+                amount
+            );
+        }
+
+        let module = compile(&[Type::I32, Type::I32], Type::I32, |b, params| {
+            op(b, params[0], params[1])
+        });
+        let f = module.get_typed::<fn(i32, i32) -> i32>("f").unwrap();
+        for amount in AMOUNTS.iter() {
+            let amount = *amount as i32;
+            assert_eq!(
+                f.call((X32, amount)),
+                expected32(name, X32, amount),
+                "{} {}",
+                name,
+                amount
+            );
+        }
+    }
+}
+
+#[test]
+fn shift_amount_in_rcx() {
+    // the fourth argument is passed in RCX, the value shifted in RDI
+    let int = Type::I64;
+    for (name, op) in SHIFTS.iter() {
+        let module = compile(&[int, int, int, int], int, |b, params| {
+            let r = op(b, params[0], params[3])?;
+            // RCX is used again after the shift
+            let r = b.iadd(r, params[3])?;
+            let r = b.iadd(r, params[1])?;
+            b.iadd(r, params[2])
+        });
+        let f = module
+            .get_typed::<fn(i64, i64, i64, i64) -> i64>("f")
+            .unwrap();
+        for amount in AMOUNTS.iter() {
+            let expected = expected64(name, X64, *amount)
+                .wrapping_add(*amount)
+                .wrapping_add(1000)
+                .wrapping_add(2000);
+            assert_eq!(
+                f.call((X64, 1000, 2000, *amount)),
+                expected,
+                "{} {}",
+                name,
+                amount
+            );
+        }
+    }
+}
+
+#[test]
+fn spilled_shift_amounts() {
+    // more amounts than allocatable registers are live at once
+    let int = Type::I64;
+    for (name, op) in SHIFTS.iter() {
+        let module = compile(&[int, int], int, |b, params| {
+            let mut amounts = vec![];
+            for idx in 0..10 {
+                let offset = b.iconst(int, idx)?;
+                amounts.push(b.iadd(params[1], offset)?);
+            }
+            let mut sum = b.iconst(int, 0)?;
+            for amount in amounts.iter().rev() {
+                let shifted = op(b, params[0], *amount)?;
+                sum = b.iadd(sum, shifted)?;
+            }
+            Ok(sum)
+        });
+        let f = module.get_typed::<fn(i64, i64) -> i64>("f").unwrap();
+        for start in [0, 30, 60].iter() {
+            let expected = (0..10).fold(0i64, |sum, idx| {
+                sum.wrapping_add(expected64(name, X64, start + idx))
+            });
+            assert_eq!(f.call((X64, *start)), expected, "{} {}", name, start);
+        }
+    }
+}
+
+type ImmOp = fn(&mut Function, Value, i64) -> Result<Value>;
+
+const IMM_SHIFTS: [(&str, ImmOp); 5] = [
+    ("ishl", Function::ishl_imm),
+    ("ushr", Function::ushr_imm),
+    ("sshr", Function::sshr_imm),
+    ("rotl", Function::rotl_imm),
+    ("rotr", Function::rotr_imm),
+];
+
+#[test]
+fn immediate_shifts() {
+    for (name, op) in IMM_SHIFTS.iter() {
+        for amount in AMOUNTS.iter() {
+            let module = compile(&[Type::I64], Type::I64, |b, params| {
+                op(b, params[0], *amount)
+            });
+            let f = module.get_typed::<fn(i64) -> i64>("f").unwrap();
+            assert_eq!(
+                f.call((X64,)),
+                expected64(name, X64, *amount),
+                "{} {}",
+                name,
+                amount
+            );
+
+            let module = compile(&[Type::I32], Type::I32, |b, params| {
+                op(b, params[0], *amount)
+            });
+            let f = module.get_typed::<fn(i32) -> i32>("f").unwrap();
+            assert_eq!(
+                f.call((X32,)),
+                expected32(name, X32, *amount as i32),
+                "{} {}",
+                name,
+                amount
+            );
+        }
+    }
+}
+
+#[test]
+fn bit_counts() {
+    type Unary = fn(&mut Function, Value) -> Result<Value>;
+    let ops: [(&str, Unary); 3] = [
+        ("popcnt", Function::popcnt),
+        ("clz", Function::clz),
+        ("ctz", Function::ctz),
+    ];
+    for (name, op) in ops.iter() {
+        let module = compile(&[Type::I64], Type::I64, |b, params| op(b, params[0]));
+        let f = module.get_typed::<fn(i64) -> i64>("f").unwrap();
+        for x in [0, 1, -1, 0x10, i64::MIN, X64].iter() {
+            let expected = match *name {
+                "popcnt" => x.count_ones(),
+                "clz" => x.leading_zeros(),
+                _ => x.trailing_zeros(),
+            };
+            assert_eq!(f.call((*x,)), expected as i64, "{} {}", name, x);
+        }
+
+        let module = compile(&[Type::I32], Type::I32, |b, params| op(b, params[0]));
+        let f = module.get_typed::<fn(i32) -> i32>("f").unwrap();
+        for x in [0, 1, -1, 0x10, i32::MIN, X32].iter() {
+            let expected = match *name {
+                "popcnt" => x.count_ones(),
+                "clz" => x.leading_zeros(),
+                _ => x.trailing_zeros(),
+            };
+            assert_eq!(f.call((*x,)), expected as i32, "{} {}", name, x);
+        }
+    }
+}
@@ -0,0 +1,82 @@
+#![cfg(all(target_arch = "x86_64", not(windows)))]
+
+use peace::module::{Linkage, Module};
+use peace::types::{Signature, Type};
+use std::arch::asm;
+
+/// `f(x) = x*1 + x*2 + x*3 + x*4 + x*5 + x*6` with all products live at
+/// once, so the allocator uses every callee-saved register it has.
+fn build(module: &mut Module) {
+    let int = Type::I64;
+    module
+        .declare_function("f", Linkage::Export, Signature::new(vec![int], int))
+        .unwrap();
+    let b = module.get_function("f").unwrap();
+    let x = b.param(0).unwrap();
+    let mut products = Vec::new();
+    for k in 1..=6 {
+        let c = b.iconst(int, k).unwrap();
+        products.push(b.imul(x, c).unwrap());
+    }
+    let mut sum = products[0];
+    for p in &products[1..] {
+        sum = b.iadd(sum, *p).unwrap();
+    }
+    b.ret(sum).unwrap();
+    b.finalize().unwrap();
+}
+
+/// Call `f(x)` with known values in RBX and R12-R15 and return the result
+/// together with the values found in those registers afterwards.
+unsafe fn call_with_live_registers(f: *const u8, x: i64, regs: &[u64; 5]) -> (i64, [u64; 5]) {
+    let mut after = *regs;
+    let result: i64;
+    asm!(
+        "push {after}",
+        "push rbx",
+        "push rbp",
+        "mov rbp, rsp",
+        "and rsp, -16",
+        "mov rbx, [{after}]",
+        "mov r12, [{after} + 8]",
+        "mov r13, [{after} + 16]",
+        "mov r14, [{after} + 24]",
+        "mov r15, [{after} + 32]",
+        "call {f}",
+        "mov rsp, rbp",
+        "pop rbp",
+        "mov rcx, [rsp + 8]",
+        "mov [rcx], rbx",
+        "mov [rcx + 8], r12",
+        "mov [rcx + 16], r13",
+        "mov [rcx + 24], r14",
+        "mov [rcx + 32], r15",
+        "pop rbx",
+        "add rsp, 8",
+        f = in(reg) f,
+        after = in(reg) after.as_mut_ptr(),
+        in("rdi") x,
+        lateout("rax") result,
+        out("r12") _,
+        out("r13") _,
+        out("r14") _,
+        out("r15") _,
+        clobber_abi("C"),
+    );
+    (result, after)
+}
+
+#[test]
+fn callee_saved_registers_survive_calls() {
+    let mut module = Module::new();
+    build(&mut module);
+    module.finish().unwrap();
+    let f = module.get_typed::<fn(i64) -> i64>("f").unwrap();
+
+    for i in 0..1000u64 {
+        let regs = [i, !i, i << 8, i.wrapping_mul(31), 0xdead_beef ^ i];
+        let (result, after) = unsafe { call_with_live_registers(f.as_ptr(), i as i64, &regs) };
+        assert_eq!(result, 21 * i as i64);
+        assert_eq!(after, regs);
+    }
+}
@@ -0,0 +1,312 @@
+#![cfg(all(target_arch = "x86_64", not(windows)))]
+
+use peace::function::OptLevel;
+use peace::module::{register_symbol, Linkage, Module};
+use peace::types::{Signature, Type, Value};
+use std::hint::black_box;
+
+#[repr(align(16))]
+struct Aligned([u8; 16]);
+
+/// Misalignment of the stack the caller left, the compiler places `local`
+/// assuming RSP was 16 byte aligned at the call.
+#[inline(never)]
+fn misalignment() -> i64 {
+    let local = Aligned([0; 16]);
+    (black_box(&local) as *const Aligned as usize % 16) as i64
+}
+
+extern "C" fn misalignment6(_: i64, _: i64, _: i64, _: i64, _: i64, _: i64) -> i64 {
+    misalignment()
+}
+
+extern "C" fn misalignment7(_: i64, _: i64, _: i64, _: i64, _: i64, _: i64, _: i64) -> i64 {
+    misalignment()
+}
+
+extern "C" fn misalignment8(_: i64, _: i64, _: i64, _: i64, _: i64, _: i64, _: i64, _: i64) -> i64 {
+    misalignment()
+}
+
+/// `f(x)` keeping `live` integers and floats derived from `x` live across a
+/// call to the misalignment check taking `args` arguments, then adding them
+/// to its result.
+fn spill_and_call(live: i64, args: usize, opt_level: OptLevel) -> Module {
+    let (int, float) = (Type::I64, Type::F64);
+    let callee = format!("peace_test_misalignment{}", args);
+    let address = match args {
+        6 => misalignment6 as *const u8,
+        7 => misalignment7 as *const u8,
+        _ => misalignment8 as *const u8,
+    };
+    register_symbol(&callee, address);
+
+    let mut module = Module::new();
+    module
+        .declare_function(
+            &callee,
+            Linkage::Import,
+            Signature::new(vec![int; args], int),
+        )
+        .unwrap();
+    module
+        .declare_function("f", Linkage::Local, Signature::new(vec![int], int))
+        .unwrap();
+    let b = module.get_function("f").unwrap();
+    b.set_opt_level(opt_level);
+    let x = b.param(0).unwrap();
+
+    let mut ints = vec![];
+    let mut floats = vec![];
+    for k in 0..live {
+        let c = b.iconst(int, k).unwrap();
+        ints.push(b.iadd(x, c).unwrap());
+        let c = b.fcvt_from_sint(float, c).unwrap();
+        floats.push(b.fadd(c, c).unwrap());
+    }
+    let call_args: Vec<Value> = vec![x; args];
+    let mut r = b.call(&callee, &call_args, int).unwrap();
+    for (i, f) in ints.iter().zip(floats.iter()) {
+        r = b.iadd(r, *i).unwrap();
+        let f = b.fcvt_to_sint(int, *f).unwrap();
+        r = b.iadd(r, f).unwrap();
+    }
+    b.ret(r).unwrap();
+    b.finalize().unwrap();
+    module.finish().unwrap();
+    module
+}
+
+#[test]
+fn stack_is_aligned_at_calls() {
+    for opt_level in [OptLevel::None, OptLevel::Speed].iter() {
+        for args in 6..=8 {
+            for live in 0..12 {
+                let module = spill_and_call(live, args, *opt_level);
+                let f = module.get_typed::<fn(i64) -> i64>("f").unwrap();
+                // x * live + 3 * (0 + 1 + ... + live - 1) when aligned
+                let expected = 100 * live + 3 * live * (live - 1) / 2;
+                assert_eq!(
+                    f.call((100,)),
+                    expected,
+                    "{:?}, {} arguments, {} live values",
+                    opt_level,
+                    args,
+                    live
+                );
+            }
+        }
+    }
+}
+
+/// Weighted sum of the arguments, so every argument has to arrive in its
+/// own place.
+#[allow(clippy::too_many_arguments)]
+extern "C" fn mixed(
+    i0: i64,
+    f0: f64,
+    i1: i32,
+    f1: f32,
+    i2: i64,
+    f2: f64,
+    i3: i32,
+    f3: f64,
+    i4: i64,
+    f4: f32,
+    i5: i64,
+    f5: f64,
+    i6: i32,
+    f6: f64,
+    i7: i64,
+    f7: f64,
+    f8: f32,
+    f9: f64,
+) -> f64 {
+    let ints = [i0, i1 as i64, i2, i3 as i64, i4, i5, i6 as i64, i7];
+    let floats = [f0, f1 as f64, f2, f3, f4 as f64, f5, f6, f7, f8 as f64, f9];
+    let ints: f64 = ints
+        .iter()
+        .enumerate()
+        .map(|(idx, i)| (*i * (idx as i64 + 1)) as f64)
+        .sum();
+    let floats: f64 = floats
+        .iter()
+        .enumerate()
+        .map(|(idx, f)| f * (idx as f64 + 100.0))
+        .sum();
+    ints + floats
+}
+
+/// Types of the arguments of `mixed`.
+const MIXED: [Type; 18] = [
+    Type::I64,
+    Type::F64,
+    Type::I32,
+    Type::F32,
+    Type::I64,
+    Type::F64,
+    Type::I32,
+    Type::F64,
+    Type::I64,
+    Type::F32,
+    Type::I64,
+    Type::F64,
+    Type::I32,
+    Type::F64,
+    Type::I64,
+    Type::F64,
+    Type::F32,
+    Type::F64,
+];
+
+#[test]
+fn mixed_arguments_on_registers_and_stack() {
+    let (int, float) = (Type::I64, Type::F64);
+    register_symbol("peace_test_mixed", mixed as *const u8);
+
+    for opt_level in [OptLevel::None, OptLevel::Speed].iter() {
+        let mut module = Module::new();
+        module
+            .declare_function(
+                "peace_test_mixed",
+                Linkage::Import,
+                Signature::new(MIXED.to_vec(), float),
+            )
+            .unwrap();
+        module
+            .declare_function("f", Linkage::Local, Signature::new(vec![int, float], float))
+            .unwrap();
+        let b = module.get_function("f").unwrap();
+        b.set_opt_level(*opt_level);
+        let (x, y) = (b.param(0).unwrap(), b.param(1).unwrap());
+
+        // the k-th integer is x + k, the k-th float y * (k + 1)
+        let (mut ints, mut floats) = (0, 0);
+        let mut args = vec![];
+        for ty in MIXED.iter() {
+            let arg = if ty.is_float() {
+                floats += 1;
+                let c = b.fconst(float, floats as f64).unwrap();
+                let arg = b.fmul(y, c).unwrap();
+                match ty {
+                    Type::F32 => b.fdemote(Type::F32, arg).unwrap(),
+                    _ => arg,
+                }
+            } else {
+                let c = b.iconst(int, ints).unwrap();
+                ints += 1;
+                let arg = b.iadd(x, c).unwrap();
+                match ty {
+                    Type::I32 => b.ireduce(Type::I32, arg).unwrap(),
+                    _ => arg,
+                }
+            };
+            args.push(arg);
+        }
+        let r = b.call("peace_test_mixed", &args, float).unwrap();
+
+        // the float arguments are still live after the call
+        let mut r = r;
+        for (arg, ty) in args.iter().zip(MIXED.iter()) {
+            let arg = match ty {
+                Type::F32 => b.fpromote(float, *arg).unwrap(),
+                Type::F64 => *arg,
+                _ => continue,
+            };
+            r = b.fadd(r, arg).unwrap();
+        }
+        b.ret(r).unwrap();
+        b.finalize().unwrap();
+        module.finish().unwrap();
+
+        let f = module.get_typed::<fn(i64, f64) -> f64>("f").unwrap();
+        let (x, y) = (-7i64, 1.25f64);
+        let expected = mixed(
+            x,
+            y,
+            x as i32 + 1,
+            (y * 2.0) as f32,
+            x + 2,
+            y * 3.0,
+            x as i32 + 3,
+            y * 4.0,
+            x + 4,
+            (y * 5.0) as f32,
+            x + 5,
+            y * 6.0,
+            x as i32 + 6,
+            y * 7.0,
+            x + 7,
+            y * 8.0,
+            (y * 9.0) as f32,
+            y * 10.0,
+        ) + (1..=10).map(|k| y * k as f64).sum::<f64>();
+        assert_eq!(f.call((x, y)), expected, "{:?}", opt_level);
+    }
+}
+
+#[test]
+fn variadic_call_with_spilled_doubles() {
+    let (int, float, ptr) = (Type::I64, Type::F64, Type::Pointer);
+    let snprintf = Signature::new_varargs(vec![ptr, int, ptr], Type::I32);
+    let text_format = "%g %g %g %g %g %g %g %g %g %g %d\0";
+
+    for opt_level in [OptLevel::None, OptLevel::Speed].iter() {
+        let mut module = Module::new();
+        module
+            .declare_function("snprintf", Linkage::Import, snprintf.clone())
+            .unwrap();
+        module
+            .declare_function(
+                "f",
+                Linkage::Local,
+                Signature::new(vec![ptr, int, ptr, float], float),
+            )
+            .unwrap();
+        let b = module.get_function("f").unwrap();
+        b.set_opt_level(*opt_level);
+        let (buf, size, format, x) = (
+            b.param(0).unwrap(),
+            b.param(1).unwrap(),
+            b.param(2).unwrap(),
+            b.param(3).unwrap(),
+        );
+
+        // ten doubles, more than fit into registers, all live at once
+        let mut args = vec![buf, size, format];
+        let mut doubles = vec![];
+        for k in 0..10 {
+            let c = b.fconst(float, k as f64 + 0.5).unwrap();
+            doubles.push(b.fadd(x, c).unwrap());
+        }
+        args.extend(doubles.iter().cloned());
+        let n = b.iconst(Type::I32, 42).unwrap();
+        args.push(n);
+        let written = b.call_with_signature("snprintf", &snprintf, &args).unwrap();
+
+        // result: written + sum of the doubles, which survive the call
+        let mut r = b.fcvt_from_sint(float, written).unwrap();
+        for d in doubles.iter() {
+            r = b.fadd(r, *d).unwrap();
+        }
+        b.ret(r).unwrap();
+        b.finalize().unwrap();
+        module.finish().unwrap();
+
+        let f = module
+            .get_typed::<fn(*mut u8, i64, *const u8, f64) -> f64>("f")
+            .unwrap();
+        let mut buf = [0u8; 256];
+        let r = f.call((
+            buf.as_mut_ptr(),
+            buf.len() as i64,
+            text_format.as_ptr(),
+            1.0,
+        ));
+        let text = std::ffi::CStr::from_bytes_until_nul(&buf).unwrap();
+        let expected = "1.5 2.5 3.5 4.5 5.5 6.5 7.5 8.5 9.5 10.5 42";
+        assert_eq!(text.to_str().unwrap(), expected, "{:?}", opt_level);
+        let sum: f64 = (0..10).map(|k| 1.5 + k as f64).sum();
+        assert_eq!(r, expected.len() as f64 + sum, "{:?}", opt_level);
+    }
+}
@@ -0,0 +1,287 @@
+use peace::backend::CondCode;
+use peace::error::{PeaceError, Result};
+use peace::function::Function;
+use peace::module::{Linkage, Module};
+use peace::trap::{catch_traps, TrapCode};
+use peace::types::{Signature, Type, Value};
+
+/// A module with `f(params) -> ret` computed by `build` from the parameters.
+fn compile<F>(params: &[Type], ret: Type, build: F) -> Module
+where
+    F: FnOnce(&mut Function, &[Value]) -> Result<Value>,
+{
+    let mut module = Module::new();
+    module
+        .declare_function("f", Linkage::Local, Signature::new(params.to_vec(), ret))
+        .unwrap();
+    let b = module.get_function("f").unwrap();
+    let params: Vec<Value> = (0..params.len()).map(|idx| b.param(idx).unwrap()).collect();
+    let r = build(b, &params).unwrap();
+    b.ret(r).unwrap();
+    b.finalize().unwrap();
+    module.finish().unwrap();
+    module
+}
+
+#[test]
+fn reduced_bytes_compare_their_low_bits() {
+    let module = compile(&[], Type::I8, |b, _| {
+        let x = b.iconst(Type::I64, 0x101)?;
+        let x = b.ireduce(Type::I8, x)?;
+        let one = b.iconst(Type::I8, 1)?;
+        b.int_cmp(x, one, CondCode::Equal)
+    });
+    let f = module.get_typed::<fn() -> u8>("f").unwrap();
+    assert_eq!(f.call(()), 1);
+
+    let module = compile(&[Type::I64, Type::I64], Type::I8, |b, params| {
+        let x = b.ireduce(Type::I8, params[0])?;
+        let y = b.ireduce(Type::I8, params[1])?;
+        b.int_cmp(x, y, CondCode::Less)
+    });
+    let less = module.get_typed::<fn(i64, i64) -> u8>("f").unwrap();
+    assert_eq!(less.call((0x1ff, 0x100)), 1);
+    assert_eq!(less.call((0x100, 0x2ff)), 0);
+    assert_eq!(less.call((0x17f, 0x280)), 0);
+}
+
+#[test]
+fn reduced_values_are_returned() {
+    let module = compile(&[Type::I64], Type::I8, |b, params| {
+        b.ireduce(Type::I8, params[0])
+    });
+    let f = module.get_typed::<fn(i64) -> u8>("f").unwrap();
+    assert_eq!(f.call((0x1ff,)), 0xff);
+
+    let module = compile(&[Type::I64], Type::I32, |b, params| {
+        b.ireduce(Type::I32, params[0])
+    });
+    let f = module.get_typed::<fn(i64) -> i32>("f").unwrap();
+    assert_eq!(f.call((0x1_8000_0002,)), i32::MIN + 2);
+
+    let module = compile(&[Type::I64], Type::I64, |b, params| {
+        let x = b.ireduce(Type::I8, params[0])?;
+        b.uextend(Type::I64, x)
+    });
+    let f = module.get_typed::<fn(i64) -> i64>("f").unwrap();
+    assert_eq!(f.call((0x1ff,)), 0xff);
+
+    let module = compile(&[Type::I64], Type::I64, |b, params| {
+        let x = b.ireduce(Type::I8, params[0])?;
+        b.sextend(Type::I64, x)
+    });
+    let f = module.get_typed::<fn(i64) -> i64>("f").unwrap();
+    assert_eq!(f.call((0x1ff,)), -1);
+    assert_eq!(f.call((0x17f,)), 127);
+}
+
+fn trap<T>(code: TrapCode) -> std::result::Result<T, PeaceError> {
+    Err(PeaceError::Trap(code))
+}
+
+#[test]
+fn trapping_conversions() {
+    let module = compile(&[Type::F64], Type::I64, |b, params| {
+        b.fcvt_to_sint(Type::I64, params[0])
+    });
+    let f = module.get_typed::<fn(f64) -> i64>("f").unwrap();
+    assert_eq!(catch_traps(|| f.call((-2.9,))), Ok(-2));
+    assert_eq!(
+        catch_traps(|| f.call((-9.223372036854775808e18,))),
+        Ok(i64::MIN)
+    );
+    assert_eq!(
+        catch_traps(|| f.call((9.223372036854775808e18,))),
+        trap(TrapCode::IntegerOverflow)
+    );
+    assert_eq!(
+        catch_traps(|| f.call((f64::NEG_INFINITY,))),
+        trap(TrapCode::IntegerOverflow)
+    );
+    assert_eq!(
+        catch_traps(|| f.call((f64::NAN,))),
+        trap(TrapCode::BadConversionToInteger)
+    );
+
+    let module = compile(&[Type::F32], Type::I32, |b, params| {
+        b.fcvt_to_sint(Type::I32, params[0])
+    });
+    let f = module.get_typed::<fn(f32) -> i32>("f").unwrap();
+    assert_eq!(catch_traps(|| f.call((-2147483648.0,))), Ok(i32::MIN));
+    assert_eq!(
+        catch_traps(|| f.call((2147483648.0,))),
+        trap(TrapCode::IntegerOverflow)
+    );
+    assert_eq!(
+        catch_traps(|| f.call((f32::NAN,))),
+        trap(TrapCode::BadConversionToInteger)
+    );
+
+    let module = compile(&[Type::F64], Type::I32, |b, params| {
+        b.fcvt_to_sint(Type::I32, params[0])
+    });
+    let f = module.get_typed::<fn(f64) -> i32>("f").unwrap();
+    assert_eq!(catch_traps(|| f.call((2147483647.9,))), Ok(i32::MAX));
+    assert_eq!(catch_traps(|| f.call((-2147483648.9,))), Ok(i32::MIN));
+    assert_eq!(
+        catch_traps(|| f.call((-2147483649.0,))),
+        trap(TrapCode::IntegerOverflow)
+    );
+
+    let module = compile(&[Type::F64], Type::I64, |b, params| {
+        b.fcvt_to_uint(Type::I64, params[0])
+    });
+    let f = module.get_typed::<fn(f64) -> u64>("f").unwrap();
+    assert_eq!(catch_traps(|| f.call((-0.9,))), Ok(0));
+    assert_eq!(
+        catch_traps(|| f.call((1.8e19,))),
+        Ok(18_000_000_000_000_000_000)
+    );
+    assert_eq!(
+        catch_traps(|| f.call((-1.0,))),
+        trap(TrapCode::IntegerOverflow)
+    );
+    assert_eq!(
+        catch_traps(|| f.call((1.8446744073709552e19,))),
+        trap(TrapCode::IntegerOverflow)
+    );
+    assert_eq!(
+        catch_traps(|| f.call((f64::NAN,))),
+        trap(TrapCode::BadConversionToInteger)
+    );
+
+    let module = compile(&[Type::F64], Type::I32, |b, params| {
+        b.fcvt_to_uint(Type::I32, params[0])
+    });
+    let f = module.get_typed::<fn(f64) -> u32>("f").unwrap();
+    assert_eq!(catch_traps(|| f.call((4294967295.5,))), Ok(u32::MAX));
+    assert_eq!(
+        catch_traps(|| f.call((4294967296.0,))),
+        trap(TrapCode::IntegerOverflow)
+    );
+}
+
+#[test]
+fn saturating_conversions() {
+    let inputs = [
+        f64::NAN,
+        f64::INFINITY,
+        f64::NEG_INFINITY,
+        9.223372036854775808e18,
+        -9.223372036854775808e18,
+        -1e300,
+        1.8446744073709552e19,
+        -0.5,
+        42.9,
+        4294967296.0,
+    ];
+
+    let module = compile(&[Type::F64], Type::I64, |b, params| {
+        b.fcvt_to_sint_sat(Type::I64, params[0])
+    });
+    let f = module.get_typed::<fn(f64) -> i64>("f").unwrap();
+    for x in inputs.iter() {
+        assert_eq!(f.call((*x,)), *x as i64, "{}", x);
+    }
+
+    let module = compile(&[Type::F64], Type::I64, |b, params| {
+        b.fcvt_to_uint_sat(Type::I64, params[0])
+    });
+    let f = module.get_typed::<fn(f64) -> u64>("f").unwrap();
+    for x in inputs.iter() {
+        assert_eq!(f.call((*x,)), *x as u64, "{}", x);
+    }
+
+    let module = compile(&[Type::F64], Type::I32, |b, params| {
+        b.fcvt_to_sint_sat(Type::I32, params[0])
+    });
+    let f = module.get_typed::<fn(f64) -> i32>("f").unwrap();
+    for x in inputs.iter() {
+        assert_eq!(f.call((*x,)), *x as i32, "{}", x);
+    }
+
+    let module = compile(&[Type::F32], Type::I32, |b, params| {
+        b.fcvt_to_uint_sat(Type::I32, params[0])
+    });
+    let f = module.get_typed::<fn(f32) -> u32>("f").unwrap();
+    for x in inputs.iter() {
+        let x = *x as f32;
+        assert_eq!(f.call((x,)), x as u32, "{}", x);
+    }
+}
+
+#[test]
+fn unsigned_to_float() {
+    let inputs = [
+        0,
+        1,
+        i64::MAX as u64,
+        1 << 63,
+        (1 << 63) + 1,
+        (1 << 63) + (1 << 11) + 1,
+        u64::MAX - 1,
+        u64::MAX,
+    ];
+
+    let module = compile(&[Type::I64], Type::F64, |b, params| {
+        b.fcvt_from_uint(Type::F64, params[0])
+    });
+    let f = module.get_typed::<fn(u64) -> f64>("f").unwrap();
+    for x in inputs.iter() {
+        assert_eq!(f.call((*x,)), *x as f64, "{}", x);
+    }
+
+    let module = compile(&[Type::I64], Type::F32, |b, params| {
+        b.fcvt_from_uint(Type::F32, params[0])
+    });
+    let f = module.get_typed::<fn(u64) -> f32>("f").unwrap();
+    for x in inputs.iter() {
+        assert_eq!(f.call((*x,)), *x as f32, "{}", x);
+    }
+
+    let module = compile(&[Type::I32], Type::F64, |b, params| {
+        b.fcvt_from_uint(Type::F64, params[0])
+    });
+    let f = module.get_typed::<fn(u32) -> f64>("f").unwrap();
+    assert_eq!(f.call((u32::MAX,)), 4294967295.0);
+}
+
+#[test]
+fn float_round_trips() {
+    let module = compile(&[Type::F32], Type::F32, |b, params| {
+        let x = b.fpromote(Type::F64, params[0])?;
+        b.fdemote(Type::F32, x)
+    });
+    let f = module.get_typed::<fn(f32) -> f32>("f").unwrap();
+    for x in [1.5, -0.0, f32::MAX, f32::MIN_POSITIVE, f32::INFINITY].iter() {
+        assert_eq!(f.call((*x,)).to_bits(), x.to_bits());
+    }
+    assert!(f.call((f32::NAN,)).is_nan());
+
+    let module = compile(&[Type::F64], Type::F32, |b, params| {
+        b.fdemote(Type::F32, params[0])
+    });
+    let f = module.get_typed::<fn(f64) -> f32>("f").unwrap();
+    assert_eq!(f.call((0.1,)), 0.1f32);
+    assert_eq!(f.call((1e300,)), f32::INFINITY);
+
+    let module = compile(&[Type::F64], Type::F64, |b, params| {
+        let x = b.bitcast(Type::I64, params[0])?;
+        let x = b.bxor_imm(x, 1)?;
+        b.bitcast(Type::F64, x)
+    });
+    let f = module.get_typed::<fn(f64) -> f64>("f").unwrap();
+    assert_eq!(f.call((1.0,)).to_bits(), 1.0f64.to_bits() ^ 1);
+
+    let module = compile(&[Type::F32], Type::I32, |b, params| {
+        b.bitcast(Type::I32, params[0])
+    });
+    let f = module.get_typed::<fn(f32) -> u32>("f").unwrap();
+    assert_eq!(f.call((-1.5,)), (-1.5f32).to_bits());
+
+    let module = compile(&[Type::I32], Type::F32, |b, params| {
+        b.bitcast(Type::F32, params[0])
+    });
+    let f = module.get_typed::<fn(u32) -> f32>("f").unwrap();
+    assert_eq!(f.call((0x3fc0_0000,)), 1.5);
+}
@@ -0,0 +1,61 @@
+use peace::function::Function;
+use peace::module::{register_symbol, Linkage, Module};
+use peace::types::{Signature, Type};
+
+extern "C" fn half(x: f64) -> f64 {
+    x / 2.0
+}
+
+/// `f(x) = half(x * 2.5)`, computing the argument before the label `tail`
+/// and calling `half` after it.
+fn build(b: &mut Function) {
+    let float = Type::F64;
+    let x = b.param(0).unwrap();
+    let c = b.fconst(float, 2.5).unwrap();
+    let y = b.fmul(x, c).unwrap();
+    b.new_label("tail").unwrap();
+    b.jump("tail").unwrap();
+    b.bind_label("tail").unwrap();
+    let r = b.call("peace_test_half", &[y], float).unwrap();
+    b.ret(r).unwrap();
+    b.finalize().unwrap();
+}
+
+fn assert_annotated(text: &str) {
+    assert!(text.starts_with("f:\n"), "{}", text);
+    assert!(text.contains("\ntail:\n"), "{}", text);
+    assert!(text.contains("peace_test_half"), "{}", text);
+    assert!(text.contains("; 2.5f64"), "{}", text);
+}
+
+#[test]
+fn disassembly_is_annotated() {
+    let float = Type::F64;
+    register_symbol("peace_test_half", half as *const u8);
+    let mut module = Module::new();
+    module
+        .declare_function(
+            "peace_test_half",
+            Linkage::Import,
+            Signature::new(vec![float], float),
+        )
+        .unwrap();
+    module
+        .declare_function("f", Linkage::Local, Signature::new(vec![float], float))
+        .unwrap();
+    let b = module.get_function("f").unwrap();
+    build(b);
+    assert_annotated(&b.disassemble_pending());
+    assert!(module.disassemble("f").is_err());
+
+    module.finish().unwrap();
+    let text = module.disassemble("f").unwrap();
+    assert_annotated(&text);
+    let f = module.get_typed::<fn(f64) -> f64>("f").unwrap();
+    assert_eq!(f.call((10.0,)), 12.5);
+    assert!(
+        text.contains(&format!("{:#x}:", f.as_ptr() as u64)),
+        "{}",
+        text
+    );
+}
@@ -0,0 +1,252 @@
+//! Loads, stores and compares in every addressing mode, decoded again with
+//! Capstone.
+
+use capstone::arch::x86::{ArchMode, ArchSyntax, X86OperandType};
+use capstone::arch::ArchOperand;
+use capstone::prelude::*;
+use peace::backend::assembler::{Assembler, Mem};
+use peace::backend::constants_x64::*;
+use peace::backend::MachineMode;
+
+const GPRS: [Register; 16] = [
+    RAX, RCX, RDX, RBX, RSP, RBP, RSI, RDI, R8, R9, R10, R11, R12, R13, R14, R15,
+];
+
+const XMMS: [XMMRegister; 4] = [XMM0, XMM7, XMM8, XMM15];
+
+const NAMES64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+const NAMES32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d",
+    "r13d", "r14d", "r15d",
+];
+const NAMES8: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+    "r13b", "r14b", "r15b",
+];
+
+/// Displacements around the disp8 and disp32 boundaries.
+const DISPS: [i32; 7] = [0, 8, -128, 127, 128, -129, 0x1234_5678];
+
+/// A decoded operand.
+#[derive(Debug, PartialEq)]
+enum Operand {
+    Reg(String),
+    Imm(i64),
+    /// Size, base, index, scale and displacement.
+    Mem(u8, Option<String>, Option<String>, i32, i64),
+}
+
+fn capstone() -> Capstone {
+    Capstone::new()
+        .x86()
+        .mode(ArchMode::Mode64)
+        .syntax(ArchSyntax::Intel)
+        .detail(true)
+        .build()
+        .unwrap()
+}
+
+/// Decode the single instruction in `code`.
+fn decode(cs: &Capstone, code: &[u8]) -> (String, Vec<Operand>) {
+    let insns = cs.disasm_all(code, 0).unwrap();
+    assert_eq!(insns.len(), 1, "{:02x?}", code);
+    let insn = insns.iter().next().unwrap();
+    assert_eq!(insn.bytes().len(), code.len(), "{:02x?}", code);
+
+    let name = |reg: RegId| cs.reg_name(reg).filter(|_| reg.0 != 0);
+    let detail = cs.insn_detail(&insn).unwrap();
+    let operands = detail
+        .arch_detail()
+        .operands()
+        .into_iter()
+        .map(|operand| match operand {
+            ArchOperand::X86Operand(op) => match op.op_type {
+                X86OperandType::Reg(reg) => Operand::Reg(name(reg).unwrap()),
+                X86OperandType::Imm(imm) => Operand::Imm(imm),
+                X86OperandType::Mem(mem) => Operand::Mem(
+                    op.size,
+                    name(mem.base()),
+                    name(mem.index()),
+                    mem.scale(),
+                    mem.disp(),
+                ),
+                other => panic!("unexpected operand {:?}", other),
+            },
+            other => panic!("unexpected operand {:?}", other),
+        })
+        .collect();
+    (insn.mnemonic().unwrap().to_owned(), operands)
+}
+
+/// Every addressing mode with the registers and displacements that need
+/// special encodings.
+fn addresses() -> Vec<Mem> {
+    let mut mems = vec![];
+    for disp in DISPS.iter().cloned() {
+        mems.push(Mem::Local(disp));
+        for base in GPRS.iter().cloned() {
+            mems.push(Mem::Base(base, disp));
+        }
+        for index in GPRS.iter().cloned().filter(|reg| *reg != RSP) {
+            for scale in [1, 2, 4, 8].iter().cloned() {
+                mems.push(Mem::Offset(index, scale, disp));
+            }
+        }
+    }
+    for base in GPRS.iter().cloned() {
+        for index in GPRS.iter().cloned().filter(|reg| *reg != RSP) {
+            for scale in [1, 2, 4, 8].iter().cloned() {
+                for disp in [0, -128, 128].iter().cloned() {
+                    mems.push(Mem::Index(base, index, scale, disp));
+                }
+            }
+        }
+    }
+    mems
+}
+
+/// The memory operand Capstone reports for `mem` accessing `size` bytes.
+fn mem_operand(mem: Mem, size: u8) -> Operand {
+    let name = |reg: Register| Some(NAMES64[reg as usize].to_owned());
+    match mem {
+        Mem::Local(disp) => Operand::Mem(size, name(RBP), None, 1, disp as i64),
+        Mem::Base(base, disp) => Operand::Mem(size, name(base), None, 1, disp as i64),
+        Mem::Index(base, index, scale, disp) => {
+            Operand::Mem(size, name(base), name(index), scale, disp as i64)
+        }
+        Mem::Offset(index, scale, disp) => {
+            Operand::Mem(size, None, name(index), scale, disp as i64)
+        }
+    }
+}
+
+/// Name of `reg` accessed with `mode`.
+fn reg_operand(reg: Register, mode: MachineMode) -> Operand {
+    let names = match mode {
+        MachineMode::Int8 => NAMES8,
+        MachineMode::Int32 => NAMES32,
+        _ => NAMES64,
+    };
+    Operand::Reg(names[reg as usize].to_owned())
+}
+
+fn check<F>(cs: &Capstone, emit: F, mnemonic: &str, operands: Vec<Operand>)
+where
+    F: FnOnce(&mut Assembler),
+{
+    let mut asm = Assembler::new();
+    emit(&mut asm);
+    let (decoded, decoded_operands) = decode(cs, asm.data());
+    assert_eq!(
+        (decoded.as_str(), &decoded_operands),
+        (mnemonic, &operands),
+        "{:02x?}",
+        asm.data()
+    );
+}
+
+const INT_MODES: [(MachineMode, u8); 3] = [
+    (MachineMode::Int8, 1),
+    (MachineMode::Int32, 4),
+    (MachineMode::Int64, 8),
+];
+
+#[test]
+fn integer_loads_and_stores() {
+    let cs = capstone();
+    for (idx, mem) in addresses().into_iter().enumerate() {
+        let reg = GPRS[idx % GPRS.len()];
+        for (mode, size) in INT_MODES.iter().cloned() {
+            // bytes are zero extended into the 32 bit register
+            let (load, dest_mode) = match mode {
+                MachineMode::Int8 => ("movzx", MachineMode::Int32),
+                _ => ("mov", mode),
+            };
+            check(
+                &cs,
+                |asm| asm.load_mem(mode, Reg::Gpr(reg), mem),
+                load,
+                vec![reg_operand(reg, dest_mode), mem_operand(mem, size)],
+            );
+            check(
+                &cs,
+                |asm| asm.store_mem(mode, mem, Reg::Gpr(reg)),
+                "mov",
+                vec![mem_operand(mem, size), reg_operand(reg, mode)],
+            );
+        }
+    }
+}
+
+#[test]
+fn float_loads_and_stores() {
+    let cs = capstone();
+    let modes = [
+        (MachineMode::Float32, 4, "movss"),
+        (MachineMode::Float64, 8, "movsd"),
+    ];
+    for (idx, mem) in addresses().into_iter().enumerate() {
+        let reg = XMMS[idx % XMMS.len()];
+        let name = || Operand::Reg(format!("xmm{}", reg as usize));
+        for (mode, size, mnemonic) in modes.iter().cloned() {
+            check(
+                &cs,
+                |asm| asm.load_mem(mode, Reg::Float(reg), mem),
+                mnemonic,
+                vec![name(), mem_operand(mem, size)],
+            );
+            check(
+                &cs,
+                |asm| asm.store_mem(mode, mem, Reg::Float(reg)),
+                mnemonic,
+                vec![mem_operand(mem, size), name()],
+            );
+        }
+    }
+}
+
+#[test]
+fn compares() {
+    let cs = capstone();
+    for (idx, mem) in addresses().into_iter().enumerate() {
+        let reg = GPRS[idx % GPRS.len()];
+        for (mode, size) in INT_MODES.iter().cloned() {
+            check(
+                &cs,
+                |asm| asm.cmp_mem(mode, mem, reg),
+                "cmp",
+                vec![mem_operand(mem, size), reg_operand(reg, mode)],
+            );
+
+            let imms: &[i32] = match mode {
+                MachineMode::Int8 => &[-5, 127],
+                _ => &[-5, 127, 128, -1000, i32::MAX],
+            };
+            for imm in imms.iter().cloned() {
+                let mut asm = Assembler::new();
+                asm.cmp_mem_imm(mode, mem, imm);
+                let (decoded, operands) = decode(&cs, asm.data());
+                assert_eq!(decoded, "cmp");
+                assert_eq!(operands[0], mem_operand(mem, size), "{:02x?}", asm.data());
+                // Capstone may show the immediate zero extended, so only
+                // compare the bits of the operand
+                let bits = (size as u32) * 8;
+                let truncate = |imm: i64| imm & ((1i128 << bits) - 1) as i64;
+                match operands[1] {
+                    Operand::Imm(decoded) => {
+                        assert_eq!(
+                            truncate(decoded),
+                            truncate(imm as i64),
+                            "{:02x?}",
+                            asm.data()
+                        )
+                    }
+                    ref other => panic!("expected an immediate, got {:?}", other),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use peace::error::Result;
+use peace::function::Function;
+use peace::module::{Linkage, Module};
+use peace::types::{Signature, Type, Value};
+
+/// A module with `f(params) -> ret` computed by `build` from the parameters.
+fn compile<F>(params: &[Type], ret: Type, build: F) -> Module
+where
+    F: FnOnce(&mut Function, &[Value]) -> Result<Value>,
+{
+    let mut module = Module::new();
+    module
+        .declare_function("f", Linkage::Local, Signature::new(params.to_vec(), ret))
+        .unwrap();
+    let b = module.get_function("f").unwrap();
+    let params: Vec<Value> = (0..params.len()).map(|idx| b.param(idx).unwrap()).collect();
+    let r = build(b, &params).unwrap();
+    b.ret(r).unwrap();
+    b.finalize().unwrap();
+    module.finish().unwrap();
+    module
+}
+
+type Op = fn(&mut Function, Value, Value) -> Result<Value>;
+
+const OPS: [(&str, Op); 6] = [
+    ("fadd", Function::fadd),
+    ("fsub", Function::fsub),
+    ("fmul", Function::fmul),
+    ("fdiv", Function::fdiv),
+    ("fmin", Function::fmin),
+    ("fmax", Function::fmax),
+];
+
+/// `op` with the semantics of the SSE instructions, `minsd` and `maxsd`
+/// return their second operand unless the first one is smaller or larger.
+fn expected64(name: &str, x: f64, y: f64) -> f64 {
+    match name {
+        "fadd" => x + y,
+        "fsub" => x - y,
+        "fmul" => x * y,
+        "fdiv" => x / y,
+        "fmin" if x < y => x,
+        "fmax" if x > y => x,
+        _ => y,
+    }
+}
+
+fn expected32(name: &str, x: f32, y: f32) -> f32 {
+    match name {
+        "fadd" => x + y,
+        "fsub" => x - y,
+        "fmul" => x * y,
+        "fdiv" => x / y,
+        "fmin" if x < y => x,
+        "fmax" if x > y => x,
+        _ => y,
+    }
+}
+
+const INPUTS: [f64; 9] = [
+    1.5,
+    -2.25,
+    0.0,
+    -0.0,
+    1e300,
+    f64::INFINITY,
+    f64::NEG_INFINITY,
+    f64::NAN,
+    f64::MIN_POSITIVE,
+];
+
+/// Equal bits, or both NaN.
+fn same64(a: f64, b: f64) -> bool {
+    a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan())
+}
+
+fn same32(a: f32, b: f32) -> bool {
+    a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan())
+}
+
+#[test]
+fn binary_operations() {
+    for (name, op) in OPS.iter() {
+        let module = compile(&[Type::F64, Type::F64], Type::F64, |b, params| {
+            op(b, params[0], params[1])
+        });
+        let f = module.get_typed::<fn(f64, f64) -> f64>("f").unwrap();
+        for x in INPUTS.iter() {
+            for y in INPUTS.iter() {
+                let (r, expected) = (f.call((*x, *y)), expected64(name, *x, *y));
+                assert!(same64(r, expected), "{} {} {}: {}", name, x, y, r);
+            }
+        }
+
+        let module = compile(&[Type::F32, Type::F32], Type::F32, |b, params| {
+            op(b, params[0], params[1])
+        });
+        let f = module.get_typed::<fn(f32, f32) -> f32>("f").unwrap();
+        for x in INPUTS.iter() {
+            for y in INPUTS.iter() {
+                let (x, y) = (*x as f32, *y as f32);
+                let (r, expected) = (f.call((x, y)), expected32(name, x, y));
+                assert!(same32(r, expected), "{} {} {}: {}", name, x, y, r);
+            }
+        }
+    }
+}
+
+#[test]
+fn spilled_operands() {
+    // more sums than allocatable registers are live at once
+    let float = Type::F64;
+    for (name, op) in OPS.iter() {
+        let module = compile(&[float, float], float, |b, params| {
+            let mut xs = vec![];
+            for k in 0..10 {
+                let c = b.fconst(float, k as f64)?;
+                xs.push(b.fadd(params[0], c)?);
+            }
+            let mut r = b.fconst(float, 0.0)?;
+            for x in xs.iter().rev() {
+                let y = op(b, *x, params[1])?;
+                let y = op(b, params[1], y)?;
+                r = b.fadd(r, y)?;
+            }
+            Ok(r)
+        });
+        let f = module.get_typed::<fn(f64, f64) -> f64>("f").unwrap();
+        let (x, y) = (0.75, -3.5);
+        let expected = (0..10).rev().fold(0.0, |r, k| {
+            let v = expected64(name, x + k as f64, y);
+            r + expected64(name, y, v)
+        });
+        assert_eq!(f.call((x, y)), expected, "{}", name);
+    }
+}
+
+#[test]
+fn unary_operations() {
+    type Unary = fn(&mut Function, Value) -> Result<Value>;
+    let ops: [(&str, Unary); 3] = [
+        ("fneg", Function::fneg),
+        ("fabs", Function::fabs),
+        ("fsqrt", Function::fsqrt),
+    ];
+    for (name, op) in ops.iter() {
+        let module = compile(&[Type::F64], Type::F64, |b, params| op(b, params[0]));
+        let f = module.get_typed::<fn(f64) -> f64>("f").unwrap();
+        for x in INPUTS.iter() {
+            let expected = match *name {
+                "fneg" => -x,
+                "fabs" => x.abs(),
+                _ => x.sqrt(),
+            };
+            let r = f.call((*x,));
+            assert!(same64(r, expected), "{} {}: {}", name, x, r);
+        }
+
+        let module = compile(&[Type::F32], Type::F32, |b, params| op(b, params[0]));
+        let f = module.get_typed::<fn(f32) -> f32>("f").unwrap();
+        for x in INPUTS.iter() {
+            let x = *x as f32;
+            let expected = match *name {
+                "fneg" => -x,
+                "fabs" => x.abs(),
+                _ => x.sqrt(),
+            };
+            let r = f.call((x,));
+            assert!(same32(r, expected), "{} {}: {}", name, x, r);
+        }
+    }
+}
+
+#[test]
+fn signed_zeros() {
+    let module = compile(&[Type::F64], Type::F64, |b, params| b.fabs(params[0]));
+    let f = module.get_typed::<fn(f64) -> f64>("f").unwrap();
+    assert_eq!(f.call((-0.0,)).to_bits(), 0.0f64.to_bits());
+
+    let module = compile(&[Type::F32], Type::F32, |b, params| b.fabs(params[0]));
+    let f = module.get_typed::<fn(f32) -> f32>("f").unwrap();
+    assert_eq!(f.call((-0.0,)).to_bits(), 0.0f32.to_bits());
+
+    // both zero or either NaN: the second operand
+    for op in [Function::fmin as Op, Function::fmax].iter() {
+        let module = compile(&[Type::F64, Type::F64], Type::F64, |b, params| {
+            op(b, params[0], params[1])
+        });
+        let f = module.get_typed::<fn(f64, f64) -> f64>("f").unwrap();
+        assert_eq!(f.call((0.0, -0.0)).to_bits(), (-0.0f64).to_bits());
+        assert_eq!(f.call((-0.0, 0.0)).to_bits(), 0.0f64.to_bits());
+        assert_eq!(f.call((f64::NAN, 1.0)), 1.0);
+        assert!(f.call((1.0, f64::NAN)).is_nan());
+    }
+}
+
+#[test]
+fn constants() {
+    let module = compile(&[], Type::F64, |b, _| {
+        let x = b.fconst(Type::F64, 0.1)?;
+        let y = b.fconst(Type::F64, -0.0)?;
+        b.fadd(x, y)
+    });
+    let f = module.get_typed::<fn() -> f64>("f").unwrap();
+    assert_eq!(f.call(()), 0.1);
+
+    let module = compile(&[], Type::F32, |b, _| {
+        let x = b.fconst(Type::F32, 0.1)?;
+        let y = b.fconst(Type::F32, 3.0)?;
+        b.fmul(x, y)
+    });
+    let f = module.get_typed::<fn() -> f32>("f").unwrap();
+    assert_eq!(f.call(()), 0.1f32 * 3.0);
+}
@@ -0,0 +1,178 @@
+use peace::backend::CondCode;
+use peace::error::PeaceError;
+use peace::module::{Linkage, Module};
+use peace::types::{Block, Signature, Type, Value};
+
+#[test]
+fn loop_with_block_params() {
+    let mut module = Module::new();
+    let int = Type::I64;
+    module
+        .declare_function("sum", Linkage::Local, Signature::new(vec![int], int))
+        .unwrap();
+    let b = module.get_function("sum").unwrap();
+    let n = b.param(0).unwrap();
+    let header = b.create_block();
+    let body = b.create_block();
+    let exit = b.create_block();
+    let i = b.append_block_param(header, int).unwrap();
+    let s = b.append_block_param(header, int).unwrap();
+    let zero = b.iconst(int, 0).unwrap();
+    b.br(header, &[zero, zero]).unwrap();
+    b.switch_to_block(header).unwrap();
+    let c = b.int_cmp(i, n, CondCode::Less).unwrap();
+    b.brif(c, body, &[], exit, &[]).unwrap();
+    b.switch_to_block(body).unwrap();
+    let one = b.iconst(int, 1).unwrap();
+    let next_i = b.iadd(i, one).unwrap();
+    let next_s = b.iadd(s, i).unwrap();
+    b.br(header, &[next_i, next_s]).unwrap();
+    b.switch_to_block(exit).unwrap();
+    b.ret(s).unwrap();
+    b.finalize().unwrap();
+    module.finish().unwrap();
+
+    let sum = module.get_typed::<fn(i64) -> i64>("sum").unwrap();
+    assert_eq!(sum.call((10,)), 45);
+}
+
+#[test]
+fn branch_to_entry_block_is_rejected() {
+    let mut module = Module::new();
+    let int = Type::I64;
+    module
+        .declare_function("f", Linkage::Local, Signature::new(vec![int], int))
+        .unwrap();
+    let b = module.get_function("f").unwrap();
+    let x = b.param(0).unwrap();
+    let entry = b.body().layout[0];
+    let other = b.create_block();
+
+    assert!(matches!(b.br(entry, &[x]), Err(PeaceError::Unsupported(_))));
+    assert!(matches!(
+        b.brif(x, entry, &[x], other, &[]),
+        Err(PeaceError::Unsupported(_))
+    ));
+}
+
+#[test]
+fn finish_rejects_function_that_was_not_finalized() {
+    let mut module = Module::new();
+    let int = Type::I64;
+    module
+        .declare_function("f", Linkage::Local, Signature::new(vec![], int))
+        .unwrap();
+    module
+        .declare_function("g", Linkage::Local, Signature::new(vec![], int))
+        .unwrap();
+    let b = module.get_function("g").unwrap();
+    let one = b.iconst(int, 1).unwrap();
+    b.ret(one).unwrap();
+    b.finalize().unwrap();
+
+    match module.finish() {
+        Err(PeaceError::Unsupported(msg)) => assert_eq!(msg, "f was not finalized"),
+        _ => panic!("finish placed f without code"),
+    }
+}
+
+#[test]
+fn values_and_blocks_of_other_functions_are_rejected() {
+    let mut module = Module::new();
+    let int = Type::I64;
+    module
+        .declare_function("f", Linkage::Local, Signature::new(vec![int], int))
+        .unwrap();
+    let b = module.get_function("f").unwrap();
+    let x = b.param(0).unwrap();
+    let missing_value = Value(100);
+    let missing_block = Block(100);
+
+    assert!(matches!(
+        b.get_value_type(missing_value),
+        Err(PeaceError::TypeMismatch(_))
+    ));
+    assert!(b.iadd(x, missing_value).is_err());
+    assert!(b.ret(missing_value).is_err());
+    assert!(b.switch_to_block(missing_block).is_err());
+    assert!(b.append_block_param(missing_block, int).is_err());
+    assert!(b.br(missing_block, &[]).is_err());
+
+    let other = b.create_block();
+    b.append_block_param(other, int).unwrap();
+    assert!(b.br(other, &[missing_value]).is_err());
+    b.br(other, &[x]).unwrap();
+}
+
+#[test]
+fn labels_and_symbols_are_checked() {
+    let mut module = Module::new();
+    module
+        .declare_function("f", Linkage::Local, Signature::new(vec![], Type::Void))
+        .unwrap();
+    let b = module.get_function("f").unwrap();
+
+    b.new_label("exit").unwrap();
+    assert!(matches!(
+        b.new_label("exit"),
+        Err(PeaceError::Unsupported(_))
+    ));
+    assert!(matches!(
+        b.symbol_addr(""),
+        Err(PeaceError::UnresolvedSymbol(_))
+    ));
+    assert!(b.symbol_addr("a\0b").is_err());
+    b.symbol_addr("f").unwrap();
+}
+
+#[test]
+fn parameters_passed_on_the_stack() {
+    let mut module = Module::new();
+    let (int, float) = (Type::I64, Type::F64);
+    module
+        .declare_function("ints", Linkage::Local, Signature::new(vec![int; 8], int))
+        .unwrap();
+    let b = module.get_function("ints").unwrap();
+    // p0 + p1 * 16 + p2 * 256 ..., one hex digit per parameter
+    let mut r = b.iconst(int, 0).unwrap();
+    for idx in 0..8 {
+        let p = b.param(idx).unwrap();
+        let weight = b.iconst(int, 1 << (4 * idx)).unwrap();
+        let p = b.imul(p, weight).unwrap();
+        r = b.iadd(r, p).unwrap();
+    }
+    b.ret(r).unwrap();
+    b.finalize().unwrap();
+
+    module
+        .declare_function(
+            "floats",
+            Linkage::Local,
+            Signature::new(vec![float; 10], float),
+        )
+        .unwrap();
+    let b = module.get_function("floats").unwrap();
+    let mut r = b.fconst(float, 0.0).unwrap();
+    for idx in 0..10 {
+        let p = b.param(idx).unwrap();
+        let weight = b.fconst(float, (1u64 << (4 * idx)) as f64).unwrap();
+        let p = b.fmul(p, weight).unwrap();
+        r = b.fadd(r, p).unwrap();
+    }
+    b.ret(r).unwrap();
+    b.finalize().unwrap();
+    module.finish().unwrap();
+
+    let ints = module
+        .get_typed::<fn(i64, i64, i64, i64, i64, i64, i64, i64) -> i64>("ints")
+        .unwrap();
+    assert_eq!(ints.call((1, 2, 3, 4, 5, 6, 7, 8)), 0x8765_4321);
+
+    type Floats = extern "C" fn(f64, f64, f64, f64, f64, f64, f64, f64, f64, f64) -> f64;
+    let floats = module.get_finalized_function("floats").unwrap();
+    let floats: Floats = unsafe { std::mem::transmute(floats) };
+    assert_eq!(
+        floats(1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0),
+        0xa98_7654_321u64 as f64
+    );
+}
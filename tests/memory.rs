@@ -0,0 +1,95 @@
+use peace::backend::memory::Memory;
+use peace::error::PeaceError;
+
+#[test]
+fn misuse_is_reported() {
+    let mut memory = Memory::new();
+    assert!(matches!(memory.allocate(16, 3), Err(PeaceError::Memory(_))));
+    assert!(matches!(
+        memory.allocate(16, 1 << 30),
+        Err(PeaceError::Memory(_))
+    ));
+
+    let ptr = memory.allocate(32, 16).unwrap();
+    let other = [0u8; 16];
+    unsafe {
+        assert!(memory.free(other.as_ptr(), 16).is_err());
+        assert!(memory.free(ptr, 64).is_err());
+        memory.free(ptr, 32).unwrap();
+        assert!(memory.free(ptr, 32).is_err());
+    }
+    assert_eq!(memory.stats().used, 0);
+    assert_eq!(memory.stats().freed, 32);
+}
+
+#[test]
+fn released_pages_are_reused() {
+    let mut memory = Memory::new();
+    let first = memory.allocate(64, 16).unwrap();
+    memory.set_readable_and_executable().unwrap();
+    let reserved = memory.stats().reserved;
+
+    unsafe { memory.free(first, 64).unwrap() };
+    let second = memory.allocate(64, 16).unwrap();
+    assert_eq!(second, first);
+    assert_eq!(memory.stats().reserved, reserved);
+    assert_eq!(memory.stats().used, 64);
+    // reused pages are writable again
+    unsafe { second.write(0xc3) };
+}
+
+/// Permissions of the mapping holding `ptr`, like `r-xp`, and whether any
+/// mapping of the process is writable and executable at once.
+#[cfg(target_os = "linux")]
+fn protection(ptr: *const u8) -> (String, bool) {
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+    let mut found = None;
+    let mut any_wx = false;
+    for line in maps.lines() {
+        let mut fields = line.split_whitespace();
+        let range = fields.next().unwrap();
+        let perms = fields.next().unwrap();
+        any_wx |= perms.contains('w') && perms.contains('x');
+
+        let mut bounds = range
+            .split('-')
+            .map(|bound| usize::from_str_radix(bound, 16).unwrap());
+        let (start, end) = (bounds.next().unwrap(), bounds.next().unwrap());
+        if (start..end).contains(&(ptr as usize)) {
+            found = Some(perms.to_owned());
+        }
+    }
+    (found.expect("address is not mapped"), any_wx)
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn pages_are_never_writable_and_executable() {
+    let check = |ptr: *const u8, expected: &str| {
+        let (perms, any_wx) = protection(ptr);
+        assert_eq!(&perms[..3], expected, "{:p}", ptr);
+        assert!(!any_wx, "a mapping is writable and executable");
+    };
+
+    let mut memory = Memory::new();
+    let first = memory.allocate(64, 16).unwrap();
+    check(first, "rw-");
+    memory.set_readable_and_executable().unwrap();
+    check(first, "r-x");
+
+    let second = memory.allocate(64, 16).unwrap();
+    check(second, "rw-");
+    check(first, "r-x");
+    memory.set_readable_and_executable().unwrap();
+    check(second, "r-x");
+
+    // freed blocks are writable before they are handed out again
+    unsafe { memory.free(first, 64).unwrap() };
+    check(first, "rw-");
+    check(second, "r-x");
+    let third = memory.allocate(64, 16).unwrap();
+    assert_eq!(third, first);
+    check(third, "rw-");
+    memory.set_readable_and_executable().unwrap();
+    check(third, "r-x");
+}
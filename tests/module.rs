@@ -0,0 +1,302 @@
+use peace::error::PeaceError;
+use peace::function::Function;
+use peace::module::{lookup_symbol, Linkage, Module};
+use peace::types::{Signature, Type};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn define_const(module: &mut Module, name: &str, value: i64) {
+    define_linked_const(module, name, Linkage::Local, value);
+}
+
+fn define_linked_const(module: &mut Module, name: &str, linkage: Linkage, value: i64) {
+    let int = Type::I64;
+    module
+        .declare_function(name, linkage, Signature::new(vec![], int))
+        .unwrap();
+    let b = module.get_function(name).unwrap();
+    let c = b.iconst(int, value).unwrap();
+    b.ret(c).unwrap();
+    b.finalize().unwrap();
+}
+
+/// Define `caller() -> i64` returning the result of calling `callee`, which
+/// has to be declared already.
+fn define_caller(module: &mut Module, caller: &str, linkage: Linkage, callee: &str) {
+    let int = Type::I64;
+    module
+        .declare_function(caller, linkage, Signature::new(vec![], int))
+        .unwrap();
+    let b = module.get_function(caller).unwrap();
+    let r = b.call(callee, &[], int).unwrap();
+    b.ret(r).unwrap();
+    b.finalize().unwrap();
+}
+
+/// A module calling `name` exported by another module.
+fn import_and_call(name: &str) -> peace::error::Result<Module> {
+    let mut module = Module::new();
+    module.declare_function(name, Linkage::Import, Signature::new(vec![], Type::I64))?;
+    define_caller(&mut module, "caller", Linkage::Local, name);
+    module.finish()?;
+    Ok(module)
+}
+
+#[test]
+fn second_finish_places_only_new_functions() {
+    let mut module = Module::new();
+    define_const(&mut module, "one", 1);
+    module.finish().unwrap();
+    let one = module.get_finalized_function("one").unwrap();
+    let before = module.memory_stats();
+
+    module.finish().unwrap();
+    assert_eq!(module.memory_stats().used, before.used);
+
+    define_const(&mut module, "two", 2);
+    module.finish().unwrap();
+    assert_eq!(module.get_finalized_function("one").unwrap(), one);
+    let two = module.get_typed::<fn() -> i64>("two").unwrap();
+    assert_eq!(two.call(()), 2);
+    let one = module.get_typed::<fn() -> i64>("one").unwrap();
+    assert_eq!(one.call(()), 1);
+}
+
+#[test]
+fn define_after_finish_is_rejected() {
+    let mut module = Module::new();
+    define_const(&mut module, "one", 1);
+    module.finish().unwrap();
+
+    let int = Type::I64;
+    let mut func = Function::new("one", Linkage::Local, Signature::new(vec![], int));
+    let c = func.iconst(int, 3).unwrap();
+    func.ret(c).unwrap();
+    func.finalize().unwrap();
+    assert!(matches!(
+        module.define_function(func),
+        Err(PeaceError::Unsupported(_))
+    ));
+}
+
+#[test]
+fn conflicting_declarations_are_rejected() {
+    let mut module = Module::new();
+    let int = Type::I64;
+    let sig = Signature::new(vec![int], int);
+    module
+        .declare_function("f", Linkage::Local, sig.clone())
+        .unwrap();
+    module
+        .declare_function("f", Linkage::Local, sig.clone())
+        .unwrap();
+    assert!(matches!(
+        module.declare_function("f", Linkage::Local, Signature::new(vec![], int)),
+        Err(PeaceError::TypeMismatch(_))
+    ));
+    assert!(matches!(
+        module.declare_function("f", Linkage::Export, sig),
+        Err(PeaceError::Unsupported(_))
+    ));
+    assert!(module.declare_data("f".to_owned(), Linkage::Local).is_err());
+    assert!(module.define_data("f".to_owned(), &[0]).is_err());
+
+    module
+        .declare_data("d".to_owned(), Linkage::Export)
+        .unwrap();
+    module
+        .define_data_owned("d".to_owned(), vec![1, 2])
+        .unwrap();
+    module
+        .declare_data("d".to_owned(), Linkage::Export)
+        .unwrap();
+    assert_eq!(module.data["d"].size, 2);
+    assert!(module.declare_data("d".to_owned(), Linkage::Local).is_err());
+    assert!(module.define_data_owned("d".to_owned(), vec![3]).is_err());
+    assert!(module
+        .declare_function("d", Linkage::Local, Signature::new(vec![], int))
+        .is_err());
+
+    module
+        .declare_data("extern".to_owned(), Linkage::Import)
+        .unwrap();
+    assert!(module.define_data("extern".to_owned(), &[0]).is_err());
+}
+
+#[test]
+fn removed_functions_are_freed() {
+    let mut module = Module::new();
+    define_const(&mut module, "one", 1);
+    module.finish().unwrap();
+    let used = module.memory_stats().used;
+    assert!(used > 0);
+
+    module.remove_function("one").unwrap();
+    assert!(module.get_finalized_function("one").is_err());
+    assert_eq!(module.memory_stats().used, 0);
+    assert_eq!(module.memory_stats().freed, used);
+    let reserved = module.memory_stats().reserved;
+
+    define_const(&mut module, "two", 2);
+    module.finish().unwrap();
+    assert_eq!(module.memory_stats().reserved, reserved);
+    let two = module.get_typed::<fn() -> i64>("two").unwrap();
+    assert_eq!(two.call(()), 2);
+}
+
+#[test]
+fn removing_a_called_function_is_rejected() {
+    let mut module = Module::new();
+    let int = Type::I64;
+    define_const(&mut module, "one", 1);
+    module
+        .declare_function("caller", Linkage::Local, Signature::new(vec![], int))
+        .unwrap();
+    let b = module.get_function("caller").unwrap();
+    let r = b.call("one", &[], int).unwrap();
+    b.ret(r).unwrap();
+    b.finalize().unwrap();
+    module.finish().unwrap();
+
+    assert!(matches!(
+        module.remove_function("one"),
+        Err(PeaceError::Unsupported(_))
+    ));
+    let caller = module.get_typed::<fn() -> i64>("caller").unwrap();
+    assert_eq!(caller.call(()), 1);
+}
+
+#[test]
+fn removed_code_is_kept_while_pinned() {
+    let mut module = Module::new();
+    define_const(&mut module, "one", 1);
+    module.finish().unwrap();
+    let one = module.get_typed::<fn() -> i64>("one").unwrap();
+
+    module.remove_function("one").unwrap();
+    assert_eq!(module.memory_stats().freed, 0);
+    assert_eq!(one.call(()), 1);
+
+    drop(one);
+    module.free_retired_code().unwrap();
+    assert_eq!(module.memory_stats().used, 0);
+}
+
+#[test]
+fn exported_functions_are_imported_by_other_modules() {
+    let mut exporter = Module::new();
+    define_linked_const(&mut exporter, "peace_test_exported", Linkage::Export, 42);
+    exporter.finish().unwrap();
+    let addr = exporter
+        .get_finalized_function("peace_test_exported")
+        .unwrap();
+    assert_eq!(
+        lookup_symbol("peace_test_exported"),
+        Some(addr as *const u8)
+    );
+
+    let importer = import_and_call("peace_test_exported").unwrap();
+    let caller = importer.get_typed::<fn() -> i64>("caller").unwrap();
+    assert_eq!(caller.call(()), 42);
+}
+
+#[test]
+fn dropping_a_module_unregisters_its_exports() {
+    let mut exporter = Module::new();
+    define_linked_const(&mut exporter, "peace_test_dropped", Linkage::Export, 1);
+    define_linked_const(&mut exporter, "peace_test_local", Linkage::Local, 2);
+    exporter.finish().unwrap();
+    assert!(lookup_symbol("peace_test_dropped").is_some());
+    assert!(lookup_symbol("peace_test_local").is_none());
+    assert!(import_and_call("peace_test_dropped").is_ok());
+
+    drop(exporter);
+    assert!(lookup_symbol("peace_test_dropped").is_none());
+    assert!(matches!(
+        import_and_call("peace_test_dropped"),
+        Err(PeaceError::UnresolvedSymbol(_))
+    ));
+}
+
+const PREEMPT_C: &str = r#"
+#include <stdio.h>
+
+long preemptible(void) { return 10; }
+long exported(void) { return 20; }
+long call_preemptible(void);
+long call_exported(void);
+
+int main(void) {
+    printf("%ld %ld\n", call_preemptible(), call_exported());
+    return 0;
+}
+"#;
+
+#[test]
+fn preemptible_calls_go_through_the_got() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("preemptible_calls");
+    fs::create_dir_all(&dir).unwrap();
+    let obj = dir.join("lib.o");
+    let lib = dir.join("libpreempt.so");
+    let main_c = dir.join("main.c");
+    let exe = dir.join("main");
+
+    let mut module = Module::new();
+    define_linked_const(&mut module, "preemptible", Linkage::Preemptible, 1);
+    define_linked_const(&mut module, "exported", Linkage::Export, 2);
+    define_caller(
+        &mut module,
+        "call_preemptible",
+        Linkage::Export,
+        "preemptible",
+    );
+    define_caller(&mut module, "call_exported", Linkage::Export, "exported");
+    module.write_object(&obj).unwrap();
+    fs::write(&main_c, PREEMPT_C).unwrap();
+
+    let status = Command::new("cc")
+        .args(&["-shared", "-o"])
+        .arg(&lib)
+        .arg(&obj)
+        .status()
+        .expect("running cc");
+    assert!(status.success());
+
+    // only the call of the preemptible function is left to the dynamic linker
+    let output = Command::new("readelf")
+        .args(&["--relocs", "--wide"])
+        .arg(&lib)
+        .output()
+        .expect("running readelf");
+    let relocs = String::from_utf8(output.stdout).unwrap();
+    let slots: Vec<&str> = relocs
+        .lines()
+        .filter(|line| line.contains("JUMP_SLOT") || line.contains("GLOB_DAT"))
+        .collect();
+    assert!(
+        slots.iter().any(|line| line.contains(" preemptible")),
+        "{}",
+        relocs
+    );
+    assert!(
+        !slots.iter().any(|line| line.contains(" exported")),
+        "{}",
+        relocs
+    );
+
+    let status = Command::new("cc")
+        .arg(&main_c)
+        .arg(&lib)
+        .arg("-o")
+        .arg(&exe)
+        .arg(format!("-Wl,-rpath,{}", dir.display()))
+        .status()
+        .expect("running cc");
+    assert!(status.success());
+
+    // the executable's definition takes the place of the preemptible one
+    let output = Command::new(&exe).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "10 2\n");
+}
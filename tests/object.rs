@@ -0,0 +1,100 @@
+use peace::module::{Linkage, Module};
+use peace::types::{Signature, Type};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn data_defined_without_declaration() {
+    let mut module = Module::new();
+    module
+        .define_data("answer".to_owned(), &42i64.to_le_bytes())
+        .unwrap();
+    let obj = module.emit_object().unwrap();
+    assert_eq!(&obj[..4], b"\x7fELF");
+}
+
+const MAIN_C: &str = r#"
+#include <stdio.h>
+
+long host_value = 100;
+extern long table[];
+long compute(long x);
+
+int main(void) {
+    printf("%ld %ld\n", compute(-200), table[2]);
+    return 0;
+}
+"#;
+
+/// `compute(x) = labs(twice(table[1] + host_value + x))` with `table`
+/// exported from the object, `host_value` and `labs` imported.
+fn build(module: &mut Module) {
+    let int = Type::I64;
+    let table: Vec<u8> = [10i64, 20, 30]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect();
+    module
+        .declare_data("table".to_owned(), Linkage::Export)
+        .unwrap();
+    module.define_data_owned("table".to_owned(), table).unwrap();
+    module
+        .declare_data("host_value".to_owned(), Linkage::Import)
+        .unwrap();
+    module
+        .declare_function("labs", Linkage::Import, Signature::new(vec![int], int))
+        .unwrap();
+
+    module
+        .declare_function("twice", Linkage::Local, Signature::new(vec![int], int))
+        .unwrap();
+    let b = module.get_function("twice").unwrap();
+    let x = b.param(0).unwrap();
+    let y = b.iadd(x, x).unwrap();
+    b.ret(y).unwrap();
+    b.finalize().unwrap();
+
+    module
+        .declare_function("compute", Linkage::Export, Signature::new(vec![int], int))
+        .unwrap();
+    let b = module.get_function("compute").unwrap();
+    let x = b.param(0).unwrap();
+    let table = b.symbol_addr("table").unwrap();
+    let t = b.load(table, 8, int).unwrap();
+    let host = b.symbol_addr("host_value").unwrap();
+    let h = b.load(host, 0, int).unwrap();
+    let s = b.iadd(t, h).unwrap();
+    let s = b.iadd(s, x).unwrap();
+    let s = b.call("twice", &[s], int).unwrap();
+    let r = b.call("labs", &[s], int).unwrap();
+    b.ret(r).unwrap();
+    b.finalize().unwrap();
+}
+
+#[test]
+fn object_links_and_runs() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("object_links_and_runs");
+    fs::create_dir_all(&dir).unwrap();
+    let obj = dir.join("compute.o");
+    let main_c = dir.join("main.c");
+    let exe = dir.join("main");
+
+    let mut module = Module::new();
+    build(&mut module);
+    module.write_object(&obj).unwrap();
+    fs::write(&main_c, MAIN_C).unwrap();
+
+    let status = Command::new("cc")
+        .arg(&main_c)
+        .arg(&obj)
+        .arg("-o")
+        .arg(&exe)
+        .status()
+        .expect("running cc");
+    assert!(status.success());
+
+    let output = Command::new(&exe).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "160 30\n");
+}
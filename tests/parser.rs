@@ -0,0 +1,109 @@
+use peace::error::PeaceError;
+use peace::parser::parse_module;
+
+const SUM: &str = "function export sum(i64) -> i64 {
+block0(v0: i64):
+    v1 = iconst.i64 0
+    jump block1(v1, v1)
+block1(v2: i64, v3: i64):
+    v4 = icmp.lt v2, v0
+    brif v4, block2, block3
+block2:
+    v5 = iconst.i64 1
+    v6 = iadd.i64 v2, v5
+    v7 = iadd.i64 v3, v2
+    jump block1(v6, v7)
+block3:
+    return v3
+}
+";
+
+fn parse_error(text: &str) -> String {
+    match parse_module(text) {
+        Err(PeaceError::Parse(message)) => message,
+        Err(err) => panic!("expected a parse error, got {}", err),
+        Ok(_) => panic!("parsed {}", text),
+    }
+}
+
+#[test]
+fn printed_module_reads_back_unchanged() {
+    let module = parse_module(SUM).unwrap();
+    assert_eq!(module.to_string(), SUM);
+}
+
+#[test]
+fn sparse_numbers_are_renumbered() {
+    let text = "function export f(i64) -> i64 {
+block0(v0: i64):
+    v4000000000 = band_imm.i64 v0, 255
+    jump block4294967295(v4000000000)
+block4294967295(v4294967295: i64):
+    return v4294967295
+}
+";
+    let mut module = parse_module(text).unwrap();
+    assert_eq!(
+        module.to_string(),
+        "function export f(i64) -> i64 {
+block0(v0: i64):
+    v1 = band_imm.i64 v0, 255
+    jump block1(v1)
+block1(v2: i64):
+    return v2
+}
+"
+    );
+
+    module.get_function("f").unwrap().finalize().unwrap();
+    module.finish().unwrap();
+    let f = module.get_typed::<fn(i64) -> i64>("f").unwrap();
+    assert_eq!(f.call((0x1234,)), 0x34);
+}
+
+#[test]
+fn errors_name_the_line_of_the_instruction() {
+    let text = "function local f(i64) -> i64 {
+block0(v0: i64):
+    v1 = iconst.i64 1
+    v1 = iconst.i64 2
+    return v1
+}
+";
+    assert_eq!(parse_error(text), "line 4: v1 is defined twice");
+
+    let text = "function local f(i64) -> i64 {
+block0(v0: i64):
+    return v0
+block0:
+    return v0
+}
+";
+    assert_eq!(parse_error(text), "line 4: block0 is defined twice");
+}
+
+#[test]
+fn immediates_are_range_checked() {
+    let text = |inst: &str| {
+        format!(
+            "function local f(i64) -> i64 {{
+block0(v0: i64):
+    v1 = {}
+    return v1
+}}
+",
+            inst
+        )
+    };
+
+    let message = parse_error(&text("band_imm.i64 v0, 4294967296"));
+    assert!(
+        message.starts_with("line 3: immediate 4294967296"),
+        "{}",
+        message
+    );
+    assert!(parse_error(&text("ishl_imm.i64 v0, 64")).starts_with("line 3:"));
+    assert!(parse_error(&text("ushr_imm.i64 v0, -1")).starts_with("line 3:"));
+    parse_module(&text("band_imm.i64 v0, -2147483648")).unwrap();
+    parse_module(&text("ishl_imm.i64 v0, 63")).unwrap();
+}
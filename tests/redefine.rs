@@ -0,0 +1,100 @@
+use peace::error::PeaceError;
+use peace::module::{Linkage, Module};
+use peace::types::{Signature, Type};
+
+/// `foo` returning `value` and `caller` returning the result of `foo`, each
+/// placed by its own `finish`.
+fn module(indirect_calls: bool, value: i64) -> Module {
+    let int = Type::I64;
+    let mut module = Module::new();
+    module.set_indirect_calls(indirect_calls);
+    module
+        .declare_function("foo", Linkage::Local, Signature::new(vec![], int))
+        .unwrap();
+    let b = module.get_function("foo").unwrap();
+    let c = b.iconst(int, value).unwrap();
+    b.ret(c).unwrap();
+    b.finalize().unwrap();
+    module.finish().unwrap();
+
+    module
+        .declare_function("caller", Linkage::Local, Signature::new(vec![], int))
+        .unwrap();
+    let b = module.get_function("caller").unwrap();
+    let r = b.call("foo", &[], int).unwrap();
+    b.ret(r).unwrap();
+    b.finalize().unwrap();
+
+    module.finish().unwrap();
+    module
+}
+
+#[test]
+fn callers_see_the_new_definition() {
+    let int = Type::I64;
+    let mut module = module(true, 1);
+    let caller = module.get_typed::<fn() -> i64>("caller").unwrap();
+    let old_foo = module.get_typed::<fn() -> i64>("foo").unwrap();
+    assert_eq!(caller.call(()), 1);
+
+    module
+        .redefine_function("foo", |b| {
+            let c = b.iconst(int, 2)?;
+            b.ret(c)
+        })
+        .unwrap();
+    assert_eq!(caller.call(()), 2);
+    let foo = module.get_typed::<fn() -> i64>("foo").unwrap();
+    assert_eq!(foo.call(()), 2);
+    // the old code is kept while a handle to it is alive
+    assert_eq!(old_foo.call(()), 1);
+    assert_eq!(module.memory_stats().freed, 0);
+
+    // and while any other guard taken before the redefinition is
+    drop(old_foo);
+    module.free_retired_code().unwrap();
+    assert_eq!(module.memory_stats().freed, 0);
+    drop(caller);
+    module.free_retired_code().unwrap();
+    assert!(module.memory_stats().freed > 0);
+    let caller = module.get_typed::<fn() -> i64>("caller").unwrap();
+    assert_eq!(caller.call(()), 2);
+}
+
+#[test]
+fn failed_redefinition_keeps_the_old_code() {
+    let int = Type::I64;
+    let mut module = module(true, 1);
+    let err = module
+        .redefine_function("foo", |b| {
+            let c = b.fconst(Type::F64, 2.0)?;
+            b.ret(c)
+        })
+        .unwrap_err();
+    assert!(matches!(err, PeaceError::TypeMismatch(_)), "{:?}", err);
+    let caller = module.get_typed::<fn() -> i64>("caller").unwrap();
+    assert_eq!(caller.call(()), 1);
+
+    assert!(matches!(
+        module.redefine_function("bar", |b| {
+            let c = b.iconst(int, 2)?;
+            b.ret(c)
+        }),
+        Err(PeaceError::UnresolvedSymbol(_))
+    ));
+}
+
+#[test]
+fn direct_callers_prevent_redefinition() {
+    let int = Type::I64;
+    let mut module = module(false, 1);
+    assert!(matches!(
+        module.redefine_function("foo", |b| {
+            let c = b.iconst(int, 2)?;
+            b.ret(c)
+        }),
+        Err(PeaceError::Unsupported(_))
+    ));
+    let caller = module.get_typed::<fn() -> i64>("caller").unwrap();
+    assert_eq!(caller.call(()), 1);
+}
@@ -0,0 +1,105 @@
+use peace::backend::CondCode;
+use peace::function::OptLevel;
+use peace::module::{Linkage, Module};
+use peace::types::{Signature, Type};
+
+const INTS: usize = 8;
+const FLOATS: usize = 9;
+
+/// `f(n)` running a loop `n` times that updates `INTS` integer and `FLOATS`
+/// float block parameters, using as many constants defined before the loop.
+/// All of them are live across the back edge.
+fn pressure(opt_level: OptLevel) -> Module {
+    let (int, float) = (Type::I64, Type::F64);
+    let mut module = Module::new();
+    module
+        .declare_function("f", Linkage::Local, Signature::new(vec![int], int))
+        .unwrap();
+    let b = module.get_function("f").unwrap();
+    b.set_opt_level(opt_level);
+    let n = b.param(0).unwrap();
+
+    let mut int_steps = vec![];
+    for k in 0..INTS {
+        int_steps.push(b.iconst(int, k as i64 + 1).unwrap());
+    }
+    let mut float_steps = vec![];
+    for k in 0..FLOATS {
+        float_steps.push(b.fconst(float, k as f64 + 0.5).unwrap());
+    }
+    let zero = b.iconst(int, 0).unwrap();
+    let fzero = b.fconst(float, 0.0).unwrap();
+
+    let header = b.create_block();
+    let body = b.create_block();
+    let exit = b.create_block();
+    let i = b.append_block_param(header, int).unwrap();
+    let mut ints = vec![];
+    for _ in 0..INTS {
+        ints.push(b.append_block_param(header, int).unwrap());
+    }
+    let mut floats = vec![];
+    for _ in 0..FLOATS {
+        floats.push(b.append_block_param(header, float).unwrap());
+    }
+    let mut args = vec![zero; INTS + 1];
+    args.extend(vec![fzero; FLOATS]);
+    b.br(header, &args).unwrap();
+
+    b.switch_to_block(header).unwrap();
+    let c = b.int_cmp(i, n, CondCode::Less).unwrap();
+    b.brif(c, body, &[], exit, &[]).unwrap();
+
+    // a_k += (k + 1) * i and f_k += k + 0.5
+    b.switch_to_block(body).unwrap();
+    let one = b.iconst(int, 1).unwrap();
+    let mut args = vec![b.iadd(i, one).unwrap()];
+    for (a, step) in ints.iter().zip(int_steps.iter()) {
+        let d = b.imul(*step, i).unwrap();
+        args.push(b.iadd(*a, d).unwrap());
+    }
+    for (f, step) in floats.iter().zip(float_steps.iter()) {
+        args.push(b.fadd(*f, *step).unwrap());
+    }
+    b.br(header, &args).unwrap();
+
+    // sum of a_k * (k + 1) + f_k * (k + 1)
+    b.switch_to_block(exit).unwrap();
+    let mut sum = zero;
+    for (a, step) in ints.iter().zip(int_steps.iter()) {
+        let a = b.imul(*a, *step).unwrap();
+        sum = b.iadd(sum, a).unwrap();
+    }
+    let mut fsum = fzero;
+    for (k, f) in floats.iter().enumerate() {
+        let weight = b.fconst(float, k as f64 + 1.0).unwrap();
+        let f = b.fmul(*f, weight).unwrap();
+        fsum = b.fadd(fsum, f).unwrap();
+    }
+    let fsum = b.fcvt_to_sint(int, fsum).unwrap();
+    let sum = b.iadd(sum, fsum).unwrap();
+    b.ret(sum).unwrap();
+    b.finalize().unwrap();
+    module.finish().unwrap();
+    module
+}
+
+fn expected(n: i64) -> i64 {
+    let triangle = n * (n - 1) / 2;
+    let ints: i64 = (0..INTS as i64).map(|k| (k + 1) * (k + 1) * triangle).sum();
+    let floats: f64 = (0..FLOATS)
+        .map(|k| (k as f64 + 0.5) * n as f64 * (k as f64 + 1.0))
+        .sum();
+    ints + floats as i64
+}
+
+#[test]
+fn values_live_across_a_loop() {
+    for opt_level in [OptLevel::None, OptLevel::Speed].iter() {
+        let module = pressure(*opt_level);
+        let f = module.get_typed::<fn(i64) -> i64>("f").unwrap();
+        for n in [0, 1, 2, 10, 1000].iter() {
+            assert_eq!(f.call((*n,)), expected(*n), "{:?} {}", opt_level, n);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use peace::function::OptLevel;
+use peace::module::{register_symbol, Linkage, Module};
+use peace::types::{Signature, Type};
+use std::cell::Cell;
+
+const OFFSET: i32 = 8;
+const SLOT_SIZE: usize = 64;
+
+thread_local! {
+    static SNAPSHOT: Cell<[u8; SLOT_SIZE]> = Cell::new([0; SLOT_SIZE]);
+}
+
+/// Remember the contents of the stack slot at `ptr`.
+extern "C" fn snapshot(ptr: *const u8) {
+    let mut bytes = [0; SLOT_SIZE];
+    unsafe { std::ptr::copy_nonoverlapping(ptr, bytes.as_mut_ptr(), SLOT_SIZE) };
+    SNAPSHOT.with(|cell| cell.set(bytes));
+}
+
+/// `f(x)` storing `x + k` for k in 0..4 with `store_indexed` into a zeroed
+/// stack slot, taking a snapshot of it, and returning the sum of the
+/// values read back with `load_indexed`.
+fn store_and_load(scale: u8, opt_level: OptLevel) -> Module {
+    let int = Type::I64;
+    let ty = match scale {
+        1 | 2 => Type::I8,
+        4 => Type::I32,
+        _ => Type::I64,
+    };
+    let mut module = Module::new();
+    module
+        .declare_function(
+            "peace_test_snapshot",
+            Linkage::Import,
+            Signature::new(vec![Type::Pointer], Type::Void),
+        )
+        .unwrap();
+    module
+        .declare_function("f", Linkage::Local, Signature::new(vec![int], int))
+        .unwrap();
+    let b = module.get_function("f").unwrap();
+    b.set_opt_level(opt_level);
+    let x = b.param(0).unwrap();
+    let slot = b.stack_slot(SLOT_SIZE as u32, 8).unwrap();
+    let addr = b.stack_addr(slot).unwrap();
+    let zero = b.iconst(int, 0).unwrap();
+    for offset in (0..SLOT_SIZE as i32).step_by(8) {
+        b.store(zero, addr, offset).unwrap();
+    }
+
+    for k in 0..4 {
+        let c = b.iconst(int, k).unwrap();
+        let v = b.iadd(x, c).unwrap();
+        let v = match ty {
+            Type::I64 => v,
+            _ => b.ireduce(ty, v).unwrap(),
+        };
+        b.store_indexed(v, addr, c, scale, OFFSET).unwrap();
+    }
+    b.call("peace_test_snapshot", &[addr], Type::Void).unwrap();
+
+    let mut sum = zero;
+    for k in 0..4 {
+        let c = b.iconst(int, k).unwrap();
+        let v = b.load_indexed(addr, c, scale, OFFSET, ty).unwrap();
+        let v = match ty {
+            Type::I64 => v,
+            _ => b.uextend(int, v).unwrap(),
+        };
+        sum = b.iadd(sum, v).unwrap();
+    }
+    b.ret(sum).unwrap();
+    b.finalize().unwrap();
+    module.finish().unwrap();
+    module
+}
+
+#[test]
+fn indexed_stores_into_a_stack_slot() {
+    register_symbol("peace_test_snapshot", snapshot as *const u8);
+    let x: i64 = 0x1234_5678_9abc_defe;
+    for opt_level in [OptLevel::None, OptLevel::Speed].iter() {
+        for scale in [1u8, 2, 4, 8].iter() {
+            let module = store_and_load(*scale, *opt_level);
+            let f = module.get_typed::<fn(i64) -> i64>("f").unwrap();
+            let sum = f.call((x,));
+
+            let size = match scale {
+                1 | 2 => 1,
+                4 => 4,
+                _ => 8,
+            };
+            let mut expected = [0u8; SLOT_SIZE];
+            let mut expected_sum = 0i64;
+            for k in 0..4 {
+                let v = x + k;
+                let at = OFFSET as usize + k as usize * *scale as usize;
+                expected[at..at + size].copy_from_slice(&v.to_le_bytes()[..size]);
+                expected_sum += match size {
+                    1 => v as u8 as i64,
+                    4 => v as u32 as i64,
+                    _ => v,
+                };
+            }
+            let what = format!("{:?}, scale {}", opt_level, scale);
+            assert_eq!(SNAPSHOT.with(|cell| cell.get()), expected, "{}", what);
+            assert_eq!(sum, expected_sum, "{}", what);
+        }
+    }
+}
+
+/// Set `ptr[k] = k * k + 1` for k in 0..n.
+extern "C" fn fill(ptr: *mut i64, n: i64) {
+    for k in 0..n {
+        unsafe { *ptr.add(k as usize) = k * k + 1 };
+    }
+}
+
+#[test]
+fn stack_slots_are_written_by_callees() {
+    let (int, ptr) = (Type::I64, Type::Pointer);
+    const N: i64 = 6;
+    register_symbol("peace_test_fill", fill as *const u8);
+
+    for opt_level in [OptLevel::None, OptLevel::Speed].iter() {
+        let mut module = Module::new();
+        module
+            .declare_function(
+                "peace_test_fill",
+                Linkage::Import,
+                Signature::new(vec![ptr, int], Type::Void),
+            )
+            .unwrap();
+        module
+            .declare_function("f", Linkage::Local, Signature::new(vec![int], int))
+            .unwrap();
+        let b = module.get_function("f").unwrap();
+        b.set_opt_level(*opt_level);
+        let index = b.param(0).unwrap();
+
+        // a second slot written before the call has to survive it
+        let filled = b.stack_slot(8 * N as u32, 8).unwrap();
+        let kept = b.stack_slot(8, 8).unwrap();
+        let kept_addr = b.stack_addr(kept).unwrap();
+        let marker = b.iconst(int, 1000).unwrap();
+        b.store(marker, kept_addr, 0).unwrap();
+
+        let addr = b.stack_addr(filled).unwrap();
+        let n = b.iconst(int, N).unwrap();
+        b.call("peace_test_fill", &[addr, n], Type::Void).unwrap();
+
+        // sum of the filled elements, the one at `index` and the marker
+        let mut sum = b.load(kept_addr, 0, int).unwrap();
+        let addr = b.stack_addr(filled).unwrap();
+        for k in 0..N {
+            let v = b.load(addr, 8 * k as i32, int).unwrap();
+            sum = b.iadd(sum, v).unwrap();
+        }
+        let v = b.load_indexed(addr, index, 8, 0, int).unwrap();
+        let sum = b.iadd(sum, v).unwrap();
+        b.ret(sum).unwrap();
+        b.finalize().unwrap();
+        module.finish().unwrap();
+
+        let f = module.get_typed::<fn(i64) -> i64>("f").unwrap();
+        let total: i64 = 1000 + (0..N).map(|k| k * k + 1).sum::<i64>();
+        for index in 0..N {
+            assert_eq!(
+                f.call((index,)),
+                total + index * index + 1,
+                "{:?}",
+                opt_level
+            );
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::sync::Arc;
+use std::thread;
+
+use peace::error::PeaceError;
+use peace::function::Function;
+use peace::module::{Linkage, Module};
+use peace::types::{Signature, Type};
+
+/// `name(x)` returning `x * factor`, not finalized yet.
+fn times(name: &str, factor: i64) -> Function {
+    let int = Type::I64;
+    let mut func = Function::new(name, Linkage::Local, Signature::new(vec![int], int));
+    let x = func.param(0).unwrap();
+    let f = func.iconst(int, factor).unwrap();
+    let r = func.imul(x, f).unwrap();
+    func.ret(r).unwrap();
+    func
+}
+
+#[test]
+fn functions_built_on_worker_threads() {
+    let workers: Vec<_> = (0..8)
+        .map(|idx| {
+            thread::spawn(move || {
+                let mut func = times(&format!("times{}", idx), idx);
+                func.finalize().unwrap();
+                func
+            })
+        })
+        .collect();
+
+    let mut module = Module::new();
+    for worker in workers {
+        module.define_function(worker.join().unwrap()).unwrap();
+    }
+    module.finish().unwrap();
+    for idx in 0..8 {
+        let f = module
+            .get_typed::<fn(i64) -> i64>(&format!("times{}", idx))
+            .unwrap();
+        assert_eq!(f.call((3,)), 3 * idx);
+    }
+}
+
+#[test]
+fn finalize_functions_in_parallel() {
+    let mut module = Module::new();
+    for idx in 0..64 {
+        module
+            .define_function(times(&format!("times{}", idx), idx))
+            .unwrap();
+    }
+    module.finalize_functions().unwrap();
+    let module = Arc::new(module.compile().unwrap());
+
+    let callers: Vec<_> = (0..4)
+        .map(|thread| {
+            let module = module.clone();
+            thread::spawn(move || {
+                for idx in 0..64 {
+                    let f = module
+                        .get_typed::<fn(i64) -> i64>(&format!("times{}", idx))
+                        .unwrap();
+                    assert_eq!(f.call((thread,)), thread * idx);
+                }
+            })
+        })
+        .collect();
+    for caller in callers {
+        caller.join().unwrap();
+    }
+}
+
+#[test]
+fn finalize_functions_reports_the_first_failure() {
+    let int = Type::I64;
+    let mut module = Module::new();
+    for idx in 0..64 {
+        module
+            .define_function(times(&format!("f{:02}", idx), idx))
+            .unwrap();
+    }
+    let sig = Signature::new(vec![int], int);
+    // f10 does not return, f50 jumps to a label that is never bound
+    let mut func = Function::new("f10", Linkage::Local, sig.clone());
+    func.iconst(int, 1).unwrap();
+    module.define_function(func).unwrap();
+    let mut func = Function::new("f50", Linkage::Local, sig);
+    func.new_label("nowhere").unwrap();
+    func.jump("nowhere").unwrap();
+    module.define_function(func).unwrap();
+
+    let err = module.finalize_functions().unwrap_err();
+    assert!(matches!(err, PeaceError::Verifier(_)), "{:?}", err);
+    assert!(err.to_string().contains("missing return"), "{}", err);
+}
@@ -0,0 +1,98 @@
+use peace::error::{PeaceError, Result};
+use peace::function::Function;
+use peace::module::{Linkage, Module};
+use peace::trap::{catch_traps, TrapCode, TrapPolicy};
+use peace::types::{Signature, Type, Value};
+
+const POLICIES: [TrapPolicy; 2] = [TrapPolicy::Hardware, TrapPolicy::Checked];
+
+/// `name(x, y)` computing `op(x, y)` on `ty` with `policy`.
+fn define<F>(module: &mut Module, name: &str, ty: Type, policy: TrapPolicy, op: F)
+where
+    F: FnOnce(&mut Function, Value, Value) -> Result<Value>,
+{
+    module
+        .declare_function(name, Linkage::Local, Signature::new(vec![ty, ty], ty))
+        .unwrap();
+    let b = module.get_function(name).unwrap();
+    b.set_trap_policy(policy);
+    let x = b.param(0).unwrap();
+    let y = b.param(1).unwrap();
+    let r = op(b, x, y).unwrap();
+    b.ret(r).unwrap();
+    b.finalize().unwrap();
+}
+
+#[test]
+fn i64_division() {
+    for policy in POLICIES.iter() {
+        let mut module = Module::new();
+        define(&mut module, "div", Type::I64, *policy, |b, x, y| {
+            b.idiv(x, y)
+        });
+        define(&mut module, "rem", Type::I64, *policy, |b, x, y| {
+            b.imod(x, y)
+        });
+        define(&mut module, "udiv", Type::I64, *policy, |b, x, y| {
+            b.udiv(x, y)
+        });
+        module.finish().unwrap();
+        let div = module.get_typed::<fn(i64, i64) -> i64>("div").unwrap();
+        let rem = module.get_typed::<fn(i64, i64) -> i64>("rem").unwrap();
+        let udiv = module.get_typed::<fn(i64, i64) -> i64>("udiv").unwrap();
+
+        assert_eq!(catch_traps(|| div.call((-7, 2))), Ok(-3));
+        assert_eq!(catch_traps(|| rem.call((-7, 2))), Ok(-1));
+        assert_eq!(
+            catch_traps(|| rem.call((i64::MIN, -1))),
+            Ok(0),
+            "{:?}",
+            policy
+        );
+        assert_eq!(catch_traps(|| udiv.call((-1, 2))), Ok(i64::MAX));
+        assert_eq!(
+            catch_traps(|| div.call((i64::MIN, -1))),
+            Err(PeaceError::Trap(TrapCode::IntegerOverflow))
+        );
+        assert_eq!(
+            catch_traps(|| div.call((1, 0))),
+            Err(PeaceError::Trap(TrapCode::IntegerDivisionByZero))
+        );
+        assert_eq!(
+            catch_traps(|| rem.call((1, 0))),
+            Err(PeaceError::Trap(TrapCode::IntegerDivisionByZero))
+        );
+    }
+}
+
+#[test]
+fn i32_division() {
+    for policy in POLICIES.iter() {
+        let mut module = Module::new();
+        define(&mut module, "div", Type::I32, *policy, |b, x, y| {
+            b.idiv(x, y)
+        });
+        define(&mut module, "rem", Type::I32, *policy, |b, x, y| {
+            b.imod(x, y)
+        });
+        module.finish().unwrap();
+        let div = module.get_typed::<fn(i32, i32) -> i32>("div").unwrap();
+        let rem = module.get_typed::<fn(i32, i32) -> i32>("rem").unwrap();
+
+        assert_eq!(
+            catch_traps(|| rem.call((i32::MIN, -1))),
+            Ok(0),
+            "{:?}",
+            policy
+        );
+        assert_eq!(catch_traps(|| rem.call((7, -1))), Ok(0));
+        assert_eq!(
+            catch_traps(|| div.call((i32::MIN, -1))),
+            Err(PeaceError::Trap(TrapCode::IntegerOverflow))
+        );
+        assert_eq!(
+            catch_traps(|| rem.call((1, 0))),
+            Err(PeaceError::Trap(TrapCode::IntegerDivisionByZero))
+        );
+    }
+}
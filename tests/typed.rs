@@ -0,0 +1,76 @@
+use peace::error::PeaceError;
+use peace::module::{Linkage, Module};
+use peace::types::{Signature, Type};
+
+/// `f(x, y)` returning `x + y as i64` and `nothing()`.
+fn module() -> Module {
+    let mut module = Module::new();
+    module
+        .declare_function(
+            "f",
+            Linkage::Local,
+            Signature::new(vec![Type::I64, Type::F64], Type::I64),
+        )
+        .unwrap();
+    let b = module.get_function("f").unwrap();
+    let x = b.param(0).unwrap();
+    let y = b.param(1).unwrap();
+    let y = b.fcvt_to_sint(Type::I64, y).unwrap();
+    let r = b.iadd(x, y).unwrap();
+    b.ret(r).unwrap();
+    b.finalize().unwrap();
+
+    module
+        .declare_function(
+            "nothing",
+            Linkage::Local,
+            Signature::new(vec![], Type::Void),
+        )
+        .unwrap();
+    let b = module.get_function("nothing").unwrap();
+    b.ret_void().unwrap();
+    b.finalize().unwrap();
+
+    module.finish().unwrap();
+    module
+}
+
+fn is_mismatch<T>(result: Result<T, PeaceError>) -> bool {
+    matches!(result, Err(PeaceError::TypeMismatch(_)))
+}
+
+#[test]
+fn matching_signatures_are_accepted() {
+    let module = module();
+    let f = module.get_typed::<fn(i64, f64) -> i64>("f").unwrap();
+    assert_eq!(f.call((40, 2.5)), 42);
+    let f = module
+        .get_typed::<extern "C" fn(u64, f64) -> isize>("f")
+        .unwrap();
+    assert_eq!(f.call((1, -3.0)), -2);
+    let nothing = module.get_typed::<fn()>("nothing").unwrap();
+    nothing.call(());
+}
+
+#[test]
+fn mismatched_signatures_are_rejected() {
+    let module = module();
+    assert!(is_mismatch(module.get_typed::<fn(i64) -> i64>("f")));
+    assert!(is_mismatch(module.get_typed::<fn(f64, i64) -> i64>("f")));
+    assert!(is_mismatch(module.get_typed::<fn(i32, f64) -> i64>("f")));
+    assert!(is_mismatch(module.get_typed::<fn(i64, f64) -> f64>("f")));
+    assert!(is_mismatch(module.get_typed::<fn(i64, f64)>("f")));
+    assert!(is_mismatch(module.get_typed::<fn() -> i64>("nothing")));
+    assert!(matches!(
+        module.get_typed::<fn()>("missing"),
+        Err(PeaceError::UnresolvedSymbol(_))
+    ));
+}
+
+#[test]
+fn handles_outlive_the_module() {
+    let module = module();
+    let f = module.get_typed::<fn(i64, f64) -> i64>("f").unwrap();
+    drop(module);
+    assert_eq!(f.call((1, 1.0)), 2);
+}
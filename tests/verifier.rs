@@ -0,0 +1,176 @@
+use peace::ir::{BinaryOp, Body, InstData};
+use peace::types::{Block, Signature, Type, Value};
+use peace::verifier::{verify, VerifierError};
+
+/// A body whose entry block takes an I64 parameter `v0`.
+fn entry_body() -> (Body, Block, Value) {
+    let mut body = Body::new();
+    let entry = body.make_block();
+    body.layout.push(entry);
+    let x = body.make_value(Type::I64);
+    body.blocks[entry.0 as usize].params.push(x);
+    (body, entry, x)
+}
+
+fn messages(errors: Vec<VerifierError>) -> Vec<String> {
+    errors.into_iter().map(|error| error.message).collect()
+}
+
+fn ret(body: &mut Body, block: Block, value: Value) {
+    body.push_inst(block, InstData::Return { value: Some(value) }, None);
+}
+
+#[test]
+fn branch_to_entry_block() {
+    let (mut body, entry, x) = entry_body();
+    let other = body.make_block();
+    body.layout.push(other);
+    body.push_inst(
+        entry,
+        InstData::Jump {
+            block: other,
+            args: vec![],
+        },
+        None,
+    );
+    body.push_inst(
+        other,
+        InstData::Jump {
+            block: entry,
+            args: vec![x],
+        },
+        None,
+    );
+
+    let sig = Signature::new(vec![Type::I64], Type::I64);
+    let errors = messages(verify(&body, &sig).unwrap_err());
+    assert_eq!(errors, ["branch to the entry block block0"]);
+}
+
+#[test]
+fn binary_imm_out_of_range() {
+    let sig = Signature::new(vec![Type::I64], Type::I64);
+    let cases = [
+        (BinaryOp::BAnd, 1 << 32, false),
+        (BinaryOp::BAnd, i32::MIN as i64, true),
+        (BinaryOp::BOr, i32::MAX as i64 + 1, false),
+        (BinaryOp::IShl, 63, true),
+        (BinaryOp::IShl, 64, false),
+        (BinaryOp::UShr, -1, false),
+    ];
+    for (op, imm, ok) in cases.iter() {
+        let (mut body, entry, x) = entry_body();
+        let y = body.make_value(Type::I64);
+        body.push_inst(
+            entry,
+            InstData::BinaryImm {
+                op: *op,
+                x,
+                imm: *imm,
+            },
+            Some(y),
+        );
+        ret(&mut body, entry, y);
+        assert_eq!(verify(&body, &sig).is_ok(), *ok, "{:?} {}", op, imm);
+    }
+}
+
+#[test]
+fn untyped_operand_is_reported_once() {
+    let (mut body, entry, x) = entry_body();
+    let missing = Value(100);
+    let y = body.make_value(Type::I64);
+    body.push_inst(
+        entry,
+        InstData::Binary {
+            op: BinaryOp::IAdd,
+            x,
+            y: missing,
+        },
+        Some(y),
+    );
+    let z = body.make_value(Type::I64);
+    body.push_inst(
+        entry,
+        InstData::Binary {
+            op: BinaryOp::IAdd,
+            x: y,
+            y: missing,
+        },
+        Some(z),
+    );
+    ret(&mut body, entry, z);
+
+    let sig = Signature::new(vec![Type::I64], Type::I64);
+    let errors = messages(verify(&body, &sig).unwrap_err());
+    assert_eq!(errors, ["v100 is used but never defined"]);
+}
+
+#[test]
+fn void_operand_is_reported_once() {
+    let (mut body, entry, x) = entry_body();
+    let void = body.make_value(Type::Void);
+    body.push_inst(
+        entry,
+        InstData::Call {
+            name: "f".to_owned(),
+            args: vec![],
+            signature: Signature::new(vec![], Type::Void),
+        },
+        Some(void),
+    );
+    let y = body.make_value(Type::I64);
+    body.push_inst(
+        entry,
+        InstData::Binary {
+            op: BinaryOp::IAdd,
+            x,
+            y: void,
+        },
+        Some(y),
+    );
+    let z = body.make_value(Type::I64);
+    body.push_inst(
+        entry,
+        InstData::Binary {
+            op: BinaryOp::IAdd,
+            x: y,
+            y: void,
+        },
+        Some(z),
+    );
+    ret(&mut body, entry, z);
+
+    let sig = Signature::new(vec![Type::I64], Type::I64);
+    let errors = messages(verify(&body, &sig).unwrap_err());
+    assert_eq!(errors, ["v1 of type Void used as operand"]);
+}
+
+#[test]
+fn types_are_printed_without_option() {
+    let (mut body, entry, x) = entry_body();
+    let f = body.make_value(Type::F64);
+    body.push_inst(
+        entry,
+        InstData::FConst {
+            ty: Type::F64,
+            imm: 1.0,
+        },
+        Some(f),
+    );
+    let y = body.make_value(Type::I64);
+    body.push_inst(
+        entry,
+        InstData::Binary {
+            op: BinaryOp::IAdd,
+            x,
+            y: f,
+        },
+        Some(y),
+    );
+    ret(&mut body, entry, y);
+
+    let sig = Signature::new(vec![Type::I64], Type::I64);
+    let errors = messages(verify(&body, &sig).unwrap_err());
+    assert_eq!(errors[0], "IAdd of I64 and F64");
+}